@@ -0,0 +1,113 @@
+//! Unguessable document ids and the capability tokens that guard them.
+//!
+//! Opening a document returns its id and a token. Every later request on
+//! the document must present the token, as `Authorization: Bearer <token>`
+//! or, where headers cannot be set (WebSocket handshakes, plain links), as
+//! a `token` query parameter.
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts};
+
+/// Random bytes in a document id.
+const ID_BYTES: usize = 16;
+/// Random bytes in a capability token.
+const TOKEN_BYTES: usize = 32;
+
+/// `len` random bytes from the operating system, hex-encoded.
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    getrandom::getrandom(&mut bytes).expect("the operating system provides randomness");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// A new 128-bit id for a document or upload.
+pub fn new_id() -> String {
+    random_hex(ID_BYTES)
+}
+
+pub fn new_token() -> String {
+    random_hex(TOKEN_BYTES)
+}
+
+/// The token a request presents, if any.
+#[derive(Debug, Clone, Default)]
+pub struct Capability(Option<String>);
+
+impl Capability {
+    /// Whether this is `token`. Takes as long for any wrong token of the
+    /// right length, so timing does not reveal how much of it matched.
+    pub fn grants(&self, token: &str) -> bool {
+        let Some(presented) = &self.0 else {
+            return false;
+        };
+        presented.len() == token.len()
+            && presented
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Capability {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        // Tokens are hex, so the query value needs no decoding.
+        let query = parts.uri.query().and_then(|query| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+        });
+        Ok(Capability(
+            bearer.or(query).map(|token| token.trim().to_string()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    async fn presented(request: Request<()>) -> Capability {
+        let (mut parts, ()) = request.into_parts();
+        Capability::from_request_parts(&mut parts, &())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_come_from_the_header_or_the_query() {
+        let token = new_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(new_id(), new_id());
+
+        let header = Request::builder()
+            .uri("/api/pdf/x")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(())
+            .unwrap();
+        assert!(presented(header).await.grants(&token));
+        let query = Request::builder()
+            .uri(format!("/api/ws/x?v=1&token={token}"))
+            .body(())
+            .unwrap();
+        assert!(presented(query).await.grants(&token));
+
+        let none = Request::builder().uri("/api/pdf/x").body(()).unwrap();
+        assert!(!presented(none).await.grants(&token));
+        let wrong = Request::builder()
+            .uri(format!("/api/pdf/x?token={}", new_token()))
+            .body(())
+            .unwrap();
+        assert!(!presented(wrong).await.grants(&token));
+    }
+}
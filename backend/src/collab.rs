@@ -0,0 +1,550 @@
+//! Real-time collaboration over WebSocket.
+//!
+//! Everyone connected to a document shares a room. Batches applied to the
+//! document, over the socket or over HTTP, are broadcast to the room, as
+//! are each collaborator's name and selection.
+//!
+//! A batch names the revision it was made against. When the document has
+//! moved on since, the batch is still applied as long as none of the
+//! objects it edits were edited in between; otherwise it is rejected, so
+//! of two edits to the same object the one that reached the server first
+//! wins.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{Message, WebSocket};
+use tokio::sync::broadcast;
+
+use crate::pdf::patch::{target_of, BatchError};
+use crate::types::{ClientMessage, PatchOperation, PatchTarget, Peer, ServerMessage};
+use crate::{lifecycle, ApiError, AppState};
+
+/// How many recent changes a document remembers for rebasing.
+const CHANGE_LOG_LEN: usize = 64;
+/// Messages buffered per collaborator before they fall behind.
+const ROOM_CAPACITY: usize = 256;
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The rooms of all documents someone is connected to.
+#[derive(Clone, Default)]
+pub struct Rooms(Arc<Mutex<HashMap<String, Arc<Room>>>>);
+
+pub struct Room {
+    events: broadcast::Sender<ServerMessage>,
+    peers: Mutex<BTreeMap<String, Peer>>,
+}
+
+impl Rooms {
+    /// Add `peer` to the room of `doc_id`, opening it if need be, and
+    /// tell everyone there.
+    fn join(&self, doc_id: &str, peer: Peer) -> Arc<Room> {
+        let mut rooms = self.0.lock().unwrap();
+        let room = rooms.entry(doc_id.to_string()).or_insert_with(|| {
+            Arc::new(Room {
+                events: broadcast::channel(ROOM_CAPACITY).0,
+                peers: Mutex::default(),
+            })
+        });
+        let client_id = peer.client_id.clone();
+        room.peers.lock().unwrap().insert(client_id.clone(), peer);
+        room.update_peer(&client_id, |_| {});
+        room.clone()
+    }
+
+    /// Remove `client_id` from the room of `doc_id`, closing the room when
+    /// it was the last one there.
+    fn leave(&self, doc_id: &str, client_id: &str) {
+        let mut rooms = self.0.lock().unwrap();
+        let Some(room) = rooms.get(doc_id) else {
+            return;
+        };
+        let mut peers = room.peers.lock().unwrap();
+        peers.remove(client_id);
+        if peers.is_empty() {
+            drop(peers);
+            rooms.remove(doc_id);
+        } else {
+            let peers = peers.values().cloned().collect();
+            let _ = room.events.send(ServerMessage::Presence { peers });
+        }
+    }
+
+    /// Send `message` to everyone connected to `doc_id`, if anyone is.
+    pub fn broadcast(&self, doc_id: &str, message: ServerMessage) {
+        if let Some(room) = self.0.lock().unwrap().get(doc_id) {
+            // Fails only when nobody is subscribed.
+            let _ = room.events.send(message);
+        }
+    }
+}
+
+impl Room {
+    /// Change this collaborator's entry and tell everyone.
+    fn update_peer(&self, client_id: &str, update: impl FnOnce(&mut Peer)) {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get_mut(client_id) {
+            update(peer);
+        }
+        let peers = peers.values().cloned().collect();
+        let _ = self.events.send(ServerMessage::Presence { peers });
+    }
+}
+
+/// Why a batch cannot be applied on top of the changes made since its
+/// base revision.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum Conflict {
+    #[error("objects edited by the batch changed since revision {base}")]
+    Objects {
+        base: u64,
+        targets: Vec<PatchTarget>,
+    },
+    #[error("a batch was undone or redone since revision {base}")]
+    HistoryStepped { base: u64 },
+    #[error("revision {base} is not a recent revision of the document")]
+    UnknownBase { base: u64 },
+}
+
+/// The most recent changes to a document, newest last.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeLog {
+    entries: VecDeque<Change>,
+}
+
+#[derive(Debug, Clone)]
+struct Change {
+    /// The revision the change produced.
+    revision: u64,
+    /// The batch applied, or `None` for an undo or redo.
+    ops: Option<Vec<PatchOperation>>,
+}
+
+impl ChangeLog {
+    /// Record the change that brought the document to `revision`.
+    pub fn record(&mut self, revision: u64, ops: Option<Vec<PatchOperation>>) {
+        if self.entries.len() == CHANGE_LOG_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Change { revision, ops });
+    }
+
+    /// Check that `ops`, made against revision `base`, can be applied to
+    /// the document at revision `current`.
+    ///
+    /// Object ids are positions in paint order, so a delete, a reorder
+    /// or emptying a text object shifts the ids of other objects on its
+    /// page. A batch conflicts with an earlier one when it targets an
+    /// object that one targeted, or any object on a page where that one
+    /// shifted ids.
+    /// Insertions add objects at the end and shift nothing.
+    pub fn rebase(&self, base: u64, current: u64, ops: &[PatchOperation]) -> Result<(), Conflict> {
+        if base == current {
+            return Ok(());
+        }
+        let since: Vec<&Change> = self
+            .entries
+            .iter()
+            .filter(|change| change.revision > base)
+            .collect();
+        if base > current || since.len() as u64 != current - base {
+            return Err(Conflict::UnknownBase { base });
+        }
+        let mut edited = HashSet::new();
+        let mut shifted = HashSet::new();
+        for change in since {
+            let Some(ops) = &change.ops else {
+                return Err(Conflict::HistoryStepped { base });
+            };
+            for op in ops {
+                let Some(target) = target_of(op) else {
+                    continue;
+                };
+                if shifts_ids(op) {
+                    shifted.insert(target.page);
+                }
+                edited.insert(target);
+            }
+        }
+        let mut targets = Vec::new();
+        for target in ops.iter().filter_map(target_of) {
+            let conflicting = edited.contains(target) || shifted.contains(&target.page);
+            if conflicting && !targets.contains(target) {
+                targets.push(target.clone());
+            }
+        }
+        if targets.is_empty() {
+            Ok(())
+        } else {
+            Err(Conflict::Objects { base, targets })
+        }
+    }
+}
+
+/// Whether `op` removes or moves objects on its page, renumbering the
+/// positional ids of those after it. Editing a text object to nothing
+/// drops it like a delete.
+fn shifts_ids(op: &PatchOperation) -> bool {
+    match op {
+        PatchOperation::Delete { .. } | PatchOperation::Reorder { .. } => true,
+        PatchOperation::EditText { text, .. } => text.is_empty(),
+        _ => false,
+    }
+}
+
+/// Serve one collaborator on `socket` until they disconnect.
+pub async fn session(mut socket: WebSocket, state: AppState, doc_id: String) {
+    let client_id = format!(
+        "client-{:04}",
+        NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let peer = Peer {
+        client_id: client_id.clone(),
+        name: None,
+        selection: Vec::new(),
+    };
+    let room = state.rooms.join(&doc_id, peer);
+    let mut events = room.events.subscribe();
+    tracing::info!(doc_id, client_id, "collaborator joined");
+
+    let revision = match state.store.read().await.get(&doc_id) {
+        Some(entry) => entry.revision,
+        None => {
+            state.rooms.leave(&doc_id, &client_id);
+            return;
+        }
+    };
+    let peers = room.peers.lock().unwrap().values().cloned().collect();
+    let welcome = ServerMessage::Welcome {
+        client_id: client_id.clone(),
+        revision,
+        peers,
+    };
+    if send(&mut socket, &welcome).await.is_ok() {
+        loop {
+            let reply = tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        handle(&state, &room, &doc_id, &client_id, &text).await
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => None,
+                },
+                event = events.recv() => match event {
+                    Ok(event @ ServerMessage::Closed { .. }) => {
+                        let _ = send(&mut socket, &event).await;
+                        break;
+                    }
+                    Ok(event) => Some(event),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!(doc_id, client_id, skipped, "collaborator fell behind");
+                        let store = state.store.read().await;
+                        let Some(entry) = store.get(&doc_id) else { break };
+                        Some(ServerMessage::Resync { revision: entry.revision })
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+            if let Some(reply) = reply {
+                if send(&mut socket, &reply).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    state.rooms.leave(&doc_id, &client_id);
+    tracing::info!(doc_id, client_id, "collaborator left");
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).expect("server messages serialize");
+    socket.send(Message::Text(text)).await
+}
+
+/// Act on one message from `client_id`. Returns the reply meant for them
+/// alone; anything for the whole room is broadcast.
+async fn handle(
+    state: &AppState,
+    room: &Room,
+    doc_id: &str,
+    client_id: &str,
+    text: &str,
+) -> Option<ServerMessage> {
+    let message = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(err) => {
+            return Some(ServerMessage::Error {
+                message: format!("invalid message: {err}"),
+            })
+        }
+    };
+    match message {
+        ClientMessage::Hello { name } => {
+            room.update_peer(client_id, |peer| peer.name = Some(name));
+            None
+        }
+        ClientMessage::Select { targets } => {
+            room.update_peer(client_id, |peer| peer.selection = targets);
+            None
+        }
+        ClientMessage::Patch {
+            request_id,
+            base_revision,
+            ops,
+        } => submit(state, doc_id, client_id, request_id, base_revision, ops).await,
+    }
+}
+
+/// Apply a batch from `client_id`, rebasing it onto the current revision.
+/// Returns the rejection when it is not applied.
+async fn submit(
+    state: &AppState,
+    doc_id: &str,
+    client_id: &str,
+    request_id: Option<String>,
+    base: u64,
+    ops: Vec<PatchOperation>,
+) -> Option<ServerMessage> {
+    let origin = (Some(client_id.to_string()), request_id.clone());
+    loop {
+        let snapshot = {
+            let store = state.store.read().await;
+            // Access was checked when the socket was opened.
+            let Some(entry) = store.get(doc_id) else {
+                return Some(ServerMessage::Error {
+                    message: ApiError::NotFound.to_string(),
+                });
+            };
+            entry.touch();
+            if let Err(conflict) = entry.changes.rebase(base, entry.revision, &ops) {
+                tracing::info!(doc_id, client_id, %conflict, "batch conflicts");
+                let conflicts = match &conflict {
+                    Conflict::Objects { targets, .. } => targets.clone(),
+                    _ => Vec::new(),
+                };
+                return Some(ServerMessage::Rejected {
+                    request_id,
+                    revision: entry.revision,
+                    message: conflict.to_string(),
+                    conflicts,
+                    errors: Vec::new(),
+                });
+            }
+            entry.snapshot(&ops)
+        };
+        let revision = snapshot.revision;
+        let (message, errors) = match crate::patch_snapshot(state, snapshot, &ops).await {
+            Ok(outcome) => {
+                let mut store = state.store.write().await;
+                let Some(entry) = store.get_mut(doc_id) else {
+                    return Some(ServerMessage::Error {
+                        message: ApiError::NotFound.to_string(),
+                    });
+                };
+                if entry.revision != revision {
+                    // Another batch landed while this one was applied;
+                    // rebase onto it and apply again.
+                    continue;
+                }
+                let (_, saved) = crate::commit_batch(state, doc_id, entry, ops, outcome, origin);
+                lifecycle::enforce_quota(state, &mut store, doc_id);
+                drop(store);
+                if let Some(saved) = saved {
+                    crate::log_unsaved(doc_id, saved.wait().await);
+                }
+                return None;
+            }
+            Err(ApiError::Patch(BatchError::Rejected(failures))) => (
+                "patch batch rejected; nothing was applied".to_string(),
+                crate::op_errors(&failures),
+            ),
+            Err(err) => {
+                tracing::error!(doc_id, client_id, error = %err, "patch failed");
+                ("internal error".to_string(), Vec::new())
+            }
+        };
+        return Some(ServerMessage::Rejected {
+            request_id,
+            revision,
+            message,
+            conflicts: Vec::new(),
+            errors,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::testing::TestPdf;
+    use crate::types::StylePayload;
+    use crate::DocumentEntry;
+
+    fn target(page: usize, id: &str) -> PatchTarget {
+        PatchTarget {
+            page,
+            id: id.to_string(),
+        }
+    }
+
+    fn delete(page: usize, id: &str) -> PatchOperation {
+        PatchOperation::Delete {
+            target: target(page, id),
+        }
+    }
+
+    fn translate(page: usize, id: &str) -> PatchOperation {
+        PatchOperation::Transform {
+            target: target(page, id),
+            delta_matrix_pt: [1.0, 0.0, 0.0, 1.0, 5.0, 0.0],
+            kind: "path".to_string(),
+        }
+    }
+
+    #[test]
+    fn batches_rebase_unless_an_object_they_edit_changed() {
+        let mut log = ChangeLog::default();
+        log.record(1, Some(vec![translate(0, "p:1")]));
+        log.record(2, Some(vec![delete(1, "t:0")]));
+
+        assert_eq!(log.rebase(2, 2, &[delete(1, "t:3")]), Ok(()));
+        // Other objects on an untouched page, or on a page that was only
+        // edited in place, merge.
+        assert_eq!(log.rebase(0, 2, &[translate(0, "p:2")]), Ok(()));
+        assert_eq!(log.rebase(1, 2, &[translate(0, "p:1")]), Ok(()));
+        assert_eq!(
+            log.rebase(0, 2, &[translate(0, "p:1"), translate(1, "t:4")]),
+            Err(Conflict::Objects {
+                base: 0,
+                targets: vec![target(0, "p:1"), target(1, "t:4")],
+            })
+        );
+
+        log.record(3, None);
+        assert_eq!(
+            log.rebase(2, 3, &[translate(2, "p:0")]),
+            Err(Conflict::HistoryStepped { base: 2 })
+        );
+        assert_eq!(
+            log.rebase(4, 3, &[]),
+            Err(Conflict::UnknownBase { base: 4 })
+        );
+    }
+
+    #[test]
+    fn emptying_a_text_object_shifts_ids_like_a_delete() {
+        let edit = |text: &str| PatchOperation::EditText {
+            target: target(0, "t:0"),
+            text: text.to_string(),
+            font_pref: None,
+        };
+        let mut log = ChangeLog::default();
+        log.record(1, Some(vec![edit("Hello")]));
+        assert_eq!(log.rebase(0, 1, &[translate(0, "t:1")]), Ok(()));
+
+        log.record(2, Some(vec![edit("")]));
+        assert_eq!(
+            log.rebase(1, 2, &[translate(0, "t:1")]),
+            Err(Conflict::Objects {
+                base: 1,
+                targets: vec![target(0, "t:1")],
+            })
+        );
+    }
+
+    #[test]
+    fn old_changes_are_forgotten() {
+        let mut log = ChangeLog::default();
+        for revision in 1..=CHANGE_LOG_LEN as u64 + 1 {
+            log.record(revision, Some(Vec::new()));
+        }
+        let current = CHANGE_LOG_LEN as u64 + 1;
+        assert_eq!(log.rebase(1, current, &[]), Ok(()));
+        assert_eq!(
+            log.rebase(0, current, &[]),
+            Err(Conflict::UnknownBase { base: 0 })
+        );
+    }
+
+    fn recolor(page: usize, id: &str) -> PatchOperation {
+        PatchOperation::SetStyle {
+            target: target(page, id),
+            style: StylePayload {
+                fill_color: Some([1.0, 0.0, 0.0]),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_batches_merge_or_lose_to_the_first_edit() {
+        let (bytes, document) = {
+            let mut pdf = TestPdf::new();
+            let font = pdf.helvetica();
+            let resources = || lopdf::dictionary! { "Font" => lopdf::dictionary! { "F1" => font } };
+            pdf.page(b"BT /F1 12 Tf 72 700 Td (One) Tj ET", resources());
+            pdf.page(b"BT /F1 12 Tf 72 700 Td (Two) Tj ET", resources());
+            pdf.finish()
+        };
+        let doc_id = "doc-8080";
+        let state = AppState::default();
+        state
+            .store
+            .write()
+            .await
+            .insert(doc_id.into(), DocumentEntry::new(bytes, document));
+        let watcher = Peer {
+            client_id: "watcher".into(),
+            name: None,
+            selection: Vec::new(),
+        };
+        let mut events = state.rooms.join(doc_id, watcher).events.subscribe();
+
+        // Both collaborators start from revision 0.
+        let first = submit(&state, doc_id, "a", None, 0, vec![recolor(0, "t:0")]).await;
+        assert_eq!(first, None);
+        let other_page = submit(&state, doc_id, "b", None, 0, vec![recolor(1, "t:0")]).await;
+        assert_eq!(other_page, None);
+        let same_object = submit(
+            &state,
+            doc_id,
+            "b",
+            Some("r1".into()),
+            0,
+            vec![recolor(0, "t:0")],
+        )
+        .await;
+        assert_eq!(
+            same_object,
+            Some(ServerMessage::Rejected {
+                request_id: Some("r1".into()),
+                revision: 2,
+                message: "objects edited by the batch changed since revision 0".into(),
+                conflicts: vec![target(0, "t:0")],
+                errors: Vec::new(),
+            })
+        );
+        assert_eq!(state.store.read().await[doc_id].revision, 2);
+
+        let ServerMessage::Applied {
+            client_id,
+            revision,
+            touched_pages,
+            ..
+        } = events.recv().await.unwrap()
+        else {
+            panic!("expected the first batch to be broadcast");
+        };
+        assert_eq!(
+            (client_id.as_deref(), revision, touched_pages),
+            (Some("a"), 1, vec![0])
+        );
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ServerMessage::Applied { revision: 2, .. }
+        ));
+        assert!(events.try_recv().is_err());
+    }
+}
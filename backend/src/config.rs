@@ -0,0 +1,371 @@
+//! Server configuration, read from a TOML file and then from environment
+//! variables, which take precedence.
+//!
+//! The file is the one named by `PDF_EDITOR_CONFIG`, or `pdf-editor.toml`
+//! in the working directory if that exists. Every setting has a default,
+//! so neither is required:
+//!
+//! ```toml
+//! bind = "127.0.0.1"              # PDF_EDITOR_BIND
+//! port = 8787                     # PDF_EDITOR_PORT
+//! allowed_origins = ["http://localhost:5173"]  # PDF_EDITOR_ALLOWED_ORIGINS, comma-separated
+//!
+//! [limits]                        # PDF_EDITOR_MAX_UPLOAD_BYTES, PDF_EDITOR_MAX_PAGES, ...
+//! max_upload_bytes = 104857600
+//!
+//! [quota]                         # PDF_EDITOR_MAX_DOCUMENTS, PDF_EDITOR_MAX_STORE_BYTES,
+//! idle_ttl_secs = 86400           # PDF_EDITOR_IDLE_TTL_SECS
+//!
+//! [storage]
+//! backend = "filesystem"          # PDF_EDITOR_STORAGE: "memory" or "filesystem"
+//! dir = "data"                    # PDF_EDITOR_STORAGE_DIR
+//!
+//! [fonts]
+//! dirs = ["/usr/share/fonts"]     # PDF_EDITOR_FONT_DIRS, a path list
+//!
+//! [log]
+//! format = "json"                 # PDF_EDITOR_LOG_FORMAT: "text" or "json"
+//! filter = "info"                 # RUST_LOG
+//! ```
+//!
+//! `demo = true` (`PDF_EDITOR_DEMO`) makes `/api/open` without a file open
+//! the bundled sample instead of failing, and `local_root = "/srv/pdfs"`
+//! (`PDF_EDITOR_LOCAL_ROOT`) lets it open files under that directory by
+//! path.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use axum::http::HeaderValue;
+use serde::Deserialize;
+
+use crate::lifecycle::Quota;
+use crate::pdf::loader::LoadLimits;
+
+const DEFAULT_FILE: &str = "pdf-editor.toml";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Origins allowed to call the API from a browser; `*` allows any.
+    pub allowed_origins: Vec<String>,
+    pub limits: LoadLimits,
+    pub quota: QuotaConfig,
+    pub storage: StorageConfig,
+    pub fonts: FontConfig,
+    pub log: LogConfig,
+    /// Open the bundled sample PDF when `/api/open` is sent no file.
+    pub demo: bool,
+    /// The directory `/api/open` may open server-side files from; opening
+    /// by path is refused when unset.
+    pub local_root: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub max_documents: usize,
+    pub max_store_bytes: usize,
+    pub idle_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Where the filesystem backend keeps documents.
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Memory,
+    Filesystem,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FontConfig {
+    /// Directories searched for fonts; the system font directories when
+    /// empty.
+    pub dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// A `tracing_subscriber::EnvFilter` directive.
+    pub filter: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8787,
+            allowed_origins: vec!["http://localhost:5173".into()],
+            limits: LoadLimits::default(),
+            quota: QuotaConfig::default(),
+            storage: StorageConfig::default(),
+            fonts: FontConfig::default(),
+            log: LogConfig::default(),
+            demo: false,
+            local_root: None,
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        let quota = Quota::default();
+        Self {
+            max_documents: quota.max_documents,
+            max_store_bytes: quota.max_bytes,
+            idle_ttl_secs: quota.idle_ttl.as_secs(),
+        }
+    }
+}
+
+impl QuotaConfig {
+    pub fn quota(&self) -> Quota {
+        Quota {
+            max_documents: self.max_documents,
+            max_bytes: self.max_store_bytes,
+            idle_ttl: Duration::from_secs(self.idle_ttl_secs),
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn dir(&self) -> &Path {
+        self.dir.as_deref().unwrap_or(Path::new("data"))
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            filter: "info".into(),
+        }
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "memory" => Ok(Self::Memory),
+            "filesystem" => Ok(Self::Filesystem),
+            _ => bail!("expected \"memory\" or \"filesystem\""),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("expected \"text\" or \"json\""),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration file, if any, and the environment.
+    pub fn load() -> Result<Self> {
+        let path = std::env::var_os("PDF_EDITOR_CONFIG").map(PathBuf::from);
+        let file = match &path {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {}", path.display()))?,
+            ),
+            None => std::fs::read_to_string(DEFAULT_FILE).ok(),
+        };
+        Self::from_sources(file.as_deref(), |name| std::env::var(name).ok())
+    }
+
+    /// Parse `file` and apply the variables `env` returns on top.
+    pub fn from_sources(file: Option<&str>, env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config: Config = match file {
+            Some(text) => toml::from_str(text).context("parsing the configuration file")?,
+            None => Config::default(),
+        };
+        config.apply_env(&env)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self, env: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+        fn set<T>(env: &dyn Fn(&str) -> Option<String>, name: &str, target: &mut T) -> Result<()>
+        where
+            T: FromStr,
+            T::Err: std::fmt::Display,
+        {
+            if let Some(value) = env(name) {
+                *target = value
+                    .trim()
+                    .parse()
+                    .map_err(|err| anyhow::anyhow!("invalid {name} {value:?}: {err}"))?;
+            }
+            Ok(())
+        }
+
+        set(env, "PDF_EDITOR_BIND", &mut self.bind)?;
+        set(env, "PDF_EDITOR_PORT", &mut self.port)?;
+        if let Some(origins) = env("PDF_EDITOR_ALLOWED_ORIGINS") {
+            self.allowed_origins = origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(String::from)
+                .collect();
+        }
+
+        let limits = &mut self.limits;
+        set(
+            env,
+            "PDF_EDITOR_MAX_UPLOAD_BYTES",
+            &mut limits.max_upload_bytes,
+        )?;
+        set(env, "PDF_EDITOR_MAX_PAGES", &mut limits.max_pages)?;
+        set(env, "PDF_EDITOR_MAX_OBJECTS", &mut limits.max_objects)?;
+        set(
+            env,
+            "PDF_EDITOR_MAX_DECODED_STREAM_BYTES",
+            &mut limits.max_decoded_stream_bytes,
+        )?;
+        set(
+            env,
+            "PDF_EDITOR_MAX_NESTING_DEPTH",
+            &mut limits.max_nesting_depth,
+        )?;
+
+        let quota = &mut self.quota;
+        set(env, "PDF_EDITOR_MAX_DOCUMENTS", &mut quota.max_documents)?;
+        set(
+            env,
+            "PDF_EDITOR_MAX_STORE_BYTES",
+            &mut quota.max_store_bytes,
+        )?;
+        set(env, "PDF_EDITOR_IDLE_TTL_SECS", &mut quota.idle_ttl_secs)?;
+
+        set(env, "PDF_EDITOR_STORAGE", &mut self.storage.backend)?;
+        if let Some(dir) = env("PDF_EDITOR_STORAGE_DIR") {
+            self.storage.dir = Some(dir.into());
+        }
+        if let Some(dirs) = env("PDF_EDITOR_FONT_DIRS") {
+            self.fonts.dirs = std::env::split_paths(&dirs).collect();
+        }
+
+        set(env, "PDF_EDITOR_DEMO", &mut self.demo)?;
+        if let Some(root) = env("PDF_EDITOR_LOCAL_ROOT") {
+            self.local_root = Some(root.into());
+        }
+        set(env, "PDF_EDITOR_LOG_FORMAT", &mut self.log.format)?;
+        if let Some(filter) = env("RUST_LOG") {
+            self.log.filter = filter;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        for origin in &self.allowed_origins {
+            if origin != "*" && HeaderValue::from_str(origin).is_err() {
+                bail!("invalid allowed origin {origin:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(file: Option<&str>, env: &[(&str, &str)]) -> Result<Config> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::from_sources(file, |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let file = r#"
+            bind = "0.0.0.0"
+            port = 9000
+            allowed_origins = ["https://editor.example"]
+
+            [limits]
+            max_pages = 10
+
+            [storage]
+            backend = "filesystem"
+            dir = "/var/lib/pdf-editor"
+
+            [log]
+            format = "json"
+        "#;
+        let config = load(
+            Some(file),
+            &[
+                ("PDF_EDITOR_PORT", "9100"),
+                ("PDF_EDITOR_MAX_OBJECTS", "500"),
+                (
+                    "PDF_EDITOR_ALLOWED_ORIGINS",
+                    "https://a.example, https://b.example",
+                ),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.port, 9100);
+        assert_eq!(
+            config.allowed_origins,
+            ["https://a.example", "https://b.example"]
+        );
+        assert_eq!(config.limits.max_pages, 10);
+        assert_eq!(config.limits.max_objects, 500);
+        assert_eq!(
+            config.limits.max_upload_bytes,
+            LoadLimits::default().max_upload_bytes
+        );
+        assert_eq!(config.storage.backend, StorageBackend::Filesystem);
+        assert_eq!(config.storage.dir(), Path::new("/var/lib/pdf-editor"));
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.quota.quota().idle_ttl, Quota::default().idle_ttl);
+    }
+
+    #[test]
+    fn mistakes_are_errors_rather_than_defaults() {
+        assert_eq!(load(None, &[]).unwrap(), Config::default());
+        assert!(load(Some("prot = 80"), &[]).is_err());
+        assert!(load(None, &[("PDF_EDITOR_PORT", "eighty")]).is_err());
+        assert!(load(None, &[("PDF_EDITOR_STORAGE", "s3")]).is_err());
+        assert!(load(None, &[("PDF_EDITOR_ALLOWED_ORIGINS", "bad\norigin")]).is_err());
+    }
+}
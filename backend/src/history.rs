@@ -0,0 +1,161 @@
+//! Undo and redo of patch batches.
+//!
+//! Every batch is appended to the PDF as an incremental update, so the
+//! bytes of an earlier revision are a prefix of those of a later one.
+//! Undoing a batch truncates the file to where its update starts; the bytes
+//! cut off are kept, so redoing it appends them again.
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use crate::types::PatchOperation;
+
+/// A patch batch and where its update lies in the file.
+#[derive(Debug, Clone)]
+pub struct Revision {
+    /// The document revision the batch produced.
+    pub revision: u64,
+    pub ops: Vec<PatchOperation>,
+    /// Byte range of the incremental update the batch appended.
+    pub update: Range<usize>,
+    pub touched_pages: BTreeSet<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    revisions: Vec<Revision>,
+    /// How many revisions are applied; the rest were undone.
+    applied: usize,
+    /// The updates of the undone revisions, in order.
+    undone: Vec<u8>,
+}
+
+impl History {
+    /// Rebuild a history from its revisions and the updates of those
+    /// undone, as saved by [`crate::storage`].
+    pub fn restore(revisions: Vec<Revision>, applied: usize, undone: Vec<u8>) -> Self {
+        Self {
+            revisions,
+            applied,
+            undone,
+        }
+    }
+
+    /// Revisions oldest first, including undone ones.
+    pub fn revisions(&self) -> &[Revision] {
+        &self.revisions
+    }
+
+    pub fn applied(&self) -> usize {
+        self.applied
+    }
+
+    /// Bytes held for undone revisions.
+    pub fn undone_len(&self) -> usize {
+        self.undone.len()
+    }
+
+    /// The incremental update of the revision at `index`, whether applied
+    /// to `pdf` or undone.
+    pub fn update<'a>(&'a self, index: usize, pdf: &'a [u8]) -> &'a [u8] {
+        let range = &self.revisions[index].update;
+        match index.checked_sub(self.applied) {
+            None => &pdf[range.clone()],
+            Some(_) => {
+                let start = range.start - self.revisions[self.applied].update.start;
+                &self.undone[start..start + range.len()]
+            }
+        }
+    }
+
+    /// Record a batch that appended `update` to the file, bringing the
+    /// document to `revision`. Undone revisions can no longer be redone.
+    pub fn record(
+        &mut self,
+        revision: u64,
+        ops: Vec<PatchOperation>,
+        update: Range<usize>,
+        touched_pages: BTreeSet<usize>,
+    ) {
+        self.revisions.truncate(self.applied);
+        self.undone.clear();
+        self.revisions.push(Revision {
+            revision,
+            ops,
+            update,
+            touched_pages,
+        });
+        self.applied += 1;
+    }
+
+    /// The revision [`History::undo`] would cut off, if any.
+    pub fn last_applied(&self) -> Option<&Revision> {
+        self.revisions[..self.applied].last()
+    }
+
+    /// The revision [`History::redo`] would append again, if any.
+    pub fn first_undone(&self) -> Option<&Revision> {
+        self.revisions.get(self.applied)
+    }
+
+    /// Cut the last applied revision's update off `pdf`. Returns the
+    /// revision, or `None` when there is nothing to undo.
+    pub fn undo(&mut self, pdf: &mut Vec<u8>) -> Option<&Revision> {
+        let revision = &self.revisions[self.applied.checked_sub(1)?];
+        debug_assert_eq!(pdf.len(), revision.update.end);
+        let mut tail = pdf.split_off(revision.update.start);
+        tail.append(&mut self.undone);
+        self.undone = tail;
+        self.applied -= 1;
+        Some(revision)
+    }
+
+    /// Append the first undone revision's update to `pdf` again. Returns
+    /// the revision, or `None` when there is nothing to redo.
+    pub fn redo(&mut self, pdf: &mut Vec<u8>) -> Option<&Revision> {
+        let revision = self.revisions.get(self.applied)?;
+        debug_assert_eq!(pdf.len(), revision.update.start);
+        pdf.extend(self.undone.drain(..revision.update.len()));
+        self.applied += 1;
+        Some(revision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_truncates_and_redo_appends_the_same_bytes() {
+        let mut history = History::default();
+        let mut pdf = b"%PDF base".to_vec();
+        for (revision, update) in [(1, &b" one"[..]), (2, b" two")] {
+            let start = pdf.len();
+            pdf.extend_from_slice(update);
+            history.record(revision, Vec::new(), start..pdf.len(), BTreeSet::from([0]));
+        }
+
+        assert_eq!(history.last_applied().unwrap().revision, 2);
+        assert!(history.first_undone().is_none());
+        assert_eq!(history.undo(&mut pdf).unwrap().update, 13..17);
+        assert_eq!(history.first_undone().unwrap().revision, 2);
+        assert!(history.undo(&mut pdf).is_some());
+        assert!(history.undo(&mut pdf).is_none());
+        assert_eq!(pdf, b"%PDF base");
+        assert_eq!(history.applied(), 0);
+
+        assert!(history.redo(&mut pdf).is_some());
+        assert_eq!(pdf, b"%PDF base one");
+        assert_eq!(history.update(0, &pdf), b" one");
+        assert_eq!(history.update(1, &pdf), b" two");
+
+        // A new batch drops the revision still undone.
+        let start = pdf.len();
+        pdf.extend_from_slice(b" three");
+        history.record(5, Vec::new(), start..pdf.len(), BTreeSet::new());
+        assert!(history.redo(&mut pdf).is_none());
+        assert_eq!(history.revisions().len(), 2);
+        assert_eq!(history.undo(&mut pdf).unwrap().update, 13..19);
+        assert_eq!(pdf, b"%PDF base one");
+    }
+}
@@ -0,0 +1,169 @@
+//! Closing documents: on request, when idle too long, and when the open
+//! documents exceed their quota.
+//!
+//! Closing a document removes it from memory and from the storage, and
+//! tells anyone collaborating on it.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::types::{CloseReason, ServerMessage};
+use crate::{AppState, DocumentEntry};
+
+/// How many documents may be open and how long they may sit idle.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub max_documents: usize,
+    /// Total of [`DocumentEntry::bytes`] over all open documents.
+    pub max_bytes: usize,
+    /// Documents untouched for this long are closed.
+    pub idle_ttl: Duration,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            max_documents: 100,
+            max_bytes: 4 * 1024 * 1024 * 1024,
+            idle_ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Remove `doc_id` from `store` and queue removing it from the storage.
+/// Returns whether it was open.
+pub fn close(
+    state: &AppState,
+    store: &mut HashMap<String, DocumentEntry>,
+    doc_id: &str,
+    reason: CloseReason,
+) -> bool {
+    if store.remove(doc_id).is_none() {
+        return false;
+    }
+    let storage = state.storage.clone();
+    let id = doc_id.to_string();
+    state.saves.queue(doc_id, move || storage.delete(&id));
+    state
+        .rooms
+        .broadcast(doc_id, ServerMessage::Closed { reason });
+    tracing::info!(doc_id, ?reason, "closed document");
+    true
+}
+
+/// Close the least recently touched documents other than `keep` until the
+/// rest fit the quota.
+pub fn enforce_quota(state: &AppState, store: &mut HashMap<String, DocumentEntry>, keep: &str) {
+    let quota = &state.quota;
+    let mut bytes: usize = store.values().map(DocumentEntry::bytes).sum();
+    let mut by_age: Vec<(u64, String)> = store
+        .iter()
+        .filter(|(doc_id, _)| doc_id.as_str() != keep)
+        .map(|(doc_id, entry)| (entry.last_touched(), doc_id.clone()))
+        .collect();
+    by_age.sort();
+    for (_, doc_id) in by_age {
+        if store.len() <= quota.max_documents && bytes <= quota.max_bytes {
+            break;
+        }
+        bytes -= store[&doc_id].bytes();
+        close(state, store, &doc_id, CloseReason::Evicted);
+    }
+}
+
+/// Close every document idle for longer than the quota's TTL.
+pub async fn close_idle(state: &AppState) {
+    let cutoff = now_ms().saturating_sub(state.quota.idle_ttl.as_millis() as u64);
+    let mut store = state.store.write().await;
+    let idle: Vec<String> = store
+        .iter()
+        .filter(|(_, entry)| entry.last_touched() < cutoff)
+        .map(|(doc_id, _)| doc_id.clone())
+        .collect();
+    for doc_id in idle {
+        close(state, &mut store, &doc_id, CloseReason::Idle);
+    }
+}
+
+/// Check for idle documents in the background, several times per TTL.
+pub fn spawn_reaper(state: AppState) {
+    let period = (state.quota.idle_ttl / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            close_idle(&state).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::testing::TestPdf;
+
+    fn entry(touched: u64) -> DocumentEntry {
+        let (bytes, document) = {
+            let mut pdf = TestPdf::new();
+            pdf.page(b"", lopdf::dictionary! {});
+            pdf.finish()
+        };
+        let entry = DocumentEntry::new(bytes, document);
+        entry
+            .touched
+            .store(touched, std::sync::atomic::Ordering::Relaxed);
+        entry
+    }
+
+    #[tokio::test]
+    async fn least_recently_touched_documents_go_first() {
+        let size = entry(0).bytes();
+        let state = AppState {
+            quota: Quota {
+                max_documents: 3,
+                max_bytes: size * 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut store = state.store.write().await;
+        for (doc_id, touched) in [("doc-0001", 30), ("doc-0002", 10), ("doc-0003", 20)] {
+            store.insert(doc_id.into(), entry(touched));
+        }
+
+        // Over the byte quota by two documents; the oldest two go, even
+        // though the one being kept is older still.
+        store.insert("doc-0004".into(), entry(5));
+        enforce_quota(&state, &mut store, "doc-0004");
+        let mut open: Vec<&String> = store.keys().collect();
+        open.sort();
+        assert_eq!(open, ["doc-0001", "doc-0004"]);
+    }
+
+    #[tokio::test]
+    async fn idle_documents_are_closed() {
+        let state = AppState {
+            quota: Quota {
+                idle_ttl: Duration::from_secs(60),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        {
+            let mut store = state.store.write().await;
+            store.insert("doc-0001".into(), entry(now_ms() - 61_000));
+            store.insert("doc-0002".into(), entry(now_ms() - 1_000));
+        }
+        close_idle(&state).await;
+        let store = state.store.read().await;
+        assert!(!store.contains_key("doc-0001"));
+        assert!(store.contains_key("doc-0002"));
+    }
+}
@@ -0,0 +1,111 @@
+//! Opening PDFs that are already on the server's disk.
+//!
+//! Batch jobs name a file by path instead of uploading it. Only files
+//! under the configured root can be opened; the path is resolved, symlinks
+//! included, before it is checked, so `..` and links cannot lead outside.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LocalError {
+    #[error("opening server-side files is not enabled")]
+    Disabled,
+    #[error("{0:?} is not inside the directory files may be opened from")]
+    OutsideRoot(String),
+    #[error("no file at {0:?}")]
+    NotFound(String),
+    #[error("file is {size} bytes, the limit is {limit}")]
+    TooLarge { size: u64, limit: usize },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The file `requested` names under `root`, as an absolute path. Relative
+/// paths are taken relative to `root`.
+fn resolve(root: &Path, requested: &str) -> Result<PathBuf, LocalError> {
+    // Refuse paths that leave the root on their face before touching the
+    // filesystem, so requests cannot probe which files exist elsewhere.
+    let joined = root.join(requested);
+    let climbs = joined
+        .components()
+        .any(|component| component == Component::ParentDir);
+    if climbs || !joined.starts_with(root) {
+        return Err(LocalError::OutsideRoot(requested.into()));
+    }
+    let path = joined.canonicalize().map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => LocalError::NotFound(requested.into()),
+        _ => LocalError::Io(err),
+    })?;
+    if !path.starts_with(root) {
+        return Err(LocalError::OutsideRoot(requested.into()));
+    }
+    if !path.is_file() {
+        return Err(LocalError::NotFound(requested.into()));
+    }
+    Ok(path)
+}
+
+/// Read the file `requested` names under `root`, refusing files over
+/// `limit` bytes before reading them. `root` must be canonical.
+pub fn read(root: Option<&Path>, requested: &str, limit: usize) -> Result<Vec<u8>, LocalError> {
+    let root = root.ok_or(LocalError::Disabled)?;
+    let path = resolve(root, requested)?;
+    let size = path.metadata()?.len();
+    if size > limit as u64 {
+        return Err(LocalError::TooLarge { size, limit });
+    }
+    Ok(std::fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_files_under_the_root_can_be_read() {
+        let dir = std::env::temp_dir().join(format!("pdf-editor-local-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("root/jobs")).unwrap();
+        std::fs::write(dir.join("root/jobs/a.pdf"), b"%PDF-1.7").unwrap();
+        std::fs::write(dir.join("secret.pdf"), b"%PDF-1.7").unwrap();
+        let root = dir.join("root").canonicalize().unwrap();
+
+        assert_eq!(read(Some(&root), "jobs/a.pdf", 100).unwrap(), b"%PDF-1.7");
+        let absolute = root.join("jobs/a.pdf");
+        assert!(read(Some(&root), absolute.to_str().unwrap(), 100).is_ok());
+        assert!(matches!(
+            read(Some(&root), "jobs/a.pdf", 4),
+            Err(LocalError::TooLarge { size: 8, limit: 4 })
+        ));
+        assert!(matches!(
+            read(Some(&root), "../secret.pdf", 100),
+            Err(LocalError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            read(Some(&root), "/etc/hostname", 100),
+            Err(LocalError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            read(Some(&root), "jobs", 100),
+            Err(LocalError::NotFound(_))
+        ));
+        assert!(matches!(
+            read(Some(&root), "jobs/missing.pdf", 100),
+            Err(LocalError::NotFound(_))
+        ));
+        assert!(matches!(
+            read(None, "jobs/a.pdf", 100),
+            Err(LocalError::Disabled)
+        ));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.pdf"), root.join("link.pdf")).unwrap();
+            assert!(matches!(
+                read(Some(&root), "link.pdf", 100),
+                Err(LocalError::OutsideRoot(_))
+            ));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
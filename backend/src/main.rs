@@ -1,246 +1,2080 @@
+mod auth;
+mod collab;
+mod config;
+mod history;
+mod lifecycle;
+mod local;
 mod pdf;
+mod storage;
 mod types;
 mod util;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     net::SocketAddr,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, Ordering},
         Arc,
     },
 };
 
+use anyhow::Context;
 use axum::{
-    extract::{Multipart, Path, State},
-    http::{header, StatusCode},
+    extract::{
+        multipart::MultipartRejection,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        ws::{rejection::WebSocketUpgradeRejection, WebSocketUpgrade},
+        DefaultBodyLimit, FromRequest, Multipart, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
+use bytes::Bytes;
 use tokio::{net::TcpListener, sync::RwLock};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::types::{DocumentIR, PatchOperation, PatchResponse};
+use crate::auth::Capability;
+use crate::collab::{ChangeLog, Rooms};
+use crate::config::{Config, LogConfig, LogFormat, StorageBackend};
+use crate::history::History;
+use crate::lifecycle::{now_ms, Quota};
+use crate::local::LocalError;
+use crate::pdf::filters::UnsupportedFilter;
+use crate::pdf::fonts::library::FontLibrary;
+use crate::pdf::loader::{LoadError, LoadLimits};
+use crate::pdf::patch::{BatchError, OpFailure, PatchContext, PatchError, PatchOutcome};
+use crate::storage::{FileStorage, MemoryStorage, SaveQueue, Saved, Storage, StoredDocument};
+use crate::types::{
+    CloseReason, DocumentIR, DocumentInfo, ErrorResponse, HistoryResponse, ImageSource, PageIR,
+    PatchOpError, PatchOperation, PatchResponse, PatchTarget, PdfMode, PdfUpdate, RevisionSummary,
+    ServerMessage,
+};
 
 const SAMPLE_PDF: &[u8] = include_bytes!("../../e2e/sample.pdf");
-static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+/// Field content types accepted for a PDF upload. Browsers send
+/// `application/octet-stream` when they cannot tell.
+const PDF_CONTENT_TYPES: &[&str] = &[
+    "application/pdf",
+    "application/x-pdf",
+    "application/octet-stream",
+];
+/// Headroom on top of `max_upload_bytes` for multipart boundaries and headers.
+const MULTIPART_OVERHEAD_BYTES: usize = 64 * 1024;
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 struct AppState {
     store: Arc<RwLock<HashMap<String, DocumentEntry>>>,
+    storage: Arc<dyn Storage>,
+    /// Writes to `storage` made after opening a document.
+    saves: SaveQueue,
+    limits: LoadLimits,
+    quota: Quota,
+    fonts: Arc<FontLibrary>,
+    rooms: Rooms,
+    /// Whether `/api/open` without a file opens [`SAMPLE_PDF`].
+    demo: bool,
+    /// Canonical directory `/api/open` may read files from by path.
+    local_root: Option<PathBuf>,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self {
+            store: Arc::default(),
+            storage: Arc::new(MemoryStorage),
+            saves: SaveQueue::default(),
+            limits: LoadLimits::default(),
+            quota: Quota::default(),
+            fonts: Arc::default(),
+            rooms: Rooms::default(),
+            demo: false,
+            local_root: None,
+        }
+    }
 }
 
 #[derive(Clone)]
 struct DocumentEntry {
-    ir: DocumentIR,
-    pdf: Vec<u8>,
+    /// Shared so downloads can stream it without copying; made unique
+    /// again, copying only if a download is still running, by undo and
+    /// redo.
+    pdf: Arc<Vec<u8>>,
+    /// `pdf` parsed; patches are applied to this. Replaced, never mutated,
+    /// so a clone of the `Arc` identifies the revision it was taken from.
+    document: Arc<lopdf::Document>,
+    /// Page IR extracted so far, indexed by page. Entries are filled on
+    /// demand and cleared when a patch touches the page.
+    pages: Vec<Option<PageIR>>,
+    /// Images uploaded for `insertImage` operations, by upload id.
+    uploads: HashMap<String, Vec<u8>>,
+    history: History,
+    /// Bumped whenever `pdf` changes; sent as the `ETag` of responses
+    /// that reflect the document, and expected in `If-Match` on writes.
+    revision: u64,
+    /// Recent changes, for rebasing batches sent by collaborators.
+    changes: ChangeLog,
+    /// The capability token requests on the document must present.
+    token: String,
+    /// The name of the file the document was opened from, if known.
+    filename: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    created_at_ms: u64,
+    /// When a request last used the document, in milliseconds since the
+    /// Unix epoch. Shared so lookups under a read lock can bump it.
+    touched: Arc<AtomicU64>,
+}
+
+impl DocumentEntry {
+    fn new(pdf: Vec<u8>, document: lopdf::Document) -> Self {
+        let page_count = document.get_pages().len();
+        Self {
+            pdf: Arc::new(pdf),
+            document: Arc::new(document),
+            pages: vec![None; page_count],
+            uploads: HashMap::new(),
+            history: History::default(),
+            revision: 0,
+            changes: ChangeLog::default(),
+            token: auth::new_token(),
+            filename: None,
+            created_at_ms: now_ms(),
+            touched: Arc::new(AtomicU64::new(now_ms())),
+        }
+    }
+
+    /// Reopen a document saved by a [`Storage`]. `document` is its current
+    /// bytes parsed.
+    fn restore(stored: StoredDocument, document: lopdf::Document) -> Self {
+        let mut entry = Self::new(stored.pdf, document);
+        for (index, page) in stored.pages {
+            if let Some(slot) = entry.pages.get_mut(index) {
+                *slot = Some(page);
+            }
+        }
+        entry.uploads = stored.uploads;
+        entry.history = stored.history;
+        entry.revision = stored.revision;
+        entry.created_at_ms = stored.created_at_ms;
+        entry.token = stored.token;
+        entry.filename = stored.filename;
+        entry
+    }
+
+    fn touch(&self) {
+        self.touched.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn last_touched(&self) -> u64 {
+        self.touched.load(Ordering::Relaxed)
+    }
+
+    /// What applying `ops` needs from the document, so they can be
+    /// applied without holding the store lock. Only the uploads `ops`
+    /// draw on are copied.
+    fn snapshot(&self, ops: &[PatchOperation]) -> Snapshot {
+        let uploads = ops
+            .iter()
+            .filter_map(|op| match op {
+                PatchOperation::InsertImage {
+                    image: ImageSource::Upload { upload_id },
+                    ..
+                }
+                | PatchOperation::ReplaceImage {
+                    image: ImageSource::Upload { upload_id },
+                    ..
+                } => Some(upload_id),
+                _ => None,
+            })
+            .filter_map(|id| Some((id.clone(), self.uploads.get(id)?.clone())))
+            .collect();
+        Snapshot {
+            revision: self.revision,
+            pdf: self.pdf.clone(),
+            document: self.document.clone(),
+            uploads,
+        }
+    }
+
+    /// Memory held for the document: the PDF, the updates of undone
+    /// revisions and uploaded images.
+    fn bytes(&self) -> usize {
+        let uploads: usize = self.uploads.values().map(Vec::len).sum();
+        self.pdf.len() + self.history.undone_len() + uploads
+    }
+}
+
+/// A document as it stood at `revision`, taken out of the store to be
+/// patched.
+struct Snapshot {
+    revision: u64,
+    pdf: Arc<Vec<u8>>,
+    document: Arc<lopdf::Document>,
+    uploads: HashMap<String, Vec<u8>>,
+}
+
+/// Look up `doc_id` for a request presenting `capability`, counting the
+/// request as activity on it.
+fn lookup<'a>(
+    store: &'a HashMap<String, DocumentEntry>,
+    doc_id: &str,
+    capability: &Capability,
+) -> Result<&'a DocumentEntry, ApiError> {
+    let entry = store.get(doc_id).ok_or(ApiError::NotFound)?;
+    authorize(entry, capability)?;
+    entry.touch();
+    Ok(entry)
+}
+
+fn lookup_mut<'a>(
+    store: &'a mut HashMap<String, DocumentEntry>,
+    doc_id: &str,
+    capability: &Capability,
+) -> Result<&'a mut DocumentEntry, ApiError> {
+    let entry = store.get_mut(doc_id).ok_or(ApiError::NotFound)?;
+    authorize(entry, capability)?;
+    entry.touch();
+    Ok(entry)
+}
+
+fn authorize(entry: &DocumentEntry, capability: &Capability) -> Result<(), ApiError> {
+    if capability.grants(&entry.token) {
+        Ok(())
+    } else {
+        Err(ApiError::Unauthorized)
+    }
+}
+
+/// A JSON `/api/open` request, naming a file on the server.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct OpenRequest {
+    /// Absolute, or relative to the configured `local_root`.
+    path: String,
 }
 
 #[derive(Debug, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 struct OpenResponse {
     doc_id: String,
+    /// Send as `Authorization: Bearer <token>` on every later request.
+    token: String,
+}
+
+/// The query of patch, undo and redo requests. Other parameters, such as
+/// `token`, are left to their own extractors.
+#[derive(Debug, Default, serde::Deserialize)]
+struct PatchQuery {
+    #[serde(default)]
+    pdf: PdfMode,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadResponse {
+    upload_id: String,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let config = Config::load()?;
+    init_tracing(&config.log)?;
+    tracing::info!(?config, "configuration");
+
+    let state = AppState::from_config(&config)?;
+    restore_documents(&state).await?;
+    open_arguments(&state, std::env::args().skip(1)).await?;
+    lifecycle::spawn_reaper(state.clone());
+    let app = build_router(state, &config);
 
-    let state = AppState::default();
+    let addr = SocketAddr::new(config.bind, config.port);
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
 
-    let app = Router::new()
+    Ok(())
+}
+
+/// Open the files named on the command line as a JSON `/api/open` would,
+/// printing one `{ docId, token }` line for each.
+async fn open_arguments(
+    state: &AppState,
+    paths: impl IntoIterator<Item = String>,
+) -> anyhow::Result<()> {
+    for path in paths {
+        let open = open_local(state, path.clone())
+            .await
+            .with_context(|| format!("opening {path}"))?;
+        println!("{}", serde_json::to_string(&open)?);
+    }
+    Ok(())
+}
+
+fn init_tracing(log: &LogConfig) -> anyhow::Result<()> {
+    let filter = tracing_subscriber::EnvFilter::try_new(&log.filter)
+        .with_context(|| format!("invalid log filter {:?}", log.filter))?;
+    let registry = tracing_subscriber::registry().with(filter);
+    match log.format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+    }
+    Ok(())
+}
+
+impl AppState {
+    fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let storage: Arc<dyn Storage> = match config.storage.backend {
+            StorageBackend::Memory => Arc::new(MemoryStorage),
+            StorageBackend::Filesystem => Arc::new(FileStorage::new(config.storage.dir())?),
+        };
+        let fonts = if config.fonts.dirs.is_empty() {
+            FontLibrary::default()
+        } else {
+            FontLibrary::new(config.fonts.dirs.clone())
+        };
+        Ok(Self {
+            storage,
+            limits: config.limits,
+            quota: config.quota.quota(),
+            fonts: Arc::new(fonts),
+            demo: config.demo,
+            local_root: match &config.local_root {
+                Some(root) => Some(
+                    root.canonicalize()
+                        .with_context(|| format!("local_root {}", root.display()))?,
+                ),
+                None => None,
+            },
+            ..Default::default()
+        })
+    }
+}
+
+/// The API routes, with the request body limit and CORS policy from
+/// `config`.
+fn build_router(state: AppState, config: &Config) -> Router {
+    let origins = if config.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        // Origins were validated when the configuration was loaded.
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .filter_map(|origin| origin.parse().ok()),
+        )
+    };
+    let cors = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            header::IF_MATCH,
+            header::IF_NONE_MATCH,
+            header::IF_RANGE,
+            header::RANGE,
+        ])
+        .expose_headers([
+            header::ETAG,
+            header::ACCEPT_RANGES,
+            header::CONTENT_RANGE,
+            header::CONTENT_LENGTH,
+            header::CONTENT_DISPOSITION,
+        ]);
+
+    Router::new()
         .route("/api/open", post(open_document))
+        .route(
+            "/api/doc/:doc_id",
+            get(get_document).delete(delete_document),
+        )
         .route("/api/ir/:doc_id", get(get_ir))
+        .route("/api/ir/:doc_id/page/:index", get(get_page_ir))
         .route("/api/patch/:doc_id", post(apply_patch))
+        .route("/api/undo/:doc_id", post(undo))
+        .route("/api/redo/:doc_id", post(redo))
+        .route("/api/history/:doc_id", get(get_history))
+        .route("/api/ws/:doc_id", get(collaborate))
+        .route("/api/image/:doc_id", post(upload_image))
         .route("/api/pdf/:doc_id", get(download_pdf))
-        .with_state(state);
-
-    // Bind & serve (Axum 0.7 style)
-    // Use 127.0.0.1 for local-only; switch to ([0,0,0,0], 8787) to listen on all interfaces.
-    let addr: SocketAddr = ([127, 0, 0, 1], 8787).into();
-    let listener = TcpListener::bind(addr).await?;
-    tracing::info!("listening on http://{}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+        .fallback(route_not_found)
+        .layer(DefaultBodyLimit::max(
+            config
+                .limits
+                .max_upload_bytes
+                .saturating_add(MULTIPART_OVERHEAD_BYTES),
+        ))
+        .layer(cors)
+        .with_state(state)
+}
 
+/// Load the documents kept by the storage into memory. Documents that no
+/// longer load are skipped.
+async fn restore_documents(state: &AppState) -> anyhow::Result<()> {
+    // Earlier revisions were parsed before, with the same limits.
+    let limits = LoadLimits {
+        max_upload_bytes: usize::MAX,
+        ..state.limits
+    };
+    let mut store = state.store.write().await;
+    for doc_id in state.storage.list()? {
+        let result = state.storage.load(&doc_id).and_then(|stored| {
+            let stored = stored.context("document disappeared")?;
+            let document = pdf::loader::parse_document(&stored.pdf, &limits)?;
+            Ok(DocumentEntry::restore(stored, document))
+        });
+        match result {
+            Ok(entry) => {
+                store.insert(doc_id, entry);
+            }
+            Err(err) => tracing::warn!(doc_id, error = ?err, "could not restore document"),
+        }
+    }
+    tracing::info!(count = store.len(), "restored documents");
     Ok(())
 }
 
+/// Log a failure to save a change that was already made in memory; the
+/// document stays usable, but a restart would lose the change.
+fn log_unsaved(doc_id: &str, result: anyhow::Result<()>) {
+    if let Err(err) = result {
+        tracing::error!(doc_id, error = ?err, "could not save document");
+    }
+}
+
+/// Open a PDF uploaded as multipart form data or, with a JSON
+/// [`OpenRequest`], one already on the server.
 async fn open_document(
     State(state): State<AppState>,
-    mut multipart: Multipart,
+    request: Request,
 ) -> Result<Json<OpenResponse>, ApiError> {
-    let mut pdf_bytes: Vec<u8> = Vec::new();
-    while let Some(field) = multipart.next_field().await? {
-        if field.name() == Some("file") {
-            pdf_bytes = field.bytes().await?.to_vec();
-            break;
-        }
+    let is_json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if is_json {
+        let Json(open) = Json::<OpenRequest>::from_request(request, &state).await?;
+        return open_local(&state, open.path).await.map(Json);
     }
-    if pdf_bytes.is_empty() {
-        pdf_bytes = SAMPLE_PDF.to_vec();
+    let multipart = Multipart::from_request(request, &state).await;
+    let (pdf_bytes, filename) = read_upload(&state, multipart).await?;
+    open_bytes(&state, pdf_bytes, filename).await.map(Json)
+}
+
+/// Open the file `path` names under the configured `local_root`.
+async fn open_local(state: &AppState, path: String) -> Result<OpenResponse, ApiError> {
+    let filename = std::path::Path::new(&path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    let root = state.local_root.clone();
+    let limit = state.limits.max_upload_bytes;
+    let pdf_bytes = tokio::task::spawn_blocking(move || local::read(root.as_deref(), &path, limit))
+        .await
+        .map_err(anyhow::Error::from)??;
+    open_bytes(state, pdf_bytes, filename).await
+}
+
+/// The PDF in a multipart `/api/open` request and its file name.
+async fn read_upload(
+    state: &AppState,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<(Vec<u8>, Option<String>), ApiError> {
+    Ok(
+        match read_file_field(multipart?, state.limits.max_upload_bytes).await {
+            Err(ApiError::MissingFile) if state.demo => {
+                (SAMPLE_PDF.to_vec(), Some("sample.pdf".into()))
+            }
+            file => {
+                let file = file?;
+                if let Some(content_type) = file.content_type {
+                    if !PDF_CONTENT_TYPES.contains(&content_type.as_str()) {
+                        return Err(ApiError::WrongContentType {
+                            expected: "application/pdf",
+                            found: content_type,
+                        });
+                    }
+                }
+                (file.bytes, file.file_name)
+            }
+        },
+    )
+}
+
+/// Parse `pdf_bytes`, store the document and issue its id and token.
+async fn open_bytes(
+    state: &AppState,
+    pdf_bytes: Vec<u8>,
+    filename: Option<String>,
+) -> Result<OpenResponse, ApiError> {
+    let doc_id = auth::new_id();
+    let (limits, max_bytes) = (state.limits, state.quota.max_bytes);
+    let storage = state.storage.clone();
+    let id = doc_id.clone();
+    // Parsing and writing the original file both block, so neither runs on
+    // the async runtime.
+    let entry = tokio::task::spawn_blocking(move || {
+        let document = pdf::loader::parse_document(&pdf_bytes, &limits)?;
+        let mut entry = DocumentEntry::new(pdf_bytes, document);
+        entry.filename = filename;
+        if entry.bytes() > max_bytes {
+            return Err(ApiError::QuotaExceeded { limit: max_bytes });
+        }
+        storage.create(
+            &id,
+            &entry.pdf,
+            entry.created_at_ms,
+            &entry.token,
+            entry.filename.as_deref(),
+        )?;
+        Ok(entry)
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    let token = entry.token.clone();
+
+    let mut store = state.store.write().await;
+    store.insert(doc_id.clone(), entry);
+    lifecycle::enforce_quota(state, &mut store, &doc_id);
+
+    Ok(OpenResponse { doc_id, token })
+}
+
+/// Store an image for later `insertImage` operations on `doc_id`.
+async fn upload_image(
+    Path(doc_id): Path<String>,
+    State(state): State<AppState>,
+    capability: Capability,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Json<UploadResponse>, ApiError> {
+    lookup(&*state.store.read().await, &doc_id, &capability)?;
+    let image = read_file_field(multipart?, state.limits.max_upload_bytes)
+        .await?
+        .bytes;
+    if pdf::images::sniff(&image).is_none() {
+        return Err(ApiError::UnsupportedImage);
     }
 
-    let ir = DocumentIR::sample();
-    let doc_id = new_doc_id();
+    let upload_id = auth::new_id();
+    let saved = {
+        // Queued while the document is known to be open, so its deletion,
+        // should it be closed meanwhile, is queued after.
+        let store = state.store.read().await;
+        lookup(&store, &doc_id, &capability)?;
+        let storage = state.storage.clone();
+        let (id, upload, image) = (doc_id.clone(), upload_id.clone(), image.clone());
+        state
+            .saves
+            .queue(&doc_id, move || storage.save_upload(&id, &upload, &image))
+    };
+    saved.wait().await?;
 
     let mut store = state.store.write().await;
-    store.insert(
-        doc_id.clone(),
-        DocumentEntry {
-            ir: ir.clone(),
-            pdf: pdf_bytes,
-        },
-    );
+    let entry = lookup_mut(&mut store, &doc_id, &capability)?;
+    entry.uploads.insert(upload_id.clone(), image);
+    lifecycle::enforce_quota(&state, &mut store, &doc_id);
+    Ok(Json(UploadResponse { upload_id }))
+}
+
+/// The multipart field named "file".
+struct FileField {
+    /// The field's own `Content-Type`, if it declares one.
+    content_type: Option<String>,
+    /// The name of the uploaded file, if the client sent one.
+    file_name: Option<String>,
+    bytes: Vec<u8>,
+}
 
-    Ok(Json(OpenResponse { doc_id }))
+/// Read the "file" field of `multipart`, failing if there is none.
+async fn read_file_field(mut multipart: Multipart, limit: usize) -> Result<FileField, ApiError> {
+    while let Some(mut field) = multipart.next_field().await? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let content_type = field.content_type().map(str::to_string);
+        let file_name = field.file_name().map(str::to_string);
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.chunk().await? {
+            if bytes.len() + chunk.len() > limit {
+                return Err(ApiError::UploadTooLarge { limit });
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        return Ok(FileField {
+            content_type,
+            file_name,
+            bytes,
+        });
+    }
+    Err(ApiError::MissingFile)
 }
 
 async fn get_ir(
     Path(doc_id): Path<String>,
     State(state): State<AppState>,
-) -> Result<Json<DocumentIR>, ApiError> {
-    let store = state.store.read().await;
-    let entry = store.get(&doc_id).ok_or(ApiError::NotFound)?;
-    Ok(Json(entry.ir.clone()))
+    capability: Capability,
+) -> Result<Tagged<DocumentIR>, ApiError> {
+    let (document, cached, revision) = {
+        let store = state.store.read().await;
+        let entry = lookup(&store, &doc_id, &capability)?;
+        (entry.document.clone(), entry.pages.clone(), entry.revision)
+    };
+    let missing: Vec<usize> = (0..cached.len())
+        .filter(|&index| cached[index].is_none())
+        .collect();
+    if missing.is_empty() {
+        return Ok(tagged(
+            revision,
+            DocumentIR {
+                pages: cached.into_iter().flatten().collect(),
+            },
+        ));
+    }
+
+    let limits = state.limits;
+    let extracted = {
+        let document = document.clone();
+        let missing = missing.clone();
+        tokio::task::spawn_blocking(move || {
+            pdf::extract::extract_pages(&document, &missing, &limits)
+        })
+        .await
+        .map_err(anyhow::Error::from)??
+    };
+
+    let mut pages = cached;
+    for (index, page) in missing.into_iter().zip(extracted) {
+        pages[index] = Some(page);
+    }
+    cache_pages(&state, &doc_id, &document, &pages).await;
+    Ok(tagged(
+        revision,
+        DocumentIR {
+            pages: pages.into_iter().flatten().collect(),
+        },
+    ))
+}
+
+async fn get_page_ir(
+    path: Result<Path<(String, usize)>, PathRejection>,
+    State(state): State<AppState>,
+    capability: Capability,
+) -> Result<Tagged<PageIR>, ApiError> {
+    let Path((doc_id, index)) = path?;
+    let (document, revision) = {
+        let store = state.store.read().await;
+        let entry = lookup(&store, &doc_id, &capability)?;
+        match entry.pages.get(index) {
+            None => return Err(ApiError::PageNotFound { index }),
+            Some(Some(page)) => return Ok(tagged(entry.revision, page.clone())),
+            Some(None) => (entry.document.clone(), entry.revision),
+        }
+    };
+
+    let limits = state.limits;
+    let page = {
+        let document = document.clone();
+        tokio::task::spawn_blocking(move || {
+            pdf::extract::extract_page(&document, index, &limits).map(|page| page.ir)
+        })
+        .await
+        .map_err(anyhow::Error::from)??
+    };
+
+    let mut pages = vec![None; index + 1];
+    pages[index] = Some(page.clone());
+    cache_pages(&state, &doc_id, &document, &pages).await;
+    Ok(tagged(revision, page))
+}
+
+/// A JSON response carrying the document revision it reflects as its
+/// `ETag`.
+type Tagged<T> = ([(header::HeaderName, String); 1], Json<T>);
+
+fn tagged<T>(revision: u64, body: T) -> Tagged<T> {
+    ([(header::ETAG, etag(revision))], Json(body))
+}
+
+fn etag(revision: u64) -> String {
+    format!("\"{revision}\"")
+}
+
+/// Check a write against the revision it was based on. Without an
+/// `If-Match` header the write is refused when `required`, and allowed
+/// otherwise.
+fn check_revision(headers: &HeaderMap, current: u64, required: bool) -> Result<(), ApiError> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return if required {
+            Err(ApiError::RevisionRequired)
+        } else {
+            Ok(())
+        };
+    };
+    if etag_matches(value, current) {
+        Ok(())
+    } else {
+        Err(ApiError::StaleRevision { current })
+    }
+}
+
+/// Whether an `If-Match` or `If-None-Match` list names `revision`.
+fn etag_matches(value: &HeaderValue, revision: u64) -> bool {
+    let tag = etag(revision);
+    value.to_str().is_ok_and(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate == tag)
+    })
+}
+
+/// Store freshly extracted pages, unless a patch replaced the document
+/// while they were being extracted.
+async fn cache_pages(
+    state: &AppState,
+    doc_id: &str,
+    document: &Arc<lopdf::Document>,
+    pages: &[Option<PageIR>],
+) {
+    let mut store = state.store.write().await;
+    let Some(entry) = store.get_mut(doc_id) else {
+        return;
+    };
+    if !Arc::ptr_eq(&entry.document, document) {
+        return;
+    }
+    let mut filled = Vec::new();
+    for (slot, page) in entry.pages.iter_mut().zip(pages) {
+        if let (None, Some(page)) = (&slot, page) {
+            *slot = Some(page.clone());
+            filled.push(page.clone());
+        }
+    }
+    // Queued under the lock, so a later patch's save drops these pages
+    // again; only a cache, so nothing waits for it.
+    let storage = state.storage.clone();
+    let id = doc_id.to_string();
+    state.saves.queue(doc_id, move || {
+        storage.save_pages(&id, &filled.iter().collect::<Vec<_>>())
+    });
 }
 
 async fn apply_patch(
     Path(doc_id): Path<String>,
     State(state): State<AppState>,
-    Json(ops): Json<Vec<PatchOperation>>,
-) -> Result<Json<PatchResponse>, ApiError> {
+    capability: Capability,
+    headers: HeaderMap,
+    query: Result<Query<PatchQuery>, QueryRejection>,
+    ops: Result<Json<Vec<PatchOperation>>, JsonRejection>,
+) -> Result<Tagged<PatchResponse>, ApiError> {
+    let Query(query) = query?;
+    let Json(ops) = ops?;
     tracing::info!(doc_id, op_count = ops.len(), "received patch batch");
+    let snapshot = {
+        let store = state.store.read().await;
+        let entry = lookup(&store, &doc_id, &capability)?;
+        check_revision(&headers, entry.revision, true)?;
+        entry.snapshot(&ops)
+    };
+    let base = (snapshot.revision, snapshot.pdf.len());
+    let outcome = patch_snapshot(&state, snapshot, &ops).await?;
+
     let mut store = state.store.write().await;
-    let entry = store.get_mut(&doc_id).ok_or(ApiError::NotFound)?;
-    let mut ir = entry.ir.clone();
-    pdf::patch::apply_patches(&mut ir, &ops)?; // stubbed in MVP
-    entry.ir = ir;
+    let entry = lookup_mut(&mut store, &doc_id, &capability)?;
+    // Another write may have landed while the batch was applied; even
+    // under `If-Match: *` the outcome is only valid for the revision it
+    // was computed from.
+    if entry.revision != base.0 {
+        return Err(ApiError::StaleRevision {
+            current: entry.revision,
+        });
+    }
+    let (created, saved) = commit_batch(&state, &doc_id, entry, ops, outcome, (None, None));
+    let revision = entry.revision;
+    let mut response = PatchResponse {
+        ok: true,
+        created,
+        revision: Some(revision),
+        ..Default::default()
+    };
+    attach_pdf(&mut response, query.pdf, &entry.pdf, base);
+    lifecycle::enforce_quota(&state, &mut store, &doc_id);
+    drop(store);
+    if let Some(saved) = saved {
+        log_unsaved(&doc_id, saved.wait().await);
+    }
+
+    Ok(tagged(revision, response))
+}
 
-    let encoded = format!("data:application/pdf;base64,{}", BASE64.encode(&entry.pdf));
+/// Apply `ops` to `snapshot` on the blocking pool, so neither the runtime
+/// nor the store lock waits on the content streams being rewritten.
+async fn patch_snapshot(
+    state: &AppState,
+    snapshot: Snapshot,
+    ops: &[PatchOperation],
+) -> Result<PatchOutcome, ApiError> {
+    let limits = state.limits;
+    let fonts = state.fonts.clone();
+    let ops = ops.to_vec();
+    let outcome = tokio::task::spawn_blocking(move || {
+        let context = PatchContext {
+            limits: &limits,
+            fonts: &fonts,
+            uploads: &snapshot.uploads,
+        };
+        pdf::patch::apply_patches(&snapshot.document, &snapshot.pdf, &ops, &context)
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    Ok(outcome)
+}
 
-    Ok(Json(PatchResponse {
+/// Make `outcome` of applying `ops` the current state of `entry`, record
+/// the batch for undo and rebasing, and tell the collaborators connected
+/// to `doc_id`. `entry` must still be at the revision the batch was
+/// applied to. `origin` is the collaborator and request id the batch came
+/// with, if it came over the socket. Returns the objects the batch
+/// created and, if it changed anything, its save, to wait for once the
+/// store lock is released.
+fn commit_batch(
+    state: &AppState,
+    doc_id: &str,
+    entry: &mut DocumentEntry,
+    ops: Vec<PatchOperation>,
+    outcome: PatchOutcome,
+    origin: (Option<String>, Option<String>),
+) -> (Vec<PatchTarget>, Option<Saved>) {
+    for &page in &outcome.touched_pages {
+        entry.pages[page] = None;
+    }
+    let update = entry.pdf.len()..outcome.pdf.len();
+    entry.pdf = Arc::new(outcome.pdf);
+    entry.document = Arc::new(outcome.document);
+    if update.is_empty() {
+        return (outcome.created, None);
+    }
+
+    entry.revision += 1;
+    entry.changes.record(entry.revision, Some(ops.clone()));
+    let (client_id, request_id) = origin;
+    state.rooms.broadcast(
+        doc_id,
+        ServerMessage::Applied {
+            client_id,
+            request_id,
+            revision: entry.revision,
+            ops: ops.clone(),
+            touched_pages: outcome.touched_pages.iter().copied().collect(),
+            created: outcome.created.clone(),
+        },
+    );
+    entry
+        .history
+        .record(entry.revision, ops, update, outcome.touched_pages.clone());
+    let saved = save_history(state, doc_id, entry, outcome.touched_pages);
+    (outcome.created, Some(saved))
+}
+
+/// Queue saving the history of `entry` after a change to the pages in
+/// `touched`.
+fn save_history(
+    state: &AppState,
+    doc_id: &str,
+    entry: &DocumentEntry,
+    touched: BTreeSet<usize>,
+) -> Saved {
+    let storage = state.storage.clone();
+    let id = doc_id.to_string();
+    let (pdf, history, revision) = (entry.pdf.clone(), entry.history.clone(), entry.revision);
+    state.saves.queue(doc_id, move || {
+        storage.save_history(&id, &pdf, &history, revision, &touched)
+    })
+}
+
+fn op_errors(failures: &[OpFailure]) -> Vec<PatchOpError> {
+    failures
+        .iter()
+        .map(|failure| PatchOpError {
+            index: failure.index,
+            target: failure.target.clone(),
+            code: failure.error.code().into(),
+            message: match failure.error.code() {
+                "internal" => "internal error".to_string(),
+                _ => failure.error.to_string(),
+            },
+        })
+        .collect()
+}
+
+fn pdf_data_url(pdf: &[u8]) -> String {
+    format!("data:application/pdf;base64,{}", BASE64.encode(pdf))
+}
+
+/// Put the PDF into `response` as `mode` asks. `base` is the revision and
+/// length of the PDF before the request. Patches and redo only append to
+/// it and undo only truncates it, so the bytes before the shorter of the
+/// two lengths are unchanged.
+fn attach_pdf(response: &mut PatchResponse, mode: PdfMode, pdf: &[u8], base: (u64, usize)) {
+    match mode {
+        PdfMode::None => {}
+        PdfMode::Full => response.updated_pdf = Some(pdf_data_url(pdf)),
+        PdfMode::Incremental => {
+            let (base_revision, base_len) = base;
+            let offset = base_len.min(pdf.len());
+            response.pdf_update = Some(PdfUpdate {
+                base_revision,
+                offset,
+                data: BASE64.encode(&pdf[offset..]),
+                size_bytes: pdf.len(),
+            });
+        }
+    }
+}
+
+/// Take back the last patch batch applied to `doc_id`.
+async fn undo(
+    Path(doc_id): Path<String>,
+    State(state): State<AppState>,
+    capability: Capability,
+    headers: HeaderMap,
+    query: Result<Query<PatchQuery>, QueryRejection>,
+) -> Result<Tagged<PatchResponse>, ApiError> {
+    let Query(query) = query?;
+    step_history(&state, &doc_id, &capability, &headers, query.pdf, false).await
+}
+
+/// Apply the last undone patch batch of `doc_id` again.
+async fn redo(
+    Path(doc_id): Path<String>,
+    State(state): State<AppState>,
+    capability: Capability,
+    headers: HeaderMap,
+    query: Result<Query<PatchQuery>, QueryRejection>,
+) -> Result<Tagged<PatchResponse>, ApiError> {
+    let Query(query) = query?;
+    step_history(&state, &doc_id, &capability, &headers, query.pdf, true).await
+}
+
+/// Undo or redo a batch. `If-Match` is optional here: undo and redo act
+/// on the server's history rather than on objects a client has seen.
+async fn step_history(
+    state: &AppState,
+    doc_id: &str,
+    capability: &Capability,
+    headers: &HeaderMap,
+    mode: PdfMode,
+    redo: bool,
+) -> Result<Tagged<PatchResponse>, ApiError> {
+    let (base, pdf, undone, update, touched_pages) = {
+        let store = state.store.read().await;
+        let entry = lookup(&store, doc_id, capability)?;
+        check_revision(headers, entry.revision, false)?;
+        let history = &entry.history;
+        // The bytes redo appends again.
+        let (revision, undone) = if redo {
+            let revision = history.first_undone().ok_or(ApiError::NothingToRedo)?;
+            (
+                revision,
+                history.update(history.applied(), &entry.pdf).to_vec(),
+            )
+        } else {
+            let revision = history.last_applied().ok_or(ApiError::NothingToUndo)?;
+            (revision, Vec::new())
+        };
+        (
+            (entry.revision, entry.pdf.len()),
+            entry.pdf.clone(),
+            undone,
+            revision.update.clone(),
+            revision.touched_pages.clone(),
+        )
+    };
+    // Earlier revisions were parsed before, with the same limits.
+    let limits = LoadLimits {
+        max_upload_bytes: usize::MAX,
+        ..state.limits
+    };
+    // Parse the file as the step leaves it on the blocking pool; the store
+    // only changes below, once the step is known to parse.
+    let document = tokio::task::spawn_blocking(move || {
+        if redo {
+            let mut stepped = Vec::with_capacity(update.end);
+            stepped.extend_from_slice(&pdf);
+            stepped.extend_from_slice(&undone);
+            pdf::loader::parse_document(&stepped, &limits)
+        } else {
+            pdf::loader::parse_document(&pdf[..update.start], &limits)
+        }
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+
+    let mut store = state.store.write().await;
+    let entry = lookup_mut(&mut store, doc_id, capability)?;
+    if entry.revision != base.0 {
+        return Err(ApiError::StaleRevision {
+            current: entry.revision,
+        });
+    }
+    let (pdf, history) = (Arc::make_mut(&mut entry.pdf), &mut entry.history);
+    if redo {
+        history.redo(pdf);
+    } else {
+        history.undo(pdf);
+    }
+    for &page in &touched_pages {
+        entry.pages[page] = None;
+    }
+    entry.document = Arc::new(document);
+    entry.revision += 1;
+    entry.changes.record(entry.revision, None);
+    state.rooms.broadcast(
+        doc_id,
+        ServerMessage::Stepped {
+            revision: entry.revision,
+            redo,
+            touched_pages: touched_pages.iter().copied().collect(),
+        },
+    );
+    let saved = save_history(state, doc_id, entry, touched_pages);
+    tracing::info!(
+        doc_id,
+        redo,
+        applied = entry.history.applied(),
+        "stepped history"
+    );
+
+    let mut response = PatchResponse {
         ok: true,
-        updated_pdf: Some(encoded),
-        remap: None,
-        message: Some("Patch handling is stubbed in the MVP backend".into()),
+        revision: Some(entry.revision),
+        ..Default::default()
+    };
+    attach_pdf(&mut response, mode, &entry.pdf, base);
+    let revision = entry.revision;
+    drop(store);
+    log_unsaved(doc_id, saved.wait().await);
+    Ok(tagged(revision, response))
+}
+
+async fn get_history(
+    Path(doc_id): Path<String>,
+    State(state): State<AppState>,
+    capability: Capability,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    let store = state.store.read().await;
+    let history = &lookup(&store, &doc_id, &capability)?.history;
+    let revisions = history
+        .revisions()
+        .iter()
+        .map(|revision| RevisionSummary {
+            ops: revision.ops.clone(),
+            touched_pages: revision.touched_pages.iter().copied().collect(),
+            update_bytes: revision.update.len(),
+        })
+        .collect();
+    Ok(Json(HistoryResponse {
+        revisions,
+        applied: history.applied(),
     }))
 }
 
-async fn download_pdf(
+/// Size, page count and timestamps of an open document.
+async fn get_document(
     Path(doc_id): Path<String>,
     State(state): State<AppState>,
-) -> Result<Response, ApiError> {
+    capability: Capability,
+) -> Result<Json<DocumentInfo>, ApiError> {
     let store = state.store.read().await;
+    // Asking about a document does not count as using it.
     let entry = store.get(&doc_id).ok_or(ApiError::NotFound)?;
-    let mut response = Response::new(entry.pdf.clone().into());
-    *response.status_mut() = StatusCode::OK;
-    response.headers_mut().insert(
+    authorize(entry, &capability)?;
+    Ok(Json(DocumentInfo {
+        size_bytes: entry.pdf.len(),
+        stored_bytes: entry.bytes(),
+        pages: entry.pages.len(),
+        revision: entry.revision,
+        created_at_ms: entry.created_at_ms,
+        last_touched_at_ms: entry.last_touched(),
+        filename: entry.filename.clone(),
+        doc_id,
+    }))
+}
+
+/// Close `doc_id`, dropping it from memory and storage.
+async fn delete_document(
+    Path(doc_id): Path<String>,
+    State(state): State<AppState>,
+    capability: Capability,
+) -> Result<StatusCode, ApiError> {
+    let mut store = state.store.write().await;
+    lookup(&store, &doc_id, &capability)?;
+    lifecycle::close(&state, &mut store, &doc_id, CloseReason::Deleted);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Join the collaboration room of `doc_id`; see [`collab`].
+async fn collaborate(
+    Path(doc_id): Path<String>,
+    State(state): State<AppState>,
+    capability: Capability,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, ApiError> {
+    lookup(&*state.store.read().await, &doc_id, &capability)?;
+    let ws = ws?;
+    Ok(ws.on_upgrade(move |socket| collab::session(socket, state, doc_id)))
+}
+
+/// The document's bytes, tagged with its revision. A single `Range` is
+/// answered with 206 so pdf.js can load large files progressively; the
+/// body shares the stored buffer rather than copying it.
+async fn download_pdf(
+    Path(doc_id): Path<String>,
+    State(state): State<AppState>,
+    capability: Capability,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let (pdf, revision, filename) = {
+        let store = state.store.read().await;
+        let entry = lookup(&store, &doc_id, &capability)?;
+        (entry.pdf.clone(), entry.revision, entry.filename.clone())
+    };
+    let len = pdf.len() as u64;
+    let mut body = Bytes::from_owner(SharedPdf(pdf));
+
+    let mut response = Response::new(axum::body::Body::empty());
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(tag) = HeaderValue::from_str(&etag(revision)) {
+        response_headers.insert(header::ETAG, tag);
+    }
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| etag_matches(value, revision))
+    {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(response);
+    }
+    response_headers.insert(
         header::CONTENT_TYPE,
-        header::HeaderValue::from_static("application/pdf"),
+        HeaderValue::from_static("application/pdf"),
     );
+    if let Ok(disposition) = HeaderValue::from_str(&content_disposition(
+        filename.as_deref().unwrap_or("document.pdf"),
+    )) {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    // A range read against another revision would splice two documents,
+    // so `If-Range` must name the current one exactly.
+    let current = headers
+        .get(header::IF_RANGE)
+        .is_none_or(|value| value.to_str().is_ok_and(|value| value == etag(revision)));
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(value) if current => util::range::parse(value, len)
+            .map_err(|util::range::Unsatisfiable| ApiError::RangeNotSatisfiable { len })?,
+        _ => None,
+    };
+    if let Some(range) = range {
+        let content_range = format!("bytes {}-{}/{len}", range.start, range.end - 1);
+        if let Ok(value) = HeaderValue::from_str(&content_range) {
+            response_headers.insert(header::CONTENT_RANGE, value);
+        }
+        body = body.slice(range.start as usize..range.end as usize);
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+    response
+        .headers_mut()
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    *response.body_mut() = body.into();
     Ok(response)
 }
 
+/// Lets a response body borrow a document's stored bytes.
+struct SharedPdf(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedPdf {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A `Content-Disposition` that saves the download as `filename`: quoted
+/// ASCII for every browser, plus the exact name percent-encoded (RFC 6266).
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
 #[derive(Debug, thiserror::Error)]
 enum ApiError {
     #[error("document not found")]
     NotFound,
+    #[error("no such route")]
+    RouteNotFound,
+    #[error("page {index} does not exist")]
+    PageNotFound { index: usize },
+    #[error("upload exceeds the {limit} byte limit")]
+    UploadTooLarge { limit: usize },
+    #[error("document has {count} pages, the limit is {limit}")]
+    TooManyPages { count: usize, limit: usize },
+    #[error("document has {count} objects, the limit is {limit}")]
+    TooManyObjects { count: usize, limit: usize },
+    #[error("a stream decodes to more than {limit} bytes")]
+    StreamTooLarge { limit: usize },
+    #[error("objects are nested deeper than {limit} levels")]
+    NestingTooDeep { limit: usize },
+    #[error("the request has no \"file\" field")]
+    MissingFile,
+    #[error("expected {expected}, got {found}")]
+    WrongContentType {
+        expected: &'static str,
+        found: String,
+    },
+    #[error("upload is not a PDF: it has no %PDF- header")]
+    NotPdf,
+    #[error("the document is encrypted; remove the password and upload it again")]
+    Encrypted,
+    #[error("invalid PDF: {0}")]
+    InvalidPdf(lopdf::Error),
+    #[error("upload is not a PNG or JPEG image")]
+    UnsupportedImage,
+    #[error(transparent)]
+    UnsupportedFilter(UnsupportedFilter),
+    #[error("document exceeds the {limit} byte quota for open documents")]
+    QuotaExceeded { limit: usize },
+    #[error("the request does not carry the document's access token")]
+    Unauthorized,
+    #[error("patch requests must send If-Match with the document revision")]
+    RevisionRequired,
+    #[error("the document changed; it is now at revision {current}")]
+    StaleRevision { current: u64 },
+    #[error("there is no patch batch to undo")]
+    NothingToUndo,
+    #[error("there is no undone patch batch to redo")]
+    NothingToRedo,
+    #[error("the requested range lies outside the {len} byte document")]
+    RangeNotSatisfiable { len: u64 },
+    /// A request axum's extractors refused, such as a malformed JSON body.
+    #[error("{message}")]
+    InvalidRequest { status: StatusCode, message: String },
+    #[error(transparent)]
+    Local(#[from] LocalError),
+    #[error(transparent)]
+    Patch(#[from] BatchError),
     #[error(transparent)]
     Multipart(#[from] axum::extract::multipart::MultipartError),
     #[error(transparent)]
-    Internal(#[from] anyhow::Error),
+    Internal(anyhow::Error),
+}
+
+impl From<LoadError> for ApiError {
+    fn from(err: LoadError) -> Self {
+        match err {
+            LoadError::TooLarge { limit, .. } => ApiError::UploadTooLarge { limit },
+            LoadError::TooManyPages { count, limit } => ApiError::TooManyPages { count, limit },
+            LoadError::TooManyObjects { count, limit } => ApiError::TooManyObjects { count, limit },
+            LoadError::StreamTooLarge { limit, .. } => ApiError::StreamTooLarge { limit },
+            LoadError::NestingTooDeep { limit } => ApiError::NestingTooDeep { limit },
+            LoadError::NotPdf => ApiError::NotPdf,
+            LoadError::Encrypted => ApiError::Encrypted,
+            LoadError::Parse(err) => ApiError::InvalidPdf(err),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<UnsupportedFilter>() {
+            Ok(err) => ApiError::UnsupportedFilter(err),
+            Err(err) => ApiError::Internal(err),
+        }
+    }
+}
+
+impl From<MultipartRejection> for ApiError {
+    fn from(_: MultipartRejection) -> Self {
+        // The only rejection is a missing or malformed multipart boundary.
+        ApiError::WrongContentType {
+            expected: "multipart/form-data",
+            found: "a body without a multipart boundary".into(),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(err: JsonRejection) -> Self {
+        ApiError::InvalidRequest {
+            status: err.status(),
+            message: err.body_text(),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(err: QueryRejection) -> Self {
+        ApiError::InvalidRequest {
+            status: err.status(),
+            message: err.body_text(),
+        }
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(err: PathRejection) -> Self {
+        ApiError::InvalidRequest {
+            status: err.status(),
+            message: err.body_text(),
+        }
+    }
+}
+
+impl From<WebSocketUpgradeRejection> for ApiError {
+    fn from(err: WebSocketUpgradeRejection) -> Self {
+        ApiError::InvalidRequest {
+            status: err.status(),
+            message: err.body_text(),
+        }
+    }
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound | ApiError::RouteNotFound | ApiError::PageNotFound { .. } => {
+                StatusCode::NOT_FOUND
+            }
+            ApiError::UploadTooLarge { .. } | ApiError::StreamTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ApiError::TooManyPages { .. }
+            | ApiError::TooManyObjects { .. }
+            | ApiError::NestingTooDeep { .. }
+            | ApiError::Encrypted
+            | ApiError::InvalidPdf(_)
+            | ApiError::UnsupportedFilter(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::MissingFile => StatusCode::BAD_REQUEST,
+            ApiError::WrongContentType { .. } | ApiError::NotPdf | ApiError::UnsupportedImage => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            ApiError::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::RevisionRequired => StatusCode::PRECONDITION_REQUIRED,
+            ApiError::StaleRevision { .. } | ApiError::NothingToUndo | ApiError::NothingToRedo => {
+                StatusCode::CONFLICT
+            }
+            ApiError::RangeNotSatisfiable { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+            ApiError::InvalidRequest { status, .. } => *status,
+            ApiError::Local(LocalError::Disabled | LocalError::OutsideRoot(_)) => {
+                StatusCode::FORBIDDEN
+            }
+            ApiError::Local(LocalError::NotFound(_)) => StatusCode::NOT_FOUND,
+            ApiError::Local(LocalError::TooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Local(LocalError::Io(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Patch(BatchError::Rejected(failures)) => match &failures[0].error {
+                PatchError::PageNotFound(_)
+                | PatchError::UnknownTarget { .. }
+                | PatchError::UnknownUpload(_) => StatusCode::NOT_FOUND,
+                error if error.code() == "internal" => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            },
+            ApiError::Multipart(err) if err.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ApiError::Multipart(_) => StatusCode::BAD_REQUEST,
+            ApiError::Patch(BatchError::Other(_)) | ApiError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// The [`ErrorResponse::code`] for this error.
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound => "document_not_found",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::PageNotFound { .. } => "page_not_found",
+            ApiError::UploadTooLarge { .. }
+            | ApiError::TooManyPages { .. }
+            | ApiError::TooManyObjects { .. }
+            | ApiError::StreamTooLarge { .. }
+            | ApiError::NestingTooDeep { .. } => "limit_exceeded",
+            ApiError::MissingFile => "missing_file",
+            ApiError::WrongContentType { .. } => "wrong_content_type",
+            ApiError::NotPdf => "not_a_pdf",
+            ApiError::Encrypted => "encrypted",
+            ApiError::InvalidPdf(_) => "invalid_pdf",
+            ApiError::UnsupportedImage => "unsupported_image",
+            ApiError::UnsupportedFilter(_) => "unsupported_filter",
+            ApiError::QuotaExceeded { .. } => "quota_exceeded",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::RevisionRequired => "revision_required",
+            ApiError::StaleRevision { .. } => "stale_revision",
+            ApiError::NothingToUndo => "nothing_to_undo",
+            ApiError::NothingToRedo => "nothing_to_redo",
+            ApiError::RangeNotSatisfiable { .. } => "range_not_satisfiable",
+            ApiError::InvalidRequest { status, .. } => match *status {
+                StatusCode::PAYLOAD_TOO_LARGE => "limit_exceeded",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE => "wrong_content_type",
+                _ => "invalid_request",
+            },
+            ApiError::Local(LocalError::Disabled) => "local_open_disabled",
+            ApiError::Local(LocalError::OutsideRoot(_)) => "path_not_allowed",
+            ApiError::Local(LocalError::NotFound(_)) => "file_not_found",
+            ApiError::Local(LocalError::TooLarge { .. }) => "limit_exceeded",
+            ApiError::Local(LocalError::Io(_)) => "internal",
+            ApiError::Patch(BatchError::Rejected(failures)) => failures[0].error.code(),
+            ApiError::Multipart(err) if err.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                "limit_exceeded"
+            }
+            ApiError::Multipart(_) => "invalid_multipart",
+            ApiError::Patch(BatchError::Other(_)) | ApiError::Internal(_) => "internal",
+        }
+    }
+
+    /// The response body: the code and message, plus where the error
+    /// happened and what it depends on.
+    fn body(&self) -> ErrorResponse {
+        let mut body = ErrorResponse {
+            code: self.code().into(),
+            message: self.to_string(),
+            op_index: None,
+            object_id: None,
+            details: serde_json::Value::Null,
+        };
+        let limit = |name: &str, max: usize| serde_json::json!({ "limit": name, "max": max });
+        match self {
+            ApiError::PageNotFound { index } => {
+                body.details = serde_json::json!({ "page": index });
+            }
+            ApiError::UploadTooLarge { limit: max } => {
+                body.details = limit("max_upload_bytes", *max);
+            }
+            ApiError::TooManyPages { count, limit: max } => {
+                body.details = limit("max_pages", *max);
+                body.details["actual"] = (*count).into();
+            }
+            ApiError::TooManyObjects { count, limit: max } => {
+                body.details = limit("max_objects", *max);
+                body.details["actual"] = (*count).into();
+            }
+            ApiError::StreamTooLarge { limit: max } => {
+                body.details = limit("max_decoded_stream_bytes", *max);
+            }
+            ApiError::NestingTooDeep { limit: max } => {
+                body.details = limit("max_nesting_depth", *max);
+            }
+            ApiError::Local(LocalError::TooLarge { limit: max, .. }) => {
+                body.details = limit("max_upload_bytes", *max);
+            }
+            ApiError::Local(LocalError::Io(_)) => body.message = "internal server error".into(),
+            ApiError::QuotaExceeded { limit: max } => {
+                body.details = limit("max_store_bytes", *max);
+            }
+            ApiError::UnsupportedFilter(err) => {
+                body.details = serde_json::json!({
+                    "filter": err.filter,
+                    "stream": format!("{} {} R", err.stream.0, err.stream.1),
+                });
+            }
+            ApiError::StaleRevision { current } => {
+                body.details = serde_json::json!({ "revision": current });
+            }
+            ApiError::RangeNotSatisfiable { len } => {
+                body.details = serde_json::json!({ "length": len });
+            }
+            ApiError::Patch(BatchError::Rejected(failures)) => {
+                let errors = op_errors(failures);
+                let first = &errors[0];
+                body.message = format!(
+                    "operation {} failed: {}; nothing was applied",
+                    first.index, first.message
+                );
+                body.op_index = Some(first.index);
+                body.object_id = first.target.as_ref().map(|target| target.id.clone());
+                body.details = serde_json::json!({ "errors": errors });
+            }
+            ApiError::Multipart(err) if err.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                body.message = "upload exceeds the size limit".into();
+            }
+            ApiError::Multipart(_) => body.message = "invalid multipart payload".into(),
+            ApiError::Patch(BatchError::Other(_)) | ApiError::Internal(_) => {
+                body.message = "internal server error".into();
+            }
+            _ => {}
+        }
+        body
+    }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() && status != StatusCode::INSUFFICIENT_STORAGE {
+            tracing::error!(error = ?self, "request failed");
+        } else if matches!(
+            status,
+            StatusCode::PAYLOAD_TOO_LARGE
+                | StatusCode::UNPROCESSABLE_ENTITY
+                | StatusCode::INSUFFICIENT_STORAGE
+        ) {
+            tracing::warn!(code = self.code(), error = %self, "request rejected");
+        }
+        let mut response = (status, Json(self.body())).into_response();
+        let headers = response.headers_mut();
         match self {
-            ApiError::NotFound => (StatusCode::NOT_FOUND, "document not found").into_response(),
-            ApiError::Multipart(err) => {
-                tracing::error!(error = %err, "multipart error");
-                (StatusCode::BAD_REQUEST, "invalid multipart payload").into_response()
+            ApiError::Unauthorized => {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
             }
-            ApiError::Internal(err) => {
-                tracing::error!(error = %err, "internal error");
-                (StatusCode::INTERNAL_SERVER_ERROR, "internal server error").into_response()
+            ApiError::StaleRevision { current } => {
+                tracing::info!(current, "stale write rejected");
+                if let Ok(tag) = HeaderValue::from_str(&etag(current)) {
+                    headers.insert(header::ETAG, tag);
+                }
             }
+            ApiError::RangeNotSatisfiable { len } => {
+                if let Ok(range) = HeaderValue::from_str(&format!("bytes */{len}")) {
+                    headers.insert(header::CONTENT_RANGE, range);
+                }
+            }
+            _ => {}
         }
+        response
     }
 }
 
-fn new_doc_id() -> String {
-    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-    format!("doc-{id:04}")
+/// Answers requests for routes that do not exist.
+async fn route_not_found() -> ApiError {
+    ApiError::RouteNotFound
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{PatchTarget, StylePayload};
+    use crate::pdf::testing::{test_png, TestPdf};
+    use crate::types::{ImageSource, PageObject, PatchTarget, StylePayload};
     use axum::{
         body::{to_bytes, Body},
         http::{header, Request, StatusCode},
-        routing::{get, post},
         Router,
     };
     use tower::util::ServiceExt;
 
+    #[tokio::test]
+    async fn only_configured_origins_pass_cors() {
+        let config = Config {
+            allowed_origins: vec!["https://editor.example".into()],
+            ..Config::default()
+        };
+        let app = build_router(AppState::default(), &config);
+        for (origin, allowed) in [
+            ("https://editor.example", true),
+            ("https://elsewhere.example", false),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::OPTIONS)
+                        .uri("/api/patch/doc")
+                        .header(header::ORIGIN, origin)
+                        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "if-match")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let echoed = response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .map(|value| value.to_str().unwrap().to_string());
+            assert_eq!(echoed.as_deref() == Some(origin), allowed, "{origin}");
+        }
+    }
+
+    #[test]
+    fn sample_ir_contains_objects() {
+        let ir = DocumentIR::sample();
+        assert_eq!(ir.pages.len(), 1);
+        assert_eq!(ir.pages[0].objects.len(), 2);
+    }
+
+    /// The token of documents the tests put in the store themselves.
+    const TEST_TOKEN: &str = "0123456789abcdef";
+    const TEST_AUTH: &str = "Bearer 0123456789abcdef";
+
+    /// A document entry that requests with `TEST_AUTH` may use.
+    fn test_entry(pdf: Vec<u8>, document: lopdf::Document) -> DocumentEntry {
+        let mut entry = DocumentEntry::new(pdf, document);
+        entry.token = TEST_TOKEN.into();
+        entry
+    }
+
+    fn test_router(state: AppState) -> Router {
+        build_router(state, &Config::default())
+    }
+
+    async fn seed_state_with_sample(doc_id: &str) -> AppState {
+        let state = AppState::default();
+        let document = pdf::loader::parse_document(SAMPLE_PDF, &state.limits).unwrap();
+        {
+            let mut store = state.store.write().await;
+            store.insert(
+                doc_id.to_string(),
+                test_entry(SAMPLE_PDF.to_vec(), document),
+            );
+        }
+        state
+    }
+
+    fn multipart_request(pdf: &[u8]) -> Request<Body> {
+        multipart_upload("/api/open", pdf)
+    }
+
+    fn multipart_upload(uri: &str, data: &[u8]) -> Request<Body> {
+        let mut body = Vec::new();
+        body.extend_from_slice(
+            b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"upload\"\r\n\
+              Content-Type: application/octet-stream\r\n\r\n",
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+        Request::builder()
+            .header(header::AUTHORIZATION, TEST_AUTH)
+            .method("POST")
+            .uri(uri)
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn open_request(content_type: &str, body: &[u8]) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("/api/open")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_vec()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn open_document_rejects_anything_but_a_pdf_file() {
+        const MULTIPART: &str = "multipart/form-data; boundary=boundary";
+        let no_file = b"--boundary\r\nContent-Disposition: form-data; name=\"other\"\r\n\r\nx\r\n--boundary--\r\n";
+        let html =
+            b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.html\"\r\n\
+                     Content-Type: text/html\r\n\r\n<html>\r\n--boundary--\r\n";
+        let cases = [
+            (
+                open_request(MULTIPART, no_file),
+                StatusCode::BAD_REQUEST,
+                "missing_file",
+            ),
+            (
+                open_request("application/pdf", SAMPLE_PDF),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "wrong_content_type",
+            ),
+            (
+                open_request(MULTIPART, html),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "wrong_content_type",
+            ),
+            (
+                multipart_request(b"GIF89a"),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "not_a_pdf",
+            ),
+            (
+                multipart_request(b"%PDF-1.7\ntruncated"),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_pdf",
+            ),
+        ];
+        let state = AppState::default();
+        for (request, status, code) in cases {
+            let response = test_router(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status, "{code}");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["code"], code);
+            assert!(json["message"].is_string());
+        }
+        assert!(state.store.read().await.is_empty());
+
+        let demo = AppState {
+            demo: true,
+            ..Default::default()
+        };
+        let response = test_router(demo.clone())
+            .oneshot(open_request(MULTIPART, no_file))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let store = demo.store.read().await;
+        assert_eq!(*store.values().next().unwrap().pdf, SAMPLE_PDF);
+    }
+
+    #[tokio::test]
+    async fn documents_open_by_path_only_under_the_local_root() {
+        let dir = std::env::temp_dir().join(format!("pdf-editor-open-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("jobs")).unwrap();
+        std::fs::write(dir.join("jobs/sample.pdf"), SAMPLE_PDF).unwrap();
+        let state = AppState {
+            local_root: Some(dir.canonicalize().unwrap()),
+            ..Default::default()
+        };
+        let open = |state: &AppState, path: &str| {
+            let body = serde_json::json!({ "path": path }).to_string();
+            test_router(state.clone()).oneshot(open_request("application/json", body.as_bytes()))
+        };
+
+        let response = open(&state, "jobs/sample.pdf").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let opened: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let doc_id = opened["docId"].as_str().unwrap();
+        assert_eq!(*state.store.read().await[doc_id].pdf, SAMPLE_PDF);
+
+        for (state, path, status, code) in [
+            (
+                &state,
+                "../elsewhere.pdf",
+                StatusCode::FORBIDDEN,
+                "path_not_allowed",
+            ),
+            (
+                &state,
+                "jobs/missing.pdf",
+                StatusCode::NOT_FOUND,
+                "file_not_found",
+            ),
+            (
+                &AppState::default(),
+                "jobs/sample.pdf",
+                StatusCode::FORBIDDEN,
+                "local_open_disabled",
+            ),
+        ] {
+            let response = open(state, path).await.unwrap();
+            assert_eq!(response.status(), status, "{path}");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error.code, code);
+        }
+
+        // The command line goes through the same checks.
+        open_arguments(&state, ["jobs/sample.pdf".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(state.store.read().await.len(), 2);
+        let err = open_arguments(&state, ["../elsewhere.pdf".to_string()])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ApiError::Local(LocalError::OutsideRoot(_)))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_document_stores_uploaded_pdf() {
+        let state = AppState::default();
+        let app = test_router(state.clone());
+
+        let response = app.oneshot(multipart_request(SAMPLE_PDF)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let doc_id = json["docId"].as_str().expect("doc id in response");
+        let store = state.store.read().await;
+        assert_eq!(*store[doc_id].pdf, SAMPLE_PDF);
+        assert_eq!(store[doc_id].filename.as_deref(), Some("upload"));
+    }
+
+    #[tokio::test]
+    async fn documents_in_file_storage_survive_a_restart() {
+        let root = std::env::temp_dir().join(format!("pdf-editor-restart-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let state = AppState {
+            storage: Arc::new(FileStorage::new(&root).unwrap()),
+            ..Default::default()
+        };
+        let app = test_router(state.clone());
+        let response = app
+            .clone()
+            .oneshot(multipart_request(SAMPLE_PDF))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let open: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let doc_id = open["docId"].as_str().unwrap();
+        let token = open["token"].as_str().unwrap();
+        let authorization = format!("Bearer {token}");
+
+        let ops = vec![PatchOperation::SetStyle {
+            target: PatchTarget {
+                page: 0,
+                id: "t:0".into(),
+            },
+            style: StylePayload {
+                fill_color: Some([1.0, 0.0, 0.0]),
+                ..Default::default()
+            },
+        }];
+        let patch = Request::builder()
+            .method("POST")
+            .uri(format!("/api/patch/{doc_id}"))
+            .header(header::AUTHORIZATION, &authorization)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::IF_MATCH, "\"0\"")
+            .body(Body::from(serde_json::to_vec(&ops).unwrap()))
+            .unwrap();
+        let response = app.clone().oneshot(patch).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let get_ir = Request::builder()
+            .uri(format!("/api/ir/{doc_id}"))
+            .header(header::AUTHORIZATION, &authorization)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(get_ir).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let ir: DocumentIR = serde_json::from_slice(&body).unwrap();
+        let before = state.store.read().await[doc_id].clone();
+        // Nothing waits for the page cache to be saved; a write queued after
+        // it runs once it has.
+        state.saves.queue(doc_id, || Ok(())).wait().await.unwrap();
+
+        let restarted = AppState {
+            storage: Arc::new(FileStorage::new(&root).unwrap()),
+            ..Default::default()
+        };
+        restore_documents(&restarted).await.unwrap();
+        let after = restarted.store.read().await[doc_id].clone();
+        assert_eq!(after.pdf, before.pdf);
+        assert_eq!(after.revision, 1);
+        assert_eq!(after.history.revisions()[0].ops, ops);
+        assert_eq!(after.token, token);
+        assert_eq!(
+            after.pages,
+            ir.pages.into_iter().map(Some).collect::<Vec<_>>()
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_document_rejects_uploads_over_the_size_limit() {
+        let state = AppState {
+            limits: LoadLimits {
+                max_upload_bytes: 128,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let app = test_router(state.clone());
+
+        let response = app.oneshot(multipart_request(SAMPLE_PDF)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(state.store.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn open_document_rejects_documents_over_the_page_limit() {
+        let state = AppState {
+            limits: LoadLimits {
+                max_pages: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let app = test_router(state);
+
+        let response = app.oneshot(multipart_request(SAMPLE_PDF)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
     #[test]
-    fn sample_ir_contains_objects() {
-        let ir = DocumentIR::sample();
-        assert_eq!(ir.pages.len(), 1);
-        assert_eq!(ir.pages[0].objects.len(), 2);
+    fn load_errors_map_to_distinct_api_errors() {
+        let too_large: ApiError = LoadError::TooLarge { size: 2, limit: 1 }.into();
+        let nested: ApiError = LoadError::NestingTooDeep { limit: 4 }.into();
+        let stream: ApiError = LoadError::StreamTooLarge {
+            id: (7, 0),
+            limit: 9,
+        }
+        .into();
+
+        assert!(matches!(too_large, ApiError::UploadTooLarge { limit: 1 }));
+        assert!(matches!(nested, ApiError::NestingTooDeep { limit: 4 }));
+        assert_eq!(
+            stream.into_response().status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
     }
 
     #[test]
-    fn new_doc_id_increments_with_zero_padding() {
-        NEXT_ID.store(1, Ordering::Relaxed);
-        let first = new_doc_id();
-        let second = new_doc_id();
+    fn domain_errors_carry_specific_codes_and_details() {
+        let stream = ApiError::from(LoadError::StreamTooLarge {
+            id: (7, 0),
+            limit: 9,
+        })
+        .body();
+        assert_eq!(stream.code, "limit_exceeded");
+        assert_eq!(
+            stream.details,
+            serde_json::json!({ "limit": "max_decoded_stream_bytes", "max": 9 })
+        );
+        assert_eq!(ApiError::from(LoadError::Encrypted).code(), "encrypted");
 
-        assert_eq!(first, "doc-0001");
-        assert_eq!(second, "doc-0002");
-    }
+        // Extraction reports filters through anyhow, under added context.
+        let filter = anyhow::Error::from(UnsupportedFilter {
+            stream: (4, 0),
+            filter: "JBIG2Decode".into(),
+        })
+        .context("extracting page 0");
+        let filter = ApiError::from(filter);
+        assert_eq!(filter.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(filter.body().details["filter"], "JBIG2Decode");
 
-    fn test_router(state: AppState) -> Router {
-        Router::new()
-            .route("/api/ir/:doc_id", get(get_ir))
-            .route("/api/patch/:doc_id", post(apply_patch))
-            .route("/api/pdf/:doc_id", get(download_pdf))
-            .with_state(state)
+        let internal = ApiError::from(anyhow::anyhow!("secret path /srv/x"));
+        assert_eq!(internal.code(), "internal");
+        assert_eq!(internal.body().message, "internal server error");
     }
 
-    async fn seed_state_with_sample(doc_id: &str, pdf: Vec<u8>) -> AppState {
-        let state = AppState::default();
-        {
-            let mut store = state.store.write().await;
-            store.insert(
-                doc_id.to_string(),
-                DocumentEntry {
-                    ir: DocumentIR::sample(),
-                    pdf,
-                },
-            );
+    #[tokio::test]
+    async fn every_route_answers_errors_with_the_json_envelope() {
+        let doc_id = "doc-4747";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state);
+        let request = |method: &str, uri: String, auth: bool, body: &str| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::IF_MATCH, "\"0\"");
+            if auth {
+                request = request.header(header::AUTHORIZATION, TEST_AUTH);
+            }
+            request.body(Body::from(body.to_string())).unwrap()
+        };
+        let unknown_target = serde_json::to_string(&[PatchOperation::SetStyle {
+            target: PatchTarget {
+                page: 0,
+                id: "t:99".into(),
+            },
+            style: StylePayload::default(),
+        }])
+        .unwrap();
+        let cases = [
+            (
+                request("GET", "/api/nope".into(), true, ""),
+                StatusCode::NOT_FOUND,
+                "route_not_found",
+            ),
+            (
+                request("GET", format!("/api/ir/{doc_id}"), false, ""),
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+            ),
+            (
+                request("GET", format!("/api/ir/{doc_id}/page/first"), true, ""),
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                request("GET", format!("/api/ir/{doc_id}/page/7"), true, ""),
+                StatusCode::NOT_FOUND,
+                "page_not_found",
+            ),
+            (
+                request("POST", format!("/api/patch/{doc_id}"), true, "[{"),
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                request(
+                    "POST",
+                    format!("/api/patch/{doc_id}"),
+                    true,
+                    &unknown_target,
+                ),
+                StatusCode::NOT_FOUND,
+                "unknown_target",
+            ),
+            (
+                request("POST", format!("/api/undo/{doc_id}"), true, ""),
+                StatusCode::CONFLICT,
+                "nothing_to_undo",
+            ),
+            (
+                request("GET", format!("/api/ws/{doc_id}"), true, ""),
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                request("DELETE", "/api/doc/missing".into(), true, ""),
+                StatusCode::NOT_FOUND,
+                "document_not_found",
+            ),
+        ];
+        for (request, status, code) in cases {
+            let uri = request.uri().to_string();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status, "{uri}");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error.code, code, "{uri}");
+            if code == "unknown_target" {
+                assert_eq!(error.op_index, Some(0));
+                assert_eq!(error.object_id.as_deref(), Some("t:99"));
+            }
         }
-        state
     }
 
     #[tokio::test]
     async fn get_ir_endpoint_returns_serialised_ir() {
         let doc_id = "doc-9001";
-        let state = seed_state_with_sample(doc_id, SAMPLE_PDF.to_vec()).await;
+        let state = seed_state_with_sample(doc_id).await;
         let app = test_router(state);
 
         let response = app
             .oneshot(
                 Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
                     .method("GET")
                     .uri(format!("/api/ir/{doc_id}"))
                     .body(Body::empty())
@@ -253,7 +2087,11 @@ mod tests {
 
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
         let ir: DocumentIR = serde_json::from_slice(&body).unwrap();
-        assert_eq!(ir, DocumentIR::sample());
+        assert_eq!(ir.pages.len(), 1);
+        let PageObject::Text(text) = &ir.pages[0].objects[0] else {
+            panic!("sample page starts with text");
+        };
+        assert_eq!(text.unicode, "Hello world");
     }
 
     #[tokio::test]
@@ -263,6 +2101,7 @@ mod tests {
         let response = app
             .oneshot(
                 Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
                     .method("GET")
                     .uri("/api/ir/missing")
                     .body(Body::empty())
@@ -274,18 +2113,129 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
+    async fn get_json<T: serde::de::DeserializeOwned>(
+        app: Router,
+        uri: String,
+    ) -> (StatusCode, Option<T>) {
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
+                    .method("GET")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    #[tokio::test]
+    async fn get_page_ir_endpoint_extracts_and_caches_the_page() {
+        let doc_id = "doc-7000";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state.clone());
+
+        let (status, page) =
+            get_json::<PageIR>(app.clone(), format!("/api/ir/{doc_id}/page/0")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let page = page.unwrap();
+        assert_eq!(page.index, 0);
+        let store = state.store.read().await;
+        assert_eq!(store[doc_id].pages, [Some(page)]);
+    }
+
+    #[tokio::test]
+    async fn get_page_ir_endpoint_returns_not_found_past_the_last_page() {
+        let doc_id = "doc-7001";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state);
+
+        let (status, _) =
+            get_json::<serde_json::Value>(app, format!("/api/ir/{doc_id}/page/1")).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn patches_invalidate_only_the_pages_they_touch() {
+        let (bytes, document) = {
+            let mut pdf = TestPdf::new();
+            let font = pdf.helvetica();
+            let resources = || lopdf::dictionary! { "Font" => lopdf::dictionary! { "F1" => font } };
+            pdf.page(b"BT /F1 12 Tf 72 700 Td (One) Tj ET", resources());
+            pdf.page(b"BT /F1 12 Tf 72 700 Td (Two) Tj ET", resources());
+            pdf.finish()
+        };
+        let doc_id = "doc-7002";
+        let state = AppState::default();
+        state
+            .store
+            .write()
+            .await
+            .insert(doc_id.into(), test_entry(bytes, document));
+        let app = test_router(state.clone());
+
+        let (_, ir) = get_json::<DocumentIR>(app.clone(), format!("/api/ir/{doc_id}")).await;
+        let ir = ir.unwrap();
+        assert_eq!(ir.pages.len(), 2);
+        assert!(state.store.read().await[doc_id]
+            .pages
+            .iter()
+            .all(Option::is_some));
+
+        let ops = vec![PatchOperation::SetStyle {
+            target: PatchTarget {
+                page: 1,
+                id: "t:0".into(),
+            },
+            style: StylePayload {
+                fill_color: Some([1.0, 0.0, 0.0]),
+                ..Default::default()
+            },
+        }];
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
+                    .method("POST")
+                    .uri(format!("/api/patch/{doc_id}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::IF_MATCH, "\"0\"")
+                    .body(Body::from(serde_json::to_vec(&ops).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let pages = state.store.read().await[doc_id].pages.clone();
+        assert_eq!(pages[0].as_ref(), Some(&ir.pages[0]));
+        assert!(pages[1].is_none());
+
+        let (_, page) = get_json::<PageIR>(app, format!("/api/ir/{doc_id}/page/1")).await;
+        let PageObject::Text(text) = &page.unwrap().objects[0] else {
+            panic!("second page starts with text");
+        };
+        assert_eq!(text.unicode, "Two");
+    }
+
     #[tokio::test]
     async fn apply_patch_endpoint_returns_base64_pdf() {
         let doc_id = "doc-4242";
-        let pdf_bytes = b"%PDF-1.4 test".to_vec();
-        let state = seed_state_with_sample(doc_id, pdf_bytes.clone()).await;
+        let state = seed_state_with_sample(doc_id).await;
         let app_state = state.clone();
         let app = test_router(state);
 
         let ops = vec![PatchOperation::SetStyle {
             target: PatchTarget {
                 page: 0,
-                id: "t:42".into(),
+                id: "t:0".into(),
             },
             style: StylePayload {
                 fill_color: Some([1.0, 0.0, 0.0]),
@@ -296,9 +2246,11 @@ mod tests {
         let response = app
             .oneshot(
                 Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
                     .method("POST")
                     .uri(format!("/api/patch/{doc_id}"))
                     .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::IF_MATCH, "\"0\"")
                     .body(Body::from(serde_json::to_vec(&ops).unwrap()))
                     .unwrap(),
             )
@@ -311,16 +2263,19 @@ mod tests {
 
         assert!(json.ok);
         assert!(json.remap.is_none());
-        assert!(json.message.is_some());
+        assert!(json.message.is_none());
         let encoded = json.updated_pdf.expect("pdf data should be returned");
-        assert!(encoded.starts_with("data:application/pdf;base64,"));
-
-        let expected = format!("data:application/pdf;base64,{}", BASE64.encode(&pdf_bytes));
-        assert_eq!(encoded, expected);
+        let encoded = encoded
+            .strip_prefix("data:application/pdf;base64,")
+            .expect("data URL prefix");
+        let updated = BASE64.decode(encoded).unwrap();
+        assert!(updated.starts_with(SAMPLE_PDF));
+        assert!(updated.len() > SAMPLE_PDF.len());
 
         let store = app_state.store.read().await;
         let entry = store.get(doc_id).expect("document should remain in store");
-        assert_eq!(entry.ir, DocumentIR::sample());
+        assert_eq!(*entry.pdf, updated);
+        assert_eq!(entry.pages, [None]);
     }
 
     #[tokio::test]
@@ -329,6 +2284,7 @@ mod tests {
         let response = app
             .oneshot(
                 Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
                     .method("POST")
                     .uri("/api/patch/missing")
                     .header(header::CONTENT_TYPE, "application/json")
@@ -341,16 +2297,390 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
+    #[tokio::test]
+    async fn apply_patch_endpoint_rejects_unknown_targets() {
+        let doc_id = "doc-4343";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state.clone());
+        let ops = vec![PatchOperation::SetStyle {
+            target: PatchTarget {
+                page: 0,
+                id: "t:42".into(),
+            },
+            style: StylePayload::default(),
+        }];
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
+                    .method("POST")
+                    .uri(format!("/api/patch/{doc_id}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::IF_MATCH, "\"0\"")
+                    .body(Body::from(serde_json::to_vec(&ops).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(*state.store.read().await[doc_id].pdf, SAMPLE_PDF);
+    }
+
+    #[tokio::test]
+    async fn apply_patch_endpoint_applies_nothing_when_an_op_fails() {
+        let doc_id = "doc-4444";
+        let state = seed_state_with_sample(doc_id).await;
+        let target = PatchTarget {
+            page: 0,
+            id: "t:0".into(),
+        };
+        let ops = vec![
+            PatchOperation::SetStyle {
+                target: target.clone(),
+                style: StylePayload {
+                    fill_color: Some([1.0, 0.0, 0.0]),
+                    ..Default::default()
+                },
+            },
+            PatchOperation::Transform {
+                target: target.clone(),
+                delta_matrix_pt: [1.0, 0.0, 0.0, 1.0, 5.0, 0.0],
+                kind: "image".into(),
+            },
+        ];
+
+        let response = test_router(state.clone())
+            .oneshot(
+                Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
+                    .method("POST")
+                    .uri(format!("/api/patch/{doc_id}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::IF_MATCH, "\"0\"")
+                    .body(Body::from(serde_json::to_vec(&ops).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json.code, "wrong_kind");
+        assert_eq!(json.op_index, Some(1));
+        assert_eq!(json.object_id.as_deref(), Some("t:0"));
+        let errors: Vec<PatchOpError> =
+            serde_json::from_value(json.details["errors"].clone()).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index, 1);
+        assert_eq!(errors[0].target, Some(target));
+        assert_eq!(errors[0].message, "t:0 is a text object, not image");
+        assert_eq!(*state.store.read().await[doc_id].pdf, SAMPLE_PDF);
+    }
+
+    async fn post_json<T: serde::de::DeserializeOwned>(
+        app: Router,
+        uri: String,
+        if_match: Option<&str>,
+        body: &impl serde::Serialize,
+    ) -> (StatusCode, Option<String>, Option<T>) {
+        let mut request = Request::builder()
+            .header(header::AUTHORIZATION, TEST_AUTH)
+            .method("POST")
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(tag) = if_match {
+            request = request.header(header::IF_MATCH, tag);
+        }
+        let body = Body::from(serde_json::to_vec(body).unwrap());
+        let response = app.oneshot(request.body(body).unwrap()).await.unwrap();
+        let status = response.status();
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, etag, serde_json::from_slice(&body).ok())
+    }
+
+    #[tokio::test]
+    async fn patches_must_name_the_current_revision() {
+        let doc_id = "doc-4646";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state.clone());
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
+                    .uri(format!("/api/ir/{doc_id}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::ETAG], "\"0\"");
+
+        let uri = format!("/api/patch/{doc_id}");
+        let ops = vec![PatchOperation::SetStyle {
+            target: PatchTarget {
+                page: 0,
+                id: "t:0".into(),
+            },
+            style: StylePayload {
+                fill_color: Some([1.0, 0.0, 0.0]),
+                ..Default::default()
+            },
+        }];
+        let (status, _, _) =
+            post_json::<serde_json::Value>(app.clone(), uri.clone(), None, &ops).await;
+        assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+
+        let (status, etag, response) =
+            post_json::<PatchResponse>(app.clone(), uri.clone(), Some("\"0\""), &ops).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(etag.as_deref(), Some("\"1\""));
+        assert_eq!(response.unwrap().revision, Some(1));
+
+        // A second tab still on revision 0 is turned away.
+        let patched = state.store.read().await[doc_id].pdf.to_vec();
+        let (status, etag, response) =
+            post_json::<ErrorResponse>(app, uri, Some("\"0\""), &ops).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(etag.as_deref(), Some("\"1\""));
+        let response = response.unwrap();
+        assert_eq!(response.code, "stale_revision");
+        assert_eq!(response.details["revision"], 1);
+        assert_eq!(*state.store.read().await[doc_id].pdf, patched);
+    }
+
+    #[tokio::test]
+    async fn undo_and_redo_step_through_patch_batches() {
+        let doc_id = "doc-4545";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state.clone());
+        let ops = vec![PatchOperation::SetStyle {
+            target: PatchTarget {
+                page: 0,
+                id: "t:0".into(),
+            },
+            style: StylePayload {
+                fill_color: Some([1.0, 0.0, 0.0]),
+                ..Default::default()
+            },
+        }];
+        let (status, _, _) = post_json::<PatchResponse>(
+            app.clone(),
+            format!("/api/patch/{doc_id}"),
+            Some("\"0\""),
+            &ops,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let patched = state.store.read().await[doc_id].pdf.to_vec();
+
+        let (_, history) =
+            get_json::<HistoryResponse>(app.clone(), format!("/api/history/{doc_id}")).await;
+        let history = history.unwrap();
+        assert_eq!(history.applied, 1);
+        assert_eq!(history.revisions[0].ops, ops);
+        assert_eq!(history.revisions[0].touched_pages, [0]);
+        assert_eq!(
+            history.revisions[0].update_bytes,
+            patched.len() - SAMPLE_PDF.len()
+        );
+
+        let undo = format!("/api/undo/{doc_id}");
+        let (status, _, response) =
+            post_json::<PatchResponse>(app.clone(), undo.clone(), None, &()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.unwrap().updated_pdf.is_some());
+        {
+            let store = state.store.read().await;
+            assert_eq!(*store[doc_id].pdf, SAMPLE_PDF);
+            assert_eq!(store[doc_id].pages, [None]);
+        }
+        let (status, _, _) = post_json::<serde_json::Value>(app.clone(), undo, None, &()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let redo = format!("/api/redo/{doc_id}");
+        let (status, _, _) =
+            post_json::<PatchResponse>(app.clone(), redo.clone(), Some("\"2\""), &()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(*state.store.read().await[doc_id].pdf, patched);
+        let (status, _, _) = post_json::<serde_json::Value>(app, redo, None, &()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn patch_responses_carry_the_pdf_as_requested() {
+        let doc_id = "doc-4747";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state.clone());
+        let ops = vec![PatchOperation::SetStyle {
+            target: PatchTarget {
+                page: 0,
+                id: "t:0".into(),
+            },
+            style: StylePayload {
+                fill_color: Some([1.0, 0.0, 0.0]),
+                ..Default::default()
+            },
+        }];
+        let apply = |pdf: &[u8], update: PdfUpdate| {
+            let mut pdf = pdf[..update.offset].to_vec();
+            pdf.extend(BASE64.decode(update.data).unwrap());
+            assert_eq!(pdf.len(), update.size_bytes);
+            pdf
+        };
+
+        let uri = format!("/api/patch/{doc_id}?pdf=incremental");
+        let (status, _, response) =
+            post_json::<PatchResponse>(app.clone(), uri, Some("\"0\""), &ops).await;
+        assert_eq!(status, StatusCode::OK);
+        let response = response.unwrap();
+        assert!(response.updated_pdf.is_none());
+        let update = response.pdf_update.expect("incremental update");
+        assert_eq!((update.base_revision, update.offset), (0, SAMPLE_PDF.len()));
+        let patched = apply(SAMPLE_PDF, update);
+        assert_eq!(*state.store.read().await[doc_id].pdf, patched);
+
+        let uri = format!("/api/undo/{doc_id}?pdf=incremental");
+        let (_, _, response) = post_json::<PatchResponse>(app.clone(), uri, None, &()).await;
+        let update = response.unwrap().pdf_update.expect("incremental update");
+        assert_eq!(update.base_revision, 1);
+        assert_eq!(apply(&patched, update), SAMPLE_PDF);
+
+        let uri = format!("/api/redo/{doc_id}?pdf=none");
+        let (status, etag, response) =
+            post_json::<PatchResponse>(app.clone(), uri, None, &()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(etag.as_deref(), Some("\"3\""));
+        let response = response.unwrap();
+        assert!(response.updated_pdf.is_none() && response.pdf_update.is_none());
+
+        let uri = format!("/api/undo/{doc_id}?pdf=everything");
+        let (status, _, _) = post_json::<ErrorResponse>(app, uri, None, &()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn documents_report_metadata_until_deleted() {
+        let doc_id = "doc-6060";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state.clone());
+        let uri = format!("/api/doc/{doc_id}");
+
+        let (status, info) = get_json::<DocumentInfo>(app.clone(), uri.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let info = info.unwrap();
+        assert_eq!(info.size_bytes, SAMPLE_PDF.len());
+        assert_eq!(info.pages, 1);
+        assert_eq!(info.revision, 0);
+        assert!(info.created_at_ms <= info.last_touched_at_ms);
+
+        let delete = || {
+            Request::builder()
+                .header(header::AUTHORIZATION, TEST_AUTH)
+                .method("DELETE")
+                .uri(&uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(delete()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.store.read().await.is_empty());
+        let response = app.clone().oneshot(delete()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let (status, _) = get_json::<DocumentInfo>(app, uri.clone()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn requests_without_the_document_token_are_unauthorized() {
+        let doc_id = "doc-4010";
+        let state = seed_state_with_sample(doc_id).await;
+        let app = test_router(state);
+        let request = |authorization: Option<&str>| {
+            let mut request = Request::builder().uri(format!("/api/pdf/{doc_id}"));
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let wrong = app
+            .clone()
+            .oneshot(request(Some("Bearer fedcba9876543210")))
+            .await
+            .unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        let query = Request::builder()
+            .uri(format!("/api/pdf/{doc_id}?token={TEST_TOKEN}"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(query).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn upload_image_endpoint_stores_images_for_insert_image() {
+        let doc_id = "doc-6060";
+        let state = seed_state_with_sample(doc_id).await;
+        let uri = format!("/api/image/{doc_id}");
+        let png = test_png(1, 1, &[0, 0, 0, 255]);
+
+        let response = test_router(state.clone())
+            .oneshot(multipart_upload(&uri, &png))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let upload_id = json["uploadId"].as_str().unwrap().to_string();
+        assert_eq!(state.store.read().await[doc_id].uploads[&upload_id], png);
+
+        let ops = vec![PatchOperation::InsertImage {
+            page: 0,
+            image: ImageSource::Upload { upload_id },
+            matrix_pt: [10.0, 0.0, 0.0, 10.0, 0.0, 0.0],
+        }];
+        let response = test_router(state.clone())
+            .oneshot(
+                Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
+                    .method("POST")
+                    .uri(format!("/api/patch/{doc_id}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::IF_MATCH, "\"0\"")
+                    .body(Body::from(serde_json::to_vec(&ops).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = test_router(state)
+            .oneshot(multipart_upload(&uri, b"GIF89a"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
     #[tokio::test]
     async fn download_pdf_endpoint_streams_binary_content() {
         let doc_id = "doc-5150";
-        let pdf_bytes = b"%PDF-Stub".to_vec();
-        let state = seed_state_with_sample(doc_id, pdf_bytes.clone()).await;
+        let state = seed_state_with_sample(doc_id).await;
         let app = test_router(state);
 
         let response = app
             .oneshot(
                 Request::builder()
+                    .header(header::AUTHORIZATION, TEST_AUTH)
                     .method("GET")
                     .uri(format!("/api/pdf/{doc_id}"))
                     .body(Body::empty())
@@ -371,6 +2701,84 @@ mod tests {
         );
 
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        assert_eq!(body.as_ref(), pdf_bytes);
+        assert_eq!(body.as_ref(), SAMPLE_PDF);
+    }
+
+    #[tokio::test]
+    async fn download_pdf_answers_range_and_conditional_requests() {
+        let doc_id = "doc-5151";
+        let state = seed_state_with_sample(doc_id).await;
+        state.store.write().await.get_mut(doc_id).unwrap().filename =
+            Some("Q3 rapport é.pdf".into());
+        let app = test_router(state);
+        let len = SAMPLE_PDF.len();
+        let get = |headers: &[(header::HeaderName, &str)]| {
+            let mut request = Request::builder()
+                .header(header::AUTHORIZATION, TEST_AUTH)
+                .uri(format!("/api/pdf/{doc_id}"));
+            for (name, value) in headers {
+                request = request.header(name, *value);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let header = |response: &Response, name| {
+            response
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let full = get(&[]).await.unwrap();
+        assert_eq!(full.status(), StatusCode::OK);
+        assert_eq!(
+            header(&full, header::ACCEPT_RANGES).as_deref(),
+            Some("bytes")
+        );
+        assert_eq!(header(&full, header::ETAG).as_deref(), Some("\"0\""));
+        assert_eq!(header(&full, header::CONTENT_LENGTH), Some(len.to_string()));
+        assert_eq!(
+            header(&full, header::CONTENT_DISPOSITION).as_deref(),
+            Some("attachment; filename=\"Q3 rapport _.pdf\"; filename*=UTF-8''Q3%20rapport%20%C3%A9.pdf")
+        );
+
+        let part = get(&[(header::RANGE, "bytes=5-14")]).await.unwrap();
+        assert_eq!(part.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            header(&part, header::CONTENT_RANGE),
+            Some(format!("bytes 5-14/{len}"))
+        );
+        assert_eq!(header(&part, header::CONTENT_LENGTH).as_deref(), Some("10"));
+        let body = to_bytes(part.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.as_ref(), &SAMPLE_PDF[5..15]);
+
+        let tail = get(&[(header::RANGE, "bytes=-4"), (header::IF_RANGE, "\"0\"")])
+            .await
+            .unwrap();
+        assert_eq!(tail.status(), StatusCode::PARTIAL_CONTENT);
+        let body = to_bytes(tail.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.as_ref(), &SAMPLE_PDF[len - 4..]);
+
+        // A range of an older revision gets the whole current document.
+        let stale = get(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"7\"")])
+            .await
+            .unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+
+        let past_end = get(&[(header::RANGE, &format!("bytes={len}-"))])
+            .await
+            .unwrap();
+        assert_eq!(past_end.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            header(&past_end, header::CONTENT_RANGE),
+            Some(format!("bytes */{len}"))
+        );
+        let body = to_bytes(past_end.into_body(), usize::MAX).await.unwrap();
+        let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, "range_not_satisfiable");
+
+        let cached = get(&[(header::IF_NONE_MATCH, "\"0\"")]).await.unwrap();
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        let body = to_bytes(cached.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
     }
 }
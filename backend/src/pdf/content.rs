@@ -1,14 +1,619 @@
-//! Content stream tokenisation helpers (stub).
+//! Content stream tokenisation helpers.
+//!
+//! Unlike `lopdf::content::Content`, the parser here keeps the byte span of
+//! every operation so edits can be spliced into the original stream without
+//! re-serialising operators we did not touch.
 
-use anyhow::Result;
+use std::ops::Range;
 
-/// Tokenise a PDF content stream.
+use anyhow::{anyhow, bail, Result};
+use lopdf::{Dictionary, Object, StringFormat};
+
+/// A single operator together with its operands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentOp {
+    pub operator: String,
+    pub operands: Vec<Object>,
+    /// Bytes covered by the operands and the operator keyword.
+    pub span: Range<usize>,
+}
+
+impl ContentOp {
+    pub fn new(operator: &str, operands: Vec<Object>) -> Self {
+        Self {
+            operator: operator.to_string(),
+            operands,
+            span: 0..0,
+        }
+    }
+
+    /// Operand `index` as a number, if it is one.
+    pub fn number(&self, index: usize) -> Option<f64> {
+        match self.operands.get(index)? {
+            Object::Integer(value) => Some(*value as f64),
+            Object::Real(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    /// All operands as numbers; `None` if any of them is not numeric.
+    pub fn numbers(&self) -> Option<Vec<f64>> {
+        (0..self.operands.len()).map(|i| self.number(i)).collect()
+    }
+
+    pub fn name(&self, index: usize) -> Option<&[u8]> {
+        match self.operands.get(index)? {
+            Object::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_op(&mut out, self);
+        out
+    }
+}
+
+/// Parse a decoded content stream into operations.
 ///
-/// The MVP ships with a simplified tokenizer that recognises only a subset
-/// of PDF operators. The real implementation will expand this list and build
-/// a structured intermediate representation for editing.
-pub fn tokenize_stream(_bytes: &[u8]) -> Result<Vec<String>> {
-    Ok(Vec::new())
+/// Inline images are returned as a single `BI` operation whose operands are
+/// the image dictionary and the raw image data. Arrays and dictionaries
+/// nested deeper than `max_depth` are an error, since they are read
+/// recursively.
+pub fn parse_content(bytes: &[u8], max_depth: usize) -> Result<Vec<ContentOp>> {
+    let mut lexer = Lexer {
+        bytes,
+        pos: 0,
+        depth: 0,
+        max_depth,
+    };
+    let mut ops = Vec::new();
+    let mut operands = Vec::new();
+    let mut start = None;
+    loop {
+        lexer.skip_whitespace();
+        let token_start = lexer.pos;
+        let Some(token) = lexer.next_token()? else {
+            break;
+        };
+        match token {
+            Token::Object(object) => {
+                start.get_or_insert(token_start);
+                operands.push(object);
+            }
+            Token::Keyword(keyword) if keyword == "BI" => {
+                let (dict, data) = lexer.inline_image()?;
+                ops.push(ContentOp {
+                    operator: keyword,
+                    operands: vec![
+                        Object::Dictionary(dict),
+                        Object::String(data, StringFormat::Literal),
+                    ],
+                    span: start.take().unwrap_or(token_start)..lexer.pos,
+                });
+                operands.clear();
+            }
+            Token::Keyword(keyword) => {
+                ops.push(ContentOp {
+                    operator: keyword,
+                    operands: std::mem::take(&mut operands),
+                    span: start.take().unwrap_or(token_start)..lexer.pos,
+                });
+            }
+            Token::Close(_) => bail!("unbalanced delimiter at offset {token_start}"),
+        }
+    }
+    Ok(ops)
+}
+
+/// Read the operands at the start of `bytes` up to the first keyword, as
+/// in the `1 0 obj` and `<< … >> stream` of file syntax. The operator is
+/// empty if the input ends first, and the span ends after the keyword.
+pub fn parse_operation(bytes: &[u8], max_depth: usize) -> Result<ContentOp> {
+    let mut lexer = Lexer {
+        bytes,
+        pos: 0,
+        depth: 0,
+        max_depth,
+    };
+    lexer.skip_whitespace();
+    let start = lexer.pos;
+    let mut operands = Vec::new();
+    loop {
+        match lexer.object()? {
+            Some(Token::Object(object)) => operands.push(object),
+            Some(Token::Keyword(operator)) => {
+                return Ok(ContentOp {
+                    operator,
+                    operands,
+                    span: start..lexer.pos,
+                })
+            }
+            Some(Token::Close(byte)) => bail!("unbalanced {:?}", byte as char),
+            None => {
+                return Ok(ContentOp {
+                    operator: String::new(),
+                    operands,
+                    span: start..lexer.pos,
+                })
+            }
+        }
+    }
+}
+
+/// Serialise operations back into content stream syntax, one per line.
+pub fn encode_ops<'a>(ops: impl IntoIterator<Item = &'a ContentOp>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for op in ops {
+        write_op(&mut out, op);
+        out.push(b'\n');
+    }
+    out
+}
+
+fn write_op(out: &mut Vec<u8>, op: &ContentOp) {
+    if op.operator == "BI" {
+        out.extend_from_slice(b"BI");
+        if let Some(Object::Dictionary(dict)) = op.operands.first() {
+            for (key, value) in dict.iter() {
+                out.push(b' ');
+                write_name(out, key);
+                out.push(b' ');
+                write_object(out, value);
+            }
+        }
+        out.extend_from_slice(b" ID ");
+        if let Some(Object::String(data, _)) = op.operands.get(1) {
+            out.extend_from_slice(data);
+        }
+        out.extend_from_slice(b"\nEI");
+        return;
+    }
+    for operand in &op.operands {
+        write_object(out, operand);
+        out.push(b' ');
+    }
+    out.extend_from_slice(op.operator.as_bytes());
+}
+
+/// Serialise a direct object in content stream syntax.
+pub fn write_object(out: &mut Vec<u8>, object: &Object) {
+    match object {
+        Object::Null => out.extend_from_slice(b"null"),
+        Object::Boolean(value) => out.extend_from_slice(if *value { b"true" } else { b"false" }),
+        Object::Integer(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Object::Real(value) => out.extend_from_slice(format_number(*value as f64).as_bytes()),
+        Object::Name(name) => write_name(out, name),
+        Object::String(bytes, StringFormat::Hexadecimal) => {
+            out.push(b'<');
+            for byte in bytes {
+                out.extend_from_slice(format!("{byte:02X}").as_bytes());
+            }
+            out.push(b'>');
+        }
+        Object::String(bytes, StringFormat::Literal) => {
+            out.push(b'(');
+            for &byte in bytes {
+                match byte {
+                    b'(' | b')' | b'\\' => out.extend_from_slice(&[b'\\', byte]),
+                    b'\r' => out.extend_from_slice(b"\\r"),
+                    b'\n' => out.extend_from_slice(b"\\n"),
+                    _ => out.push(byte),
+                }
+            }
+            out.push(b')');
+        }
+        Object::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b' ');
+                }
+                write_object(out, item);
+            }
+            out.push(b']');
+        }
+        Object::Dictionary(dict) => {
+            out.extend_from_slice(b"<<");
+            for (key, value) in dict.iter() {
+                write_name(out, key);
+                out.push(b' ');
+                write_object(out, value);
+                out.push(b' ');
+            }
+            out.extend_from_slice(b">>");
+        }
+        Object::Reference((id, gen)) => out.extend_from_slice(format!("{id} {gen} R").as_bytes()),
+        // Streams cannot appear inside content streams.
+        Object::Stream(_) => out.extend_from_slice(b"null"),
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &[u8]) {
+    out.push(b'/');
+    for &byte in name {
+        if byte.is_ascii_graphic() && !is_delimiter(byte) && byte != b'#' {
+            out.push(byte);
+        } else {
+            out.extend_from_slice(format!("#{byte:02X}").as_bytes());
+        }
+    }
+}
+
+/// Format a number compactly, with at most five decimal places.
+pub fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        return format!("{}", value as i64);
+    }
+    let text = format!("{value:.5}");
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    match text {
+        "-0" | "" => "0".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// A real-number operand.
+pub fn real(value: f64) -> Object {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        Object::Integer(value as i64)
+    } else {
+        Object::Real(value as f32)
+    }
+}
+
+/// Operands for a six-number matrix operator such as `cm` or `Tm`.
+pub fn matrix_operands(values: [f64; 6]) -> Vec<Object> {
+    values.into_iter().map(real).collect()
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b'\x0c' | b'\0')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+enum Token {
+    Object(Object),
+    Keyword(String),
+    Close(u8),
+}
+
+struct Lexer<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Arrays and dictionaries currently open.
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Lexer<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if is_whitespace(byte) {
+                self.pos += 1;
+            } else if byte == b'%' {
+                while !matches!(self.peek(), None | Some(b'\r' | b'\n')) {
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        let Some(byte) = self.peek() else {
+            return Ok(None);
+        };
+        let token = match byte {
+            b'/' => {
+                self.pos += 1;
+                Token::Object(Object::Name(self.name()))
+            }
+            b'(' => {
+                self.pos += 1;
+                Token::Object(Object::String(
+                    self.literal_string()?,
+                    StringFormat::Literal,
+                ))
+            }
+            b'<' if self.bytes.get(self.pos + 1) == Some(&b'<') => {
+                self.pos += 2;
+                self.enter()?;
+                let dict = self.dictionary()?;
+                self.depth -= 1;
+                Token::Object(Object::Dictionary(dict))
+            }
+            b'<' => {
+                self.pos += 1;
+                Token::Object(Object::String(
+                    self.hex_string()?,
+                    StringFormat::Hexadecimal,
+                ))
+            }
+            b'[' => {
+                self.pos += 1;
+                self.enter()?;
+                let items = self.array()?;
+                self.depth -= 1;
+                Token::Object(Object::Array(items))
+            }
+            b']' | b')' | b'}' => {
+                self.pos += 1;
+                Token::Close(byte)
+            }
+            b'>' if self.bytes.get(self.pos + 1) == Some(&b'>') => {
+                self.pos += 2;
+                Token::Close(b'>')
+            }
+            b'{' | b'>' => {
+                // PostScript procedure braces only show up in CMaps; treat
+                // them as keywords so the caller can ignore them.
+                self.pos += 1;
+                Token::Keyword((byte as char).to_string())
+            }
+            b'+' | b'-' | b'.' | b'0'..=b'9' => self.number()?,
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(b) if !is_whitespace(b) && !is_delimiter(b)) {
+                    self.pos += 1;
+                }
+                let word = String::from_utf8_lossy(&self.bytes[start..self.pos]).into_owned();
+                match word.as_str() {
+                    "true" => Token::Object(Object::Boolean(true)),
+                    "false" => Token::Object(Object::Boolean(false)),
+                    "null" => Token::Object(Object::Null),
+                    _ => Token::Keyword(word),
+                }
+            }
+        };
+        Ok(Some(token))
+    }
+
+    /// Open an array or dictionary, failing past `max_depth` before the
+    /// recursion can exhaust the stack.
+    fn enter(&mut self) -> Result<()> {
+        if self.depth == self.max_depth {
+            bail!("objects are nested deeper than {} levels", self.max_depth);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn object(&mut self) -> Result<Option<Token>> {
+        self.skip_whitespace();
+        self.next_token()
+    }
+
+    fn number(&mut self) -> Result<Token> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(b'+' | b'-' | b'.' | b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos])?;
+        if let Ok(value) = text.parse::<i64>() {
+            return Ok(Token::Object(Object::Integer(value)));
+        }
+        // Tolerate producer quirks such as "--5" or "5." the way viewers do.
+        let cleaned = text.trim_start_matches('+');
+        let cleaned = if cleaned.starts_with("--") {
+            &cleaned[1..]
+        } else {
+            cleaned
+        };
+        match cleaned.parse::<f64>() {
+            Ok(value) => Ok(Token::Object(Object::Real(value as f32))),
+            Err(_) if text.chars().all(|c| matches!(c, '+' | '-' | '.')) => {
+                Ok(Token::Object(Object::Integer(0)))
+            }
+            Err(_) => bail!("invalid number {text:?} at offset {start}"),
+        }
+    }
+
+    fn name(&mut self) -> Vec<u8> {
+        let mut name = Vec::new();
+        while let Some(byte) = self.peek() {
+            if is_whitespace(byte) || is_delimiter(byte) {
+                break;
+            }
+            self.pos += 1;
+            if byte == b'#' {
+                let hex = self.bytes.get(self.pos..self.pos + 2);
+                if let Some(value) = hex
+                    .and_then(|h| std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    name.push(value);
+                    self.pos += 2;
+                    continue;
+                }
+            }
+            name.push(byte);
+        }
+        name
+    }
+
+    fn literal_string(&mut self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut depth = 1;
+        loop {
+            let byte = self.peek().ok_or_else(|| anyhow!("unterminated string"))?;
+            self.pos += 1;
+            match byte {
+                b'(' => {
+                    depth += 1;
+                    out.push(byte);
+                }
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(out);
+                    }
+                    out.push(byte);
+                }
+                b'\\' => {
+                    let Some(escaped) = self.peek() else {
+                        continue;
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0c),
+                        b'0'..=b'7' => {
+                            let mut value = (escaped - b'0') as u32;
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'7') => {
+                                        value = value * 8 + (d - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            out.push(value as u8);
+                        }
+                        b'\r' => {
+                            if self.peek() == Some(b'\n') {
+                                self.pos += 1;
+                            }
+                        }
+                        b'\n' => {}
+                        other => out.push(other),
+                    }
+                }
+                _ => out.push(byte),
+            }
+        }
+    }
+
+    fn hex_string(&mut self) -> Result<Vec<u8>> {
+        let mut digits = Vec::new();
+        loop {
+            let byte = self
+                .peek()
+                .ok_or_else(|| anyhow!("unterminated hex string"))?;
+            self.pos += 1;
+            match byte {
+                b'>' => break,
+                b if b.is_ascii_hexdigit() => digits.push((b as char).to_digit(16).unwrap() as u8),
+                b if is_whitespace(b) => {}
+                other => bail!("invalid hex digit {:?}", other as char),
+            }
+        }
+        if digits.len() % 2 == 1 {
+            digits.push(0);
+        }
+        Ok(digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect())
+    }
+
+    fn array(&mut self) -> Result<Vec<Object>> {
+        let mut items = Vec::new();
+        loop {
+            match self.object()? {
+                Some(Token::Object(object)) => items.push(object),
+                Some(Token::Close(b']')) => return Ok(items),
+                // Stray keywords inside arrays (e.g. "R") are skipped.
+                Some(Token::Keyword(_)) => {}
+                Some(Token::Close(other)) => bail!("unexpected {:?} in array", other as char),
+                None => bail!("unterminated array"),
+            }
+        }
+    }
+
+    fn dictionary(&mut self) -> Result<Dictionary> {
+        let mut dict = Dictionary::new();
+        loop {
+            let key = match self.object()? {
+                Some(Token::Object(Object::Name(key))) => key,
+                Some(Token::Close(b'>')) => return Ok(dict),
+                Some(_) => bail!("dictionary keys must be names"),
+                None => bail!("unterminated dictionary"),
+            };
+            match self.object()? {
+                Some(Token::Object(value)) => {
+                    let value = self.reference(value);
+                    dict.set(key, value)
+                }
+                _ => bail!("dictionary value missing"),
+            }
+        }
+    }
+
+    /// Fold `value` and a `gen R` after it into an indirect reference.
+    /// Content streams have none, but stream dictionaries in the file do.
+    fn reference(&mut self, value: Object) -> Object {
+        let Object::Integer(number) = value else {
+            return value;
+        };
+        let (pos, depth) = (self.pos, self.depth);
+        let generation = match self.object() {
+            Ok(Some(Token::Object(Object::Integer(generation)))) => Some(generation),
+            _ => None,
+        };
+        let keyword = match self.object() {
+            Ok(Some(Token::Keyword(keyword))) => Some(keyword),
+            _ => None,
+        };
+        if let (Some(generation), Some("R")) = (generation, keyword.as_deref()) {
+            if let (Ok(number), Ok(generation)) = (u32::try_from(number), u16::try_from(generation))
+            {
+                return Object::Reference((number, generation));
+            }
+        }
+        (self.pos, self.depth) = (pos, depth);
+        value
+    }
+
+    /// Read an inline image after `BI`: key/value pairs up to `ID`, then the
+    /// raw data up to the `EI` keyword.
+    fn inline_image(&mut self) -> Result<(Dictionary, Vec<u8>)> {
+        let mut dict = Dictionary::new();
+        loop {
+            match self.object()? {
+                Some(Token::Object(Object::Name(key))) => match self.object()? {
+                    Some(Token::Object(value)) => dict.set(key, value),
+                    _ => bail!("inline image value missing"),
+                },
+                Some(Token::Keyword(keyword)) if keyword == "ID" => break,
+                _ => bail!("malformed inline image dictionary"),
+            }
+        }
+        // A single whitespace byte separates ID from the data.
+        self.pos += 1;
+        let data_start = self.pos.min(self.bytes.len());
+        let mut i = data_start;
+        while i + 2 <= self.bytes.len() {
+            let delimited_before = i == data_start || is_whitespace(self.bytes[i - 1]);
+            let delimited_after = self.bytes.get(i + 2).is_none_or(|b| is_whitespace(*b));
+            if &self.bytes[i..i + 2] == b"EI" && delimited_before && delimited_after {
+                let mut data_end = i;
+                if data_end > data_start && is_whitespace(self.bytes[data_end - 1]) {
+                    data_end -= 1;
+                }
+                self.pos = i + 2;
+                return Ok((dict, self.bytes[data_start..data_end].to_vec()));
+            }
+            i += 1;
+        }
+        bail!("inline image is missing EI")
+    }
 }
 
 #[cfg(test)]
@@ -16,8 +621,87 @@ mod tests {
     use super::*;
 
     #[test]
-    fn tokenize_stream_returns_empty_collection() {
-        let tokens = tokenize_stream(b"BT (Hello) Tj ET").expect("tokenization should succeed");
-        assert!(tokens.is_empty());
+    fn parse_content_records_operands_and_spans() {
+        let source = b"BT /F1 12 Tf 72 712.5 Td (Hello) Tj ET";
+        let ops = parse_content(source, 64).expect("tokenization should succeed");
+
+        let operators: Vec<_> = ops.iter().map(|op| op.operator.as_str()).collect();
+        assert_eq!(operators, ["BT", "Tf", "Td", "Tj", "ET"]);
+        assert_eq!(ops[1].name(0), Some(&b"F1"[..]));
+        assert_eq!(ops[2].numbers(), Some(vec![72.0, 712.5]));
+        assert_eq!(&source[ops[3].span.clone()], b"(Hello) Tj");
+    }
+
+    #[test]
+    fn parse_content_handles_strings_arrays_and_comments() {
+        let source = b"% comment\n[(A\\)B) -120 <4142>] TJ (nested (parens)\\101) Tj";
+        let ops = parse_content(source, 64).unwrap();
+
+        assert_eq!(ops.len(), 2);
+        let Object::Array(items) = &ops[0].operands[0] else {
+            panic!("TJ takes an array");
+        };
+        assert_eq!(
+            items[0],
+            Object::String(b"A)B".to_vec(), StringFormat::Literal)
+        );
+        assert_eq!(items[1], Object::Integer(-120));
+        assert_eq!(
+            items[2],
+            Object::String(b"AB".to_vec(), StringFormat::Hexadecimal)
+        );
+        assert_eq!(
+            ops[1].operands[0],
+            Object::String(b"nested (parens)A".to_vec(), StringFormat::Literal)
+        );
+    }
+
+    #[test]
+    fn parse_content_keeps_inline_image_data_intact() {
+        let source = b"q BI /W 2 /H 1 /BPC 8 /CS /G ID \x00EI\xff\nEI Q";
+        let ops = parse_content(source, 64).unwrap();
+
+        let operators: Vec<_> = ops.iter().map(|op| op.operator.as_str()).collect();
+        assert_eq!(operators, ["q", "BI", "Q"]);
+        assert_eq!(
+            ops[1].operands[1],
+            Object::String(b"\x00EI\xff".to_vec(), StringFormat::Literal)
+        );
+    }
+
+    #[test]
+    fn parse_content_limits_nesting() {
+        let nested = |depth: usize| {
+            let mut source = b"[".repeat(depth);
+            source.extend(b"1".iter().chain(&b"]".repeat(depth)));
+            source.extend_from_slice(b" TJ << /A << /B 1 >> >> BDC");
+            source
+        };
+        assert_eq!(parse_content(&nested(4), 4).unwrap().len(), 2);
+        assert!(parse_content(&nested(5), 4).is_err());
+        assert!(parse_content(&b"<<".repeat(100_000), 4).is_err());
+    }
+
+    #[test]
+    fn encode_ops_round_trips() {
+        let source = b"q 1 0 0 1 10.25 -3 cm /GS0 gs [(a\\(b) 5 <00FF>] TJ Q";
+        let ops = parse_content(source, 64).unwrap();
+        let encoded = encode_ops(&ops);
+        let reparsed = parse_content(&encoded, 64).unwrap();
+
+        let strip = |ops: Vec<ContentOp>| -> Vec<_> {
+            ops.into_iter()
+                .map(|op| (op.operator, op.operands))
+                .collect()
+        };
+        assert_eq!(strip(reparsed), strip(ops));
+    }
+
+    #[test]
+    fn format_number_trims_trailing_zeros() {
+        assert_eq!(format_number(12.0), "12");
+        assert_eq!(format_number(0.5), "0.5");
+        assert_eq!(format_number(-0.000001), "0");
+        assert_eq!(format_number(1.0 / 3.0), "0.33333");
     }
 }
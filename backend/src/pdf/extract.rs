@@ -1,23 +1,1044 @@
-//! Extraction pipeline stub.
+//! Content stream interpretation and IR extraction.
+//!
+//! Each page is run through a small graphics-state interpreter that reports
+//! every painted text run, image and path. Form XObjects are entered
+//! recursively with their `/Matrix` and `/BBox` applied, so objects inside
+//! them show up on the page like any other, tagged with the form stream
+//! that owns their operators.
+//!
+//! A page whose `/Contents` is an array of streams is interpreted as one
+//! logical stream: operators may span stream boundaries, and offsets are
+//! mapped back to the underlying stream when reported.
 
-use anyhow::Result;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
 
-use crate::types::DocumentIR;
+use anyhow::{anyhow, Context, Result};
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use rayon::prelude::*;
 
-/// Produce an intermediate representation from a PDF document.
-/// Currently returns the sample IR used by the mocked backend routes.
-pub fn extract_ir(_pdf_bytes: &[u8]) -> Result<DocumentIR> {
-    Ok(DocumentIR::sample())
+use crate::pdf::content::{parse_content, ContentOp};
+use crate::pdf::filters::{self, UnsupportedFilter};
+use crate::pdf::fonts::encoding::DocumentFont;
+use crate::pdf::loader::LoadLimits;
+use crate::pdf::resources;
+use crate::types::{
+    FontInfo, ImageObject, PageIR, PageObject, PathObject, PdfRef, Span, TextGlyph, TextObject,
+};
+use crate::util::{bbox::BBox, matrix::Matrix2D};
+
+/// Forms nested deeper than this are not entered.
+const MAX_FORM_DEPTH: usize = 32;
+/// US Letter, used when a page has no usable `/MediaBox`.
+const DEFAULT_PAGE_SIZE: [f64; 4] = [0.0, 0.0, 612.0, 792.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Text,
+    Image,
+    Path,
+}
+
+impl ObjectKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ObjectKind::Text => "text",
+            ObjectKind::Image => "image",
+            ObjectKind::Path => "path",
+        }
+    }
+}
+
+/// Colour, transparency and text parameters in effect at a paint operator.
+#[derive(Debug, Clone)]
+pub struct GraphicsState {
+    pub ctm: Matrix2D,
+    /// Page-space bounds of the current clipping path, if any.
+    pub clip: Option<BBox>,
+    /// Operators that re-establish the current fill colour.
+    pub fill: Vec<ContentOp>,
+    /// Operators that re-establish the current stroke colour.
+    pub stroke: Vec<ContentOp>,
+    pub fill_alpha: f64,
+    pub stroke_alpha: f64,
+    pub line_width: f64,
+    pub text: TextState,
+}
+
+#[derive(Debug, Clone)]
+pub struct TextState {
+    pub font_name: Vec<u8>,
+    pub font: Option<Arc<DocumentFont>>,
+    pub size: f64,
+    pub char_spacing: f64,
+    pub word_spacing: f64,
+    /// `Tz` divided by 100.
+    pub horizontal_scale: f64,
+    pub leading: f64,
+    pub rise: f64,
+}
+
+impl Default for GraphicsState {
+    fn default() -> Self {
+        Self {
+            ctm: Matrix2D::identity(),
+            clip: None,
+            fill: vec![ContentOp::new("g", vec![Object::Integer(0)])],
+            stroke: vec![ContentOp::new("G", vec![Object::Integer(0)])],
+            fill_alpha: 1.0,
+            stroke_alpha: 1.0,
+            line_width: 1.0,
+            text: TextState {
+                font_name: Vec::new(),
+                font: None,
+                size: 0.0,
+                char_spacing: 0.0,
+                word_spacing: 0.0,
+                horizontal_scale: 1.0,
+                leading: 0.0,
+                rise: 0.0,
+            },
+        }
+    }
+}
+
+/// Text matrices around a show operator, in text space (before the CTM).
+#[derive(Debug, Clone, Copy)]
+pub struct TextPosition {
+    /// Line matrix in effect for the show, after any implicit `T*`.
+    pub tlm: Matrix2D,
+    /// Text matrix at the first glyph.
+    pub tm: Matrix2D,
+    /// Text matrix after the last glyph.
+    pub tm_after: Matrix2D,
+}
+
+/// A `Do` that entered a Form XObject on the way to an object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormUse {
+    /// Resource name the form was invoked by.
+    pub name: Vec<u8>,
+    pub form: ObjectId,
+    /// Page or form whose content contains the `Do`.
+    pub parent: ObjectId,
+    /// Index of the `Do` in the parent's operations.
+    pub do_op: usize,
+}
+
+/// Where an IR object lives in the content streams.
+#[derive(Debug, Clone)]
+pub struct ObjectRecord {
+    pub id: String,
+    pub kind: ObjectKind,
+    /// Page or form whose content holds the object's operators; the key
+    /// into [`PageExtraction::contents`].
+    pub owner: ObjectId,
+    /// Operations making up the object: the positioning operators and show
+    /// operator of a text run, the construction and paint operators of a
+    /// path, or the `Do` of an image.
+    pub ops: Range<usize>,
+    /// The operator that paints the object.
+    pub paint_op: usize,
+    /// Forms entered to reach `owner`, outermost first.
+    pub forms: Vec<FormUse>,
+    pub state: GraphicsState,
+    pub text: Option<TextPosition>,
+}
+
+/// Decoded content of a page or form, parsed into operations.
+#[derive(Debug, Clone)]
+pub struct StreamContent {
+    pub data: Vec<u8>,
+    pub ops: Vec<ContentOp>,
+    /// The streams `data` was joined from, in order.
+    pub segments: Vec<Segment>,
+}
+
+/// Where one underlying stream sits in the logical content.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub id: ObjectId,
+    pub range: Range<usize>,
+}
+
+impl StreamContent {
+    /// Index of the segment holding byte `offset` of the logical content.
+    /// Offsets in the separator between two streams belong to the later one.
+    pub fn segment_at(&self, offset: usize) -> usize {
+        self.segments
+            .iter()
+            .rposition(|segment| segment.range.start <= offset)
+            .unwrap_or(0)
+    }
+}
+
+/// Everything learned about a page while extracting it.
+#[derive(Debug, Clone)]
+pub struct PageExtraction {
+    pub page_id: ObjectId,
+    pub ir: PageIR,
+    pub records: Vec<ObjectRecord>,
+    /// Content of the page and of every form it draws, keyed by page or
+    /// form object id.
+    pub contents: HashMap<ObjectId, StreamContent>,
+    /// How often each form was invoked on the page.
+    pub form_invocations: HashMap<ObjectId, usize>,
+}
+
+impl PageExtraction {
+    pub fn record(&self, id: &str) -> Option<&ObjectRecord> {
+        self.records.iter().find(|record| record.id == id)
+    }
+}
+
+/// Produce the intermediate representation of every page in `doc`,
+/// extracting pages in parallel.
+#[cfg(test)]
+pub fn extract_ir(doc: &Document, limits: &LoadLimits) -> Result<crate::types::DocumentIR> {
+    let indices: Vec<usize> = (0..resources::page_ids(doc).len()).collect();
+    let pages = extract_pages(doc, &indices, limits)?;
+    Ok(crate::types::DocumentIR { pages })
+}
+
+/// The IR of the pages at `indices`, extracted in parallel and returned in
+/// the same order.
+pub fn extract_pages(
+    doc: &Document,
+    indices: &[usize],
+    limits: &LoadLimits,
+) -> Result<Vec<PageIR>> {
+    indices
+        .par_iter()
+        .map(|&index| extract_page(doc, index, limits).map(|page| page.ir))
+        .collect()
+}
+
+/// Interpret page `index` (zero-based) and record where its objects live.
+pub fn extract_page(doc: &Document, index: usize, limits: &LoadLimits) -> Result<PageExtraction> {
+    let page_id = *resources::page_ids(doc)
+        .get(index)
+        .ok_or_else(|| anyhow!("page {index} does not exist"))?;
+    let page_box = BBox::from_rect(page_box(doc, page_id));
+
+    let mut interpreter = Interpreter {
+        doc,
+        limits: *limits,
+        contents: HashMap::new(),
+        records: Vec::new(),
+        objects: Vec::new(),
+        fonts: HashMap::new(),
+        form_stack: Vec::new(),
+        form_invocations: HashMap::new(),
+    };
+    let page_resources = resources::page_resources(doc, page_id);
+    let mut frame = Frame::new(GraphicsState::default());
+    interpreter.run(&mut frame, page_id, page_resources, &[])?;
+
+    Ok(PageExtraction {
+        page_id,
+        ir: PageIR {
+            index,
+            width_pt: page_box.width(),
+            height_pt: page_box.height(),
+            objects: interpreter.objects,
+        },
+        records: interpreter.records,
+        contents: interpreter.contents,
+        form_invocations: interpreter.form_invocations,
+    })
+}
+
+/// Decode and parse the content of a page or form. Page content split
+/// over several streams is joined with a newline between streams, which
+/// keeps tokens from running together.
+pub fn load_content(doc: &Document, owner: ObjectId, limits: &LoadLimits) -> Result<StreamContent> {
+    let limit = limits.max_decoded_stream_bytes;
+    let ids = match doc.get_object(owner)? {
+        Object::Stream(_) => vec![owner],
+        _ => doc.get_page_contents(owner),
+    };
+    let mut data = Vec::new();
+    let mut segments = Vec::with_capacity(ids.len());
+    for (i, id) in ids.into_iter().enumerate() {
+        if i > 0 {
+            data.push(b'\n');
+        }
+        let start = data.len();
+        data.extend(decode_content_stream(doc, id, limit)?);
+        segments.push(Segment {
+            id,
+            range: start..data.len(),
+        });
+        if data.len() > limit {
+            return Err(anyhow!("page content exceeds the {limit} byte limit"));
+        }
+    }
+    let ops = parse_content(&data, limits.max_nesting_depth)?;
+    Ok(StreamContent {
+        data,
+        ops,
+        segments,
+    })
+}
+
+fn decode_content_stream(doc: &Document, id: ObjectId, limit: usize) -> Result<Vec<u8>> {
+    let stream = doc
+        .get_object(id)
+        .and_then(Object::as_stream)
+        .with_context(|| format!("content stream {} {} R", id.0, id.1))?;
+    let decoded = filters::decode_stream(doc, stream, limit)?;
+    if !decoded.is_complete() {
+        return Err(UnsupportedFilter {
+            stream: id,
+            filter: decoded.unsupported.unwrap_or_default(),
+        }
+        .into());
+    }
+    Ok(decoded.data)
+}
+
+fn page_box(doc: &Document, page_id: ObjectId) -> [f64; 4] {
+    let Some(Object::Array(items)) = resources::inherited(doc, page_id, b"MediaBox") else {
+        return DEFAULT_PAGE_SIZE;
+    };
+    let numbers: Vec<f64> = items
+        .iter()
+        .filter_map(|item| match resources::resolve(doc, item) {
+            Object::Integer(value) => Some(*value as f64),
+            Object::Real(value) => Some(*value as f64),
+            _ => None,
+        })
+        .collect();
+    numbers.try_into().unwrap_or(DEFAULT_PAGE_SIZE)
+}
+
+/// Interpreter state for the content of one page or form.
+struct Frame {
+    state: GraphicsState,
+    saved: Vec<GraphicsState>,
+    tm: Matrix2D,
+    tlm: Matrix2D,
+    /// First operation of the text run being built, if inside `BT`.
+    run_start: Option<usize>,
+    path_start: Option<usize>,
+    path_points: Vec<(f64, f64)>,
+    clip_pending: bool,
+}
+
+impl Frame {
+    fn new(state: GraphicsState) -> Self {
+        Self {
+            state,
+            saved: Vec::new(),
+            tm: Matrix2D::identity(),
+            tlm: Matrix2D::identity(),
+            run_start: None,
+            path_start: None,
+            path_points: Vec::new(),
+            clip_pending: false,
+        }
+    }
+
+    fn end_path(&mut self) {
+        self.path_start = None;
+        self.path_points.clear();
+        self.clip_pending = false;
+    }
+}
+
+struct Interpreter<'a> {
+    doc: &'a Document,
+    limits: LoadLimits,
+    contents: HashMap<ObjectId, StreamContent>,
+    records: Vec<ObjectRecord>,
+    objects: Vec<PageObject>,
+    fonts: HashMap<ObjectId, Arc<DocumentFont>>,
+    form_stack: Vec<ObjectId>,
+    form_invocations: HashMap<ObjectId, usize>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn run(
+        &mut self,
+        frame: &mut Frame,
+        owner: ObjectId,
+        resources: Option<&'a Dictionary>,
+        forms: &[FormUse],
+    ) -> Result<()> {
+        if !self.contents.contains_key(&owner) {
+            let content = load_content(self.doc, owner, &self.limits)?;
+            self.contents.insert(owner, content);
+        }
+        let ops = self.contents[&owner].ops.clone();
+        for (index, op) in ops.iter().enumerate() {
+            self.step(frame, owner, resources, forms, index, op)?;
+        }
+        Ok(())
+    }
+
+    fn step(
+        &mut self,
+        frame: &mut Frame,
+        owner: ObjectId,
+        resources: Option<&'a Dictionary>,
+        forms: &[FormUse],
+        index: usize,
+        op: &ContentOp,
+    ) -> Result<()> {
+        let state = &mut frame.state;
+        match op.operator.as_str() {
+            "q" => frame.saved.push(state.clone()),
+            "Q" => {
+                if let Some(saved) = frame.saved.pop() {
+                    frame.state = saved;
+                }
+            }
+            "cm" => {
+                if let Some(m) = matrix(op) {
+                    state.ctm = state.ctm.multiply(m);
+                }
+            }
+            "w" => state.line_width = op.number(0).unwrap_or(state.line_width),
+            "gs" => {
+                let params = op
+                    .name(0)
+                    .and_then(|name| resources::lookup(self.doc, resources, b"ExtGState", name))
+                    .and_then(|(_, object)| object.as_dict().ok());
+                if let Some(params) = params {
+                    let doc = self.doc;
+                    if let Some(value) = resources::get_number(doc, params, b"ca") {
+                        state.fill_alpha = value;
+                    }
+                    if let Some(value) = resources::get_number(doc, params, b"CA") {
+                        state.stroke_alpha = value;
+                    }
+                    if let Some(value) = resources::get_number(doc, params, b"LW") {
+                        state.line_width = value;
+                    }
+                }
+            }
+            "g" | "rg" | "k" => state.fill = vec![bare(op)],
+            "G" | "RG" | "K" => state.stroke = vec![bare(op)],
+            "cs" => state.fill = vec![bare(op)],
+            "CS" => state.stroke = vec![bare(op)],
+            "sc" | "scn" => {
+                state.fill.retain(|set| set.operator == "cs");
+                state.fill.push(bare(op));
+            }
+            "SC" | "SCN" => {
+                state.stroke.retain(|set| set.operator == "CS");
+                state.stroke.push(bare(op));
+            }
+
+            "BT" => {
+                frame.tm = Matrix2D::identity();
+                frame.tlm = Matrix2D::identity();
+                frame.run_start = Some(index + 1);
+            }
+            "ET" => frame.run_start = None,
+            "Tf" => {
+                let name = op.name(0).unwrap_or_default().to_vec();
+                state.text.font = self.font(resources, &name);
+                state.text.font_name = name;
+                state.text.size = op.number(1).unwrap_or(state.text.size);
+            }
+            "Tc" => state.text.char_spacing = op.number(0).unwrap_or(0.0),
+            "Tw" => state.text.word_spacing = op.number(0).unwrap_or(0.0),
+            "Tz" => state.text.horizontal_scale = op.number(0).unwrap_or(100.0) / 100.0,
+            "TL" => state.text.leading = op.number(0).unwrap_or(0.0),
+            "Ts" => state.text.rise = op.number(0).unwrap_or(0.0),
+            "Td" | "TD" => {
+                let (tx, ty) = (op.number(0).unwrap_or(0.0), op.number(1).unwrap_or(0.0));
+                if op.operator == "TD" {
+                    state.text.leading = -ty;
+                }
+                frame.tlm = frame.tlm.multiply(Matrix2D::translate(tx, ty));
+                frame.tm = frame.tlm;
+            }
+            "Tm" => {
+                if let Some(m) = matrix(op) {
+                    frame.tlm = m;
+                    frame.tm = m;
+                }
+            }
+            "T*" => next_line(frame),
+            "Tj" | "TJ" | "'" | "\"" => self.show_text(frame, owner, forms, index, op),
+
+            "m" | "l" | "c" | "v" | "y" | "re" | "h" => {
+                frame.path_start.get_or_insert(index);
+                let ctm = frame.state.ctm;
+                let numbers = op.numbers().unwrap_or_default();
+                if op.operator == "re" && numbers.len() == 4 {
+                    let (x, y, w, h) = (numbers[0], numbers[1], numbers[2], numbers[3]);
+                    for (px, py) in [(x, y), (x + w, y), (x, y + h), (x + w, y + h)] {
+                        frame.path_points.push(ctm.apply(px, py));
+                    }
+                } else {
+                    for pair in numbers.chunks_exact(2) {
+                        frame.path_points.push(ctm.apply(pair[0], pair[1]));
+                    }
+                }
+            }
+            "W" | "W*" => frame.clip_pending = true,
+            "S" | "s" | "f" | "F" | "f*" | "B" | "B*" | "b" | "b*" => {
+                self.paint_path(frame, owner, forms, index)
+            }
+            "n" => {
+                if frame.clip_pending {
+                    clip_to_path(frame);
+                }
+                frame.end_path();
+            }
+
+            "Do" => {
+                let name = op.name(0).unwrap_or_default().to_vec();
+                if let Some((id, object)) =
+                    resources::lookup(self.doc, resources, b"XObject", &name)
+                {
+                    if let Ok(xobject) = object.as_stream() {
+                        let subtype = xobject.dict.get(b"Subtype").and_then(Object::as_name);
+                        match subtype {
+                            Ok(b"Image") => self.paint_image(frame, owner, forms, index, id, &name),
+                            Ok(b"Form") => {
+                                if let Some(form_id) = id {
+                                    self.run_form(
+                                        frame, owner, resources, forms, index, form_id, name,
+                                    )?;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn font(&mut self, resources: Option<&Dictionary>, name: &[u8]) -> Option<Arc<DocumentFont>> {
+        let (id, object) = resources::lookup(self.doc, resources, b"Font", name)?;
+        let dict = object.as_dict().ok()?;
+        match id {
+            Some(id) => Some(
+                self.fonts
+                    .entry(id)
+                    .or_insert_with(|| Arc::new(DocumentFont::load(self.doc, dict)))
+                    .clone(),
+            ),
+            None => Some(Arc::new(DocumentFont::load(self.doc, dict))),
+        }
+    }
+
+    fn show_text(
+        &mut self,
+        frame: &mut Frame,
+        owner: ObjectId,
+        forms: &[FormUse],
+        index: usize,
+        op: &ContentOp,
+    ) {
+        let run_start = frame.run_start.unwrap_or(index);
+        frame.run_start = Some(index + 1);
+        match op.operator.as_str() {
+            "'" => next_line(frame),
+            "\"" => {
+                frame.state.text.word_spacing = op.number(0).unwrap_or(0.0);
+                frame.state.text.char_spacing = op.number(1).unwrap_or(0.0);
+                next_line(frame);
+            }
+            _ => {}
+        }
+        let text = &frame.state.text;
+        let size = text.size;
+        let scale = text.horizontal_scale;
+        let font = text.font.clone();
+
+        let start = frame.tm;
+        let mut advance = 0.0;
+        let mut unicode = String::new();
+        let mut glyphs = Vec::new();
+        let mut show = |bytes: &[u8], advance: &mut f64| {
+            let Some(font) = &font else {
+                return;
+            };
+            for ch in font.decode(bytes) {
+                let spacing = text.char_spacing + if ch.is_space { text.word_spacing } else { 0.0 };
+                *advance += (ch.width / 1000.0 * size + spacing) * scale;
+                unicode.push_str(&ch.text);
+                glyphs.push(TextGlyph {
+                    gid: ch.code,
+                    dx: ch.width,
+                    dy: 0.0,
+                });
+            }
+        };
+        match op.operands.last() {
+            Some(Object::String(bytes, _)) => show(bytes, &mut advance),
+            Some(Object::Array(items)) if op.operator == "TJ" => {
+                for item in items {
+                    match item {
+                        Object::String(bytes, _) => show(bytes, &mut advance),
+                        Object::Integer(value) => advance -= *value as f64 / 1000.0 * size * scale,
+                        Object::Real(value) => advance -= *value as f64 / 1000.0 * size * scale,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        frame.tm = start.multiply(Matrix2D::translate(advance, 0.0));
+        if glyphs.is_empty() {
+            // Pure positioning, such as `[-250] TJ`, belongs to the next run.
+            frame.run_start = Some(run_start);
+            return;
+        }
+
+        let state = &frame.state;
+        let (ascent, descent) = font
+            .as_ref()
+            .map(|font| (font.ascent, font.descent))
+            .unwrap_or((800.0, -200.0));
+        let rise = state.text.rise;
+        let to_page = state.ctm.multiply(start);
+        let bbox = BBox::new(
+            advance.min(0.0),
+            rise + descent / 1000.0 * size,
+            advance.max(0.0),
+            rise + ascent / 1000.0 * size,
+        )
+        .transform(to_page);
+
+        let id = format!("t:{}", self.records.len());
+        let content = &self.contents[&owner];
+        // Spans are reported against the stream holding the show operator;
+        // a run that started in an earlier stream is cut at its boundary.
+        let segment = &content.segments[content.segment_at(op.span.start)];
+        let span_start = content.ops[run_start.min(index)]
+            .span
+            .start
+            .max(segment.range.start);
+        self.objects.push(PageObject::Text(TextObject {
+            id: id.clone(),
+            pdf_ref: pdf_ref(segment.id),
+            bt_span: Span {
+                start: (span_start - segment.range.start) as u64,
+                end: (op.span.end - segment.range.start) as u64,
+                stream_obj: segment.id.0,
+            },
+            tm: to_page.to_array(),
+            font: FontInfo {
+                res_name: String::from_utf8_lossy(&state.text.font_name).into_owned(),
+                size,
+                font_type: font
+                    .as_ref()
+                    .map(|font| font.subtype.clone())
+                    .unwrap_or_default(),
+            },
+            unicode,
+            glyphs,
+            bbox: clip(bbox, state.clip).to_array(),
+        }));
+        self.records.push(ObjectRecord {
+            id,
+            kind: ObjectKind::Text,
+            owner,
+            ops: run_start.min(index)..index + 1,
+            paint_op: index,
+            forms: forms.to_vec(),
+            state: state.clone(),
+            text: Some(TextPosition {
+                tlm: frame.tlm,
+                tm: start,
+                tm_after: frame.tm,
+            }),
+        });
+    }
+
+    fn paint_path(&mut self, frame: &mut Frame, owner: ObjectId, forms: &[FormUse], index: usize) {
+        let start = frame.path_start.unwrap_or(index);
+        if frame.clip_pending {
+            // Paths that also clip cannot be moved without changing what
+            // the clip applies to, so they are not reported.
+            clip_to_path(frame);
+            frame.end_path();
+            return;
+        }
+        let state = &frame.state;
+        let stroked = !matches!(
+            frame_operator(&self.contents[&owner], index),
+            "f" | "F" | "f*"
+        );
+        let mut bbox = BBox::from_points(frame.path_points.iter().copied())
+            .unwrap_or_else(|| BBox::new(0.0, 0.0, 0.0, 0.0));
+        if stroked {
+            let half = state.line_width * state.ctm.determinant().abs().sqrt() / 2.0;
+            bbox = BBox::new(
+                bbox.min_x - half,
+                bbox.min_y - half,
+                bbox.max_x + half,
+                bbox.max_y + half,
+            );
+        }
+        let content = &self.contents[&owner];
+        let operations = content.ops[start..=index]
+            .iter()
+            .map(|op| String::from_utf8_lossy(&op.encode()).into_owned())
+            .collect();
+        let paint = &content.ops[index];
+        let stream = content.segments[content.segment_at(paint.span.start)].id;
+
+        let id = format!("p:{}", self.records.len());
+        self.objects.push(PageObject::Path(PathObject {
+            id: id.clone(),
+            pdf_ref: pdf_ref(stream),
+            operations,
+            cm: state.ctm.to_array(),
+            bbox: clip(bbox, state.clip).to_array(),
+        }));
+        self.records.push(ObjectRecord {
+            id,
+            kind: ObjectKind::Path,
+            owner,
+            ops: start..index + 1,
+            paint_op: index,
+            forms: forms.to_vec(),
+            state: state.clone(),
+            text: None,
+        });
+        frame.end_path();
+    }
+
+    fn paint_image(
+        &mut self,
+        frame: &Frame,
+        owner: ObjectId,
+        forms: &[FormUse],
+        index: usize,
+        xobject: Option<ObjectId>,
+        name: &[u8],
+    ) {
+        let state = &frame.state;
+        let bbox = BBox::new(0.0, 0.0, 1.0, 1.0).transform(state.ctm);
+        let content = &self.contents[&owner];
+        let stream = content.segments[content.segment_at(content.ops[index].span.start)].id;
+        let id = format!("img:{}", self.records.len());
+        self.objects.push(PageObject::Image(ImageObject {
+            id: id.clone(),
+            pdf_ref: pdf_ref(xobject.unwrap_or(stream)),
+            x_object: String::from_utf8_lossy(name).into_owned(),
+            cm: state.ctm.to_array(),
+            bbox: clip(bbox, state.clip).to_array(),
+        }));
+        self.records.push(ObjectRecord {
+            id,
+            kind: ObjectKind::Image,
+            owner,
+            ops: index..index + 1,
+            paint_op: index,
+            forms: forms.to_vec(),
+            state: state.clone(),
+            text: None,
+        });
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn run_form(
+        &mut self,
+        frame: &Frame,
+        owner: ObjectId,
+        resources: Option<&'a Dictionary>,
+        forms: &[FormUse],
+        index: usize,
+        form_id: ObjectId,
+        name: Vec<u8>,
+    ) -> Result<()> {
+        if self.form_stack.len() >= MAX_FORM_DEPTH || self.form_stack.contains(&form_id) {
+            tracing::warn!(form = form_id.0, "skipping recursive or deeply nested form");
+            return Ok(());
+        }
+        let doc = self.doc;
+        let dict = &doc.get_object(form_id)?.as_stream()?.dict;
+
+        let mut state = frame.state.clone();
+        if let Some(m) = resources::get(doc, dict, b"Matrix").and_then(|m| numbers(doc, m)) {
+            state.ctm = state.ctm.multiply(Matrix2D::from_array(m));
+        }
+        if let Some(bbox) = resources::get(doc, dict, b"BBox").and_then(|b| numbers(doc, b)) {
+            let bbox = BBox::from_rect(bbox).transform(state.ctm);
+            state.clip = Some(clip(bbox, state.clip));
+        }
+        let form_resources = resources::get_dict(doc, dict, b"Resources").or(resources);
+
+        let mut chain = forms.to_vec();
+        chain.push(FormUse {
+            name,
+            form: form_id,
+            parent: owner,
+            do_op: index,
+        });
+        *self.form_invocations.entry(form_id).or_default() += 1;
+
+        self.form_stack.push(form_id);
+        let mut inner = Frame::new(state);
+        let result = self.run(&mut inner, form_id, form_resources, &chain);
+        self.form_stack.pop();
+        result
+    }
+}
+
+fn frame_operator(stream: &StreamContent, index: usize) -> &str {
+    stream.ops[index].operator.as_str()
+}
+
+fn next_line(frame: &mut Frame) {
+    let leading = frame.state.text.leading;
+    frame.tlm = frame.tlm.multiply(Matrix2D::translate(0.0, -leading));
+    frame.tm = frame.tlm;
+}
+
+fn clip_to_path(frame: &mut Frame) {
+    if let Some(bbox) = BBox::from_points(frame.path_points.iter().copied()) {
+        frame.state.clip = Some(clip(bbox, frame.state.clip));
+    }
+}
+
+fn clip(bbox: BBox, clip: Option<BBox>) -> BBox {
+    match clip {
+        Some(clip) => bbox.intersect(clip),
+        None => bbox,
+    }
+}
+
+/// A copy of `op` detached from its source position.
+fn bare(op: &ContentOp) -> ContentOp {
+    ContentOp::new(&op.operator, op.operands.clone())
+}
+
+fn matrix(op: &ContentOp) -> Option<Matrix2D> {
+    let values: [f64; 6] = op.numbers()?.try_into().ok()?;
+    Some(Matrix2D::from_array(values))
+}
+
+fn numbers<const N: usize>(doc: &Document, object: &Object) -> Option<[f64; N]> {
+    let items = object.as_array().ok()?;
+    let values: Vec<f64> = items
+        .iter()
+        .map(|item| match resources::resolve(doc, item) {
+            Object::Integer(value) => Some(*value as f64),
+            Object::Real(value) => Some(*value as f64),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+fn pdf_ref(id: ObjectId) -> PdfRef {
+    PdfRef {
+        obj: id.0,
+        gen: id.1,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pdf::testing::TestPdf;
+    use lopdf::dictionary;
+
+    fn text(page: &PageIR, index: usize) -> &TextObject {
+        match &page.objects[index] {
+            PageObject::Text(text) => text,
+            other => panic!("expected text, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn extracts_text_from_the_sample_document() {
+        let (_, doc) = TestPdf::sample();
+        let ir = extract_ir(&doc, &LoadLimits::default()).unwrap();
+
+        assert_eq!(ir.pages.len(), 1);
+        let page = &ir.pages[0];
+        assert_eq!((page.width_pt, page.height_pt), (595.0, 842.0));
+        let hello = text(page, 0);
+        assert_eq!(hello.id, "t:0");
+        assert_eq!(hello.unicode, "Hello world");
+        assert_eq!(hello.tm, [1.0, 0.0, 0.0, 1.0, 72.0, 720.0]);
+        assert_eq!(hello.font.res_name, "F1");
+        assert_eq!(hello.font.size, 24.0);
+        assert_eq!(hello.bt_span.stream_obj, 4);
+        assert_eq!(hello.glyphs[0].dx, 722.0);
+        assert!(hello.bbox[2] > 72.0 + 100.0);
+    }
+
+    #[test]
+    fn reports_paths_and_images_with_their_matrices() {
+        let mut pdf = TestPdf::new();
+        let image = pdf.image(2, 2);
+        pdf.page(
+            b"1 0 0 RG 2 w 10 10 m 110 10 l S q 50 0 0 40 200 300 cm /Im1 Do Q 0 0 10 10 re W n",
+            dictionary! { "XObject" => dictionary! { "Im1" => image } },
+        );
+        let (_, doc) = pdf.finish();
+        let extraction = extract_page(&doc, 0, &LoadLimits::default()).unwrap();
+
+        let PageObject::Path(path) = &extraction.ir.objects[0] else {
+            panic!("expected path");
+        };
+        assert_eq!(path.id, "p:0");
+        assert_eq!(path.operations, ["10 10 m", "110 10 l", "S"]);
+        assert_eq!(path.bbox, [9.0, 9.0, 111.0, 11.0]);
+        let PageObject::Image(img) = &extraction.ir.objects[1] else {
+            panic!("expected image");
+        };
+        assert_eq!(img.id, "img:1");
+        assert_eq!(img.pdf_ref.obj, image.0);
+        assert_eq!(img.cm, [50.0, 0.0, 0.0, 40.0, 200.0, 300.0]);
+        assert_eq!(img.bbox, [200.0, 300.0, 250.0, 340.0]);
+        // The clipping path is not an object.
+        assert_eq!(extraction.ir.objects.len(), 2);
+        assert_eq!(extraction.records[0].ops, 2..5);
+        assert_eq!(extraction.records[0].state.stroke[0].operator, "RG");
+    }
+
+    #[test]
+    fn tracks_text_matrices_across_runs() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        pdf.page(
+            b"BT /F1 10 Tf 14 TL 100 700 Td (AB) Tj (C) Tj T* [(D) -1000 (E)] TJ ET",
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        let (_, doc) = pdf.finish();
+        let extraction = extract_page(&doc, 0, &LoadLimits::default()).unwrap();
+        let page = &extraction.ir;
+
+        assert_eq!(page.objects.len(), 3);
+        // "AB" is 667 + 667 thousandths wide at 10pt.
+        assert!((text(page, 1).tm[4] - 113.34).abs() < 1e-9);
+        assert_eq!(text(page, 2).tm[4..], [100.0, 686.0]);
+        assert_eq!(text(page, 2).unicode, "DE");
+        let last = extraction.records[2].text.unwrap();
+        assert!((last.tm_after.e - (100.0 + 7.22 + 10.0 + 6.67)).abs() < 1e-9);
+        assert_eq!(last.tlm.f, 686.0);
+        assert_eq!(extraction.records[1].ops, 5..6);
+    }
 
     #[test]
-    fn extract_ir_returns_sample_ir() {
-        let bytes = include_bytes!("../../../e2e/sample.pdf");
-        let ir = extract_ir(bytes).expect("extracting IR should succeed");
-        assert_eq!(ir, DocumentIR::sample());
+    fn enters_form_xobjects_with_matrix_and_bbox() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        let form = pdf.form(
+            b"BT /F1 12 Tf 10 10 Td (Inside) Tj ET 0 0 500 500 re f",
+            [0.0, 0.0, 100.0, 50.0],
+            [2.0, 0.0, 0.0, 2.0, 100.0, 200.0],
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        pdf.page(
+            b"q /Fm1 Do Q",
+            dictionary! { "XObject" => dictionary! { "Fm1" => form } },
+        );
+        let (_, doc) = pdf.finish();
+        let extraction = extract_page(&doc, 0, &LoadLimits::default()).unwrap();
+
+        let inside = text(&extraction.ir, 0);
+        assert_eq!(inside.unicode, "Inside");
+        assert_eq!(inside.tm, [2.0, 0.0, 0.0, 2.0, 120.0, 220.0]);
+        assert_eq!(inside.bt_span.stream_obj, form.0);
+        assert_eq!(inside.pdf_ref.obj, form.0);
+
+        let PageObject::Path(path) = &extraction.ir.objects[1] else {
+            panic!("expected path");
+        };
+        // The 500pt square is clipped to the form's scaled /BBox.
+        assert_eq!(path.bbox, [100.0, 200.0, 300.0, 300.0]);
+
+        let record = &extraction.records[0];
+        assert_eq!(record.owner, form);
+        assert_eq!(record.forms.len(), 1);
+        assert_eq!(record.forms[0].name, b"Fm1");
+        assert_eq!(record.forms[0].do_op, 1);
+        assert_eq!(extraction.form_invocations[&form], 1);
+    }
+
+    #[test]
+    fn self_referencing_forms_terminate() {
+        let mut pdf = TestPdf::new();
+        let form = pdf.form(
+            b"/Fm1 Do 0 0 1 1 re f",
+            [0.0, 0.0, 10.0, 10.0],
+            [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            Dictionary::new(),
+        );
+        pdf.set_form_resources(
+            form,
+            dictionary! { "XObject" => dictionary! { "Fm1" => form } },
+        );
+        pdf.page(
+            b"/Fm1 Do",
+            dictionary! { "XObject" => dictionary! { "Fm1" => form } },
+        );
+        let (_, doc) = pdf.finish();
+
+        let extraction = extract_page(&doc, 0, &LoadLimits::default()).unwrap();
+        assert_eq!(extraction.ir.objects.len(), 1);
+    }
+
+    #[test]
+    fn deeply_nested_content_is_an_error_not_a_stack_overflow() {
+        let mut content = b"[".repeat(500_000);
+        content.extend_from_slice(b" 0 0 1 1 re f");
+        let mut pdf = TestPdf::new();
+        pdf.page(&content, Dictionary::new());
+        let (_, doc) = pdf.finish();
+
+        let err = extract_page(&doc, 0, &LoadLimits::default()).unwrap_err();
+        assert!(err.to_string().contains("nested deeper than 64 levels"));
+    }
+
+    #[test]
+    fn joins_content_arrays_into_one_logical_stream() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        let first = pdf.stream(b"0 0 5 5 re f BT /F1 12");
+        let second = pdf.stream(b"Tf 10 10 Td (Hi) Tj ET");
+        pdf.page_with_contents(
+            vec![first.into(), second.into()].into(),
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        let (_, doc) = pdf.finish();
+        let extraction = extract_page(&doc, 0, &LoadLimits::default()).unwrap();
+
+        let content = &extraction.contents[&extraction.page_id];
+        assert_eq!(
+            content.segments[0],
+            Segment {
+                id: first,
+                range: 0..22
+            }
+        );
+        assert_eq!(
+            content.segments[1],
+            Segment {
+                id: second,
+                range: 23..45
+            }
+        );
+        // `/F1 12` and its `Tf` sit in different streams.
+        assert_eq!(content.ops[3].operator, "Tf");
+        assert_eq!(content.segment_at(content.ops[3].span.start), 0);
+
+        let PageObject::Path(path) = &extraction.ir.objects[0] else {
+            panic!("expected path");
+        };
+        assert_eq!(path.pdf_ref.obj, first.0);
+        let hi = text(&extraction.ir, 1);
+        assert_eq!(hi.unicode, "Hi");
+        assert_eq!(hi.font.size, 12.0);
+        assert_eq!(hi.pdf_ref.obj, second.0);
+        assert_eq!(hi.bt_span.stream_obj, second.0);
+        assert_eq!((hi.bt_span.start, hi.bt_span.end), (0, 19));
     }
 }
@@ -0,0 +1,779 @@
+//! Stream filter decoding and encoding.
+//!
+//! Streams are decoded by running their `/Filter` chain in order, honouring
+//! the matching `/DecodeParms` entry for predictors and LZW early change.
+//! Image codecs we do not implement stop the chain and hand back the data
+//! decoded so far, so image payloads can still be passed through untouched.
+
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+
+/// A content stream compressed with a filter we cannot decode, so it can
+/// be neither shown nor edited.
+#[derive(Debug, thiserror::Error)]
+#[error("content stream {} {} R uses unsupported filter {filter}", .stream.0, .stream.1)]
+pub struct UnsupportedFilter {
+    pub stream: ObjectId,
+    pub filter: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FilterError {
+    #[error("{filter} data is corrupt: {reason}")]
+    Corrupt {
+        filter: &'static str,
+        reason: String,
+    },
+    #[error("decoded data exceeds the {limit} byte limit")]
+    TooLarge { limit: usize },
+    #[error("invalid /DecodeParms: {0}")]
+    InvalidParams(String),
+}
+
+/// Output of a filter chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded {
+    pub data: Vec<u8>,
+    /// The first filter that could not be applied. When set, `data` is the
+    /// output of the filters before it, i.e. the encoded codec payload.
+    pub unsupported: Option<String>,
+}
+
+impl Decoded {
+    pub fn is_complete(&self) -> bool {
+        self.unsupported.is_none()
+    }
+}
+
+/// Decode `stream` through its whole filter chain, failing once the output
+/// grows past `limit` bytes.
+pub fn decode_stream(
+    doc: &Document,
+    stream: &Stream,
+    limit: usize,
+) -> Result<Decoded, FilterError> {
+    decode(&stream.content, &filter_chain(doc, stream), limit)
+}
+
+/// Fail with [`FilterError::TooLarge`] if `stream` decodes to more than
+/// `limit` bytes, without keeping the decoded data. Filters ahead of the
+/// last one we can decode are ASCII armour or similar in practice and are
+/// run as usual; the last one is streamed and only counted. Corrupt data
+/// passes, to be reported when the stream is actually decoded.
+pub fn check_decoded_size(
+    doc: &Document,
+    stream: &Stream,
+    limit: usize,
+) -> Result<(), FilterError> {
+    let chain = filter_chain(doc, stream);
+    let too_large = Err(FilterError::TooLarge { limit });
+    let Some(last) = chain.iter().rposition(|(name, _)| is_supported(name)) else {
+        return if stream.content.len() > limit {
+            too_large
+        } else {
+            Ok(())
+        };
+    };
+    let input = match decode(&stream.content, &chain[..last], limit) {
+        Ok(decoded) if decoded.is_complete() => decoded.data,
+        Err(FilterError::TooLarge { .. }) => return too_large,
+        _ => return Ok(()),
+    };
+    // Predictors never make the data longer, so they can be left out.
+    let size = match chain[last].0 {
+        b"FlateDecode" | b"Fl" => inflated_size(&input, limit),
+        b"LZWDecode" | b"LZW" => {
+            let early_change = int_param(chain[last].1, b"EarlyChange", 1).unwrap_or(1) != 0;
+            let mut size = 0;
+            match lzw_stream(&input, early_change, limit, |chunk| size += chunk.len()) {
+                Err(FilterError::TooLarge { .. }) => return too_large,
+                _ => size,
+            }
+        }
+        b"RunLengthDecode" | b"RL" => run_length_size(&input),
+        // The ASCII filters shrink their input, or at most quadruple it.
+        _ => match decode(&input, &chain[last..=last], limit) {
+            Ok(decoded) => decoded.data.len(),
+            Err(FilterError::TooLarge { .. }) => return too_large,
+            Err(_) => 0,
+        },
+    };
+    if size > limit {
+        too_large
+    } else {
+        Ok(())
+    }
+}
+
+/// The `/Filter` names of `stream` paired with their `/DecodeParms`.
+fn filter_chain<'a>(
+    doc: &'a Document,
+    stream: &'a Stream,
+) -> Vec<(&'a [u8], Option<&'a Dictionary>)> {
+    let filters = resolve(doc, stream.dict.get(b"Filter").ok());
+    let params = resolve(doc, stream.dict.get(b"DecodeParms").ok());
+
+    let names: Vec<&[u8]> = match filters {
+        Some(Object::Name(name)) => vec![name.as_slice()],
+        Some(Object::Array(items)) => items
+            .iter()
+            .filter_map(|item| resolve(doc, Some(item)).and_then(|o| o.as_name().ok()))
+            .collect(),
+        _ => Vec::new(),
+    };
+    let params: Vec<Option<&Dictionary>> = match params {
+        Some(Object::Dictionary(dict)) => vec![Some(dict)],
+        Some(Object::Array(items)) => items
+            .iter()
+            .map(|item| resolve(doc, Some(item)).and_then(|o| o.as_dict().ok()))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (*name, params.get(i).copied().flatten()))
+        .collect()
+}
+
+/// Whether [`decode`] implements the filter `name` and it changes the
+/// data. `/Crypt` is passed through as is, so it does not count.
+fn is_supported(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"FlateDecode"
+            | b"Fl"
+            | b"LZWDecode"
+            | b"LZW"
+            | b"ASCII85Decode"
+            | b"A85"
+            | b"ASCIIHexDecode"
+            | b"AHx"
+            | b"RunLengthDecode"
+            | b"RL"
+    )
+}
+
+/// Run `data` through `chain`, a list of filter names and their parameters.
+pub fn decode(
+    data: &[u8],
+    chain: &[(&[u8], Option<&Dictionary>)],
+    limit: usize,
+) -> Result<Decoded, FilterError> {
+    if chain.is_empty() && data.len() > limit {
+        return Err(FilterError::TooLarge { limit });
+    }
+    let mut current = data.to_vec();
+    for (name, params) in chain {
+        current = match *name {
+            b"FlateDecode" | b"Fl" => {
+                let inflated = inflate(&current, limit)?;
+                apply_predictor(inflated, *params, limit)?
+            }
+            b"LZWDecode" | b"LZW" => {
+                let early_change = int_param(*params, b"EarlyChange", 1)? != 0;
+                let decoded = lzw_decode(&current, early_change, limit)?;
+                apply_predictor(decoded, *params, limit)?
+            }
+            b"ASCII85Decode" | b"A85" => ascii85_decode(&current)?,
+            b"ASCIIHexDecode" | b"AHx" => ascii_hex_decode(&current)?,
+            b"RunLengthDecode" | b"RL" => run_length_decode(&current, limit)?,
+            b"Crypt" => current,
+            other => {
+                let filter = String::from_utf8_lossy(other).into_owned();
+                tracing::debug!(filter, "leaving stream data encoded");
+                return Ok(Decoded {
+                    data: current,
+                    unsupported: Some(filter),
+                });
+            }
+        };
+        if current.len() > limit {
+            return Err(FilterError::TooLarge { limit });
+        }
+    }
+    Ok(Decoded {
+        data: current,
+        unsupported: None,
+    })
+}
+
+/// Compress `data` with zlib for a `/FlateDecode` stream.
+pub fn encode_flate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+/// Replace the payload of `stream` with Flate-compressed `data`, dropping
+/// whatever filters and parameters it had before.
+pub fn set_flate_content(stream: &mut Stream, data: &[u8]) {
+    stream.dict.remove(b"DecodeParms");
+    stream
+        .dict
+        .set("Filter", Object::Name(b"FlateDecode".to_vec()));
+    stream.set_content(encode_flate(data));
+}
+
+fn resolve<'a>(doc: &'a Document, object: Option<&'a Object>) -> Option<&'a Object> {
+    match object {
+        Some(Object::Reference(id)) => doc.get_object(*id).ok(),
+        other => other,
+    }
+}
+
+fn int_param(params: Option<&Dictionary>, key: &[u8], default: i64) -> Result<i64, FilterError> {
+    match params.and_then(|p| p.get(key).ok()) {
+        None | Some(Object::Null) => Ok(default),
+        Some(Object::Integer(value)) => Ok(*value),
+        Some(other) => Err(FilterError::InvalidParams(format!(
+            "/{} must be an integer, got {other:?}",
+            String::from_utf8_lossy(key)
+        ))),
+    }
+}
+
+fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, FilterError> {
+    let mut out = Vec::new();
+    let mut decoder = ZlibDecoder::new(data).take((limit as u64).saturating_add(1));
+    match decoder.read_to_end(&mut out) {
+        Ok(_) => {}
+        // Truncated or checksum-less streams are common in the wild; keep
+        // what inflated cleanly rather than losing the whole stream.
+        Err(err) if !out.is_empty() => {
+            tracing::debug!(error = %err, "flate stream ended early");
+        }
+        Err(err) => {
+            return Err(FilterError::Corrupt {
+                filter: "FlateDecode",
+                reason: err.to_string(),
+            })
+        }
+    }
+    if out.len() > limit {
+        return Err(FilterError::TooLarge { limit });
+    }
+    Ok(out)
+}
+
+/// Inflate `data` into nothing, counting up to `limit + 1` bytes. Data
+/// after a corrupt block is not counted.
+fn inflated_size(data: &[u8], limit: usize) -> usize {
+    let mut decoder = ZlibDecoder::new(data).take((limit as u64).saturating_add(1));
+    let mut buffer = [0u8; 8192];
+    let mut size = 0;
+    while let Ok(read @ 1..) = decoder.read(&mut buffer) {
+        size += read;
+    }
+    size
+}
+
+fn lzw_decode(data: &[u8], early_change: bool, limit: usize) -> Result<Vec<u8>, FilterError> {
+    let mut out = Vec::new();
+    lzw_stream(data, early_change, limit, |chunk| {
+        out.extend_from_slice(chunk)
+    })?;
+    Ok(out)
+}
+
+/// Decode LZW `data`, handing the output to `emit` in chunks and failing
+/// once more than `limit` bytes came out.
+fn lzw_stream(
+    data: &[u8],
+    early_change: bool,
+    limit: usize,
+    mut emit: impl FnMut(&[u8]),
+) -> Result<(), FilterError> {
+    let mut decoder = if early_change {
+        weezl::decode::Decoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+    } else {
+        weezl::decode::Decoder::new(weezl::BitOrder::Msb, 8)
+    };
+    let mut total = 0;
+    let mut buffer = [0u8; 8192];
+    let mut input = data;
+    loop {
+        let result = decoder.decode_bytes(input, &mut buffer);
+        input = &input[result.consumed_in..];
+        emit(&buffer[..result.consumed_out]);
+        total += result.consumed_out;
+        if total > limit {
+            return Err(FilterError::TooLarge { limit });
+        }
+        match result.status {
+            Ok(weezl::LzwStatus::Done) => break,
+            Ok(weezl::LzwStatus::NoProgress) => break,
+            Ok(weezl::LzwStatus::Ok) => {}
+            Err(err) => {
+                return Err(FilterError::Corrupt {
+                    filter: "LZWDecode",
+                    reason: err.to_string(),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ascii85_decode(data: &[u8]) -> Result<Vec<u8>, FilterError> {
+    let corrupt = |reason: &str| FilterError::Corrupt {
+        filter: "ASCII85Decode",
+        reason: reason.to_string(),
+    };
+    let mut out = Vec::with_capacity(data.len() * 4 / 5);
+    let mut group = [0u8; 5];
+    let mut filled = 0;
+    let mut bytes = data.iter().copied();
+    if data.starts_with(b"<~") {
+        bytes.nth(1);
+    }
+    for byte in bytes {
+        match byte {
+            b'~' => break,
+            b'z' if filled == 0 => out.extend_from_slice(&[0; 4]),
+            b'!'..=b'u' => {
+                group[filled] = byte - b'!';
+                filled += 1;
+                if filled == 5 {
+                    out.extend_from_slice(
+                        &ascii85_group(&group).ok_or_else(|| corrupt("group overflows 32 bits"))?,
+                    );
+                    filled = 0;
+                }
+            }
+            byte if byte.is_ascii_whitespace() || byte == 0 => {}
+            _ => return Err(corrupt("unexpected character")),
+        }
+    }
+    match filled {
+        0 => {}
+        1 => return Err(corrupt("dangling final character")),
+        n => {
+            for slot in group.iter_mut().skip(n) {
+                *slot = b'u' - b'!';
+            }
+            let decoded =
+                ascii85_group(&group).ok_or_else(|| corrupt("group overflows 32 bits"))?;
+            out.extend_from_slice(&decoded[..n - 1]);
+        }
+    }
+    Ok(out)
+}
+
+fn ascii85_group(group: &[u8; 5]) -> Option<[u8; 4]> {
+    let value = group.iter().try_fold(0u32, |acc, &digit| {
+        acc.checked_mul(85)?.checked_add(digit as u32)
+    })?;
+    Some(value.to_be_bytes())
+}
+
+fn ascii_hex_decode(data: &[u8]) -> Result<Vec<u8>, FilterError> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    let mut high: Option<u8> = None;
+    for &byte in data {
+        let nibble = match byte {
+            b'>' => break,
+            b'0'..=b'9' => byte - b'0',
+            b'a'..=b'f' => byte - b'a' + 10,
+            b'A'..=b'F' => byte - b'A' + 10,
+            byte if byte.is_ascii_whitespace() || byte == 0 => continue,
+            _ => {
+                return Err(FilterError::Corrupt {
+                    filter: "ASCIIHexDecode",
+                    reason: format!("unexpected byte 0x{byte:02x}"),
+                })
+            }
+        };
+        match high.take() {
+            Some(h) => out.push(h << 4 | nibble),
+            None => high = Some(nibble),
+        }
+    }
+    if let Some(h) = high {
+        out.push(h << 4);
+    }
+    Ok(out)
+}
+
+fn run_length_decode(data: &[u8], limit: usize) -> Result<Vec<u8>, FilterError> {
+    let truncated = || FilterError::Corrupt {
+        filter: "RunLengthDecode",
+        reason: "run extends past the end of the data".into(),
+    };
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let length = data[i] as usize;
+        i += 1;
+        match length {
+            0..=127 => {
+                let run = data.get(i..i + length + 1).ok_or_else(truncated)?;
+                out.extend_from_slice(run);
+                i += length + 1;
+            }
+            128 => break,
+            _ => {
+                let byte = *data.get(i).ok_or_else(truncated)?;
+                out.resize(out.len() + 257 - length, byte);
+                i += 1;
+            }
+        }
+        if out.len() > limit {
+            return Err(FilterError::TooLarge { limit });
+        }
+    }
+    Ok(out)
+}
+
+/// The length [`run_length_decode`] would produce, up to the first run
+/// that is cut off.
+fn run_length_size(data: &[u8]) -> usize {
+    let mut size = 0;
+    let mut i = 0;
+    while let Some(&length) = data.get(i) {
+        let length = length as usize;
+        match length {
+            0..=127 if i + length + 1 < data.len() => {
+                size += length + 1;
+                i += length + 2;
+            }
+            129.. if i + 1 < data.len() => {
+                size += 257 - length;
+                i += 2;
+            }
+            _ => break,
+        }
+    }
+    size
+}
+
+fn apply_predictor(
+    data: Vec<u8>,
+    params: Option<&Dictionary>,
+    limit: usize,
+) -> Result<Vec<u8>, FilterError> {
+    let predictor = int_param(params, b"Predictor", 1)?;
+    if predictor == 1 || data.is_empty() {
+        return Ok(data);
+    }
+    let colors = positive(int_param(params, b"Colors", 1)?, "Colors")?;
+    let bits = positive(
+        int_param(params, b"BitsPerComponent", 8)?,
+        "BitsPerComponent",
+    )?;
+    let columns = positive(int_param(params, b"Columns", 1)?, "Columns")?;
+    if !matches!(bits, 1 | 2 | 4 | 8 | 16) {
+        return Err(FilterError::InvalidParams(format!(
+            "/BitsPerComponent {bits} is not supported"
+        )));
+    }
+    // The parameters are untrusted: a row buffer is allocated from them,
+    // so a row must fit both the limit and the data it describes.
+    let pixel_bits = colors
+        .checked_mul(bits)
+        .ok_or_else(|| FilterError::InvalidParams("/Colors is too large".into()))?;
+    let row_len = pixel_bits
+        .checked_mul(columns)
+        .ok_or_else(|| FilterError::InvalidParams("/Columns is too large".into()))?
+        .div_ceil(8);
+    if row_len > limit {
+        return Err(FilterError::TooLarge { limit });
+    }
+    if row_len > data.len() {
+        return Err(FilterError::InvalidParams(format!(
+            "rows of {row_len} bytes do not fit in {} bytes of data",
+            data.len()
+        )));
+    }
+    match predictor {
+        2 => Ok(tiff_predictor(data, row_len, colors, bits)),
+        10..=15 => png_predictor(&data, row_len, pixel_bits.div_ceil(8)),
+        other => Err(FilterError::InvalidParams(format!(
+            "/Predictor {other} is not supported"
+        ))),
+    }
+}
+
+fn positive(value: i64, key: &str) -> Result<usize, FilterError> {
+    usize::try_from(value)
+        .ok()
+        .filter(|v| *v > 0)
+        .ok_or_else(|| FilterError::InvalidParams(format!("/{key} must be positive")))
+}
+
+/// Undo PNG row filters. Every row is prefixed by its filter type byte.
+fn png_predictor(data: &[u8], row_len: usize, bpp: usize) -> Result<Vec<u8>, FilterError> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; row_len];
+    for encoded in data.chunks(row_len + 1) {
+        let (kind, encoded) = (encoded[0], &encoded[1..]);
+        let mut row = encoded.to_vec();
+        row.resize(row_len, 0);
+        for i in 0..row_len {
+            let left = if i >= bpp { row[i - bpp] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= bpp { previous[i - bpp] } else { 0 };
+            let predicted = match kind {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                other => {
+                    return Err(FilterError::Corrupt {
+                        filter: "FlateDecode",
+                        reason: format!("unknown PNG row filter {other}"),
+                    })
+                }
+            };
+            row[i] = row[i].wrapping_add(predicted);
+        }
+        out.extend_from_slice(&row[..encoded.len()]);
+        previous = row;
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Undo TIFF predictor 2: every sample is stored as the difference to the
+/// same colour component of the pixel to its left.
+fn tiff_predictor(mut data: Vec<u8>, row_len: usize, colors: usize, bits: usize) -> Vec<u8> {
+    for row in data.chunks_mut(row_len) {
+        match bits {
+            8 => {
+                for i in colors..row.len() {
+                    row[i] = row[i].wrapping_add(row[i - colors]);
+                }
+            }
+            16 => {
+                for i in (colors * 2..row.len().saturating_sub(1)).step_by(2) {
+                    let left = u16::from_be_bytes([row[i - colors * 2], row[i - colors * 2 + 1]]);
+                    let value = u16::from_be_bytes([row[i], row[i + 1]]).wrapping_add(left);
+                    row[i..i + 2].copy_from_slice(&value.to_be_bytes());
+                }
+            }
+            _ => {
+                let mask = (1u16 << bits) - 1;
+                let samples = row.len() * 8 / bits;
+                for i in colors..samples {
+                    let left = read_bits(row, i - colors, bits);
+                    let value = (read_bits(row, i, bits) + left) & mask;
+                    write_bits(row, i, bits, value);
+                }
+            }
+        }
+    }
+    data
+}
+
+fn read_bits(row: &[u8], index: usize, bits: usize) -> u16 {
+    let bit = index * bits;
+    let shift = 8 - bits - bit % 8;
+    ((row[bit / 8] >> shift) as u16) & ((1 << bits) - 1)
+}
+
+fn write_bits(row: &mut [u8], index: usize, bits: usize, value: u16) {
+    let bit = index * bits;
+    let shift = 8 - bits - bit % 8;
+    let mask = (((1u16 << bits) - 1) as u8) << shift;
+    row[bit / 8] = (row[bit / 8] & !mask) | ((value as u8) << shift);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    const LIMIT: usize = 1 << 20;
+
+    fn run(data: &[u8], filter: &[u8], params: Option<&Dictionary>) -> Decoded {
+        decode(data, &[(filter, params)], LIMIT).expect("decoding should succeed")
+    }
+
+    #[test]
+    fn flate_round_trips_through_encode() {
+        let encoded = encode_flate(b"BT /F1 12 Tf (Hi) Tj ET");
+        let decoded = run(&encoded, b"FlateDecode", None);
+        assert_eq!(decoded.data, b"BT /F1 12 Tf (Hi) Tj ET");
+        assert!(decoded.is_complete());
+    }
+
+    #[test]
+    fn flate_applies_png_predictors() {
+        // Two rows of three bytes: "Sub" filter then "Up" filter.
+        let filtered = [1, 10, 5, 5, 2, 1, 1, 1];
+        let params = dictionary! { "Predictor" => 12, "Columns" => 3 };
+        let decoded = run(&encode_flate(&filtered), b"FlateDecode", Some(&params));
+        assert_eq!(decoded.data, vec![10, 15, 20, 11, 16, 21]);
+    }
+
+    #[test]
+    fn png_predictor_handles_average_and_paeth_rows() {
+        let filtered = [0, 10, 20, 3, 5, 5, 4, 1, 1];
+        let decoded = png_predictor(&filtered, 2, 1).unwrap();
+        assert_eq!(decoded, vec![10, 20, 10, 20, 11, 21]);
+    }
+
+    #[test]
+    fn tiff_predictor_accumulates_per_component() {
+        let params = dictionary! { "Predictor" => 2, "Colors" => 2, "Columns" => 3 };
+        let decoded = apply_predictor(vec![1, 2, 1, 1, 1, 1], Some(&params), LIMIT).unwrap();
+        assert_eq!(decoded, vec![1, 2, 2, 3, 3, 4]);
+    }
+
+    #[test]
+    fn tiff_predictor_handles_sub_byte_samples() {
+        let params = dictionary! { "Predictor" => 2, "BitsPerComponent" => 4, "Columns" => 4 };
+        let decoded = apply_predictor(vec![0x11, 0x11], Some(&params), LIMIT).unwrap();
+        assert_eq!(decoded, vec![0x12, 0x34]);
+    }
+
+    #[test]
+    fn predictor_rows_are_bounded_before_allocating() {
+        let huge = dictionary! { "Predictor" => 12, "Colors" => i64::MAX, "Columns" => 2 };
+        let err = apply_predictor(vec![0; 8], Some(&huge), LIMIT).unwrap_err();
+        assert!(matches!(err, FilterError::InvalidParams(_)));
+
+        let wide = dictionary! { "Predictor" => 12, "Columns" => 1i64 << 40 };
+        let err = apply_predictor(vec![0; 8], Some(&wide), LIMIT).unwrap_err();
+        assert!(matches!(err, FilterError::TooLarge { limit: LIMIT }));
+
+        let longer_than_data = dictionary! { "Predictor" => 2, "Columns" => 100 };
+        let err = apply_predictor(vec![0; 8], Some(&longer_than_data), LIMIT).unwrap_err();
+        assert!(matches!(err, FilterError::InvalidParams(_)));
+    }
+
+    #[test]
+    fn lzw_decodes_with_and_without_early_change() {
+        let text = b"-----A---B";
+        let early = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+            .encode(text)
+            .unwrap();
+        assert_eq!(run(&early, b"LZWDecode", None).data, text);
+
+        let late = weezl::encode::Encoder::new(weezl::BitOrder::Msb, 8)
+            .encode(text)
+            .unwrap();
+        let params = dictionary! { "EarlyChange" => 0 };
+        assert_eq!(run(&late, b"LZW", Some(&params)).data, text);
+    }
+
+    #[test]
+    fn ascii85_decodes_groups_zero_shortcut_and_partial_tail() {
+        assert_eq!(
+            run(b"<~87cURD]i,\"Ebo80~>", b"ASCII85Decode", None).data,
+            b"Hello World!"
+        );
+        assert_eq!(run(b"z~>", b"A85", None).data, vec![0; 4]);
+        assert_eq!(run(b"87cUR D]~>", b"A85", None).data, b"Hello");
+    }
+
+    #[test]
+    fn ascii_hex_ignores_whitespace_and_pads_odd_digits() {
+        assert_eq!(
+            run(b"48 65 6c6C 6\n>", b"ASCIIHexDecode", None).data,
+            b"Hell`"
+        );
+    }
+
+    #[test]
+    fn run_length_expands_literals_and_repeats() {
+        let encoded = [2, b'a', b'b', b'c', 254, b'z', 128, 9];
+        assert_eq!(run(&encoded, b"RunLengthDecode", None).data, b"abczzz");
+    }
+
+    #[test]
+    fn chains_apply_filters_in_order() {
+        let hex: String = encode_flate(b"chained")
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let decoded = decode(
+            hex.as_bytes(),
+            &[(b"ASCIIHexDecode", None), (b"FlateDecode", None)],
+            LIMIT,
+        )
+        .unwrap();
+        assert_eq!(decoded.data, b"chained");
+    }
+
+    #[test]
+    fn unsupported_image_codecs_keep_their_payload() {
+        let decoded = decode(b"4a504547>", &[(b"AHx", None), (b"DCTDecode", None)], LIMIT).unwrap();
+        assert_eq!(decoded.data, b"JPEG");
+        assert_eq!(decoded.unsupported.as_deref(), Some("DCTDecode"));
+    }
+
+    #[test]
+    fn decoding_stops_at_the_size_limit() {
+        let encoded = encode_flate(&[0u8; 4096]);
+        let err = decode(&encoded, &[(b"FlateDecode", None)], 1024).unwrap_err();
+        assert!(matches!(err, FilterError::TooLarge { limit: 1024 }));
+    }
+
+    #[test]
+    fn size_check_counts_the_last_filter_without_keeping_it() {
+        let doc = Document::with_version("1.7");
+        let stream = |filter: &[u8], content: Vec<u8>| {
+            Stream::new(
+                dictionary! { "Filter" => Object::Name(filter.to_vec()) },
+                content,
+            )
+        };
+        let flate = stream(b"FlateDecode", encode_flate(&[0u8; 4096]));
+        assert!(check_decoded_size(&doc, &flate, 4096).is_ok());
+        let err = check_decoded_size(&doc, &flate, 1024).unwrap_err();
+        assert!(matches!(err, FilterError::TooLarge { limit: 1024 }));
+
+        let lzw = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+            .encode(&[7u8; 4096])
+            .unwrap();
+        assert!(check_decoded_size(&doc, &stream(b"LZW", lzw), 1024).is_err());
+
+        let runs = stream(b"RL", vec![129, 1, 129, 2, 128]);
+        assert_eq!(run_length_size(&runs.content), 256);
+        assert!(check_decoded_size(&doc, &runs, 256).is_ok());
+        assert!(check_decoded_size(&doc, &runs, 255).is_err());
+
+        let corrupt = stream(b"FlateDecode", b"not zlib".to_vec());
+        assert!(check_decoded_size(&doc, &corrupt, 64).is_ok());
+    }
+
+    #[test]
+    fn decode_stream_reads_filter_arrays_and_params() {
+        let doc = Document::with_version("1.7");
+        let mut stream = Stream::new(
+            dictionary! {
+                "Filter" => vec![Object::Name(b"FlateDecode".to_vec())],
+                "DecodeParms" => vec![Object::Dictionary(dictionary! { "Predictor" => 2, "Columns" => 2 })],
+            },
+            encode_flate(&[5, 1, 7, 1]),
+        );
+        assert_eq!(
+            decode_stream(&doc, &stream, LIMIT).unwrap().data,
+            vec![5, 6, 7, 8]
+        );
+
+        set_flate_content(&mut stream, b"q Q");
+        assert!(!stream.dict.has(b"DecodeParms"));
+        assert_eq!(decode_stream(&doc, &stream, LIMIT).unwrap().data, b"q Q");
+    }
+}
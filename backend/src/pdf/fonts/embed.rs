@@ -1,18 +1,246 @@
-//! Font embedding stub.
+//! Embedding fonts for new text.
+//!
+//! Shaped text is written with glyph ids as two-byte codes, so the font is
+//! embedded as a Type0 font with `Identity-H` encoding over a subset of the
+//! face, plus a ToUnicode CMap built from the shaping clusters so the text
+//! stays searchable and editable.
 
-use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
 
-pub fn build_font_stream(_subset: &[u8]) -> Result<Vec<u8>> {
-    Ok(Vec::new())
+use anyhow::{Context, Result};
+use lopdf::{dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use ttf_parser::{Face, GlyphId};
+
+use super::library::FontFace;
+use super::shape::ShapedGlyph;
+use super::subset::{subset_font, Outlines};
+use crate::pdf::content::real;
+use crate::pdf::filters;
+
+/// Font descriptor flags.
+const FIXED_PITCH: i64 = 1 << 0;
+const SYMBOLIC: i64 = 1 << 2;
+const ITALIC: i64 = 1 << 6;
+
+/// Entries per `beginbfchar` block; the CMap format allows at most 100.
+const BFCHAR_BLOCK: usize = 100;
+
+/// A font added to a document for shaped text.
+#[derive(Debug, Clone)]
+pub struct EmbeddedFont {
+    /// The Type0 font dictionary.
+    pub id: ObjectId,
+    /// Advance of every glyph in the subset, in thousandths of an em, as
+    /// the PDF font reports it.
+    pub widths: BTreeMap<u16, f64>,
+}
+
+/// Add a subset of `face` covering `glyphs`, shaped from `text`, to `doc`.
+pub fn embed_font(
+    doc: &mut Document,
+    face: &FontFace,
+    glyphs: &[ShapedGlyph],
+    text: &str,
+) -> Result<EmbeddedFont> {
+    let metrics = Face::parse(&face.data, face.index).context("unreadable font file")?;
+    let scale = 1000.0 / metrics.units_per_em() as f64;
+    let gids: BTreeSet<u16> = glyphs.iter().map(|glyph| glyph.gid).collect();
+    let widths: BTreeMap<u16, f64> = gids
+        .iter()
+        .map(|&gid| {
+            let advance = metrics.glyph_hor_advance(GlyphId(gid)).unwrap_or(0);
+            (gid, advance as f64 * scale)
+        })
+        .collect();
+
+    let subset = subset_font(
+        &face.data,
+        face.index,
+        &gids.iter().copied().collect::<Vec<_>>(),
+    )?;
+    let postscript_name: String = face
+        .postscript_name
+        .chars()
+        .filter(|c| c.is_ascii_graphic() && !"[](){}<>/%".contains(*c))
+        .collect();
+    let base_font = format!("{}+{postscript_name}", subset_tag(&gids));
+
+    let mut file = Stream::new(dictionary! {}, Vec::new());
+    let (file_key, cid_subtype) = match subset.outlines {
+        Outlines::TrueType => {
+            file.dict.set("Length1", subset.data.len() as i64);
+            ("FontFile2", "CIDFontType2")
+        }
+        Outlines::Cff => {
+            file.dict.set("Subtype", "OpenType");
+            ("FontFile3", "CIDFontType0")
+        }
+    };
+    filters::set_flate_content(&mut file, &subset.data);
+    let file = doc.add_object(file);
+
+    let bbox = metrics.global_bounding_box();
+    let mut flags = SYMBOLIC;
+    if metrics.is_monospaced() {
+        flags |= FIXED_PITCH;
+    }
+    if metrics.is_italic() {
+        flags |= ITALIC;
+    }
+    let ascent = metrics.ascender() as f64 * scale;
+    let descriptor = doc.add_object(dictionary! {
+        "Type" => "FontDescriptor",
+        "FontName" => Object::Name(base_font.clone().into_bytes()),
+        "Flags" => flags,
+        "FontBBox" => [bbox.x_min, bbox.y_min, bbox.x_max, bbox.y_max]
+            .iter()
+            .map(|v| real(*v as f64 * scale))
+            .collect::<Vec<_>>(),
+        "ItalicAngle" => real(metrics.italic_angle().unwrap_or(0.0) as f64),
+        "Ascent" => real(ascent),
+        "Descent" => real(metrics.descender() as f64 * scale),
+        "CapHeight" => real(metrics.capital_height().map_or(ascent, |h| h as f64 * scale)),
+        "StemV" => 80,
+        file_key => file,
+    });
+
+    let w: Vec<Object> = widths
+        .iter()
+        .flat_map(|(&gid, &width)| [Object::from(gid as i64), vec![real(width)].into()])
+        .collect();
+    let mut cid_font = dictionary! {
+        "Type" => "Font",
+        "Subtype" => cid_subtype,
+        "BaseFont" => Object::Name(base_font.clone().into_bytes()),
+        "CIDSystemInfo" => dictionary! {
+            "Registry" => Object::String(b"Adobe".to_vec(), StringFormat::Literal),
+            "Ordering" => Object::String(b"Identity".to_vec(), StringFormat::Literal),
+            "Supplement" => 0,
+        },
+        "FontDescriptor" => descriptor,
+        "DW" => 1000,
+        "W" => w,
+    };
+    if subset.outlines == Outlines::TrueType {
+        cid_font.set("CIDToGIDMap", "Identity");
+    }
+    let cid_font = doc.add_object(cid_font);
+
+    let mut to_unicode = Stream::new(dictionary! {}, Vec::new());
+    filters::set_flate_content(&mut to_unicode, &to_unicode_cmap(glyphs, text));
+    let to_unicode = doc.add_object(to_unicode);
+
+    let id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type0",
+        "BaseFont" => Object::Name(base_font.into_bytes()),
+        "Encoding" => "Identity-H",
+        "DescendantFonts" => vec![Object::Reference(cid_font)],
+        "ToUnicode" => to_unicode,
+    });
+    Ok(EmbeddedFont { id, widths })
+}
+
+/// The six-letter tag that marks a subset font's name, derived from the
+/// glyphs it holds.
+fn subset_tag(gids: &BTreeSet<u16>) -> String {
+    let mut hash: u32 = 0x811C_9DC5;
+    for gid in gids {
+        for byte in gid.to_be_bytes() {
+            hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+        }
+    }
+    (0..6)
+        .map(|_| {
+            let letter = (b'A' + (hash % 26) as u8) as char;
+            hash /= 26;
+            letter
+        })
+        .collect()
+}
+
+/// A ToUnicode CMap mapping each glyph to the characters of its cluster.
+/// When a cluster has several glyphs the first one carries the text.
+fn to_unicode_cmap(glyphs: &[ShapedGlyph], text: &str) -> Vec<u8> {
+    let starts: BTreeSet<usize> = glyphs.iter().map(|glyph| glyph.cluster).collect();
+    let mut mapped: BTreeMap<u16, &str> = BTreeMap::new();
+    let mut seen_clusters = BTreeSet::new();
+    for glyph in glyphs {
+        if !seen_clusters.insert(glyph.cluster) {
+            continue;
+        }
+        let end = starts
+            .range(glyph.cluster + 1..)
+            .next()
+            .copied()
+            .unwrap_or(text.len());
+        if let Some(chars) = text.get(glyph.cluster..end).filter(|s| !s.is_empty()) {
+            mapped.entry(glyph.gid).or_insert(chars);
+        }
+    }
+
+    let mut cmap = String::from(
+        "/CIDInit /ProcSet findresource begin\n\
+         12 dict begin\n\
+         begincmap\n\
+         /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+         /CMapName /Adobe-Identity-UCS def\n\
+         /CMapType 2 def\n\
+         1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+    );
+    let entries: Vec<_> = mapped.into_iter().collect();
+    for block in entries.chunks(BFCHAR_BLOCK) {
+        let _ = writeln!(cmap, "{} beginbfchar", block.len());
+        for (gid, chars) in block {
+            let utf16: String = chars
+                .encode_utf16()
+                .map(|unit| format!("{unit:04X}"))
+                .collect();
+            let _ = writeln!(cmap, "<{gid:04X}> <{utf16}>");
+        }
+        cmap.push_str("endbfchar\n");
+    }
+    cmap.push_str(
+        "endcmap\n\
+         CMapName currentdict /CMap defineresource pop\n\
+         end\n\
+         end\n",
+    );
+    cmap.into_bytes()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pdf::fonts::encoding::DocumentFont;
+    use crate::pdf::fonts::library::FontLibrary;
+    use crate::pdf::fonts::shape::shape_text;
+    use crate::pdf::testing::test_font;
 
     #[test]
-    fn build_font_stream_returns_empty_placeholder() {
-        let stream = build_font_stream(&[1, 2, 3]).expect("embedding should succeed");
-        assert!(stream.is_empty());
+    fn embedded_fonts_decode_back_to_the_shaped_text() {
+        let face = FontLibrary::from_fonts(vec![test_font()])
+            .find("Test Sans")
+            .unwrap();
+        let glyphs = shape_text(&face.data, face.index, "CAB A").unwrap();
+        let mut doc = Document::with_version("1.7");
+
+        let embedded = embed_font(&mut doc, &face, &glyphs, "CAB A").unwrap();
+
+        assert_eq!(embedded.widths[&1], 600.0);
+        assert_eq!(embedded.widths[&5], 250.0);
+        let dict = doc.get_dictionary(embedded.id).unwrap();
+        let font = DocumentFont::load(&doc, dict);
+        assert!(font.two_byte);
+        assert!(font.base_font.ends_with("+TestSans-Regular"));
+        let codes: Vec<u8> = glyphs
+            .iter()
+            .flat_map(|glyph| glyph.gid.to_be_bytes())
+            .collect();
+        let decoded = font.decode(&codes);
+        let text: String = decoded.iter().map(|ch| ch.text.as_str()).collect();
+        assert_eq!(text, "CAB A");
+        assert_eq!(decoded[0].width, 600.0);
     }
 }
@@ -0,0 +1,492 @@
+//! Decoding and encoding text with fonts that already live in a document.
+//!
+//! A [`DocumentFont`] knows how a font's string bytes split into character
+//! codes, what Unicode each code stands for and how wide it is. Extraction
+//! uses it to read text, patching uses it to re-encode replacement text.
+
+use std::collections::HashMap;
+
+use lopdf::{Dictionary, Document, Object};
+
+use super::standard;
+use crate::pdf::content::parse_content;
+use crate::pdf::filters;
+
+/// Upper bound for decoding embedded CMaps, which are tiny in practice.
+const CMAP_LIMIT: usize = 4 * 1024 * 1024;
+/// CMaps nest arrays at most two deep; anything far beyond is hostile.
+const CMAP_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentFont {
+    /// `/Subtype` of the font dictionary, e.g. `Type1` or `Type0`.
+    pub subtype: String,
+    pub base_font: String,
+    /// Composite fonts use two-byte codes; simple fonts use one byte.
+    pub two_byte: bool,
+    widths: HashMap<u32, f64>,
+    default_width: f64,
+    /// Scale from the font's glyph space to thousandths of text space.
+    /// 1.0 for everything except Type 3 fonts.
+    width_scale: f64,
+    to_unicode: HashMap<u32, String>,
+    base_encoding: Option<&'static str>,
+    differences: HashMap<u32, char>,
+    /// Ascent and descent in thousandths of an em.
+    pub ascent: f64,
+    pub descent: f64,
+}
+
+/// One character code decoded from a shown string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedChar {
+    pub code: u32,
+    pub text: String,
+    /// Horizontal advance in thousandths of text space units.
+    pub width: f64,
+    /// Single-byte code 32, the only code word spacing applies to.
+    pub is_space: bool,
+}
+
+impl DocumentFont {
+    pub fn load(doc: &Document, font: &Dictionary) -> Self {
+        let subtype = name_of(font.get(b"Subtype").ok()).unwrap_or_default();
+        let base_font = name_of(font.get(b"BaseFont").ok()).unwrap_or_default();
+        let two_byte = subtype == "Type0";
+
+        let descendant = two_byte
+            .then(|| {
+                let fonts = deref(doc, font.get(b"DescendantFonts").ok())?
+                    .as_array()
+                    .ok()?;
+                deref(doc, fonts.first())?.as_dict().ok()
+            })
+            .flatten();
+        let descriptor_owner = descendant.unwrap_or(font);
+        let descriptor =
+            deref(doc, descriptor_owner.get(b"FontDescriptor").ok()).and_then(|o| o.as_dict().ok());
+
+        let (widths, default_width) = match descendant {
+            Some(cid_font) => cid_widths(doc, cid_font),
+            None => simple_widths(doc, font, descriptor),
+        };
+        let width_scale = if subtype == "Type3" {
+            deref(doc, font.get(b"FontMatrix").ok())
+                .and_then(|o| o.as_array().ok())
+                .and_then(|m| number(doc, m.first()))
+                .map(|a| a * 1000.0)
+                .unwrap_or(1.0)
+        } else {
+            1.0
+        };
+
+        let to_unicode = deref(doc, font.get(b"ToUnicode").ok())
+            .and_then(|o| o.as_stream().ok())
+            .and_then(|stream| filters::decode_stream(doc, stream, CMAP_LIMIT).ok())
+            .map(|decoded| parse_to_unicode(&decoded.data))
+            .unwrap_or_default();
+
+        let (base_encoding, differences) = if two_byte {
+            (None, HashMap::new())
+        } else {
+            simple_encoding(doc, font, &subtype)
+        };
+
+        let metric = |key: &[u8], default: f64| {
+            descriptor
+                .and_then(|d| number(doc, d.get(key).ok()))
+                .filter(|v| *v != 0.0)
+                .unwrap_or(default)
+        };
+
+        Self {
+            subtype,
+            base_font,
+            two_byte,
+            widths,
+            default_width,
+            width_scale,
+            to_unicode,
+            base_encoding,
+            differences,
+            ascent: metric(b"Ascent", 800.0),
+            descent: metric(b"Descent", -200.0),
+        }
+    }
+
+    /// Split shown string bytes into character codes.
+    pub fn decode(&self, bytes: &[u8]) -> Vec<DecodedChar> {
+        let codes: Vec<u32> = if self.two_byte {
+            bytes
+                .chunks(2)
+                .map(|pair| pair.iter().fold(0u32, |acc, b| acc << 8 | *b as u32))
+                .collect()
+        } else {
+            bytes.iter().map(|b| *b as u32).collect()
+        };
+        codes
+            .into_iter()
+            .map(|code| DecodedChar {
+                code,
+                text: self.unicode(code),
+                width: self.width(code),
+                is_space: !self.two_byte && code == 32,
+            })
+            .collect()
+    }
+
+    /// Encode `text` into string bytes for this font. Fails with the first
+    /// character the font cannot represent.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, char> {
+        let mut reverse: HashMap<&str, u32> = HashMap::new();
+        for (code, unicode) in &self.to_unicode {
+            reverse
+                .entry(unicode.as_str())
+                .and_modify(|existing| *existing = (*existing).min(*code))
+                .or_insert(*code);
+        }
+        let mut out = Vec::new();
+        for ch in text.chars() {
+            let mut buf = [0u8; 4];
+            let key: &str = ch.encode_utf8(&mut buf);
+            let code = reverse
+                .get(key)
+                .copied()
+                .or_else(|| self.simple_code_for(ch))
+                .ok_or(ch)?;
+            if self.two_byte {
+                out.extend_from_slice(&(code as u16).to_be_bytes());
+            } else {
+                out.push(code as u8);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Advance of `code` in thousandths of text space units.
+    pub fn width(&self, code: u32) -> f64 {
+        self.widths
+            .get(&code)
+            .copied()
+            .or_else(|| {
+                (!self.two_byte && self.subtype != "Type3")
+                    .then(|| standard::width(&self.base_font, code))
+                    .flatten()
+            })
+            .unwrap_or(self.default_width)
+            * self.width_scale
+    }
+
+    fn unicode(&self, code: u32) -> String {
+        if let Some(text) = self.to_unicode.get(&code) {
+            return text.clone();
+        }
+        if self.two_byte {
+            return String::new();
+        }
+        if let Some(c) = self.differences.get(&code) {
+            return c.to_string();
+        }
+        Document::decode_text(self.base_encoding, &[code as u8])
+    }
+
+    fn simple_code_for(&self, ch: char) -> Option<u32> {
+        if self.two_byte {
+            return None;
+        }
+        if let Some((code, _)) = self.differences.iter().find(|(_, c)| **c == ch) {
+            return Some(*code);
+        }
+        let encoded = Document::encode_text(self.base_encoding, &ch.to_string());
+        match encoded.as_slice() {
+            [byte] if !self.differences.contains_key(&(*byte as u32)) => Some(*byte as u32),
+            _ => None,
+        }
+    }
+}
+
+fn deref<'a>(doc: &'a Document, object: Option<&'a Object>) -> Option<&'a Object> {
+    match object? {
+        Object::Reference(id) => doc.get_object(*id).ok(),
+        other => Some(other),
+    }
+}
+
+fn number(doc: &Document, object: Option<&Object>) -> Option<f64> {
+    match deref(doc, object)? {
+        Object::Integer(v) => Some(*v as f64),
+        Object::Real(v) => Some(*v as f64),
+        _ => None,
+    }
+}
+
+fn name_of(object: Option<&Object>) -> Option<String> {
+    object?
+        .as_name()
+        .ok()
+        .map(|n| String::from_utf8_lossy(n).into_owned())
+}
+
+fn simple_widths(
+    doc: &Document,
+    font: &Dictionary,
+    descriptor: Option<&Dictionary>,
+) -> (HashMap<u32, f64>, f64) {
+    let first = number(doc, font.get(b"FirstChar").ok()).unwrap_or(0.0) as u32;
+    let widths = deref(doc, font.get(b"Widths").ok())
+        .and_then(|o| o.as_array().ok())
+        .map(|items| {
+            items
+                .iter()
+                .enumerate()
+                .filter_map(|(i, w)| Some((first + i as u32, number(doc, Some(w))?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let missing = descriptor
+        .and_then(|d| number(doc, d.get(b"MissingWidth").ok()))
+        .unwrap_or(500.0);
+    (widths, missing)
+}
+
+/// Read the `/W` array of a CIDFont: `c [w1 w2 ...]` or `c_first c_last w`.
+fn cid_widths(doc: &Document, cid_font: &Dictionary) -> (HashMap<u32, f64>, f64) {
+    let default = number(doc, cid_font.get(b"DW").ok()).unwrap_or(1000.0);
+    let mut widths = HashMap::new();
+    let Some(items) = deref(doc, cid_font.get(b"W").ok()).and_then(|o| o.as_array().ok()) else {
+        return (widths, default);
+    };
+    let mut i = 0;
+    while i < items.len() {
+        let Some(first) = number(doc, items.get(i)) else {
+            break;
+        };
+        match deref(doc, items.get(i + 1)) {
+            Some(Object::Array(list)) => {
+                for (offset, w) in list.iter().enumerate() {
+                    if let Some(w) = number(doc, Some(w)) {
+                        widths.insert(first as u32 + offset as u32, w);
+                    }
+                }
+                i += 2;
+            }
+            Some(_) => {
+                let last = number(doc, items.get(i + 1)).unwrap_or(first);
+                if let Some(w) = number(doc, items.get(i + 2)) {
+                    // Guard against absurd ranges in malformed files.
+                    for cid in (first as u32..=last as u32).take(65_536) {
+                        widths.insert(cid, w);
+                    }
+                }
+                i += 3;
+            }
+            None => break,
+        }
+    }
+    (widths, default)
+}
+
+fn simple_encoding(
+    doc: &Document,
+    font: &Dictionary,
+    subtype: &str,
+) -> (Option<&'static str>, HashMap<u32, char>) {
+    let known = |name: &[u8]| -> Option<&'static str> {
+        match name {
+            b"WinAnsiEncoding" => Some("WinAnsiEncoding"),
+            b"MacRomanEncoding" => Some("MacRomanEncoding"),
+            b"MacExpertEncoding" => Some("MacExpertEncoding"),
+            b"StandardEncoding" => Some("StandardEncoding"),
+            _ => None,
+        }
+    };
+    let default = if subtype == "TrueType" {
+        Some("WinAnsiEncoding")
+    } else {
+        Some("StandardEncoding")
+    };
+    match deref(doc, font.get(b"Encoding").ok()) {
+        Some(Object::Name(name)) => (known(name).or(default), HashMap::new()),
+        Some(Object::Dictionary(dict)) => {
+            let base = dict
+                .get(b"BaseEncoding")
+                .ok()
+                .and_then(|o| o.as_name().ok())
+                .and_then(known)
+                .or(default);
+            let mut differences = HashMap::new();
+            if let Some(items) =
+                deref(doc, dict.get(b"Differences").ok()).and_then(|o| o.as_array().ok())
+            {
+                let mut code = 0u32;
+                for item in items {
+                    match item {
+                        Object::Integer(start) => code = *start as u32,
+                        Object::Name(name) => {
+                            if let Some(c) =
+                                standard::glyph_name_to_unicode(&String::from_utf8_lossy(name))
+                            {
+                                differences.insert(code, c);
+                            }
+                            code += 1;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            (base, differences)
+        }
+        _ => (default, HashMap::new()),
+    }
+}
+
+/// Parse the `bfchar` and `bfrange` sections of a ToUnicode CMap.
+pub fn parse_to_unicode(data: &[u8]) -> HashMap<u32, String> {
+    let mut map = HashMap::new();
+    let Ok(ops) = parse_content(data, CMAP_DEPTH) else {
+        return map;
+    };
+    let code = |object: &Object| -> Option<u32> {
+        match object {
+            Object::String(bytes, _) if bytes.len() <= 4 => {
+                Some(bytes.iter().fold(0u32, |acc, b| acc << 8 | *b as u32))
+            }
+            _ => None,
+        }
+    };
+    let text = |bytes: &[u8]| -> String {
+        let units: Vec<u16> = bytes
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    for op in ops {
+        match op.operator.as_str() {
+            "endbfchar" => {
+                for pair in op.operands.chunks(2) {
+                    if let [src, Object::String(dst, _)] = pair {
+                        if let Some(src) = code(src) {
+                            map.insert(src, text(dst));
+                        }
+                    }
+                }
+            }
+            "endbfrange" => {
+                for triple in op.operands.chunks(3) {
+                    let [lo, hi, dst] = triple else {
+                        continue;
+                    };
+                    let (Some(lo), Some(hi)) = (code(lo), code(hi)) else {
+                        continue;
+                    };
+                    match dst {
+                        Object::String(start, _) => {
+                            let Some(last) = start.last().copied() else {
+                                continue;
+                            };
+                            for (offset, src) in (lo..=hi).take(65_536).enumerate() {
+                                // Only the last byte increments within a range.
+                                let mut unit = start.clone();
+                                let bumped = last as usize + offset;
+                                *unit.last_mut().unwrap() = bumped as u8;
+                                if bumped > 0xff {
+                                    break;
+                                }
+                                map.insert(src, text(&unit));
+                            }
+                        }
+                        Object::Array(items) => {
+                            for (src, item) in (lo..=hi).zip(items) {
+                                if let Object::String(dst, _) = item {
+                                    map.insert(src, text(dst));
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream};
+
+    #[test]
+    fn standard_fonts_decode_with_builtin_metrics() {
+        let doc = Document::with_version("1.7");
+        let font = DocumentFont::load(
+            &doc,
+            &dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica" },
+        );
+
+        let chars = font.decode(b"Hi ");
+        let text: String = chars.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(text, "Hi ");
+        assert_eq!(chars[0].width, 722.0);
+        assert!(chars[2].is_space);
+        assert_eq!(font.encode("iH"), Ok(b"iH".to_vec()));
+    }
+
+    #[test]
+    fn composite_fonts_use_to_unicode_and_cid_widths() {
+        let mut doc = Document::with_version("1.7");
+        let cmap = b"1 begincodespacerange <0000> <FFFF> endcodespacerange\n\
+                     2 beginbfchar <0003> <0020> <0024> <0041> endbfchar\n\
+                     1 beginbfrange <0025> <0026> <0042> endbfrange";
+        let to_unicode = doc.add_object(Stream::new(dictionary! {}, cmap.to_vec()));
+        let descendant = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "CIDFontType2",
+            "DW" => 600,
+            "W" => vec![36.into(), vec![Object::Integer(700), Object::Integer(650)].into(), 3.into(), 3.into(), 250.into()],
+        });
+        let font = DocumentFont::load(
+            &doc,
+            &dictionary! {
+                "Subtype" => "Type0",
+                "Encoding" => "Identity-H",
+                "DescendantFonts" => vec![Object::Reference(descendant)],
+                "ToUnicode" => Object::Reference(to_unicode),
+            },
+        );
+
+        let chars = font.decode(&[0, 0x24, 0, 0x03, 0, 0x26, 0, 0x50]);
+        let text: String = chars.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(text, "A C");
+        let widths: Vec<f64> = chars.iter().map(|c| c.width).collect();
+        assert_eq!(widths, vec![700.0, 250.0, 600.0, 600.0]);
+        assert_eq!(font.encode("CA"), Ok(vec![0, 0x26, 0, 0x24]));
+        assert_eq!(font.encode("Z"), Err('Z'));
+    }
+
+    #[test]
+    fn differences_override_the_base_encoding() {
+        let doc = Document::with_version("1.7");
+        let font = DocumentFont::load(
+            &doc,
+            &dictionary! {
+                "Subtype" => "Type1",
+                "BaseFont" => "Custom",
+                "FirstChar" => 65,
+                "Widths" => vec![Object::Integer(640)],
+                "Encoding" => dictionary! {
+                    "BaseEncoding" => "WinAnsiEncoding",
+                    "Differences" => vec![65.into(), Object::Name(b"quoteright".to_vec())],
+                },
+            },
+        );
+
+        let chars = font.decode(b"AB");
+        assert_eq!(chars[0].text, "\u{2019}");
+        assert_eq!(chars[0].width, 640.0);
+        assert_eq!(chars[1].text, "B");
+        assert_eq!(font.encode("\u{2019}B"), Ok(b"AB".to_vec()));
+    }
+}
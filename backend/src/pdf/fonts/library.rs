@@ -0,0 +1,208 @@
+//! Finding font files by family name.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use ttf_parser::{name_id, Face};
+
+/// How deep to descend into font directories.
+const MAX_DIR_DEPTH: usize = 8;
+
+/// Font directories searched when none are configured.
+const SYSTEM_FONT_DIRS: &[&str] = &[
+    "/usr/share/fonts",
+    "/usr/local/share/fonts",
+    "/Library/Fonts",
+    "/System/Library/Fonts",
+    "C:\\Windows\\Fonts",
+];
+
+/// A face loaded from a font file, ready for shaping and embedding.
+#[derive(Debug, Clone)]
+pub struct FontFace {
+    /// The whole font file, which may be a collection.
+    pub data: Arc<Vec<u8>>,
+    /// Index of the face within `data`.
+    pub index: u32,
+    pub family: String,
+    pub postscript_name: String,
+}
+
+/// The font files available for new text. Directories are scanned the
+/// first time a family is looked up.
+#[derive(Debug)]
+pub struct FontLibrary {
+    dirs: Vec<PathBuf>,
+    faces: OnceLock<Vec<FaceEntry>>,
+}
+
+#[derive(Debug)]
+struct FaceEntry {
+    source: Source,
+    index: u32,
+    family: String,
+    postscript_name: String,
+    regular: bool,
+}
+
+#[derive(Debug)]
+enum Source {
+    File(PathBuf),
+    #[cfg(test)]
+    Memory(Arc<Vec<u8>>),
+}
+
+impl Default for FontLibrary {
+    fn default() -> Self {
+        let mut dirs: Vec<PathBuf> = SYSTEM_FONT_DIRS.iter().map(PathBuf::from).collect();
+        if let Some(home) = std::env::var_os("HOME") {
+            dirs.push(Path::new(&home).join(".fonts"));
+            dirs.push(Path::new(&home).join(".local/share/fonts"));
+        }
+        Self::new(dirs)
+    }
+}
+
+impl FontLibrary {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self {
+            dirs,
+            faces: OnceLock::new(),
+        }
+    }
+
+    /// A library holding only the given font files.
+    #[cfg(test)]
+    pub fn from_fonts(fonts: Vec<Vec<u8>>) -> Self {
+        let faces: Vec<FaceEntry> = fonts
+            .into_iter()
+            .flat_map(|data| {
+                let data = Arc::new(data);
+                faces_in(&data, || Source::Memory(data.clone()))
+            })
+            .collect();
+        Self {
+            dirs: Vec::new(),
+            faces: OnceLock::from(faces),
+        }
+    }
+
+    /// The face for `family`, matched ignoring case, spaces and hyphens.
+    /// Regular faces are preferred over bold or italic ones.
+    pub fn find(&self, family: &str) -> Option<FontFace> {
+        let wanted = normalise(family);
+        let entry = self
+            .faces()
+            .iter()
+            .filter(|entry| normalise(&entry.family) == wanted)
+            .max_by_key(|entry| entry.regular)?;
+        let data = match &entry.source {
+            Source::File(path) => match fs::read(path) {
+                Ok(data) => Arc::new(data),
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), %err, "font file vanished");
+                    return None;
+                }
+            },
+            #[cfg(test)]
+            Source::Memory(data) => data.clone(),
+        };
+        Some(FontFace {
+            data,
+            index: entry.index,
+            family: entry.family.clone(),
+            postscript_name: entry.postscript_name.clone(),
+        })
+    }
+
+    fn faces(&self) -> &[FaceEntry] {
+        self.faces.get_or_init(|| {
+            let mut files = Vec::new();
+            for dir in &self.dirs {
+                collect_font_files(dir, MAX_DIR_DEPTH, &mut files);
+            }
+            let faces: Vec<FaceEntry> = files
+                .into_iter()
+                .filter_map(|path| {
+                    let data = fs::read(&path).ok()?;
+                    Some(faces_in(&data, || Source::File(path.clone())))
+                })
+                .flatten()
+                .collect();
+            tracing::info!(faces = faces.len(), "scanned font directories");
+            faces
+        })
+    }
+}
+
+fn collect_font_files(dir: &Path, depth: usize, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if depth > 0 {
+                collect_font_files(&path, depth - 1, files);
+            }
+            continue;
+        }
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        if matches!(extension.as_deref(), Some("ttf" | "otf" | "ttc" | "otc")) {
+            files.push(path);
+        }
+    }
+}
+
+fn faces_in(data: &[u8], source: impl Fn() -> Source) -> Vec<FaceEntry> {
+    let count = ttf_parser::fonts_in_collection(data).unwrap_or(1);
+    (0..count)
+        .filter_map(|index| {
+            let face = Face::parse(data, index).ok()?;
+            let name = |id| {
+                face.names()
+                    .into_iter()
+                    .filter(|name| name.name_id == id)
+                    .find_map(|name| name.to_string())
+            };
+            let family = name(name_id::TYPOGRAPHIC_FAMILY).or_else(|| name(name_id::FAMILY))?;
+            let postscript_name = name(name_id::POST_SCRIPT_NAME)
+                .unwrap_or_else(|| family.chars().filter(|c| !c.is_whitespace()).collect());
+            Some(FaceEntry {
+                source: source(),
+                index,
+                family,
+                postscript_name,
+                regular: !face.is_bold() && !face.is_italic(),
+            })
+        })
+        .collect()
+}
+
+fn normalise(family: &str) -> String {
+    family
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '_'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::testing::test_font;
+
+    #[test]
+    fn find_matches_family_names_loosely() {
+        let library = FontLibrary::from_fonts(vec![test_font()]);
+
+        let face = library.find("test-sans").expect("family should match");
+        assert_eq!(face.family, "Test Sans");
+        assert_eq!(face.postscript_name, "TestSans-Regular");
+        assert!(library.find("Missing Sans").is_none());
+    }
+}
@@ -1,5 +1,9 @@
-//! Font pipeline modules (stubs).
+//! Font handling: reading fonts already in a document, plus the shaping,
+//! subsetting and embedding pipeline (stubs).
 
 pub mod embed;
+pub mod encoding;
+pub mod library;
 pub mod shape;
+pub mod standard;
 pub mod subset;
@@ -1,20 +1,105 @@
-//! HarfBuzz shaping stub.
+//! Text shaping with HarfBuzz.
 
-use anyhow::Result;
+use std::os::raw::{c_char, c_int, c_uint};
+use std::ptr;
 
-use crate::types::TextGlyph;
+use anyhow::{bail, Result};
+use harfbuzz_sys as hb;
 
-pub fn shape_text(_text: &str) -> Result<Vec<TextGlyph>> {
-    Ok(Vec::new())
+/// One glyph of shaped text. Distances are in thousandths of an em.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapedGlyph {
+    pub gid: u16,
+    /// Byte offset in the shaped text of the first character this glyph
+    /// belongs to.
+    pub cluster: usize,
+    pub x_advance: f64,
+    pub x_offset: f64,
+    pub y_offset: f64,
+}
+
+/// Shape `text` with face `index` of `font_data`, left to right unless the
+/// text's script says otherwise.
+pub fn shape_text(font_data: &[u8], index: u32, text: &str) -> Result<Vec<ShapedGlyph>> {
+    let (Ok(data_len), Ok(text_len)) = (
+        c_uint::try_from(font_data.len()),
+        c_int::try_from(text.len()),
+    ) else {
+        bail!("font or text is too large to shape");
+    };
+
+    // SAFETY: the blob borrows `font_data` read-only and every HarfBuzz
+    // object created here is destroyed before returning, so nothing
+    // outlives the borrow. The info and position arrays belong to the
+    // buffer and are copied out before it is destroyed.
+    unsafe {
+        let blob = hb::hb_blob_create(
+            font_data.as_ptr() as *const c_char,
+            data_len,
+            hb::HB_MEMORY_MODE_READONLY,
+            ptr::null_mut(),
+            None,
+        );
+        let face = hb::hb_face_create(blob, index);
+        let upem = hb::hb_face_get_upem(face).max(1) as f64;
+        let font = hb::hb_font_create(face);
+        let buffer = hb::hb_buffer_create();
+        hb::hb_buffer_add_utf8(
+            buffer,
+            text.as_ptr() as *const c_char,
+            text_len,
+            0,
+            text_len,
+        );
+        hb::hb_buffer_guess_segment_properties(buffer);
+        hb::hb_shape(font, buffer, ptr::null(), 0);
+
+        let mut count = 0;
+        let infos = hb::hb_buffer_get_glyph_infos(buffer, &mut count);
+        let positions = hb::hb_buffer_get_glyph_positions(buffer, &mut count);
+        let scale = |value: hb::hb_position_t| value as f64 * 1000.0 / upem;
+        let glyphs = (0..count as usize)
+            .map(|i| {
+                let info = &*infos.add(i);
+                let position = &*positions.add(i);
+                ShapedGlyph {
+                    gid: info.codepoint as u16,
+                    cluster: info.cluster as usize,
+                    x_advance: scale(position.x_advance),
+                    x_offset: scale(position.x_offset),
+                    y_offset: scale(position.y_offset),
+                }
+            })
+            .collect();
+
+        hb::hb_buffer_destroy(buffer);
+        hb::hb_font_destroy(font);
+        hb::hb_face_destroy(face);
+        hb::hb_blob_destroy(blob);
+        Ok(glyphs)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pdf::testing::test_font;
+
+    #[test]
+    fn shape_text_maps_characters_to_glyphs_and_advances() {
+        let glyphs = shape_text(&test_font(), 0, "AB D").unwrap();
+
+        let gids: Vec<u16> = glyphs.iter().map(|glyph| glyph.gid).collect();
+        assert_eq!(gids, [1, 2, 5, 4]);
+        let clusters: Vec<usize> = glyphs.iter().map(|glyph| glyph.cluster).collect();
+        assert_eq!(clusters, [0, 1, 2, 3]);
+        assert_eq!(glyphs[0].x_advance, 600.0);
+        assert_eq!(glyphs[2].x_advance, 250.0);
+    }
 
     #[test]
-    fn shape_text_returns_empty_vector_for_now() {
-        let glyphs = shape_text("Hello").expect("shaping should succeed");
-        assert!(glyphs.is_empty());
+    fn shape_text_reports_missing_characters_as_glyph_zero() {
+        let glyphs = shape_text(&test_font(), 0, "Az").unwrap();
+        assert_eq!(glyphs[1].gid, 0);
     }
 }
@@ -0,0 +1,161 @@
+//! Metrics for the standard 14 fonts, which PDFs may use without embedding
+//! them or listing their widths.
+
+/// Helvetica advance widths for the printable ASCII range (32..=126).
+const HELVETICA: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, // ' '../
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, // 0..?
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778, // @..O
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556, // P.._
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556, // `..o
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584, // p..~
+];
+
+/// Times-Roman advance widths for the printable ASCII range (32..=126).
+const TIMES_ROMAN: [u16; 95] = [
+    250, 333, 408, 500, 500, 833, 778, 180, 333, 333, 500, 564, 250, 333, 250, 278, // ' '../
+    500, 500, 500, 500, 500, 500, 500, 500, 500, 500, 278, 278, 564, 564, 564, 444, // 0..?
+    921, 722, 667, 667, 722, 611, 556, 722, 722, 333, 389, 722, 611, 889, 722, 722, // @..O
+    556, 722, 667, 556, 611, 722, 722, 944, 722, 722, 611, 333, 278, 333, 469, 500, // P.._
+    333, 444, 500, 444, 500, 444, 333, 500, 500, 278, 278, 500, 278, 778, 500, 500, // `..o
+    500, 500, 333, 389, 278, 500, 500, 722, 500, 500, 444, 480, 200, 480, 541, // p..~
+];
+
+/// Width in thousandths of an em for `code` in the standard font
+/// `base_font`, or `None` when the font is not one we have metrics for.
+/// Bold and italic faces reuse the regular metrics, which is close enough
+/// for hit-testing.
+pub fn width(base_font: &str, code: u32) -> Option<f64> {
+    let family = base_font.split(['-', ',']).next().unwrap_or(base_font);
+    let table = match family {
+        "Helvetica" | "Arial" => &HELVETICA,
+        "Times" | "TimesNewRoman" | "Times New Roman" => &TIMES_ROMAN,
+        "Courier" | "CourierNew" => return Some(600.0),
+        "Symbol" | "ZapfDingbats" => return Some(500.0),
+        _ => return None,
+    };
+    match code {
+        32..=126 => Some(table[code as usize - 32] as f64),
+        // Outside ASCII fall back to the average lower-case width.
+        _ => Some(500.0),
+    }
+}
+
+/// Unicode for an Adobe glyph name. Covers the names that show up in
+/// `/Differences` arrays of Latin text plus the `uniXXXX`/`uXXXX` forms.
+pub fn glyph_name_to_unicode(name: &str) -> Option<char> {
+    if let Some(hex) = name.strip_prefix("uni").filter(|h| h.len() == 4) {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+    if let Some(hex) = name
+        .strip_prefix('u')
+        .filter(|h| (4..=6).contains(&h.len()))
+    {
+        if let Some(c) = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+            return Some(c);
+        }
+    }
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    let c = match name {
+        "space" | "nbspace" => ' ',
+        "exclam" => '!',
+        "quotedbl" => '"',
+        "numbersign" => '#',
+        "dollar" => '$',
+        "percent" => '%',
+        "ampersand" => '&',
+        "quotesingle" => '\'',
+        "parenleft" => '(',
+        "parenright" => ')',
+        "asterisk" => '*',
+        "plus" => '+',
+        "comma" => ',',
+        "hyphen" | "minus" => '-',
+        "period" => '.',
+        "slash" => '/',
+        "zero" => '0',
+        "one" => '1',
+        "two" => '2',
+        "three" => '3',
+        "four" => '4',
+        "five" => '5',
+        "six" => '6',
+        "seven" => '7',
+        "eight" => '8',
+        "nine" => '9',
+        "colon" => ':',
+        "semicolon" => ';',
+        "less" => '<',
+        "equal" => '=',
+        "greater" => '>',
+        "question" => '?',
+        "at" => '@',
+        "bracketleft" => '[',
+        "backslash" => '\\',
+        "bracketright" => ']',
+        "asciicircum" => '^',
+        "underscore" => '_',
+        "grave" => '`',
+        "braceleft" => '{',
+        "bar" => '|',
+        "braceright" => '}',
+        "asciitilde" => '~',
+        "quoteleft" => '\u{2018}',
+        "quoteright" => '\u{2019}',
+        "quotedblleft" => '\u{201C}',
+        "quotedblright" => '\u{201D}',
+        "endash" => '\u{2013}',
+        "emdash" => '\u{2014}',
+        "bullet" => '\u{2022}',
+        "ellipsis" => '\u{2026}',
+        "copyright" => '\u{A9}',
+        "registered" => '\u{AE}',
+        "trademark" => '\u{2122}',
+        "degree" => '\u{B0}',
+        "section" => '\u{A7}',
+        "paragraph" => '\u{B6}',
+        "euro" | "Euro" => '\u{20AC}',
+        "sterling" => '\u{A3}',
+        "yen" => '\u{A5}',
+        "fi" => '\u{FB01}',
+        "fl" => '\u{FB02}',
+        "adieresis" => 'ä',
+        "odieresis" => 'ö',
+        "udieresis" => 'ü',
+        "Adieresis" => 'Ä',
+        "Odieresis" => 'Ö',
+        "Udieresis" => 'Ü',
+        "germandbls" => 'ß',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        "agrave" => 'à',
+        "ccedilla" => 'ç',
+        _ => return None,
+    };
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn width_covers_standard_families() {
+        assert_eq!(width("Helvetica", b'H' as u32), Some(722.0));
+        assert_eq!(width("Helvetica-Bold", b' ' as u32), Some(278.0));
+        assert_eq!(width("Times-Roman", b'a' as u32), Some(444.0));
+        assert_eq!(width("Courier-Oblique", b'W' as u32), Some(600.0));
+        assert_eq!(width("MyriadPro", b'a' as u32), None);
+    }
+
+    #[test]
+    fn glyph_names_resolve_to_unicode() {
+        assert_eq!(glyph_name_to_unicode("A"), Some('A'));
+        assert_eq!(glyph_name_to_unicode("quoteright"), Some('\u{2019}'));
+        assert_eq!(glyph_name_to_unicode("uni20AC"), Some('€'));
+        assert_eq!(glyph_name_to_unicode("g123"), None);
+    }
+}
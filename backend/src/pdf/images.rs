@@ -0,0 +1,243 @@
+//! Turning PNG and JPEG files into Image XObjects.
+//!
+//! JPEG data is embedded as it is, under `/DCTDecode`; only the frame
+//! header is read for the size and colour space. PNG files are decoded and
+//! stored Flate-compressed, with any alpha channel split off into a soft
+//! mask.
+
+use lopdf::{dictionary, Object, Stream};
+
+use crate::pdf::filters;
+
+/// JPEG start-of-frame markers; the others in 0xC0..=0xCF are DHT, JPG and
+/// DAC.
+const SOF_MARKERS: [u8; 13] = [
+    0xC0, 0xC1, 0xC2, 0xC3, 0xC5, 0xC6, 0xC7, 0xC9, 0xCA, 0xCB, 0xCD, 0xCE, 0xCF,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImageError {
+    #[error("image data is not a PNG or JPEG file")]
+    Unsupported,
+    #[error("image could not be decoded: {0}")]
+    Malformed(String),
+    #[error("image decodes to more than {limit} bytes")]
+    TooLarge { limit: usize },
+}
+
+/// An Image XObject ready to be added to a document. The soft mask, when
+/// there is one, must be added too and referenced from the image's
+/// `/SMask` entry.
+#[derive(Debug, Clone)]
+pub struct ImageXObject {
+    pub image: Stream,
+    pub smask: Option<Stream>,
+    /// Size in pixels.
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The format of `data`, judged by its signature.
+pub fn sniff(data: &[u8]) -> Option<ImageFormat> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::Jpeg)
+    } else {
+        None
+    }
+}
+
+/// Build an Image XObject from PNG or JPEG bytes. `limit` caps the size of
+/// decoded PNG samples.
+pub fn image_xobject(data: &[u8], limit: usize) -> Result<ImageXObject, ImageError> {
+    match sniff(data).ok_or(ImageError::Unsupported)? {
+        ImageFormat::Jpeg => jpeg_xobject(data),
+        ImageFormat::Png => png_xobject(data, limit),
+    }
+}
+
+fn jpeg_xobject(data: &[u8]) -> Result<ImageXObject, ImageError> {
+    let malformed = || ImageError::Malformed("JPEG has no frame header".into());
+    let mut offset = 2;
+    let mut adobe = false;
+    loop {
+        let marker = data.get(offset..offset + 4).ok_or_else(malformed)?;
+        if marker[0] != 0xFF {
+            return Err(malformed());
+        }
+        if marker[1] == 0xFF {
+            // Fill byte before a marker.
+            offset += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([marker[2], marker[3]]) as usize;
+        let segment = data
+            .get(offset + 4..offset + 2 + length)
+            .ok_or_else(malformed)?;
+        if marker[1] == 0xEE && segment.starts_with(b"Adobe") {
+            adobe = true;
+        }
+        if SOF_MARKERS.contains(&marker[1]) {
+            if segment.len() < 6 {
+                return Err(malformed());
+            }
+            let bits = segment[0];
+            let height = u16::from_be_bytes([segment[1], segment[2]]) as u32;
+            let width = u16::from_be_bytes([segment[3], segment[4]]) as u32;
+            let color_space = match segment[5] {
+                1 => "DeviceGray",
+                3 => "DeviceRGB",
+                4 => "DeviceCMYK",
+                n => return Err(ImageError::Malformed(format!("JPEG has {n} components"))),
+            };
+            if width == 0 || height == 0 {
+                return Err(ImageError::Malformed("JPEG has no size".into()));
+            }
+            let mut dict = dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "ColorSpace" => color_space,
+                "BitsPerComponent" => bits as i64,
+                "Filter" => "DCTDecode",
+            };
+            if adobe && color_space == "DeviceCMYK" {
+                // Photoshop writes CMYK JPEGs inverted.
+                let decode: Vec<Object> = [1, 0, 1, 0, 1, 0, 1, 0].map(Object::from).into();
+                dict.set("Decode", decode);
+            }
+            let mut image = Stream::new(dict, data.to_vec());
+            image.allows_compression = false;
+            return Ok(ImageXObject {
+                image,
+                smask: None,
+                width,
+                height,
+            });
+        }
+        offset += 2 + length;
+    }
+}
+
+fn png_xobject(data: &[u8], limit: usize) -> Result<ImageXObject, ImageError> {
+    let malformed = |err: png::DecodingError| ImageError::Malformed(err.to_string());
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(malformed)?;
+    let size = reader.output_buffer_size();
+    if size > limit {
+        return Err(ImageError::TooLarge { limit });
+    }
+    let mut pixels = vec![0; size];
+    let frame = reader.next_frame(&mut pixels).map_err(malformed)?;
+    pixels.truncate(frame.buffer_size());
+
+    let (color_space, channels, has_alpha) = match frame.color_type {
+        png::ColorType::Grayscale => ("DeviceGray", 1, false),
+        png::ColorType::GrayscaleAlpha => ("DeviceGray", 1, true),
+        png::ColorType::Rgb => ("DeviceRGB", 3, false),
+        png::ColorType::Rgba => ("DeviceRGB", 3, true),
+        png::ColorType::Indexed => {
+            return Err(ImageError::Malformed("PNG palette was not expanded".into()))
+        }
+    };
+    let (color, alpha) = if has_alpha {
+        let mut color = Vec::with_capacity(pixels.len() / (channels + 1) * channels);
+        let mut alpha = Vec::with_capacity(pixels.len() / (channels + 1));
+        for pixel in pixels.chunks_exact(channels + 1) {
+            color.extend_from_slice(&pixel[..channels]);
+            alpha.push(pixel[channels]);
+        }
+        // A fully opaque alpha channel needs no mask.
+        let alpha = alpha.iter().any(|&a| a != u8::MAX).then_some(alpha);
+        (color, alpha)
+    } else {
+        (pixels, None)
+    };
+
+    let (width, height) = (frame.width, frame.height);
+    let flate = |color_space: &str, samples: &[u8]| {
+        let mut stream = Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "ColorSpace" => color_space,
+                "BitsPerComponent" => 8,
+            },
+            Vec::new(),
+        );
+        filters::set_flate_content(&mut stream, samples);
+        stream
+    };
+    Ok(ImageXObject {
+        image: flate(color_space, &color),
+        smask: alpha.map(|alpha| flate("DeviceGray", &alpha)),
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::testing::{test_jpeg, test_png};
+
+    #[test]
+    fn jpeg_passes_through_as_dct() {
+        let jpeg = test_jpeg(3, 2);
+        let xobject = image_xobject(&jpeg, usize::MAX).unwrap();
+
+        assert_eq!((xobject.width, xobject.height), (3, 2));
+        assert_eq!(xobject.image.content, jpeg);
+        let dict = &xobject.image.dict;
+        assert_eq!(dict.get(b"Width").unwrap().as_i64().unwrap(), 3);
+        assert_eq!(dict.get(b"Height").unwrap().as_i64().unwrap(), 2);
+        assert_eq!(
+            dict.get(b"Filter").unwrap().as_name().unwrap(),
+            b"DCTDecode"
+        );
+        assert_eq!(
+            dict.get(b"ColorSpace").unwrap().as_name().unwrap(),
+            b"DeviceRGB"
+        );
+        assert!(xobject.smask.is_none());
+    }
+
+    #[test]
+    fn png_alpha_becomes_a_soft_mask() {
+        let png = test_png(2, 1, &[255, 0, 0, 255, 0, 0, 255, 128]);
+        let xobject = image_xobject(&png, usize::MAX).unwrap();
+
+        let decode = |stream: &Stream| {
+            filters::decode(&stream.content, &[(b"FlateDecode", None)], usize::MAX)
+                .unwrap()
+                .data
+        };
+        assert_eq!(decode(&xobject.image), [255, 0, 0, 0, 0, 255]);
+        assert_eq!(decode(xobject.smask.as_ref().unwrap()), [255, 128]);
+    }
+
+    #[test]
+    fn opaque_png_needs_no_mask_and_limits_apply() {
+        let png = test_png(1, 1, &[1, 2, 3, 255]);
+        assert!(image_xobject(&png, usize::MAX).unwrap().smask.is_none());
+        assert!(matches!(
+            image_xobject(&png, 3),
+            Err(ImageError::TooLarge { limit: 3 })
+        ));
+        assert!(matches!(
+            image_xobject(b"GIF89a", usize::MAX),
+            Err(ImageError::Unsupported)
+        ));
+    }
+}
@@ -1,23 +1,350 @@
 //! Utilities for loading and caching PDFs.
 
-use anyhow::Result;
-use lopdf::Document;
+use std::collections::{BTreeMap, HashSet};
 
-/// Placeholder loader that simply parses bytes into a `lopdf::Document`.
-/// In the future this module will provide caching and sanitisation.
-pub fn parse_document(bytes: &[u8]) -> Result<Document> {
-    let mut doc = Document::load_mem(bytes)?;
+use lopdf::{Document, Object, ObjectId, ObjectStream, Reader, Stream};
+
+use crate::pdf::content;
+use crate::pdf::filters::{self, FilterError};
+
+/// Upper bounds applied while loading untrusted uploads.
+///
+/// Every limit is checked before the corresponding allocation happens where
+/// possible, so a hostile file fails with a [`LoadError`] instead of
+/// exhausting memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadLimits {
+    pub max_upload_bytes: usize,
+    pub max_pages: usize,
+    pub max_objects: usize,
+    pub max_decoded_stream_bytes: usize,
+    pub max_nesting_depth: usize,
+}
+
+impl Default for LoadLimits {
+    fn default() -> Self {
+        Self {
+            max_upload_bytes: 100 * 1024 * 1024,
+            max_pages: 5_000,
+            max_objects: 1_000_000,
+            max_decoded_stream_bytes: 256 * 1024 * 1024,
+            max_nesting_depth: 64,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("document is {size} bytes, the limit is {limit}")]
+    TooLarge { size: usize, limit: usize },
+    #[error("document has {count} pages, the limit is {limit}")]
+    TooManyPages { count: usize, limit: usize },
+    #[error("document has {count} objects, the limit is {limit}")]
+    TooManyObjects { count: usize, limit: usize },
+    #[error("stream {} {} R decodes to more than {limit} bytes", .id.0, .id.1)]
+    StreamTooLarge { id: ObjectId, limit: usize },
+    #[error("objects are nested deeper than {limit} levels")]
+    NestingTooDeep { limit: usize },
+    #[error("no %PDF- header in the first {HEADER_SEARCH_BYTES} bytes")]
+    NotPdf,
+    #[error("document is encrypted")]
+    Encrypted,
+    #[error("failed to parse PDF: {0}")]
+    Parse(#[from] lopdf::Error),
+}
+
+/// The `/Type` object streams carry while lopdf loads the file, so it
+/// leaves them to [`expand_object_streams`].
+const DEFERRED_OBJECT_STREAM: &[u8] = b"ObjStmDeferred";
+
+/// How far into the file the `%PDF-` header may start. Readers tolerate
+/// some leading junk, as Acrobat does.
+pub const HEADER_SEARCH_BYTES: usize = 1024;
+
+/// Parse bytes into a `lopdf::Document`, enforcing `limits` along the way.
+pub fn parse_document(bytes: &[u8], limits: &LoadLimits) -> Result<Document, LoadError> {
+    if bytes.len() > limits.max_upload_bytes {
+        return Err(LoadError::TooLarge {
+            size: bytes.len(),
+            limit: limits.max_upload_bytes,
+        });
+    }
+    let head = &bytes[..bytes.len().min(HEADER_SEARCH_BYTES)];
+    if !head.windows(5).any(|window| window == b"%PDF-") {
+        return Err(LoadError::NotPdf);
+    }
+    // lopdf parses nested objects recursively, so the depth has to be
+    // checked on the raw bytes before handing them over.
+    check_nesting_depth(bytes, limits.max_nesting_depth)?;
+    check_xref_streams(bytes, limits)?;
+
+    let reader = Reader {
+        buffer: bytes,
+        document: Document::new(),
+    };
+    let mut doc = reader.read(Some(defer_object_stream))?;
     doc.version = "1.7".to_string();
+    // Streams and strings would be ciphertext, and edits written in the
+    // clear would corrupt the file.
+    if doc.trailer.has(b"Encrypt") {
+        return Err(LoadError::Encrypted);
+    }
+    expand_object_streams(&mut doc, limits)?;
+
+    if doc.objects.len() > limits.max_objects {
+        return Err(LoadError::TooManyObjects {
+            count: doc.objects.len(),
+            limit: limits.max_objects,
+        });
+    }
+    let page_count = doc.get_pages().len();
+    if page_count > limits.max_pages {
+        return Err(LoadError::TooManyPages {
+            count: page_count,
+            limit: limits.max_pages,
+        });
+    }
+    let limit = limits.max_decoded_stream_bytes;
+    for (id, object) in &doc.objects {
+        if let Object::Stream(stream) = object {
+            // Corrupt data is left for extraction to report; only the size
+            // limit matters here, and the decoded bytes are not kept.
+            if let Err(FilterError::TooLarge { .. }) =
+                filters::check_decoded_size(&doc, stream, limit)
+            {
+                return Err(LoadError::StreamTooLarge { id: *id, limit });
+            }
+        }
+    }
+
     Ok(doc)
 }
 
+/// Cross-reference streams are inflated by lopdf as it reads the xref
+/// chain, before any object is loaded and with no size limit. Follow the
+/// chain from every `startxref` in the file, a superset of the one lopdf
+/// picks, and check the decoded size of each stream on it first.
+fn check_xref_streams(bytes: &[u8], limits: &LoadLimits) -> Result<(), LoadError> {
+    let depth = limits.max_nesting_depth;
+    let limit = limits.max_decoded_stream_bytes;
+    let mut pending: Vec<usize> = find_all(bytes, b"startxref")
+        .filter_map(|at| {
+            let op = content::parse_operation(&bytes[at + b"startxref".len()..], depth).ok()?;
+            match op.operands.first() {
+                Some(Object::Integer(offset)) => usize::try_from(*offset).ok(),
+                _ => None,
+            }
+        })
+        .collect();
+    let mut seen = HashSet::new();
+    while let Some(offset) = pending.pop() {
+        if offset >= bytes.len() || !seen.insert(offset) {
+            continue;
+        }
+        let input = &bytes[offset..];
+        let dict = if input.starts_with(b"xref") {
+            let Some(at) = find(input, b"trailer") else {
+                continue;
+            };
+            match content::parse_operation(&input[at + b"trailer".len()..], depth) {
+                Ok(op) => op.operands.into_iter().next(),
+                Err(_) => continue,
+            }
+        } else {
+            let Ok(header) = content::parse_operation(input, depth) else {
+                continue;
+            };
+            let id = match header.operands[..] {
+                [Object::Integer(number), Object::Integer(generation)]
+                    if header.operator == "obj" =>
+                {
+                    (number as u32, generation as u16)
+                }
+                _ => continue,
+            };
+            let start = header.span.end;
+            let Ok(body) = content::parse_operation(&input[start..], depth) else {
+                continue;
+            };
+            if let (Some(Object::Dictionary(dict)), "stream") =
+                (body.operands.first(), body.operator.as_str())
+            {
+                let data = &input[start + body.span.end..];
+                let data = data
+                    .strip_prefix(b"\r\n")
+                    .or_else(|| data.strip_prefix(b"\n"))
+                    .or_else(|| data.strip_prefix(b"\r"))
+                    .unwrap_or(data);
+                // lopdf cannot resolve an indirect `/Length` this early and
+                // reads such streams as empty.
+                let length = match dict.get(b"Length") {
+                    Ok(Object::Integer(length)) => usize::try_from(*length).unwrap_or(0),
+                    _ => 0,
+                };
+                let stream = Stream::new(dict.clone(), data[..length.min(data.len())].to_vec());
+                if let Err(FilterError::TooLarge { .. }) =
+                    filters::check_decoded_size(&Document::new(), &stream, limit)
+                {
+                    return Err(LoadError::StreamTooLarge { id, limit });
+                }
+            }
+            body.operands.into_iter().next()
+        };
+        let Some(Object::Dictionary(dict)) = dict else {
+            continue;
+        };
+        for key in [&b"Prev"[..], b"XRefStm"] {
+            if let Ok(Object::Integer(offset)) = dict.get(key) {
+                pending.extend(usize::try_from(*offset).ok());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keep lopdf from unpacking object streams while it loads the file: it
+/// would inflate them with no size limit and parse the objects inside
+/// without a depth check. Only a `None` result drops the object, so the
+/// returned copy is left empty.
+fn defer_object_stream(id: ObjectId, object: &mut Object) -> Option<(ObjectId, Object)> {
+    if let Object::Stream(stream) = object {
+        if stream.dict.type_is(b"ObjStm") {
+            stream
+                .dict
+                .set("Type", Object::Name(DEFERRED_OBJECT_STREAM.to_vec()));
+        }
+    }
+    Some((id, Object::Null))
+}
+
+/// Unpack the object streams [`defer_object_stream`] set aside, decoding
+/// each within the size limit and checking its nesting before lopdf
+/// parses the objects in it. As lopdf does, objects stored directly in
+/// the file win over copies in object streams.
+fn expand_object_streams(doc: &mut Document, limits: &LoadLimits) -> Result<(), LoadError> {
+    let mut ids = Vec::new();
+    for (id, object) in doc.objects.iter_mut() {
+        if let Object::Stream(stream) = object {
+            if stream.dict.type_is(DEFERRED_OBJECT_STREAM) {
+                stream.dict.set("Type", Object::Name(b"ObjStm".to_vec()));
+                ids.push(*id);
+            }
+        }
+    }
+    let limit = limits.max_decoded_stream_bytes;
+    let mut expanded = BTreeMap::new();
+    for id in ids {
+        let Ok(stream) = doc.get_object(id).and_then(Object::as_stream) else {
+            continue;
+        };
+        let data = match filters::decode_stream(doc, stream, limit) {
+            Ok(decoded) if decoded.is_complete() => decoded.data,
+            Err(FilterError::TooLarge { .. }) => {
+                return Err(LoadError::StreamTooLarge { id, limit })
+            }
+            // Left for extraction to report if anything needs its objects.
+            _ => continue,
+        };
+        check_nesting_depth(&data, limits.max_nesting_depth)?;
+        let mut plain = Stream::new(stream.dict.clone(), data);
+        plain.dict.remove(b"Filter");
+        plain.dict.remove(b"DecodeParms");
+        if let Ok(objects) = ObjectStream::new(&mut plain) {
+            expanded.extend(objects.objects);
+        }
+    }
+    for (id, object) in expanded {
+        doc.objects.entry(id).or_insert(object);
+    }
+    Ok(())
+}
+
+/// Scan the raw file for `[`/`<<` nesting without building any objects.
+/// Strings, comments and stream payloads are skipped so their contents
+/// cannot be mistaken for delimiters.
+fn check_nesting_depth(bytes: &[u8], limit: usize) -> Result<(), LoadError> {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                while i < bytes.len() && bytes[i] != b'\n' && bytes[i] != b'\r' {
+                    i += 1;
+                }
+            }
+            b'(' => {
+                let mut parens = 1usize;
+                i += 1;
+                while i < bytes.len() && parens > 0 {
+                    match bytes[i] {
+                        b'\\' => i += 1,
+                        b'(' => parens += 1,
+                        b')' => parens -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                continue;
+            }
+            b'<' if bytes.get(i + 1) == Some(&b'<') => {
+                depth += 1;
+                i += 1;
+            }
+            b'<' => {
+                while i < bytes.len() && bytes[i] != b'>' {
+                    i += 1;
+                }
+            }
+            b'>' if bytes.get(i + 1) == Some(&b'>') => {
+                depth = depth.saturating_sub(1);
+                i += 1;
+            }
+            b'[' => depth += 1,
+            b']' => depth = depth.saturating_sub(1),
+            b's' if bytes[i..].starts_with(b"stream") && is_stream_keyword(bytes, i) => {
+                match find(&bytes[i..], b"endstream") {
+                    Some(offset) => i += offset + b"endstream".len(),
+                    None => break,
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if depth > limit {
+            return Err(LoadError::NestingTooDeep { limit });
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+fn is_stream_keyword(bytes: &[u8], at: usize) -> bool {
+    let preceded = at == 0 || !bytes[at - 1].is_ascii_alphanumeric();
+    let followed = matches!(bytes.get(at + 6), Some(b'\r' | b'\n'));
+    preceded && followed
+}
+
+fn find_all<'a>(haystack: &'a [u8], needle: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(move |(_, window)| *window == needle)
+        .map(|(at, _)| at)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lopdf::dictionary;
+    use lopdf::{dictionary, Stream};
 
-    #[test]
-    fn parse_document_sets_version_and_preserves_pages() {
+    fn minimal_pdf(extra: impl FnOnce(&mut lopdf::Document)) -> Vec<u8> {
         let mut source = lopdf::Document::with_version("1.0");
         let root_id = lopdf::ObjectId::from((1, 0));
         source
@@ -30,14 +357,218 @@ mod tests {
             }
             .into(),
         );
+        source.max_id = 1;
+        extra(&mut source);
         let mut bytes = Vec::new();
         source
             .save_to(&mut bytes)
             .expect("constructing minimal pdf");
+        bytes
+    }
+
+    #[test]
+    fn parse_document_sets_version_and_preserves_pages() {
+        let bytes = minimal_pdf(|_| {});
 
-        let document = parse_document(&bytes).expect("generated pdf should parse");
+        let document =
+            parse_document(&bytes, &LoadLimits::default()).expect("generated pdf should parse");
 
         assert_eq!(document.version, "1.7");
         assert!(document.trailer.get(b"Root").is_ok());
     }
+
+    #[test]
+    fn parse_document_rejects_oversized_input() {
+        let bytes = minimal_pdf(|_| {});
+        let limits = LoadLimits {
+            max_upload_bytes: 16,
+            ..Default::default()
+        };
+
+        let err = parse_document(&bytes, &limits).unwrap_err();
+        assert!(matches!(err, LoadError::TooLarge { limit: 16, .. }));
+    }
+
+    #[test]
+    fn parse_document_requires_a_pdf_header() {
+        let mut bytes = b"junk before the header\n".to_vec();
+        bytes.extend(minimal_pdf(|_| {}));
+        // Leading junk shifts the xref offsets, but is not itself fatal.
+        let result = parse_document(&bytes, &LoadLimits::default());
+        assert!(!matches!(result, Err(LoadError::NotPdf)));
+
+        let err = parse_document(b"GIF89a not a pdf", &LoadLimits::default()).unwrap_err();
+        assert!(matches!(err, LoadError::NotPdf));
+        let late = [vec![b' '; HEADER_SEARCH_BYTES], minimal_pdf(|_| {})].concat();
+        let err = parse_document(&late, &LoadLimits::default()).unwrap_err();
+        assert!(matches!(err, LoadError::NotPdf));
+    }
+
+    #[test]
+    fn parse_document_rejects_encrypted_documents() {
+        let bytes = minimal_pdf(|doc| {
+            doc.trailer.set(
+                "Encrypt",
+                lopdf::dictionary! { "Filter" => "Standard", "V" => 2, "R" => 3 },
+            );
+        });
+
+        let err = parse_document(&bytes, &LoadLimits::default()).unwrap_err();
+        assert!(matches!(err, LoadError::Encrypted));
+    }
+
+    #[test]
+    fn parse_document_rejects_too_many_objects() {
+        let bytes = minimal_pdf(|doc| {
+            for _ in 0..4 {
+                doc.add_object(Object::Integer(1));
+            }
+        });
+        let limits = LoadLimits {
+            max_objects: 3,
+            ..Default::default()
+        };
+
+        let err = parse_document(&bytes, &limits).unwrap_err();
+        assert!(matches!(err, LoadError::TooManyObjects { limit: 3, .. }));
+    }
+
+    #[test]
+    fn parse_document_rejects_too_many_pages() {
+        let bytes = include_bytes!("../../../e2e/sample.pdf");
+        let limits = LoadLimits {
+            max_pages: 0,
+            ..Default::default()
+        };
+
+        let err = parse_document(bytes, &limits).unwrap_err();
+        assert!(matches!(
+            err,
+            LoadError::TooManyPages { count: 1, limit: 0 }
+        ));
+    }
+
+    #[test]
+    fn parse_document_rejects_decompression_bombs() {
+        let compressed = filters::encode_flate(&vec![0u8; 1 << 20]);
+        let bytes = minimal_pdf(|doc| {
+            doc.add_object(Stream::new(
+                dictionary! { "Filter" => "FlateDecode" },
+                compressed,
+            ));
+        });
+        let limits = LoadLimits {
+            max_decoded_stream_bytes: 64 * 1024,
+            ..Default::default()
+        };
+
+        let err = parse_document(&bytes, &limits).unwrap_err();
+        assert!(matches!(err, LoadError::StreamTooLarge { id: (2, 0), .. }));
+        parse_document(&bytes, &LoadLimits::default()).expect("default limits allow 1 MiB");
+    }
+
+    /// A file with `bodies` as objects 1, 2, … and a classic xref table,
+    /// for layouts lopdf will not write, such as object streams.
+    fn raw_pdf(bodies: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"%PDF-1.5\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, body) in bodies.iter().enumerate() {
+            offsets.push(bytes.len());
+            bytes.extend(format!("{} 0 obj\n", i + 1).bytes());
+            bytes.extend(body);
+            bytes.extend(b"\nendobj\n");
+        }
+        let xref = bytes.len();
+        let size = bodies.len() + 1;
+        bytes.extend(format!("xref\n0 {size}\n0000000000 65535 f\r\n").bytes());
+        for offset in offsets {
+            bytes.extend(format!("{offset:010} 00000 n\r\n").bytes());
+        }
+        bytes.extend(
+            format!("trailer\n<< /Size {size} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF").bytes(),
+        );
+        bytes
+    }
+
+    fn stream_body(dict: &str, data: &[u8]) -> Vec<u8> {
+        let mut body = format!("<< {dict} /Length {} >>\nstream\n", data.len()).into_bytes();
+        body.extend(data);
+        body.extend(b"\nendstream");
+        body
+    }
+
+    /// A catalog and an object stream holding object 3 as `object`.
+    fn pdf_with_object_stream(object: &[u8], padding: usize) -> Vec<u8> {
+        let mut payload = b"3 0 ".to_vec();
+        payload.extend(object);
+        payload.extend(vec![b' '; padding]);
+        raw_pdf(&[
+            b"<< /Type /Catalog /Pages 3 0 R >>".to_vec(),
+            stream_body(
+                "/Type /ObjStm /N 1 /First 4 /Filter /FlateDecode",
+                &filters::encode_flate(&payload),
+            ),
+        ])
+    }
+
+    #[test]
+    fn parse_document_unpacks_object_streams() {
+        let bytes = pdf_with_object_stream(b"<< /Type /Pages /Kids [] /Count 0 >>", 0);
+        let doc = parse_document(&bytes, &LoadLimits::default()).unwrap();
+        assert!(doc.get_dictionary((3, 0)).unwrap().type_is(b"Pages"));
+        assert_eq!(
+            doc.get_object((2, 0)).unwrap().type_name().unwrap(),
+            "ObjStm"
+        );
+    }
+
+    #[test]
+    fn parse_document_rejects_bombs_and_deep_nesting_in_object_streams() {
+        let limits = LoadLimits {
+            max_decoded_stream_bytes: 64 * 1024,
+            ..Default::default()
+        };
+        let bomb = pdf_with_object_stream(b"<< >>", 1 << 20);
+        let err = parse_document(&bomb, &limits).unwrap_err();
+        assert!(matches!(err, LoadError::StreamTooLarge { id: (2, 0), .. }));
+
+        let nested = format!("{}{}", "[".repeat(100), "]".repeat(100));
+        let deep = pdf_with_object_stream(nested.as_bytes(), 0);
+        let err = parse_document(&deep, &limits).unwrap_err();
+        assert!(matches!(err, LoadError::NestingTooDeep { limit: 64 }));
+    }
+
+    #[test]
+    fn parse_document_checks_xref_streams_before_lopdf_inflates_them() {
+        let mut bytes = b"%PDF-1.5\n1 0 obj\n<< /Type /Catalog >>\nendobj\n".to_vec();
+        let xref = bytes.len();
+        bytes.extend(b"2 0 obj\n");
+        bytes.extend(stream_body(
+            "/Type /XRef /Size 3 /W [1 4 1] /Root 1 0 R /Filter /FlateDecode",
+            &filters::encode_flate(&vec![0u8; 1 << 20]),
+        ));
+        bytes.extend(format!("\nendobj\nstartxref\n{xref}\n%%EOF").bytes());
+        let limits = LoadLimits {
+            max_decoded_stream_bytes: 64 * 1024,
+            ..Default::default()
+        };
+
+        let err = parse_document(&bytes, &limits).unwrap_err();
+        assert!(matches!(err, LoadError::StreamTooLarge { id: (2, 0), .. }));
+    }
+
+    #[test]
+    fn nesting_check_counts_arrays_and_dictionaries() {
+        let nested = format!("1 0 obj\n{}{}\nendobj", "[<<".repeat(3), ">>]".repeat(3));
+        check_nesting_depth(nested.as_bytes(), 6).expect("six levels are allowed");
+        let err = check_nesting_depth(nested.as_bytes(), 5).unwrap_err();
+        assert!(matches!(err, LoadError::NestingTooDeep { limit: 5 }));
+    }
+
+    #[test]
+    fn nesting_check_skips_strings_and_stream_data() {
+        let bytes =
+            b"1 0 obj\n<< /Length 4 >>\nstream\n[[[[\nendstream\nendobj\n2 0 obj\n(a[[[[b)\nendobj";
+        check_nesting_depth(bytes, 1).expect("payload brackets are ignored");
+    }
 }
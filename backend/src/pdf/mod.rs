@@ -2,7 +2,14 @@
 
 pub mod content;
 pub mod extract;
+pub mod filters;
 pub mod fonts;
+pub mod images;
 pub mod loader;
 pub mod patch;
+pub mod replay;
+pub mod resources;
+pub mod shapes;
+#[cfg(test)]
+pub mod testing;
 pub mod write;
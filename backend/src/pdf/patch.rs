@@ -1,45 +1,2809 @@
-//! JSON patch application placeholder.
+//! Applying JSON patch operations to content streams.
+//!
+//! Every operation re-extracts its page from the working document, finds
+//! the target's operators and splices replacement operators into the
+//! decoded stream. Untouched bytes are kept as they are. Objects that live
+//! in a Form XObject used elsewhere are edited in a private copy of the
+//! form, so other pages and other invocations keep the original. The same
+//! goes for page content streams shared between pages.
 
-use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
 
-use crate::types::{DocumentIR, PatchOperation};
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, StringFormat};
 
-/// Apply a list of patch operations to the supplied IR.
-/// The stubbed implementation simply logs the operations.
-pub fn apply_patches(ir: &mut DocumentIR, ops: &[PatchOperation]) -> Result<()> {
+use crate::pdf::content::{encode_ops, matrix_operands, real, ContentOp};
+use crate::pdf::extract::{self, ObjectKind, ObjectRecord, PageExtraction, StreamContent};
+use crate::pdf::filters::{self, FilterError};
+use crate::pdf::fonts::embed;
+use crate::pdf::fonts::library::FontLibrary;
+use crate::pdf::fonts::shape::{self, ShapedGlyph};
+use crate::pdf::images::{self, ImageError, ImageXObject};
+use crate::pdf::loader::{LoadError, LoadLimits};
+use crate::pdf::replay;
+use crate::pdf::resources;
+use crate::pdf::shapes::{self, ShapeError};
+use crate::pdf::write;
+use crate::types::{
+    ImageFit, ImageSource, PageObject, PatchOperation, PatchTarget, PathSegment, PathStyle, PdfRef,
+    ReorderDirection, StylePayload,
+};
+use crate::util::matrix::Matrix2D;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    #[error("page {0} does not exist")]
+    PageNotFound(usize),
+    #[error("object {id} not found on page {page}")]
+    UnknownTarget { page: usize, id: String },
+    #[error("font {font} has no glyph for {ch:?}")]
+    GlyphMissing { font: String, ch: char },
+    #[error("{id} is a {kind} object; {op} only applies to text")]
+    NotText {
+        id: String,
+        kind: &'static str,
+        op: &'static str,
+    },
+    #[error("{id} is a {kind} object; replaceImage only applies to images")]
+    NotImage { id: String, kind: &'static str },
+    #[error("{id} cannot move there without being clipped differently")]
+    ReorderBlocked { id: String },
+    #[error("{id} is a {actual} object, not {expected}")]
+    KindMismatch {
+        id: String,
+        expected: String,
+        actual: &'static str,
+    },
+    #[error("unknown object kind {0:?}")]
+    UnknownKind(String),
+    #[error("{field} must be finite and invertible")]
+    InvalidMatrix { field: &'static str },
+    #[error("{field} must be between 0 and 1, got {value}")]
+    OutOfRange { field: &'static str, value: f64 },
+    #[error("the object's transform is not invertible")]
+    SingularMatrix,
+    #[error("no font found for family {0:?}")]
+    FontNotFound(String),
+    #[error("there is no text to insert")]
+    EmptyText,
+    #[error("no uploaded image {0:?}")]
+    UnknownUpload(String),
+    #[error("inline image data is not valid base64")]
+    InvalidImageData,
+    #[error(transparent)]
+    Image(#[from] ImageError),
+    #[error(transparent)]
+    Shape(#[from] ShapeError),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl PatchError {
+    /// A stable, machine-readable name for the kind of failure.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PatchError::PageNotFound(_) => "page_not_found",
+            PatchError::UnknownTarget { .. } => "unknown_target",
+            PatchError::GlyphMissing { .. } => "glyph_missing",
+            PatchError::NotText { .. }
+            | PatchError::NotImage { .. }
+            | PatchError::KindMismatch { .. } => "wrong_kind",
+            PatchError::ReorderBlocked { .. } => "reorder_blocked",
+            PatchError::UnknownKind(_) => "unknown_kind",
+            PatchError::InvalidMatrix { .. } | PatchError::SingularMatrix => "invalid_matrix",
+            PatchError::OutOfRange { .. } => "out_of_range",
+            PatchError::FontNotFound(_) => "font_not_found",
+            PatchError::EmptyText => "empty_text",
+            PatchError::UnknownUpload(_) => "unknown_upload",
+            PatchError::InvalidImageData | PatchError::Image(ImageError::Malformed(_)) => {
+                "invalid_image"
+            }
+            PatchError::Image(ImageError::Unsupported) => "unsupported_image",
+            PatchError::Image(ImageError::TooLarge { .. }) => "limit_exceeded",
+            PatchError::Shape(_) => "invalid_shape",
+            PatchError::Other(err) if err.is::<filters::UnsupportedFilter>() => {
+                "unsupported_filter"
+            }
+            PatchError::Other(_) => "internal",
+        }
+    }
+}
+
+/// An operation of a batch that could not be applied.
+#[derive(Debug)]
+pub struct OpFailure {
+    /// Position of the operation in the batch.
+    pub index: usize,
+    /// The object the operation targets, for operations that have one.
+    pub target: Option<PatchTarget>,
+    pub error: PatchError,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BatchError {
+    /// Operations that failed; nothing in the batch was applied. Every
+    /// operation that fails validation is listed, or else the first one
+    /// that failed to apply.
+    #[error("operation {} failed: {}", .0[0].index, .0[0].error)]
+    Rejected(Vec<OpFailure>),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Result of applying a batch of operations.
+#[derive(Debug, Clone)]
+pub struct PatchOutcome {
+    /// The original bytes followed by one incremental update.
+    pub pdf: Vec<u8>,
+    /// The document `pdf` holds, ready to be patched again.
+    pub document: Document,
+    /// Zero-based indices of the pages whose content changed.
+    pub touched_pages: BTreeSet<usize>,
+    /// Objects added by insert operations, in the order of the operations.
+    pub created: Vec<PatchTarget>,
+}
+
+/// What operations may draw on besides the document itself.
+#[derive(Debug, Clone, Copy)]
+pub struct PatchContext<'a> {
+    pub limits: &'a LoadLimits,
+    /// Fonts for inserted text.
+    pub fonts: &'a FontLibrary,
+    /// Images uploaded for the document, by upload id.
+    pub uploads: &'a HashMap<String, Vec<u8>>,
+}
+
+/// Apply `ops` in order to the document parsed from `pdf`, all or
+/// nothing. Every operation is validated before any is applied; targets
+/// are checked as they are reached, since ids refer to the document as
+/// the operations before them left it.
+pub fn apply_patches(
+    document: &Document,
+    pdf: &[u8],
+    ops: &[PatchOperation],
+    context: &PatchContext,
+) -> Result<PatchOutcome, BatchError> {
+    let failure = |index: usize, error: PatchError| OpFailure {
+        index,
+        target: target_of(&ops[index]).cloned(),
+        error,
+    };
+    let invalid: Vec<OpFailure> = ops
+        .iter()
+        .enumerate()
+        .filter_map(|(index, op)| validate(op).err().map(|error| failure(index, error)))
+        .collect();
+    if !invalid.is_empty() {
+        return Err(BatchError::Rejected(invalid));
+    }
+
+    let limits = context.limits;
+    let mut editor = Editor {
+        doc: document.clone(),
+        context,
+        changed: BTreeSet::new(),
+        touched_pages: BTreeSet::new(),
+        created: Vec::new(),
+    };
+    for (index, op) in ops.iter().enumerate() {
+        tracing::debug!(?op, "applying patch op");
+        editor
+            .apply(op)
+            .map_err(|error| BatchError::Rejected(vec![failure(index, error)]))?;
+    }
+    let touched_pages = editor.touched_pages;
+    if editor.changed.is_empty() {
+        return Ok(PatchOutcome {
+            pdf: pdf.to_vec(),
+            document: document.clone(),
+            touched_pages,
+            created: editor.created,
+        });
+    }
+
+    // The editor's copy already holds every object the batch wrote, so it
+    // becomes the next document as is. Only what the batch added is checked
+    // against the limits the loader applies to a whole file.
+    if editor.doc.objects.len() > limits.max_objects {
+        let error = LoadError::TooManyObjects {
+            count: editor.doc.objects.len(),
+            limit: limits.max_objects,
+        };
+        return Err(anyhow::Error::from(error).into());
+    }
+    let limit = limits.max_decoded_stream_bytes;
+    for &id in &editor.changed {
+        if let Ok(Object::Stream(stream)) = editor.doc.get_object(id) {
+            if let Err(FilterError::TooLarge { .. }) =
+                filters::check_decoded_size(&editor.doc, stream, limit)
+            {
+                return Err(anyhow::Error::from(LoadError::StreamTooLarge { id, limit }).into());
+            }
+        }
+    }
+    let bytes = write::incremental_update(pdf, document, &mut editor.doc, &editor.changed)?;
+    let document = editor.doc;
+    Ok(PatchOutcome {
+        pdf: bytes,
+        document,
+        touched_pages,
+        created: editor.created,
+    })
+}
+
+/// Operators outside `q`/`Q` that would leak into content appended to a
+/// page: the CTM, graphics state parameters and text state.
+const LEAKING_OPERATORS: &[&str] = &["cm", "gs", "Tc", "Tw", "Tz", "TL", "Ts", "Tr"];
+
+/// Path construction and painting operators.
+const PATH_OPERATORS: &[&str] = &[
+    "m", "l", "c", "v", "y", "re", "h", "S", "s", "f", "F", "f*", "B", "B*", "b", "b*",
+];
+
+/// Byte range in decoded page or form content and its replacement.
+type Splice = (Range<usize>, Vec<u8>);
+
+/// The page or form whose `/Resources` names are visible to a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResourceOwner {
+    Page(ObjectId),
+    Form(ObjectId),
+}
+
+struct Editor<'a> {
+    doc: Document,
+    context: &'a PatchContext<'a>,
+    changed: BTreeSet<ObjectId>,
+    /// Pages whose content the operations changed, beyond the page each
+    /// operation names.
+    touched_pages: BTreeSet<usize>,
+    created: Vec<PatchTarget>,
+}
+
+impl Editor<'_> {
+    fn apply(&mut self, op: &PatchOperation) -> Result<(), PatchError> {
+        match op {
+            PatchOperation::Transform {
+                target,
+                delta_matrix_pt,
+                kind,
+            } => self.transform(target, Matrix2D::from_array(*delta_matrix_pt), kind)?,
+            PatchOperation::EditText { target, text, .. } => self.edit_text(target, text)?,
+            PatchOperation::SetStyle { target, style } => self.set_style(target, style)?,
+            PatchOperation::Delete { target } => self.delete(target)?,
+            PatchOperation::Reorder { target, direction } => self.reorder(target, *direction)?,
+            PatchOperation::Duplicate {
+                target,
+                to_page,
+                offset_matrix_pt,
+            } => {
+                let page = to_page.unwrap_or(target.page);
+                let id = self.duplicate(target, page, Matrix2D::from_array(*offset_matrix_pt))?;
+                self.created.push(PatchTarget { page, id });
+            }
+            PatchOperation::InsertText {
+                page,
+                matrix_pt,
+                text,
+                font_family,
+                font_size,
+                color,
+            } => {
+                let id = self.insert_text(
+                    *page,
+                    Matrix2D::from_array(*matrix_pt),
+                    text,
+                    font_family,
+                    *font_size,
+                    color.unwrap_or([0.0; 3]),
+                )?;
+                self.created.push(PatchTarget { page: *page, id });
+            }
+            PatchOperation::InsertImage {
+                page,
+                image,
+                matrix_pt,
+            } => {
+                let id = self.insert_image(*page, image, Matrix2D::from_array(*matrix_pt))?;
+                self.created.push(PatchTarget { page: *page, id });
+            }
+            PatchOperation::ReplaceImage {
+                target,
+                image,
+                fit,
+                all_placements,
+            } => self.replace_image(target, image, *fit, *all_placements)?,
+            PatchOperation::InsertPath {
+                page,
+                segments,
+                style,
+            } => {
+                let id = self.insert_path(*page, segments, style)?;
+                self.created.push(PatchTarget { page: *page, id });
+            }
+        }
+        self.touched_pages.insert(page_of(op));
+        Ok(())
+    }
+
+    fn extract(&self, page: usize) -> Result<PageExtraction, PatchError> {
+        if page >= resources::page_ids(&self.doc).len() {
+            return Err(PatchError::PageNotFound(page));
+        }
+        Ok(extract::extract_page(&self.doc, page, self.context.limits)?)
+    }
+
+    /// Extract the target's page and make sure any form holding the target
+    /// is private to it. Returns the extraction, the record and the page or
+    /// form whose content to edit.
+    fn locate(
+        &mut self,
+        target: &PatchTarget,
+    ) -> Result<(PageExtraction, ObjectRecord, ObjectId), PatchError> {
+        let (extraction, record) = self.find(target)?;
+        let owner = self.unshare_forms(&extraction, &record)?;
+        Ok((extraction, record, owner))
+    }
+
+    /// Extract the target's page and find the target, for reading only.
+    fn find(&self, target: &PatchTarget) -> Result<(PageExtraction, ObjectRecord), PatchError> {
+        let extraction = self.extract(target.page)?;
+        let record =
+            extraction
+                .record(&target.id)
+                .cloned()
+                .ok_or_else(|| PatchError::UnknownTarget {
+                    page: target.page,
+                    id: target.id.clone(),
+                })?;
+        Ok((extraction, record))
+    }
+
+    fn transform(
+        &mut self,
+        target: &PatchTarget,
+        delta: Matrix2D,
+        kind: &str,
+    ) -> Result<(), PatchError> {
+        let (extraction, record, owner) = self.locate(target)?;
+        if record.kind.as_str() != kind {
+            return Err(PatchError::KindMismatch {
+                id: record.id,
+                expected: kind.to_string(),
+                actual: record.kind.as_str(),
+            });
+        }
+        let ctm = record.state.ctm;
+        let ctm_inv = ctm.invert().ok_or(PatchError::SingularMatrix)?;
+        // `delta` acts in page space; conjugate it into the object's space.
+        let local = ctm_inv.multiply(delta).multiply(ctm);
+        let content = &extraction.contents[&record.owner];
+
+        let splices = match record.kind {
+            ObjectKind::Text => {
+                let position = record.text.expect("text records carry positions");
+                let show = &content.ops[record.paint_op];
+                let mut ops = normalise_show(show);
+                let new_tm = local.multiply(position.tm);
+                ops.insert(
+                    ops.len() - 1,
+                    ContentOp::new("Tm", matrix_operands(new_tm.to_array())),
+                );
+                ops.extend(restore_text_position(&record, &content.ops));
+                vec![(show.span.clone(), encode_inline(&ops))]
+            }
+            ObjectKind::Image | ObjectKind::Path => {
+                let prefix = [
+                    ContentOp::new("q", vec![]),
+                    ContentOp::new("cm", matrix_operands(local.to_array())),
+                ];
+                wrap(&content.ops[record.ops.clone()], &prefix)
+            }
+        };
+        self.splice(owner, content, splices)
+    }
+
+    fn edit_text(&mut self, target: &PatchTarget, text: &str) -> Result<(), PatchError> {
+        let (extraction, record, owner) = self.locate(target)?;
+        if record.kind != ObjectKind::Text {
+            return Err(PatchError::NotText {
+                id: record.id,
+                kind: record.kind.as_str(),
+                op: "editText",
+            });
+        }
+        let font = record
+            .state
+            .text
+            .font
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("text {} has no font", record.id))?;
+        let bytes = font.encode(text).map_err(|ch| PatchError::GlyphMissing {
+            font: font.base_font.clone(),
+            ch,
+        })?;
+        let format = if font.two_byte {
+            StringFormat::Hexadecimal
+        } else {
+            StringFormat::Literal
+        };
+
+        let content = &extraction.contents[&record.owner];
+        let show = &content.ops[record.paint_op];
+        let mut ops = normalise_show(show);
+        *ops.last_mut().expect("show operator") =
+            ContentOp::new("Tj", vec![Object::String(bytes, format)]);
+        // The new string has a different width; put the text matrix back
+        // where the old one left it so the rest of the line stays put.
+        ops.extend(restore_text_position(&record, &content.ops));
+        let splices = vec![(show.span.clone(), encode_inline(&ops))];
+        self.splice(owner, content, splices)
+    }
+
+    fn set_style(&mut self, target: &PatchTarget, style: &StylePayload) -> Result<(), PatchError> {
+        let (extraction, record, owner) = self.locate(target)?;
+        let resource_owner = owner_of(&extraction, &record, owner);
+        let mut setters = Vec::new();
+        if let Some([r, g, b]) = style.fill_color {
+            setters.push(ContentOp::new("rg", vec![real(r), real(g), real(b)]));
+        }
+        if let Some([r, g, b]) = style.stroke_color {
+            setters.push(ContentOp::new("RG", vec![real(r), real(g), real(b)]));
+        }
+        let state = &record.state;
+        if style.opacity_fill.is_some() || style.opacity_stroke.is_some() {
+            let name = self.add_ext_gstate(
+                resource_owner,
+                style.opacity_fill.unwrap_or(state.fill_alpha),
+                style.opacity_stroke.unwrap_or(state.stroke_alpha),
+            )?;
+            setters.push(ContentOp::new("gs", vec![Object::Name(name)]));
+        }
+        if setters.is_empty() {
+            return Ok(());
+        }
+
+        let content = &extraction.contents[&record.owner];
+        let splices = match record.kind {
+            ObjectKind::Text => {
+                // `q`/`Q` are not allowed inside BT..ET, so the previous
+                // colours are set again after the show operator.
+                let show = &content.ops[record.paint_op];
+                let mut ops = setters;
+                ops.extend(normalise_show(show));
+                if style.fill_color.is_some() {
+                    ops.extend(state.fill.iter().cloned());
+                }
+                if style.stroke_color.is_some() {
+                    ops.extend(state.stroke.iter().cloned());
+                }
+                if style.opacity_fill.is_some() || style.opacity_stroke.is_some() {
+                    let name =
+                        self.add_ext_gstate(resource_owner, state.fill_alpha, state.stroke_alpha)?;
+                    ops.push(ContentOp::new("gs", vec![Object::Name(name)]));
+                }
+                vec![(show.span.clone(), encode_inline(&ops))]
+            }
+            ObjectKind::Image | ObjectKind::Path => {
+                let mut prefix = vec![ContentOp::new("q", vec![])];
+                prefix.extend(setters);
+                wrap(&content.ops[record.ops.clone()], &prefix)
+            }
+        };
+        self.splice(owner, content, splices)
+    }
+
+    fn delete(&mut self, target: &PatchTarget) -> Result<(), PatchError> {
+        let (extraction, record, owner) = self.locate(target)?;
+        let content = &extraction.contents[&record.owner];
+        self.splice(owner, content, removal_splices(&record, content))?;
+
+        if record.kind == ObjectKind::Image {
+            self.release_xobject(&extraction, &record, owner)?;
+        }
+        Ok(())
+    }
+
+    /// Move `target` among the objects painted by the same content. The
+    /// object is taken out and drawn again at its new place inside `q`/`Q`,
+    /// together with the state it was painted in.
+    fn reorder(
+        &mut self,
+        target: &PatchTarget,
+        direction: ReorderDirection,
+    ) -> Result<(), PatchError> {
+        let (extraction, record, owner) = self.locate(target)?;
+        let content = &extraction.contents[&record.owner];
+        let siblings: Vec<&ObjectRecord> = extraction
+            .records
+            .iter()
+            .filter(|other| other.owner == record.owner && other.forms == record.forms)
+            .collect();
+        let position = siblings
+            .iter()
+            .position(|other| other.id == record.id)
+            .expect("the target is among its siblings");
+        let neighbour = match direction {
+            ReorderDirection::Forward => siblings.get(position + 1),
+            ReorderDirection::Backward => position.checked_sub(1).map(|index| &siblings[index]),
+            ReorderDirection::Front => siblings.last().filter(|_| position + 1 < siblings.len()),
+            ReorderDirection::Back => siblings.first().filter(|_| position > 0),
+        };
+        let Some(neighbour) = neighbour else {
+            // Already at that end.
+            return Ok(());
+        };
+        let blocked = || PatchError::ReorderBlocked {
+            id: record.id.clone(),
+        };
+        let after = matches!(
+            direction,
+            ReorderDirection::Forward | ReorderDirection::Front
+        );
+        let slot = insertion_slot(&siblings, content, neighbour, after).ok_or_else(blocked)?;
+
+        let from = painted_state(&record, content);
+        let at = replay::state_at(&content.ops, slot.index);
+        let mut block = vec![ContentOp::new("q", vec![])];
+        block.extend(replay::resets(&at, &from).ok_or_else(blocked)?);
+        if at
+            .ops
+            .iter()
+            .any(|op| op.operator == "gs" && !from.contains(op))
+        {
+            let name = self.add_ext_gstate(
+                owner_of(&extraction, &record, owner),
+                record.state.fill_alpha,
+                record.state.stroke_alpha,
+            )?;
+            block.push(ContentOp::new("gs", vec![Object::Name(name)]));
+        }
+        block.extend(from.ops);
+        block.extend(object_ops(&record, content));
+        block.push(ContentOp::new("Q", vec![]));
+
+        let mut inserted = b"\n".to_vec();
+        match slot.resume {
+            // The slot is inside a BT block: close it around the object
+            // and reopen it where its text was.
+            Some(resume) => {
+                inserted.extend_from_slice(b"ET\n");
+                inserted.extend(encode_ops(&block));
+                inserted.extend_from_slice(b"BT\n");
+                inserted.extend(encode_ops(&resume));
+            }
+            None => inserted.extend(encode_ops(&block)),
+        }
+        let at_byte = content
+            .ops
+            .get(slot.index)
+            .map_or(content.data.len(), |op| op.span.start);
+        let mut splices = removal_splices(&record, content);
+        splices.push((at_byte..at_byte, inserted));
+        self.splice(owner, content, splices)
+    }
+
+    /// Draw a copy of `target` on `page`, moved by `offset` in page space.
+    /// The copy goes into a new content stream with the state the target
+    /// was painted in, and the resources it names are merged into the
+    /// page's. Returns the copy's id.
+    fn duplicate(
+        &mut self,
+        target: &PatchTarget,
+        page: usize,
+        offset: Matrix2D,
+    ) -> Result<String, PatchError> {
+        let (extraction, record) = self.find(target)?;
+        let page_id = self.extract(page)?.page_id;
+        let content = &extraction.contents[&record.owner];
+        let from = painted_state(&record, content);
+        // The CTM where the target's content starts, so that replaying
+        // `from` lands on the target's own CTM.
+        let start_ctm = record
+            .state
+            .ctm
+            .multiply(from.ctm.invert().ok_or(PatchError::SingularMatrix)?);
+
+        let state = &record.state;
+        let mut ops = vec![
+            ContentOp::new("q", vec![]),
+            ContentOp::new("cm", matrix_operands(offset.multiply(start_ctm).to_array())),
+        ];
+        // Parameters set outside the target's content, by the page around a
+        // form, are taken from the extracted state.
+        for setter in state.fill.iter().chain(&state.stroke) {
+            if !from.sets(&setter.operator) {
+                ops.push(setter.clone());
+            }
+        }
+        if record.kind == ObjectKind::Text && !from.sets("Tf") {
+            let font = Object::Name(state.text.font_name.clone());
+            ops.push(ContentOp::new("Tf", vec![font, real(state.text.size)]));
+        }
+        let sets_alpha = from.ops.iter().any(|op| op.operator == "gs");
+        ops.extend(from.ops);
+        ops.extend(object_ops(&record, content));
+        ops.push(ContentOp::new("Q", vec![]));
+
+        let source = owner_of(&extraction, &record, record.owner);
+        let destination = ResourceOwner::Page(page_id);
+        if source != destination {
+            self.merge_resources(source, extraction.page_id, destination, &mut ops)?;
+        }
+        if !sets_alpha && (state.fill_alpha != 1.0 || state.stroke_alpha != 1.0) {
+            let name = self.add_ext_gstate(destination, state.fill_alpha, state.stroke_alpha)?;
+            ops.insert(2, ContentOp::new("gs", vec![Object::Name(name)]));
+        }
+        self.append_content(page_id, &encode_ops(&ops))?;
+        self.inserted_id(page, record.kind)
+    }
+
+    /// Give every resource `ops` name in `source` a name in `destination`
+    /// and rename the operands to match. A resource `destination` already
+    /// has keeps its name there; others keep theirs unless it is taken.
+    /// Names missing from a form's resources are looked up in its page's.
+    fn merge_resources(
+        &mut self,
+        source: ResourceOwner,
+        source_page: ObjectId,
+        destination: ResourceOwner,
+        ops: &mut [ContentOp],
+    ) -> Result<(), PatchError> {
+        let own = self.owner_resources(source)?;
+        let page = resources::page_resources(&self.doc, source_page).cloned();
+        let mut renamed: HashMap<(&[u8], Vec<u8>), Vec<u8>> = HashMap::new();
+        for op in ops.iter_mut() {
+            let Some((category, operand)) = resource_operand(op) else {
+                continue;
+            };
+            let Object::Name(name) = &op.operands[operand] else {
+                continue;
+            };
+            let key = (category, name.clone());
+            if let Some(new_name) = renamed.get(&key) {
+                op.operands[operand] = Object::Name(new_name.clone());
+                continue;
+            }
+            let found = [Some(&own), page.as_ref()]
+                .into_iter()
+                .find_map(|resources| {
+                    resources::lookup(&self.doc, resources, category, name)
+                        .map(|(id, object)| id.map_or_else(|| object.clone(), Object::Reference))
+                });
+            // Device colour spaces and the like are not resources.
+            let Some(object) = found else {
+                continue;
+            };
+            let new_name = self.merge_resource(destination, category, name, object)?;
+            op.operands[operand] = Object::Name(new_name.clone());
+            renamed.insert(key, new_name);
+        }
+        Ok(())
+    }
+
+    /// Register `object` in the owner's resources under the name it already
+    /// has there, under `name` if that is free, or under a fresh name.
+    fn merge_resource(
+        &mut self,
+        owner: ResourceOwner,
+        category: &[u8],
+        name: &[u8],
+        object: Object,
+    ) -> Result<Vec<u8>, PatchError> {
+        let mut resources = self.owner_resources(owner)?;
+        let mut entries = resources::get_dict(&self.doc, &resources, category)
+            .cloned()
+            .unwrap_or_default();
+        if let Some((existing, _)) = entries.iter().find(|(_, value)| **value == object) {
+            return Ok(existing.clone());
+        }
+        if entries.has(name) {
+            let prefix = String::from_utf8_lossy(name);
+            let prefix = prefix.trim_end_matches(|c: char| c.is_ascii_digit());
+            return self.add_resource(owner, category, prefix, object);
+        }
+        entries.set(name.to_vec(), object);
+        resources.set(category.to_vec(), entries);
+        self.set_owner_resources(owner, resources)?;
+        Ok(name.to_vec())
+    }
+
+    /// Drop the XObject `record` draws from its owner's resources when no
+    /// other `Do` on the page uses the name.
+    fn release_xobject(
+        &mut self,
+        extraction: &PageExtraction,
+        record: &ObjectRecord,
+        owner: ObjectId,
+    ) -> Result<(), PatchError> {
+        let paint = &extraction.contents[&record.owner].ops[record.paint_op];
+        let name = paint.name(0).unwrap_or_default();
+        let still_drawn = extraction.contents.iter().any(|(&id, content)| {
+            content.ops.iter().enumerate().any(|(index, op)| {
+                op.operator == "Do"
+                    && op.name(0) == Some(name)
+                    && (id, index) != (record.owner, record.paint_op)
+            })
+        });
+        if !still_drawn {
+            self.remove_resource(owner_of(extraction, record, owner), b"XObject", name)?;
+        }
+        Ok(())
+    }
+
+    /// Swap the image `target` draws for `source`, keeping its placement.
+    /// With `all_placements` the XObject itself is replaced, so every page
+    /// and form drawing it changes; otherwise this `Do` alone is pointed at
+    /// a new XObject.
+    fn replace_image(
+        &mut self,
+        target: &PatchTarget,
+        source: &ImageSource,
+        fit: ImageFit,
+        all_placements: bool,
+    ) -> Result<(), PatchError> {
+        let (extraction, record, owner) = self.locate(target)?;
+        if record.kind != ObjectKind::Image {
+            return Err(PatchError::NotImage {
+                id: record.id,
+                kind: record.kind.as_str(),
+            });
+        }
+        let xobject = self.image_xobject(source)?;
+        let aspect = xobject.width as f64 / xobject.height as f64;
+        let resource_owner = owner_of(&extraction, &record, owner);
+        let paint = &extraction.contents[&record.owner].ops[record.paint_op];
+        let old = resources::get_dict(
+            &self.doc,
+            &self.owner_resources(resource_owner)?,
+            b"XObject",
+        )
+        .and_then(|xobjects| xobjects.get(paint.name(0).unwrap_or_default()).ok())
+        .and_then(|object| object.as_reference().ok());
+
+        if let (true, Some(old)) = (all_placements, old) {
+            self.doc.objects.insert(old, Object::Stream(xobject.image));
+            self.changed.insert(old);
+            return self.update_placements(old, fit, aspect);
+        }
+
+        let image = self.doc.add_object(xobject.image);
+        self.changed.insert(image);
+        let name = self.add_resource(resource_owner, b"XObject", "Im", image.into())?;
+        let draw = ContentOp::new("Do", vec![Object::Name(name)]);
+        let ops = placement_ops(record.state.ctm, fit, aspect, draw)?;
+        let content = &extraction.contents[&record.owner];
+        self.splice(
+            owner,
+            content,
+            vec![(paint.span.clone(), encode_inline(&ops))],
+        )?;
+        self.release_xobject(&extraction, &record, owner)
+    }
+
+    /// Note every page drawing the image `xobject` as touched and, unless
+    /// it is stretched, wrap each `Do` so the new image fits or fills the
+    /// box the old one occupied.
+    fn update_placements(
+        &mut self,
+        xobject: ObjectId,
+        fit: ImageFit,
+        aspect: f64,
+    ) -> Result<(), PatchError> {
+        let reference = PdfRef {
+            obj: xobject.0,
+            gen: xobject.1,
+        };
+        // A form's content is fitted once, for its first placement.
+        let mut fitted_forms = HashSet::new();
+        for page in 0..resources::page_ids(&self.doc).len() {
+            let extraction = self.extract(page)?;
+            let mut splices: BTreeMap<ObjectId, Vec<Splice>> = BTreeMap::new();
+            let placements = extraction
+                .ir
+                .objects
+                .iter()
+                .filter_map(|object| match object {
+                    PageObject::Image(image) if image.pdf_ref == reference => {
+                        extraction.record(&image.id)
+                    }
+                    _ => None,
+                });
+            for record in placements {
+                self.touched_pages.insert(page);
+                if fit == ImageFit::Stretch || fitted_forms.contains(&record.owner) {
+                    continue;
+                }
+                let paint = &extraction.contents[&record.owner].ops[record.paint_op];
+                let ops = placement_ops(record.state.ctm, fit, aspect, paint.clone())?;
+                splices
+                    .entry(record.owner)
+                    .or_default()
+                    .push((paint.span.clone(), encode_inline(&ops)));
+            }
+            for (owner, splices) in splices {
+                if owner != extraction.page_id {
+                    fitted_forms.insert(owner);
+                }
+                self.splice(owner, &extraction.contents[&owner], splices)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw `text` shaped with `family` at `matrix` in a new content stream
+    /// at the end of the page. Returns the new object's id.
+    fn insert_text(
+        &mut self,
+        page: usize,
+        matrix: Matrix2D,
+        text: &str,
+        family: &str,
+        size: f64,
+        [r, g, b]: [f64; 3],
+    ) -> Result<String, PatchError> {
+        let page_id = self.extract(page)?.page_id;
+        if text.is_empty() {
+            return Err(PatchError::EmptyText);
+        }
+        let face = self
+            .context
+            .fonts
+            .find(family)
+            .ok_or_else(|| PatchError::FontNotFound(family.to_string()))?;
+        let glyphs = shape::shape_text(&face.data, face.index, text)?;
+        if let Some(missing) = glyphs.iter().find(|glyph| glyph.gid == 0) {
+            let ch = text[missing.cluster..].chars().next().unwrap_or_default();
+            return Err(PatchError::GlyphMissing {
+                font: face.family,
+                ch,
+            });
+        }
+        let font = embed::embed_font(&mut self.doc, &face, &glyphs, text)?;
+        self.changed.extend(font_objects(&self.doc, font.id));
+        let name = self.add_resource(ResourceOwner::Page(page_id), b"Font", "F", font.id.into())?;
+
+        let ops = [
+            ContentOp::new("q", vec![]),
+            ContentOp::new("rg", vec![real(r), real(g), real(b)]),
+            ContentOp::new("BT", vec![]),
+            ContentOp::new("Tf", vec![Object::Name(name), real(size)]),
+            ContentOp::new("Tm", matrix_operands(matrix.to_array())),
+            ContentOp::new(
+                "TJ",
+                vec![Object::Array(shown_glyphs(&glyphs, &font.widths))],
+            ),
+            ContentOp::new("ET", vec![]),
+            ContentOp::new("Q", vec![]),
+        ];
+        self.append_content(page_id, &encode_ops(&ops))?;
+        self.inserted_id(page, ObjectKind::Text)
+    }
+
+    /// Add an Image XObject for `source` to the page and draw it through
+    /// `matrix`. Returns the new object's id.
+    fn insert_image(
+        &mut self,
+        page: usize,
+        source: &ImageSource,
+        matrix: Matrix2D,
+    ) -> Result<String, PatchError> {
+        let page_id = self.extract(page)?.page_id;
+        let xobject = self.image_xobject(source)?;
+        let image = self.doc.add_object(xobject.image);
+        self.changed.insert(image);
+        let name =
+            self.add_resource(ResourceOwner::Page(page_id), b"XObject", "Im", image.into())?;
+
+        let ops = [
+            ContentOp::new("q", vec![]),
+            ContentOp::new("cm", matrix_operands(matrix.to_array())),
+            ContentOp::new("Do", vec![Object::Name(name)]),
+            ContentOp::new("Q", vec![]),
+        ];
+        self.append_content(page_id, &encode_ops(&ops))?;
+
+        self.inserted_id(page, ObjectKind::Image)
+    }
+
+    /// Build the Image XObject for `source`. Its soft mask, if any, is
+    /// added to the document; the image itself is left to the caller.
+    fn image_xobject(&mut self, source: &ImageSource) -> Result<ImageXObject, PatchError> {
+        let inline;
+        let data = match source {
+            ImageSource::Upload { upload_id } => self
+                .context
+                .uploads
+                .get(upload_id)
+                .ok_or_else(|| PatchError::UnknownUpload(upload_id.clone()))?,
+            ImageSource::Inline { data } => {
+                let encoded = match data.split_once(";base64,") {
+                    Some((prefix, rest)) if prefix.starts_with("data:") => rest,
+                    _ => data,
+                };
+                inline = BASE64
+                    .decode(encoded.trim())
+                    .map_err(|_| PatchError::InvalidImageData)?;
+                &inline
+            }
+        };
+        let mut xobject =
+            images::image_xobject(data, self.context.limits.max_decoded_stream_bytes)?;
+        if let Some(smask) = xobject.smask.take() {
+            let smask = self.doc.add_object(smask);
+            self.changed.insert(smask);
+            xobject.image.dict.set("SMask", smask);
+        }
+        Ok(xobject)
+    }
+
+    /// Draw `segments` on the page as one new path object. Returns its id.
+    fn insert_path(
+        &mut self,
+        page: usize,
+        segments: &[PathSegment],
+        style: &PathStyle,
+    ) -> Result<String, PatchError> {
+        let page_id = self.extract(page)?.page_id;
+        let ops = shapes::path_ops(segments, style)?;
+        self.append_content(page_id, &encode_ops(&ops))?;
+        self.inserted_id(page, ObjectKind::Path)
+    }
+
+    /// The id of the object an insert just appended to `page`.
+    fn inserted_id(&self, page: usize, kind: ObjectKind) -> Result<String, PatchError> {
+        let extraction = self.extract(page)?;
+        let record = extraction
+            .records
+            .last()
+            .filter(|record| record.kind == kind)
+            .context("inserted object was not found on the page")?;
+        Ok(record.id.clone())
+    }
+
+    /// Add `data` as a new content stream after the page's others. When the
+    /// existing content leaves the graphics state changed, it is wrapped in
+    /// `q`/`Q` first so the new stream starts from the default state.
+    fn append_content(&mut self, page: ObjectId, data: &[u8]) -> Result<(), PatchError> {
+        let current = extract::load_content(&self.doc, page, self.context.limits)?;
+        let mut contents: Vec<Object> = self
+            .doc
+            .get_page_contents(page)
+            .into_iter()
+            .map(Object::Reference)
+            .collect();
+        let mut data = data.to_vec();
+        if leaves_state_changed(&current.ops) {
+            let opening = self.new_content_stream(b"q")?;
+            contents.insert(0, opening.into());
+            data.splice(0..0, b"Q\n".iter().copied());
+        }
+        let stream = self.new_content_stream(&data)?;
+        contents.push(stream.into());
+        self.doc
+            .get_dictionary_mut(page)
+            .map_err(anyhow::Error::from)?
+            .set("Contents", contents);
+        self.changed.insert(page);
+        Ok(())
+    }
+
+    fn new_content_stream(&mut self, data: &[u8]) -> Result<ObjectId, PatchError> {
+        let mut stream = lopdf::Stream::new(Dictionary::new(), Vec::new());
+        filters::set_flate_content(&mut stream, data);
+        let id = self.doc.add_object(stream);
+        self.changed.insert(id);
+        Ok(id)
+    }
+
+    /// Copy every form on the way to `record` that other pages or other
+    /// invocations on this page also draw, and point this invocation at
+    /// the copy. Returns the page or form that now holds the record.
+    fn unshare_forms(
+        &mut self,
+        extraction: &PageExtraction,
+        record: &ObjectRecord,
+    ) -> Result<ObjectId, PatchError> {
+        let mut parent_form = None;
+        let mut parent_owner = ResourceOwner::Page(extraction.page_id);
+        let mut copying = false;
+        for form_use in &record.forms {
+            let parent = parent_form.unwrap_or(form_use.parent);
+            copying = copying
+                || extraction
+                    .form_invocations
+                    .get(&form_use.form)
+                    .copied()
+                    .unwrap_or(0)
+                    > 1
+                || pages_using(&self.doc, form_use.form) > 1;
+            let form = if copying {
+                let original = self
+                    .doc
+                    .get_object(form_use.form)
+                    .map_err(anyhow::Error::from)?;
+                let copy = self.doc.add_object(original.clone());
+                self.changed.insert(copy);
+                let name = self.add_resource(parent_owner, b"XObject", "Fm", copy.into())?;
+                let content = extract::load_content(&self.doc, parent, self.context.limits)?;
+                let invocation = &content.ops[form_use.do_op];
+                let replacement = ContentOp::new("Do", vec![Object::Name(name)]);
+                self.splice(
+                    parent,
+                    &content,
+                    vec![(invocation.span.clone(), replacement.encode())],
+                )?;
+                tracing::debug!(form = form_use.form.0, copy = copy.0, "copied shared form");
+                copy
+            } else {
+                form_use.form
+            };
+            parent_form = Some(form);
+            parent_owner = ResourceOwner::Form(form);
+        }
+        Ok(parent_form.unwrap_or(record.owner))
+    }
+
+    /// Register `object` under a fresh name in the owner's resources.
+    fn add_resource(
+        &mut self,
+        owner: ResourceOwner,
+        category: &[u8],
+        prefix: &str,
+        object: Object,
+    ) -> Result<Vec<u8>, PatchError> {
+        let mut resources = self.owner_resources(owner)?;
+        let mut entries = resources::get_dict(&self.doc, &resources, category)
+            .cloned()
+            .unwrap_or_default();
+        let name = (1..)
+            .map(|n| format!("{prefix}{n}").into_bytes())
+            .find(|name| !entries.has(name))
+            .expect("unbounded name search");
+        entries.set(name.clone(), object);
+        resources.set(category.to_vec(), entries);
+        self.set_owner_resources(owner, resources)?;
+        Ok(name)
+    }
+
+    /// Drop `name` from the owner's own resources. Names the owner inherits
+    /// from elsewhere are left alone.
+    fn remove_resource(
+        &mut self,
+        owner: ResourceOwner,
+        category: &[u8],
+        name: &[u8],
+    ) -> Result<(), PatchError> {
+        let mut resources = self.owner_resources(owner)?;
+        let Some(mut entries) = resources::get_dict(&self.doc, &resources, category).cloned()
+        else {
+            return Ok(());
+        };
+        if entries.remove(name).is_none() {
+            return Ok(());
+        }
+        resources.set(category.to_vec(), entries);
+        self.set_owner_resources(owner, resources)?;
+        tracing::debug!(name = %String::from_utf8_lossy(name), "released unused resource");
+        Ok(())
+    }
+
+    /// A copy of the resource dictionary the owner names itself, or an
+    /// empty one.
+    fn owner_resources(&self, owner: ResourceOwner) -> Result<Dictionary, PatchError> {
+        let resources = match owner {
+            ResourceOwner::Page(page) => resources::page_resources(&self.doc, page).cloned(),
+            ResourceOwner::Form(form) => {
+                let dict = &self
+                    .doc
+                    .get_object(form)
+                    .and_then(Object::as_stream)
+                    .map_err(anyhow::Error::from)?
+                    .dict;
+                resources::get_dict(&self.doc, dict, b"Resources").cloned()
+            }
+        }
+        .unwrap_or_default();
+        Ok(resources)
+    }
+
+    /// Store `resources` directly in the owner, so pages or forms sharing
+    /// its previous dictionary do not see the change.
+    fn set_owner_resources(
+        &mut self,
+        owner: ResourceOwner,
+        resources: Dictionary,
+    ) -> Result<(), PatchError> {
+        let (id, dict) = match owner {
+            ResourceOwner::Page(page) => (page, self.doc.get_dictionary_mut(page)),
+            ResourceOwner::Form(form) => (
+                form,
+                self.doc
+                    .get_object_mut(form)
+                    .and_then(Object::as_stream_mut)
+                    .map(|stream| &mut stream.dict),
+            ),
+        };
+        dict.map_err(anyhow::Error::from)?
+            .set("Resources", resources);
+        self.changed.insert(id);
+        Ok(())
+    }
+
+    fn add_ext_gstate(
+        &mut self,
+        owner: ResourceOwner,
+        fill_alpha: f64,
+        stroke_alpha: f64,
+    ) -> Result<Vec<u8>, PatchError> {
+        let id = self.doc.add_object(dictionary! {
+            "Type" => "ExtGState",
+            "ca" => real(fill_alpha),
+            "CA" => real(stroke_alpha),
+        });
+        self.changed.insert(id);
+        self.add_resource(owner, b"ExtGState", "GS", id.into())
+    }
+
+    /// Apply `splices` to `content`, the current content of the page or
+    /// form `owner`, and store every underlying stream that changed
+    /// Flate-compressed. Page streams shared with other pages are copied
+    /// first.
+    fn splice(
+        &mut self,
+        owner: ObjectId,
+        content: &StreamContent,
+        mut splices: Vec<Splice>,
+    ) -> Result<(), PatchError> {
+        splices.sort_by_key(|(range, _)| range.start);
+        let mut data = content.data.clone();
+        for (range, replacement) in splices.iter().rev() {
+            data.splice(range.clone(), replacement.iter().copied());
+        }
+
+        let is_form = matches!(self.doc.get_object(owner), Ok(Object::Stream(_)));
+        for (index, segment) in content.segments.iter().enumerate() {
+            let start = shift(segment.range.start, &splices, false);
+            let end = shift(segment.range.end, &splices, true).max(start);
+            if data[start..end] == content.data[segment.range.clone()] {
+                continue;
+            }
+            let target = if is_form {
+                owner
+            } else {
+                self.private_content_stream(owner, index, segment.id)?
+            };
+            let stream = self
+                .doc
+                .get_object_mut(target)
+                .and_then(Object::as_stream_mut)
+                .map_err(anyhow::Error::from)?;
+            filters::set_flate_content(stream, &data[start..end]);
+            self.changed.insert(target);
+        }
+        Ok(())
+    }
+
+    /// The `index`th content stream of `page`, copied and swapped into the
+    /// page's `/Contents` if any other page, or another slot of this one,
+    /// also draws it.
+    fn private_content_stream(
+        &mut self,
+        page: ObjectId,
+        index: usize,
+        id: ObjectId,
+    ) -> Result<ObjectId, PatchError> {
+        let uses: usize = resources::page_ids(&self.doc)
+            .into_iter()
+            .map(|other| {
+                let contents = self.doc.get_page_contents(other);
+                contents.into_iter().filter(|stream| *stream == id).count()
+            })
+            .sum();
+        if uses <= 1 {
+            return Ok(id);
+        }
+        let original = self.doc.get_object(id).map_err(anyhow::Error::from)?;
+        let copy = self.doc.add_object(original.clone());
+        let mut contents: Vec<Object> = self
+            .doc
+            .get_page_contents(page)
+            .into_iter()
+            .map(Object::Reference)
+            .collect();
+        contents[index] = copy.into();
+        self.doc
+            .get_dictionary_mut(page)
+            .map_err(anyhow::Error::from)?
+            .set("Contents", contents);
+        self.changed.insert(page);
+        tracing::debug!(stream = id.0, copy = copy.0, "copied shared content stream");
+        Ok(copy)
+    }
+}
+
+/// Check what can be checked about `op` without the document: matrices
+/// are invertible, colours and opacities lie in 0..1, kinds are known.
+fn validate(op: &PatchOperation) -> Result<(), PatchError> {
+    match op {
+        PatchOperation::Transform {
+            delta_matrix_pt,
+            kind,
+            ..
+        } => {
+            if !["text", "image", "path"].contains(&kind.as_str()) {
+                return Err(PatchError::UnknownKind(kind.clone()));
+            }
+            check_matrix("deltaMatrixPt", delta_matrix_pt)
+        }
+        PatchOperation::SetStyle { style, .. } => {
+            check_unit("fillColor", style.fill_color.iter().flatten())?;
+            check_unit("strokeColor", style.stroke_color.iter().flatten())?;
+            check_unit("opacityFill", &style.opacity_fill)?;
+            check_unit("opacityStroke", &style.opacity_stroke)
+        }
+        PatchOperation::Duplicate {
+            offset_matrix_pt, ..
+        } => check_matrix("offsetMatrixPt", offset_matrix_pt),
+        PatchOperation::InsertText {
+            matrix_pt, color, ..
+        } => {
+            check_matrix("matrixPt", matrix_pt)?;
+            check_unit("color", color.iter().flatten())
+        }
+        PatchOperation::InsertImage { matrix_pt, .. } => check_matrix("matrixPt", matrix_pt),
+        PatchOperation::InsertPath { style, .. } => {
+            check_unit("fillColor", style.fill_color.iter().flatten())?;
+            check_unit("strokeColor", style.stroke_color.iter().flatten())
+        }
+        PatchOperation::EditText { .. }
+        | PatchOperation::Delete { .. }
+        | PatchOperation::Reorder { .. }
+        | PatchOperation::ReplaceImage { .. } => Ok(()),
+    }
+}
+
+fn check_matrix(field: &'static str, values: &[f64; 6]) -> Result<(), PatchError> {
+    let invertible = values.iter().all(|value| value.is_finite())
+        && Matrix2D::from_array(*values).invert().is_some();
+    if invertible {
+        Ok(())
+    } else {
+        Err(PatchError::InvalidMatrix { field })
+    }
+}
+
+fn check_unit<'a>(
+    field: &'static str,
+    values: impl IntoIterator<Item = &'a f64>,
+) -> Result<(), PatchError> {
+    match values
+        .into_iter()
+        .find(|value| !(0.0..=1.0).contains(*value))
+    {
+        Some(&value) => Err(PatchError::OutOfRange { field, value }),
+        None => Ok(()),
+    }
+}
+
+pub fn target_of(op: &PatchOperation) -> Option<&PatchTarget> {
+    match op {
+        PatchOperation::Transform { target, .. }
+        | PatchOperation::EditText { target, .. }
+        | PatchOperation::SetStyle { target, .. }
+        | PatchOperation::Delete { target }
+        | PatchOperation::Reorder { target, .. }
+        | PatchOperation::Duplicate { target, .. }
+        | PatchOperation::ReplaceImage { target, .. } => Some(target),
+        PatchOperation::InsertText { .. }
+        | PatchOperation::InsertImage { .. }
+        | PatchOperation::InsertPath { .. } => None,
+    }
+}
+
+fn page_of(op: &PatchOperation) -> usize {
+    match op {
+        PatchOperation::Transform { target, .. }
+        | PatchOperation::EditText { target, .. }
+        | PatchOperation::SetStyle { target, .. }
+        | PatchOperation::Delete { target }
+        | PatchOperation::Reorder { target, .. }
+        | PatchOperation::ReplaceImage { target, .. } => target.page,
+        PatchOperation::InsertText { page, .. }
+        | PatchOperation::InsertImage { page, .. }
+        | PatchOperation::InsertPath { page, .. } => *page,
+        PatchOperation::Duplicate {
+            target, to_page, ..
+        } => to_page.unwrap_or(target.page),
+    }
+}
+
+fn owner_of(extraction: &PageExtraction, record: &ObjectRecord, owner: ObjectId) -> ResourceOwner {
+    if record.forms.is_empty() {
+        ResourceOwner::Page(extraction.page_id)
+    } else {
+        ResourceOwner::Form(owner)
+    }
+}
+
+/// Where byte `position` of the content ends up once `splices` (sorted,
+/// non-overlapping) are applied. Text inserted exactly at `position` counts
+/// as before it when `after_insertions` is set, so a segment's end keeps
+/// insertions at its end and its start keeps insertions at its start.
+/// Positions inside a replaced range move to the end of the replacement.
+fn shift(position: usize, splices: &[Splice], after_insertions: bool) -> usize {
+    let mut shifted = position as isize;
+    for (range, replacement) in splices {
+        let delta = replacement.len() as isize - range.len() as isize;
+        if range.end < position
+            || (range.end == position && (!range.is_empty() || after_insertions))
+        {
+            shifted += delta;
+        } else if range.start < position {
+            return (shifted + (range.start + replacement.len()) as isize - position as isize)
+                as usize;
+        } else {
+            break;
+        }
+    }
+    shifted as usize
+}
+
+/// Number of pages whose resources reach `form`, directly or through other
+/// forms.
+fn pages_using(doc: &Document, form: ObjectId) -> usize {
+    resources::page_ids(doc)
+        .into_iter()
+        .filter(|&page| {
+            let mut seen = HashSet::new();
+            let mut pending: Vec<&Dictionary> =
+                resources::page_resources(doc, page).into_iter().collect();
+            while let Some(res) = pending.pop() {
+                let Some(xobjects) = resources::get_dict(doc, res, b"XObject") else {
+                    continue;
+                };
+                for (_, value) in xobjects.iter() {
+                    let Ok(id) = value.as_reference() else {
+                        continue;
+                    };
+                    if id == form {
+                        return true;
+                    }
+                    if !seen.insert(id) {
+                        continue;
+                    }
+                    let nested = doc
+                        .get_object(id)
+                        .and_then(Object::as_stream)
+                        .ok()
+                        .and_then(|stream| resources::get_dict(doc, &stream.dict, b"Resources"));
+                    pending.extend(nested);
+                }
+            }
+            false
+        })
+        .count()
+}
+
+/// The show operator as `Tj`/`TJ`, with the line move and spacing of `'`
+/// and `"` spelled out so operators can be placed right before the show.
+fn normalise_show(show: &ContentOp) -> Vec<ContentOp> {
+    let string = show.operands.last().cloned().unwrap_or(Object::Null);
+    match show.operator.as_str() {
+        "'" => vec![
+            ContentOp::new("T*", vec![]),
+            ContentOp::new("Tj", vec![string]),
+        ],
+        "\"" => vec![
+            ContentOp::new("Tw", vec![show.operands[0].clone()]),
+            ContentOp::new("Tc", vec![show.operands[1].clone()]),
+            ContentOp::new("T*", vec![]),
+            ContentOp::new("Tj", vec![string]),
+        ],
+        _ => vec![ContentOp::new(&show.operator, show.operands.clone())],
+    }
+}
+
+/// The resource category and operand index of the name `op` refers to,
+/// for operators that name a resource.
+fn resource_operand(op: &ContentOp) -> Option<(&'static [u8], usize)> {
+    Some(match op.operator.as_str() {
+        "Tf" => (b"Font", 0),
+        "Do" => (b"XObject", 0),
+        "gs" => (b"ExtGState", 0),
+        "cs" | "CS" => (b"ColorSpace", 0),
+        "sh" => (b"Shading", 0),
+        "scn" | "SCN" if matches!(op.operands.last(), Some(Object::Name(_))) => {
+            (b"Pattern", op.operands.len() - 1)
+        }
+        _ => return None,
+    })
+}
+
+/// The state `record` was painted in, as operators from the start of its
+/// content.
+fn painted_state(record: &ObjectRecord, content: &StreamContent) -> replay::ReplayState {
+    let end = match record.kind {
+        ObjectKind::Text => record.paint_op,
+        ObjectKind::Image | ObjectKind::Path => record.ops.start,
+    };
+    replay::state_at(&content.ops, end)
+}
+
+/// Operators that paint `record` by themselves once the state it was
+/// painted in is set up. Text gets its own BT block.
+fn object_ops(record: &ObjectRecord, content: &StreamContent) -> Vec<ContentOp> {
+    match record.kind {
+        ObjectKind::Text => {
+            let position = record.text.expect("text records carry positions");
+            let show = normalise_show(&content.ops[record.paint_op]);
+            let mut ops = vec![
+                ContentOp::new("BT", vec![]),
+                ContentOp::new("Tm", matrix_operands(position.tm.to_array())),
+            ];
+            ops.extend(show.into_iter().filter(|op| op.operator != "T*"));
+            ops.push(ContentOp::new("ET", vec![]));
+            ops
+        }
+        ObjectKind::Image | ObjectKind::Path => content.ops[record.ops.clone()].to_vec(),
+    }
+}
+
+/// Where an object moved next to another goes in its content.
+struct Slot {
+    /// Operation index to insert before.
+    index: usize,
+    /// Set when the slot is inside a BT block: what restores the text
+    /// position after the block is reopened.
+    resume: Option<Vec<ContentOp>>,
+}
+
+/// A slot right after `neighbour` or right before it, among `siblings`
+/// painted by `content`. Of the places between `neighbour` and the object
+/// on that side of it, the one nested in fewest `q`s is used, so the moved
+/// object does not land inside another one's clipping group. Places
+/// inside BT blocks are only used when there is no other.
+fn insertion_slot(
+    siblings: &[&ObjectRecord],
+    content: &StreamContent,
+    neighbour: &ObjectRecord,
+    after: bool,
+) -> Option<Slot> {
+    let ops = &content.ops;
+    let (mut depth, mut in_text) = (Vec::with_capacity(ops.len() + 1), Vec::new());
+    let (mut level, mut text) = (0usize, false);
+    for op in ops {
+        depth.push(level);
+        in_text.push(text);
+        match op.operator.as_str() {
+            "q" => level += 1,
+            "Q" => level = level.saturating_sub(1),
+            "BT" => text = true,
+            "ET" => text = false,
+            _ => {}
+        }
+    }
+    depth.push(level);
+    in_text.push(text);
+
+    // Everything that paints, so slots stay between objects.
+    let painted: Vec<Range<usize>> = siblings
+        .iter()
+        .map(|record| record.ops.clone())
+        .chain(
+            ops.iter()
+                .enumerate()
+                .filter(|(_, op)| matches!(op.operator.as_str(), "Do" | "BI" | "sh"))
+                .map(|(index, _)| index..index + 1),
+        )
+        .collect();
+    let gap = if after {
+        let start = neighbour.paint_op + 1;
+        let end = painted
+            .iter()
+            .map(|range| range.start)
+            .filter(|&index| index >= start)
+            .min()
+            .unwrap_or(ops.len());
+        start..end + 1
+    } else {
+        let end = neighbour.ops.start;
+        let start = painted
+            .iter()
+            .map(|range| range.end)
+            .filter(|&index| index <= end)
+            .max()
+            .unwrap_or(0);
+        start..end + 1
+    };
+
+    let candidates = gap.clone().filter(|&index| !in_text[index]);
+    let best = if after {
+        candidates.min_by_key(|&index| depth[index])
+    } else {
+        candidates.rev().min_by_key(|&index| depth[index])
+    };
+    if let Some(index) = best {
+        return Some(Slot {
+            index,
+            resume: None,
+        });
+    }
+    // Split the BT block right after the text before the gap.
+    let previous = siblings
+        .iter()
+        .find(|record| record.paint_op + 1 == gap.start)?;
+    previous.text?;
+    Some(Slot {
+        index: gap.start,
+        resume: Some(restore_text_position(previous, ops)),
+    })
+}
+
+/// Splices that take `record` out of `content` while leaving the state
+/// it changed in place for what follows.
+fn removal_splices(record: &ObjectRecord, content: &StreamContent) -> Vec<Splice> {
+    let paint = &content.ops[record.paint_op];
+    match record.kind {
+        ObjectKind::Text => {
+            // The show goes, but its effect on the text state stays so
+            // later runs in the same BT keep their place.
+            let mut ops = Vec::new();
+            if paint.operator == "\"" {
+                ops.push(ContentOp::new("Tw", vec![paint.operands[0].clone()]));
+                ops.push(ContentOp::new("Tc", vec![paint.operands[1].clone()]));
+            }
+            ops.extend(restore_text_position(record, &content.ops));
+            vec![(paint.span.clone(), encode_inline(&ops))]
+        }
+        ObjectKind::Image => vec![(paint.span.clone(), Vec::new())],
+        // State operators that crept in between the path operators
+        // still apply to what follows, so only the path itself goes.
+        ObjectKind::Path => content.ops[record.ops.clone()]
+            .iter()
+            .filter(|op| PATH_OPERATORS.contains(&op.operator.as_str()))
+            .map(|op| (op.span.clone(), Vec::new()))
+            .collect(),
+    }
+}
+
+/// Operators that put the text and line matrices back to where the
+/// original show left them, or nothing when the next operator resets them.
+fn restore_text_position(record: &ObjectRecord, ops: &[ContentOp]) -> Vec<ContentOp> {
+    let next = ops.get(record.paint_op + 1).map(|op| op.operator.as_str());
+    if matches!(next, None | Some("ET" | "Tm" | "BT")) {
+        return Vec::new();
+    }
+    let position = record.text.expect("text records carry positions");
+    let mut restore = vec![ContentOp::new(
+        "Tm",
+        matrix_operands(position.tlm.to_array()),
+    )];
+    let text = &record.state.text;
+    let step = text.size * text.horizontal_scale;
+    let offset = position
+        .tlm
+        .invert()
+        .map(|inverse| inverse.multiply(position.tm_after));
+    match offset {
+        Some(offset) if offset.approx_eq(Matrix2D::identity()) => {}
+        // Moving along the line is what TJ adjustments do without
+        // touching the line matrix.
+        Some(offset) if step != 0.0 && offset.approx_eq(Matrix2D::translate(offset.e, 0.0)) => {
+            let adjustment = real(-offset.e * 1000.0 / step);
+            restore.push(ContentOp::new("TJ", vec![Object::Array(vec![adjustment])]));
+        }
+        _ => {
+            restore = vec![ContentOp::new(
+                "Tm",
+                matrix_operands(position.tm_after.to_array()),
+            )]
+        }
+    }
+    restore
+}
+
+/// Splices that surround `ops` with `prefix` and a closing `Q`.
+fn wrap(ops: &[ContentOp], prefix: &[ContentOp]) -> Vec<Splice> {
+    let (Some(first), Some(last)) = (ops.first(), ops.last()) else {
+        return Vec::new();
+    };
+    let mut opening = encode_inline(prefix);
+    opening.push(b' ');
+    vec![
+        (first.span.start..first.span.start, opening),
+        (last.span.end..last.span.end, b" Q".to_vec()),
+    ]
+}
+
+/// Whether `ops` end with a different CTM, graphics state or text state
+/// than they started with, as far as drawing appended text is concerned.
+fn leaves_state_changed(ops: &[ContentOp]) -> bool {
+    let mut depth = 0usize;
     for op in ops {
-        tracing::info!(?op, "received patch op");
+        match op.operator.as_str() {
+            "q" => depth += 1,
+            "Q" => match depth.checked_sub(1) {
+                Some(outer) => depth = outer,
+                None => return true,
+            },
+            operator if depth == 0 && LEAKING_OPERATORS.contains(&operator) => return true,
+            _ => {}
+        }
     }
-    let _ = ir; // suppress unused warning until real logic lands
-    Ok(())
+    depth != 0
+}
+
+/// `TJ` operands drawing `glyphs` as two-byte glyph ids, with adjustments
+/// wherever the shaped position differs from the font's advance by half a
+/// thousandth of an em or more.
+fn shown_glyphs(glyphs: &[ShapedGlyph], widths: &BTreeMap<u16, f64>) -> Vec<Object> {
+    let mut items = Vec::new();
+    let mut run = Vec::new();
+    let mut pending = 0.0;
+    for glyph in glyphs {
+        // TJ numbers move the pen left, in thousandths of an em.
+        let before = pending - glyph.x_offset;
+        if before.abs() >= 0.5 {
+            if !run.is_empty() {
+                items.push(Object::String(
+                    std::mem::take(&mut run),
+                    StringFormat::Hexadecimal,
+                ));
+            }
+            items.push(real(before));
+        }
+        run.extend_from_slice(&glyph.gid.to_be_bytes());
+        let width = widths.get(&glyph.gid).copied().unwrap_or(0.0);
+        pending = width + glyph.x_offset - glyph.x_advance;
+    }
+    if !run.is_empty() {
+        items.push(Object::String(run, StringFormat::Hexadecimal));
+    }
+    items
+}
+
+/// The objects making up an embedded font, reached from its dictionary.
+fn font_objects(doc: &Document, font: ObjectId) -> Vec<ObjectId> {
+    let mut found = Vec::new();
+    let mut pending = vec![font];
+    while let Some(id) = pending.pop() {
+        if found.contains(&id) {
+            continue;
+        }
+        found.push(id);
+        let dict = match doc.get_object(id) {
+            Ok(Object::Dictionary(dict)) => dict,
+            Ok(Object::Stream(stream)) => &stream.dict,
+            _ => continue,
+        };
+        let mut values: Vec<&Object> = dict.iter().map(|(_, value)| value).collect();
+        while let Some(value) = values.pop() {
+            match value {
+                Object::Reference(id) => pending.push(*id),
+                Object::Array(items) => values.extend(items),
+                Object::Dictionary(inner) => values.extend(inner.iter().map(|(_, v)| v)),
+                _ => {}
+            }
+        }
+    }
+    found
+}
+
+/// Operators drawing `draw`, an image `Do`, so that an image of the given
+/// width-to-height `aspect` takes the place of one drawn with `ctm`.
+fn placement_ops(
+    ctm: Matrix2D,
+    fit: ImageFit,
+    aspect: f64,
+    draw: ContentOp,
+) -> Result<Vec<ContentOp>, PatchError> {
+    if fit == ImageFit::Stretch {
+        return Ok(vec![draw]);
+    }
+    let [a, b, c, d, _, _] = ctm.to_array();
+    let (width, height) = (a.hypot(b), c.hypot(d));
+    if width == 0.0 || height == 0.0 || !aspect.is_finite() {
+        return Err(PatchError::SingularMatrix);
+    }
+    // Scale the unit square in one direction so the image keeps its
+    // proportions on the page, then centre it.
+    let ratio = aspect / (width / height);
+    let (sx, sy) = match (fit, ratio > 1.0) {
+        (ImageFit::Fit, true) | (ImageFit::Fill, false) => (1.0, 1.0 / ratio),
+        _ => (ratio, 1.0),
+    };
+    let mut ops = vec![ContentOp::new("q", vec![])];
+    if fit == ImageFit::Fill {
+        ops.push(ContentOp::new("re", [0, 0, 1, 1].map(Object::from).into()));
+        ops.push(ContentOp::new("W", vec![]));
+        ops.push(ContentOp::new("n", vec![]));
+    }
+    ops.push(ContentOp::new(
+        "cm",
+        matrix_operands([sx, 0.0, 0.0, sy, (1.0 - sx) / 2.0, (1.0 - sy) / 2.0]),
+    ));
+    ops.push(draw);
+    ops.push(ContentOp::new("Q", vec![]));
+    Ok(ops)
+}
+
+/// Encode operators for splicing into the middle of a stream.
+fn encode_inline(ops: &[ContentOp]) -> Vec<u8> {
+    let mut out = encode_ops(ops);
+    out.pop();
+    out
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pdf::testing::{test_font, test_jpeg, test_png, TestPdf};
+
+    fn target(page: usize, id: &str) -> PatchTarget {
+        PatchTarget {
+            page,
+            id: id.into(),
+        }
+    }
+
+    fn page_ops(doc: &Document, page: usize) -> Vec<String> {
+        let id = resources::page_ids(doc)[page];
+        extract::load_content(doc, id, &limits())
+            .unwrap()
+            .ops
+            .iter()
+            .map(|op| String::from_utf8_lossy(&op.encode()).into_owned())
+            .collect()
+    }
+
+    fn limits() -> LoadLimits {
+        LoadLimits::default()
+    }
+
+    fn apply(
+        doc: &Document,
+        pdf: &[u8],
+        ops: &[PatchOperation],
+    ) -> Result<PatchOutcome, PatchError> {
+        apply_with_uploads(doc, pdf, ops, &HashMap::new())
+    }
+
+    fn apply_with_uploads(
+        doc: &Document,
+        pdf: &[u8],
+        ops: &[PatchOperation],
+        uploads: &HashMap<String, Vec<u8>>,
+    ) -> Result<PatchOutcome, PatchError> {
+        let fonts = FontLibrary::from_fonts(vec![test_font()]);
+        let context = PatchContext {
+            limits: &limits(),
+            fonts: &fonts,
+            uploads,
+        };
+        apply_patches(doc, pdf, ops, &context).map_err(|err| match err {
+            BatchError::Rejected(mut failures) => failures.remove(0).error,
+            BatchError::Other(err) => PatchError::Other(err),
+        })
+    }
+
+    #[test]
+    fn transform_moves_text_and_keeps_the_original_bytes() {
+        let (pdf, doc) = TestPdf::sample();
+        let ops = vec![PatchOperation::Transform {
+            target: target(0, "t:0"),
+            delta_matrix_pt: [1.0, 0.0, 0.0, 1.0, 10.0, -20.0],
+            kind: "text".into(),
+        }];
+        let outcome = apply(&doc, &pdf, &ops).unwrap();
+
+        assert!(outcome.pdf.starts_with(&pdf));
+        assert_eq!(outcome.touched_pages, BTreeSet::from([0]));
+        let ir = extract::extract_ir(&outcome.document, &limits()).unwrap();
+        let PageObject::Text(text) = &ir.pages[0].objects[0] else {
+            panic!("expected text");
+        };
+        assert_eq!(text.tm, [1.0, 0.0, 0.0, 1.0, 82.0, 700.0]);
+        assert_eq!(text.unicode, "Hello world");
+    }
+
+    #[test]
+    fn outcome_document_matches_the_written_bytes() {
+        let (pdf, doc) = TestPdf::sample();
+        let ops = vec![PatchOperation::EditText {
+            target: target(0, "t:0"),
+            text: "Edited".into(),
+            font_pref: None,
+        }];
+        let outcome = apply(&doc, &pdf, &ops).unwrap();
+
+        let reparsed = crate::pdf::loader::parse_document(&outcome.pdf, &limits()).unwrap();
+        assert_eq!(outcome.document.xref_start, reparsed.xref_start);
+        assert_eq!(outcome.document.trailer, reparsed.trailer);
+        assert_eq!(page_ops(&outcome.document, 0), page_ops(&reparsed, 0));
+    }
 
     #[test]
-    fn apply_patches_is_noop_in_stub() {
-        let mut ir = DocumentIR::sample();
-        let original = ir.clone();
+    fn batches_that_grow_past_the_limits_are_rejected() {
+        let (pdf, doc) = TestPdf::sample();
+        let ops = vec![insert_text(0, "AB C", "Test Sans")];
+        let fonts = FontLibrary::from_fonts(vec![test_font()]);
+        let limits = LoadLimits {
+            max_objects: doc.objects.len(),
+            ..limits()
+        };
+        let context = PatchContext {
+            limits: &limits,
+            fonts: &fonts,
+            uploads: &HashMap::new(),
+        };
+        let Err(BatchError::Other(err)) = apply_patches(&doc, &pdf, &ops, &context) else {
+            panic!("expected the batch to fail");
+        };
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LoadError::TooManyObjects { .. })
+        ));
+    }
+
+    #[test]
+    fn edit_text_restores_the_position_of_following_runs() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        pdf.page(
+            b"BT /F1 10 Tf 100 700 Td (AB) Tj (C) Tj ET",
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![PatchOperation::EditText {
+            target: target(0, "t:0"),
+            text: "Wide text".into(),
+            font_pref: None,
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        let ir = extract::extract_ir(&outcome.document, &limits()).unwrap();
+        let texts: Vec<_> = ir.pages[0]
+            .objects
+            .iter()
+            .filter_map(|object| match object {
+                PageObject::Text(text) => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts[0].unicode, "Wide text");
+        assert_eq!(texts[1].unicode, "C");
+        assert!((texts[1].tm[4] - 113.34).abs() < 1e-3);
+    }
+
+    #[test]
+    fn edit_text_reports_missing_glyphs() {
+        let (pdf, doc) = TestPdf::sample();
+        let ops = vec![PatchOperation::EditText {
+            target: target(0, "t:0"),
+            text: "Hello 世界".into(),
+            font_pref: None,
+        }];
+        let err = apply(&doc, &pdf, &ops).unwrap_err();
+        assert!(matches!(err, PatchError::GlyphMissing { ch: '世', .. }));
+    }
+
+    #[test]
+    fn set_style_wraps_paths_and_restores_text_colour() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        pdf.page(
+            b"0 0 1 rg 0 0 10 10 re f BT /F1 10 Tf (A) Tj (B) Tj ET",
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let style = StylePayload {
+            fill_color: Some([1.0, 0.0, 0.0]),
+            opacity_fill: Some(0.5),
+            ..Default::default()
+        };
+        let ops = vec![
+            PatchOperation::SetStyle {
+                target: target(0, "p:0"),
+                style: style.clone(),
+            },
+            PatchOperation::SetStyle {
+                target: target(0, "t:1"),
+                style,
+            },
+        ];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        assert_eq!(
+            page_ops(&outcome.document, 0),
+            [
+                "0 0 1 rg",
+                "q",
+                "1 0 0 rg",
+                "/GS1 gs",
+                "0 0 10 10 re",
+                "f",
+                "Q",
+                "BT",
+                "/F1 10 Tf",
+                "1 0 0 rg",
+                "/GS2 gs",
+                "(A) Tj",
+                "0 0 1 rg",
+                "/GS3 gs",
+                "(B) Tj",
+                "ET",
+            ]
+        );
+    }
+
+    #[test]
+    fn transform_inside_a_form_edits_the_form_stream() {
+        let mut pdf = TestPdf::new();
+        let form = pdf.form(
+            b"0 0 10 10 re f",
+            [0.0, 0.0, 100.0, 100.0],
+            [2.0, 0.0, 0.0, 2.0, 0.0, 0.0],
+            Dictionary::new(),
+        );
+        pdf.page(
+            b"/Fm1 Do",
+            dictionary! { "XObject" => dictionary! { "Fm1" => form } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![PatchOperation::Transform {
+            target: target(0, "p:0"),
+            delta_matrix_pt: [1.0, 0.0, 0.0, 1.0, 50.0, 0.0],
+            kind: "path".into(),
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        // The page stream is untouched and the form is edited in place.
+        assert_eq!(page_ops(&outcome.document, 0), ["/Fm1 Do"]);
+        let content = extract::load_content(&outcome.document, form, &limits()).unwrap();
+        assert_eq!(content.data, b"q\n1 0 0 1 25 0 cm 0 0 10 10 re f Q");
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        let PageObject::Path(path) = &extraction.ir.objects[0] else {
+            panic!("expected path");
+        };
+        assert_eq!(path.bbox, [50.0, 0.0, 70.0, 20.0]);
+        assert_eq!(path.pdf_ref.obj, form.0);
+    }
+
+    #[test]
+    fn forms_shared_between_pages_are_copied_before_editing() {
+        let mut pdf = TestPdf::new();
+        let form = pdf.form(
+            b"0 0 10 10 re f",
+            [0.0, 0.0, 100.0, 100.0],
+            [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            Dictionary::new(),
+        );
+        let resources = dictionary! { "XObject" => dictionary! { "Fm1" => form } };
+        pdf.page(b"/Fm1 Do", resources.clone());
+        pdf.page(b"/Fm1 Do", resources);
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![PatchOperation::Transform {
+            target: target(1, "p:0"),
+            delta_matrix_pt: [1.0, 0.0, 0.0, 1.0, 5.0, 5.0],
+            kind: "path".into(),
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+        let doc = &outcome.document;
+
+        assert_eq!(page_ops(doc, 0), ["/Fm1 Do"]);
+        assert_eq!(page_ops(doc, 1), ["/Fm2 Do"]);
+        let original = extract::load_content(doc, form, &limits()).unwrap();
+        assert_eq!(original.data, b"0 0 10 10 re f");
+
+        let first = extract::extract_page(doc, 0, &limits()).unwrap();
+        let second = extract::extract_page(doc, 1, &limits()).unwrap();
+        assert_eq!(first.records[0].owner, form);
+        assert_ne!(second.records[0].owner, form);
+        let PageObject::Path(moved) = &second.ir.objects[0] else {
+            panic!("expected path");
+        };
+        assert_eq!(moved.bbox, [5.0, 5.0, 15.0, 15.0]);
+
+        // Editing the copy again does not copy it a second time.
+        let again = apply(doc, &outcome.pdf, &ops).unwrap();
+        assert_eq!(page_ops(&again.document, 1), ["/Fm2 Do"]);
+    }
+
+    #[test]
+    fn repeated_invocations_on_one_page_are_copied() {
+        let mut pdf = TestPdf::new();
+        let form = pdf.form(
+            b"0 0 10 10 re f",
+            [0.0, 0.0, 100.0, 100.0],
+            [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            Dictionary::new(),
+        );
+        pdf.page(
+            b"/Fm1 Do 1 0 0 1 50 0 cm /Fm1 Do",
+            dictionary! { "XObject" => dictionary! { "Fm1" => form } },
+        );
+        let (bytes, doc) = pdf.finish();
         let ops = vec![PatchOperation::SetStyle {
-            target: crate::types::PatchTarget {
-                page: 0,
-                id: "t:42".into(),
+            target: target(0, "p:1"),
+            style: StylePayload {
+                fill_color: Some([0.0, 1.0, 0.0]),
+                ..Default::default()
             },
-            style: crate::types::StylePayload {
-                fill_color: Some([1.0, 0.0, 0.0]),
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        assert_eq!(
+            page_ops(&outcome.document, 0),
+            ["/Fm1 Do", "1 0 0 1 50 0 cm", "/Fm2 Do"]
+        );
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        assert_eq!(extraction.records[0].owner, form);
+        assert_eq!(extraction.records[1].state.fill[0].operator, "rg");
+    }
+
+    #[test]
+    fn delete_text_keeps_following_runs_in_place() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        pdf.page(
+            b"BT /F1 10 Tf 100 700 Td (AB) Tj (C) Tj ET",
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let before = extract::extract_page(&doc, 0, &limits()).unwrap();
+        let ops = vec![PatchOperation::Delete {
+            target: target(0, "t:0"),
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        let after = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        assert_eq!(after.ir.objects.len(), 1);
+        let (PageObject::Text(old), PageObject::Text(new)) =
+            (&before.ir.objects[1], &after.ir.objects[0])
+        else {
+            panic!("expected text");
+        };
+        assert_eq!(new.unicode, "C");
+        assert!((new.tm[4] - old.tm[4]).abs() < 1e-3);
+    }
+
+    #[test]
+    fn delete_path_keeps_q_balance_and_state() {
+        let mut pdf = TestPdf::new();
+        pdf.page(
+            b"q 1 0 0 rg 0 0 10 10 re f Q 5 5 m 20 20 l S",
+            Dictionary::new(),
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![PatchOperation::Delete {
+            target: target(0, "p:0"),
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        assert_eq!(
+            page_ops(&outcome.document, 0),
+            ["q", "1 0 0 rg", "Q", "5 5 m", "20 20 l", "S"]
+        );
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        assert_eq!(extraction.records.len(), 1);
+        assert_eq!(extraction.records[0].id, "p:0");
+    }
+
+    #[test]
+    fn delete_image_releases_xobjects_nothing_else_draws() {
+        let mut pdf = TestPdf::new();
+        let once = pdf.image(1, 1);
+        let twice = pdf.image(1, 1);
+        pdf.page(
+            b"q 10 0 0 10 0 0 cm /Im1 Do Q /Im2 Do /Im2 Do",
+            dictionary! { "XObject" => dictionary! { "Im1" => once, "Im2" => twice } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![
+            PatchOperation::Delete {
+                target: target(0, "img:0"),
+            },
+            PatchOperation::Delete {
+                target: target(0, "img:0"),
+            },
+        ];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+        let doc = &outcome.document;
+
+        assert_eq!(page_ops(doc, 0), ["q", "10 0 0 10 0 0 cm", "Q", "/Im2 Do"]);
+        let page = resources::page_ids(doc)[0];
+        let xobjects = resources::page_resources(doc, page)
+            .and_then(|res| resources::get_dict(doc, res, b"XObject"))
+            .unwrap();
+        assert!(!xobjects.has(b"Im1"));
+        assert!(xobjects.has(b"Im2"));
+    }
+
+    #[test]
+    fn reorder_moves_paths_with_the_state_they_were_painted_in() {
+        let mut pdf = TestPdf::new();
+        pdf.page(
+            b"q 1 0 0 rg 2 0 0 2 0 0 cm 0 0 10 10 re f Q 0 0 1 rg 5 5 m 20 20 l S",
+            Dictionary::new(),
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![PatchOperation::Reorder {
+            target: target(0, "p:0"),
+            direction: ReorderDirection::Forward,
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        assert_eq!(
+            page_ops(&outcome.document, 0),
+            [
+                "q",
+                "1 0 0 rg",
+                "2 0 0 2 0 0 cm",
+                "Q",
+                "0 0 1 rg",
+                "5 5 m",
+                "20 20 l",
+                "S",
+                "q",
+                "1 0 0 rg",
+                "2 0 0 2 0 0 cm",
+                "0 0 10 10 re",
+                "f",
+                "Q"
+            ]
+        );
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        let PageObject::Path(moved) = &extraction.ir.objects[1] else {
+            panic!("expected path");
+        };
+        assert_eq!(moved.id, "p:1");
+        assert_eq!(moved.bbox, [0.0, 0.0, 20.0, 20.0]);
+        assert_eq!(extraction.records[1].state.fill[0].operator, "rg");
+
+        // Already in front: nothing changes.
+        let front = vec![PatchOperation::Reorder {
+            target: target(0, "p:1"),
+            direction: ReorderDirection::Front,
+        }];
+        let unchanged = apply(&outcome.document, &outcome.pdf, &front).unwrap();
+        assert_eq!(
+            page_ops(&unchanged.document, 0),
+            page_ops(&outcome.document, 0)
+        );
+    }
+
+    #[test]
+    fn reorder_keeps_text_position_and_font() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        pdf.page(
+            b"0 0 10 10 re f BT /F1 10 Tf 100 700 Td (A) Tj ET",
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let before = extract::extract_page(&doc, 0, &limits()).unwrap();
+        let ops = vec![PatchOperation::Reorder {
+            target: target(0, "t:1"),
+            direction: ReorderDirection::Back,
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        let after = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        let (PageObject::Text(old), PageObject::Text(new)) =
+            (&before.ir.objects[1], &after.ir.objects[0])
+        else {
+            panic!("expected text first");
+        };
+        assert_eq!(new.id, "t:0");
+        assert_eq!(new.unicode, "A");
+        assert_eq!(new.font, old.font);
+        assert!((new.tm[4] - old.tm[4]).abs() < 1e-3);
+        assert!((new.tm[5] - old.tm[5]).abs() < 1e-3);
+        assert!(matches!(after.ir.objects[1], PageObject::Path(_)));
+    }
+
+    #[test]
+    fn invalid_operations_are_all_reported_before_anything_applies() {
+        let mut pdf = TestPdf::new();
+        pdf.page(b"0 0 10 10 re f", Dictionary::new());
+        let (bytes, doc) = pdf.finish();
+        let fonts = FontLibrary::from_fonts(Vec::new());
+        let uploads = HashMap::new();
+        let context = PatchContext {
+            limits: &limits(),
+            fonts: &fonts,
+            uploads: &uploads,
+        };
+        let ops = vec![
+            PatchOperation::Transform {
+                target: target(0, "p:0"),
+                delta_matrix_pt: [0.0, 0.0, 0.0, 0.0, 5.0, 5.0],
+                kind: "path".into(),
+            },
+            PatchOperation::Delete {
+                target: target(0, "p:0"),
+            },
+            PatchOperation::SetStyle {
+                target: target(0, "p:0"),
+                style: StylePayload {
+                    fill_color: Some([0.0, 1.5, 0.0]),
+                    ..Default::default()
+                },
+            },
+            PatchOperation::Transform {
+                target: target(0, "p:0"),
+                delta_matrix_pt: [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+                kind: "shape".into(),
+            },
+        ];
+
+        let Err(BatchError::Rejected(failures)) = apply_patches(&doc, &bytes, &ops, &context)
+        else {
+            panic!("expected the batch to be rejected");
+        };
+        let indices: Vec<usize> = failures.iter().map(|failure| failure.index).collect();
+        assert_eq!(indices, [0, 2, 3]);
+        assert!(matches!(
+            failures[0].error,
+            PatchError::InvalidMatrix {
+                field: "deltaMatrixPt"
+            }
+        ));
+        assert!(matches!(
+            failures[1].error,
+            PatchError::OutOfRange {
+                field: "fillColor",
+                value
+            } if value == 1.5
+        ));
+        assert!(matches!(&failures[2].error, PatchError::UnknownKind(kind) if kind == "shape"));
+        assert_eq!(failures[2].target, Some(target(0, "p:0")));
+
+        // Targets are checked against the document as earlier operations
+        // left it.
+        let ops = vec![
+            PatchOperation::Delete {
+                target: target(0, "p:0"),
+            },
+            PatchOperation::Delete {
+                target: target(0, "p:0"),
+            },
+        ];
+        let Err(BatchError::Rejected(failures)) = apply_patches(&doc, &bytes, &ops, &context)
+        else {
+            panic!("expected the batch to be rejected");
+        };
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].index, 1);
+        assert!(matches!(
+            failures[0].error,
+            PatchError::UnknownTarget { .. }
+        ));
+    }
+
+    fn duplicate(id: &str, to_page: Option<usize>, offset: [f64; 6]) -> PatchOperation {
+        PatchOperation::Duplicate {
+            target: target(0, id),
+            to_page,
+            offset_matrix_pt: offset,
+        }
+    }
+
+    #[test]
+    fn duplicate_text_to_another_page_renames_clashing_fonts() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        let other_font = pdf.helvetica();
+        pdf.page(
+            b"BT /F1 10 Tf 100 700 Td (AB) Tj ET",
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        pdf.page(
+            b"BT /F1 10 Tf 0 0 Td (Z) Tj ET",
+            dictionary! { "Font" => dictionary! { "F1" => other_font } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![duplicate("t:0", Some(1), [1.0, 0.0, 0.0, 1.0, 0.0, -20.0])];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+        let doc = &outcome.document;
+
+        assert_eq!(outcome.created, [target(1, "t:1")]);
+        assert_eq!(outcome.touched_pages, BTreeSet::from([1]));
+        let page = resources::page_ids(doc)[1];
+        let fonts = resources::page_resources(doc, page)
+            .and_then(|res| resources::get_dict(doc, res, b"Font"))
+            .unwrap();
+        assert_eq!(fonts.get(b"F1").unwrap(), &Object::Reference(other_font));
+        assert_eq!(fonts.get(b"F2").unwrap(), &Object::Reference(font));
+
+        let extraction = extract::extract_page(doc, 1, &limits()).unwrap();
+        let PageObject::Text(copy) = &extraction.ir.objects[1] else {
+            panic!("expected text");
+        };
+        assert_eq!(copy.unicode, "AB");
+        assert_eq!(copy.tm[4..], [100.0, 680.0]);
+        // The original stays where it was.
+        assert_eq!(
+            page_ops(doc, 0),
+            ["BT", "/F1 10 Tf", "100 700 Td", "(AB) Tj", "ET"]
+        );
+    }
+
+    #[test]
+    fn duplicate_keeps_the_state_an_object_was_painted_in() {
+        let mut pdf = TestPdf::new();
+        let image = pdf.image(1, 1);
+        pdf.page(
+            b"q /GS1 gs 10 0 0 10 0 0 cm /Im1 Do Q",
+            dictionary! {
+                "XObject" => dictionary! { "Im1" => image },
+                "ExtGState" => dictionary! { "GS1" => dictionary! { "ca" => 0.5 } },
+            },
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![duplicate("img:0", None, [1.0, 0.0, 0.0, 1.0, 20.0, 0.0])];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        assert_eq!(outcome.created, [target(0, "img:1")]);
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        let PageObject::Image(copy) = &extraction.ir.objects[1] else {
+            panic!("expected image");
+        };
+        assert_eq!(copy.x_object, "Im1");
+        assert_eq!(copy.bbox, [20.0, 0.0, 30.0, 10.0]);
+        assert_eq!(extraction.records[1].state.fill_alpha, 0.5);
+    }
+
+    #[test]
+    fn duplicate_from_a_form_draws_in_page_space() {
+        let mut pdf = TestPdf::new();
+        let form = pdf.form(
+            b"0 0 1 rg 0 0 10 10 re f",
+            [0.0, 0.0, 100.0, 100.0],
+            [1.0, 0.0, 0.0, 1.0, 5.0, 5.0],
+            Dictionary::new(),
+        );
+        pdf.page(
+            b"2 0 0 2 0 0 cm /Fm1 Do",
+            dictionary! { "XObject" => dictionary! { "Fm1" => form } },
+        );
+        pdf.page(b"", Dictionary::new());
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![duplicate("p:0", Some(1), [1.0, 0.0, 0.0, 1.0, 0.0, 0.0])];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+
+        let extraction = extract::extract_page(&outcome.document, 1, &limits()).unwrap();
+        let PageObject::Path(copy) = &extraction.ir.objects[0] else {
+            panic!("expected path");
+        };
+        assert_eq!(copy.bbox, [10.0, 10.0, 30.0, 30.0]);
+        assert_eq!(extraction.records[0].state.fill[0].encode(), b"0 0 1 rg");
+    }
+
+    #[test]
+    fn reorder_into_another_clip_is_refused() {
+        let mut pdf = TestPdf::new();
+        pdf.page(
+            b"q 0 0 50 50 re W n 0 0 10 10 re f 5 5 m 20 20 l S Q 1 1 m 2 2 l S",
+            Dictionary::new(),
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![PatchOperation::Reorder {
+            target: target(0, "p:2"),
+            direction: ReorderDirection::Backward,
+        }];
+        assert!(matches!(
+            apply(&doc, &bytes, &ops),
+            Err(PatchError::ReorderBlocked { id }) if id == "p:2"
+        ));
+    }
+
+    fn insert_text(page: usize, text: &str, family: &str) -> PatchOperation {
+        PatchOperation::InsertText {
+            page,
+            matrix_pt: [1.0, 0.0, 0.0, 1.0, 100.0, 200.0],
+            text: text.into(),
+            font_family: family.into(),
+            font_size: 20.0,
+            color: Some([0.0, 0.0, 1.0]),
+        }
+    }
+
+    #[test]
+    fn insert_text_appends_a_stream_with_an_embedded_subset() {
+        let (pdf, doc) = TestPdf::sample();
+        let ops = vec![insert_text(0, "AB C", "Test Sans")];
+        let outcome = apply(&doc, &pdf, &ops).unwrap();
+
+        assert_eq!(outcome.created, [target(0, "t:1")]);
+        let doc = &outcome.document;
+        let page = resources::page_ids(doc)[0];
+        assert_eq!(doc.get_page_contents(page).len(), 2);
+        let extraction = extract::extract_page(doc, 0, &limits()).unwrap();
+        let PageObject::Text(text) = &extraction.ir.objects[1] else {
+            panic!("expected text");
+        };
+        assert_eq!(text.unicode, "AB C");
+        assert_eq!(text.tm, [1.0, 0.0, 0.0, 1.0, 100.0, 200.0]);
+        assert_eq!(text.font.font_type, "Type0");
+        // 600 + 600 + 250 + 600 thousandths of 20pt.
+        assert!((text.bbox[2] - 141.0).abs() < 1e-6);
+        let record = &extraction.records[1];
+        assert_eq!(record.state.fill[0].operator, "rg");
+        let font = record.state.text.font.as_ref().unwrap();
+        assert!(font.base_font.ends_with("+TestSans-Regular"));
+    }
+
+    #[test]
+    fn insert_text_isolates_content_that_leaves_state_changed() {
+        let mut pdf = TestPdf::new();
+        pdf.page(b"2 0 0 2 0 0 cm 0 0 10 10 re f", Dictionary::new());
+        let (bytes, doc) = pdf.finish();
+        let outcome = apply(&doc, &bytes, &[insert_text(0, "A", "Test Sans")]).unwrap();
+
+        let ops = page_ops(&outcome.document, 0);
+        assert_eq!(ops.first().map(String::as_str), Some("q"));
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        let PageObject::Text(text) = &extraction.ir.objects[1] else {
+            panic!("expected text");
+        };
+        assert_eq!(text.tm[4..], [100.0, 200.0]);
+    }
+
+    #[test]
+    fn insert_text_reports_unknown_fonts_and_missing_glyphs() {
+        let (pdf, doc) = TestPdf::sample();
+        let err = apply(&doc, &pdf, &[insert_text(0, "A", "Nope")]).unwrap_err();
+        assert!(matches!(err, PatchError::FontNotFound(family) if family == "Nope"));
+
+        let err = apply(&doc, &pdf, &[insert_text(0, "Az", "Test Sans")]).unwrap_err();
+        assert!(matches!(err, PatchError::GlyphMissing { ch: 'z', .. }));
+    }
+
+    fn insert_image(page: usize, image: ImageSource) -> PatchOperation {
+        PatchOperation::InsertImage {
+            page,
+            image,
+            matrix_pt: [40.0, 0.0, 0.0, 20.0, 100.0, 200.0],
+        }
+    }
+
+    #[test]
+    fn insert_image_draws_a_png_with_its_alpha_as_a_soft_mask() {
+        let (pdf, doc) = TestPdf::sample();
+        let png = test_png(2, 1, &[255, 0, 0, 255, 0, 0, 255, 0]);
+        let data = format!("data:image/png;base64,{}", BASE64.encode(png));
+        let ops = vec![insert_image(0, ImageSource::Inline { data })];
+        let outcome = apply(&doc, &pdf, &ops).unwrap();
+
+        assert_eq!(outcome.created, [target(0, "img:1")]);
+        let doc = &outcome.document;
+        let extraction = extract::extract_page(doc, 0, &limits()).unwrap();
+        let PageObject::Image(image) = &extraction.ir.objects[1] else {
+            panic!("expected an image");
+        };
+        assert_eq!(image.bbox, [100.0, 200.0, 140.0, 220.0]);
+        let ops = page_ops(doc, 0);
+        assert_eq!(
+            ops[ops.len() - 4..],
+            ["q", "40 0 0 20 100 200 cm", "/Im1 Do", "Q"]
+        );
+
+        let page = resources::page_ids(doc)[0];
+        let xobjects = resources::page_resources(doc, page)
+            .and_then(|res| resources::get_dict(doc, res, b"XObject"))
+            .unwrap();
+        let image = doc
+            .get_object(xobjects.get(b"Im1").unwrap().as_reference().unwrap())
+            .and_then(Object::as_stream)
+            .unwrap();
+        assert_eq!(
+            image.dict.get(b"Filter").unwrap().as_name().unwrap(),
+            b"FlateDecode"
+        );
+        let smask = image.dict.get(b"SMask").unwrap().as_reference().unwrap();
+        assert!(doc.get_object(smask).and_then(Object::as_stream).is_ok());
+    }
+
+    #[test]
+    fn insert_image_uses_uploads_and_reports_unknown_ones() {
+        let (pdf, doc) = TestPdf::sample();
+        let uploads = HashMap::from([("upload-1".to_string(), test_jpeg(4, 4))]);
+        let upload = |id: &str| ImageSource::Upload {
+            upload_id: id.into(),
+        };
+        let outcome =
+            apply_with_uploads(&doc, &pdf, &[insert_image(0, upload("upload-1"))], &uploads)
+                .unwrap();
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        let PageObject::Image(image) = &extraction.ir.objects[1] else {
+            panic!("expected an image");
+        };
+        assert_eq!(image.cm, [40.0, 0.0, 0.0, 20.0, 100.0, 200.0]);
+        let patched = &outcome.document;
+        let page = resources::page_ids(patched)[0];
+        let stream = resources::page_resources(patched, page)
+            .and_then(|res| resources::get_dict(patched, res, b"XObject"))
+            .and_then(|xobjects| xobjects.get(image.x_object.as_bytes()).ok())
+            .and_then(|image| patched.get_object(image.as_reference().ok()?).ok())
+            .and_then(|image| image.as_stream().ok())
+            .unwrap();
+        assert_eq!(
+            stream.dict.get(b"Filter").unwrap().as_name().unwrap(),
+            b"DCTDecode"
+        );
+        assert_eq!(stream.content, test_jpeg(4, 4));
+
+        let err = apply_with_uploads(&doc, &pdf, &[insert_image(0, upload("nope"))], &uploads)
+            .unwrap_err();
+        assert!(matches!(err, PatchError::UnknownUpload(id) if id == "nope"));
+        let inline = ImageSource::Inline {
+            data: "not base64!".into(),
+        };
+        let err = apply(&doc, &pdf, &[insert_image(0, inline)]).unwrap_err();
+        assert!(matches!(err, PatchError::InvalidImageData));
+    }
+
+    fn replace_image(id: &str, fit: ImageFit, all_placements: bool) -> PatchOperation {
+        PatchOperation::ReplaceImage {
+            target: target(0, id),
+            image: ImageSource::Inline {
+                data: BASE64.encode(test_jpeg(4, 4)),
+            },
+            fit,
+            all_placements,
+        }
+    }
+
+    #[test]
+    fn replace_image_fits_one_placement_of_a_shared_xobject() {
+        let mut pdf = TestPdf::new();
+        let image = pdf.image(2, 1);
+        pdf.page(
+            b"q 20 0 0 10 0 0 cm /Im1 Do Q q 20 0 0 10 50 0 cm /Im1 Do Q",
+            dictionary! { "XObject" => dictionary! { "Im1" => image } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![replace_image("img:0", ImageFit::Fit, false)];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+        let doc = &outcome.document;
+
+        assert_eq!(
+            page_ops(doc, 0),
+            [
+                "q",
+                "20 0 0 10 0 0 cm",
+                "q",
+                "0.5 0 0 1 0.25 0 cm",
+                "/Im2 Do",
+                "Q",
+                "Q",
+                "q",
+                "20 0 0 10 50 0 cm",
+                "/Im1 Do",
+                "Q",
+            ]
+        );
+        let extraction = extract::extract_page(doc, 0, &limits()).unwrap();
+        let PageObject::Image(replaced) = &extraction.ir.objects[0] else {
+            panic!("expected an image");
+        };
+        assert_eq!(replaced.bbox, [5.0, 0.0, 15.0, 10.0]);
+        let PageObject::Image(untouched) = &extraction.ir.objects[1] else {
+            panic!("expected an image");
+        };
+        assert_eq!(untouched.pdf_ref.obj, image.0);
+    }
+
+    #[test]
+    fn replace_image_can_update_every_placement() {
+        let mut pdf = TestPdf::new();
+        let image = pdf.image(2, 1);
+        let resources = dictionary! { "XObject" => dictionary! { "Im1" => image } };
+        pdf.page(b"q 20 0 0 10 0 0 cm /Im1 Do Q", resources.clone());
+        pdf.page(b"q 20 0 0 10 0 0 cm /Im1 Do Q", resources);
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![replace_image("img:0", ImageFit::Fill, true)];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+        let doc = &outcome.document;
+
+        let stream = doc.get_object(image).and_then(Object::as_stream).unwrap();
+        assert_eq!(stream.content, test_jpeg(4, 4));
+        for page in 0..2 {
+            assert_eq!(
+                page_ops(doc, page)[2..8],
+                ["q", "0 0 1 1 re", "W", "n", "1 0 0 2 0 -0.5 cm", "/Im1 Do"]
+            );
+        }
+        assert_eq!(outcome.touched_pages, BTreeSet::from([0, 1]));
+    }
+
+    #[test]
+    fn replace_image_rejects_other_kinds() {
+        let (pdf, doc) = TestPdf::sample();
+        let err = apply(
+            &doc,
+            &pdf,
+            &[replace_image("t:0", ImageFit::Stretch, false)],
+        )
+        .unwrap_err();
+        assert!(matches!(err, PatchError::NotImage { kind: "text", .. }));
+    }
+
+    #[test]
+    fn insert_path_appends_vector_content() {
+        let (pdf, doc) = TestPdf::sample();
+        let ops = vec![PatchOperation::InsertPath {
+            page: 0,
+            segments: vec![PathSegment::Polyline {
+                points: vec![[10.0, 10.0], [50.0, 10.0], [30.0, 40.0]],
+                closed: true,
+            }],
+            style: PathStyle {
+                stroke_color: Some([1.0, 0.0, 0.0]),
+                line_width: Some(3.0),
                 ..Default::default()
             },
         }];
+        let outcome = apply(&doc, &pdf, &ops).unwrap();
+
+        assert_eq!(outcome.created, [target(0, "p:1")]);
+        let extraction = extract::extract_page(&outcome.document, 0, &limits()).unwrap();
+        let PageObject::Path(path) = &extraction.ir.objects[1] else {
+            panic!("expected a path");
+        };
+        // Widened by half the line width.
+        assert_eq!(path.bbox, [8.5, 8.5, 51.5, 41.5]);
+        let ops = page_ops(&outcome.document, 0);
+        assert_eq!(
+            ops[ops.len() - 9..],
+            ["q", "1 0 0 RG", "3 w", "10 10 m", "50 10 l", "30 40 l", "h", "S", "Q"]
+        );
+
+        let ops = vec![PatchOperation::InsertPath {
+            page: 0,
+            segments: vec![],
+            style: PathStyle::default(),
+        }];
+        let err = apply(&doc, &pdf, &ops).unwrap_err();
+        assert!(matches!(err, PatchError::Shape(ShapeError::Empty)));
+    }
+
+    #[test]
+    fn shown_glyphs_adjusts_for_kerning_and_offsets() {
+        let glyph = |gid, x_advance, x_offset| ShapedGlyph {
+            gid,
+            cluster: 0,
+            x_advance,
+            x_offset,
+            y_offset: 0.0,
+        };
+        let widths = BTreeMap::from([(1, 600.0), (2, 500.0)]);
+        let glyphs = [
+            glyph(1, 600.0, 0.0),
+            glyph(2, 450.0, 0.0),
+            glyph(1, 0.0, -300.0),
+            glyph(2, 500.0, 0.0),
+        ];
+
+        let items = shown_glyphs(&glyphs, &widths);
+
+        let hex = |bytes: &[u8]| Object::String(bytes.to_vec(), StringFormat::Hexadecimal);
+        assert_eq!(
+            items,
+            [
+                hex(&[0, 1, 0, 2]),
+                real(350.0),
+                hex(&[0, 1]),
+                real(300.0),
+                hex(&[0, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_targets_are_reported() {
+        let (pdf, doc) = TestPdf::sample();
+        let ops = vec![PatchOperation::SetStyle {
+            target: target(0, "t:99"),
+            style: StylePayload::default(),
+        }];
+        let err = apply(&doc, &pdf, &ops).unwrap_err();
+        assert!(matches!(err, PatchError::UnknownTarget { page: 0, .. }));
+
+        let ops = vec![PatchOperation::SetStyle {
+            target: target(3, "t:0"),
+            style: StylePayload::default(),
+        }];
+        let err = apply(&doc, &pdf, &ops).unwrap_err();
+        assert!(matches!(err, PatchError::PageNotFound(3)));
+    }
+
+    #[test]
+    fn shift_maps_segment_boundaries_through_splices() {
+        let splices = vec![(2..2, b"ab".to_vec()), (5..9, b"x".to_vec())];
+        assert_eq!(shift(2, &splices, false), 2);
+        assert_eq!(shift(2, &splices, true), 4);
+        assert_eq!(shift(5, &splices, false), 7);
+        assert_eq!(shift(7, &splices, true), 8);
+        assert_eq!(shift(9, &splices, false), 8);
+        assert_eq!(shift(12, &splices, false), 11);
+    }
 
-        apply_patches(&mut ir, &ops).expect("patch application should succeed");
-        assert_eq!(ir, original);
+    #[test]
+    fn edits_in_multi_stream_pages_touch_only_the_streams_involved() {
+        let mut pdf = TestPdf::new();
+        let font = pdf.helvetica();
+        let first = pdf.stream(b"BT /F1 12 Tf 10 10 Td (Hi)");
+        let second = pdf.stream(b"Tj ET");
+        let third = pdf.stream(b"0 0 5 5 re f");
+        pdf.page_with_contents(
+            vec![first.into(), second.into(), third.into()].into(),
+            dictionary! { "Font" => dictionary! { "F1" => font } },
+        );
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![
+            PatchOperation::EditText {
+                target: target(0, "t:0"),
+                text: "Bye".into(),
+                font_pref: None,
+            },
+            PatchOperation::Transform {
+                target: target(0, "p:1"),
+                delta_matrix_pt: [1.0, 0.0, 0.0, 1.0, 3.0, 0.0],
+                kind: "path".into(),
+            },
+        ];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+        let doc = &outcome.document;
+
+        let stream = |id| extract::load_content(doc, id, &limits()).unwrap().data;
+        // The show operator spanned two streams; its replacement lands in
+        // the first and the rest of the second is kept.
+        assert_eq!(stream(first), b"BT /F1 12 Tf 10 10 Td (Bye) Tj");
+        assert_eq!(stream(second), b" ET");
+        assert_eq!(stream(third), b"q\n1 0 0 1 3 0 cm 0 0 5 5 re f Q");
+        assert_eq!(
+            doc.get_page_contents(resources::page_ids(doc)[0]),
+            [first, second, third]
+        );
+
+        let ir = extract::extract_ir(doc, &limits()).unwrap();
+        let PageObject::Text(text) = &ir.pages[0].objects[0] else {
+            panic!("expected text");
+        };
+        assert_eq!(text.unicode, "Bye");
     }
 
     #[test]
-    fn apply_patches_accepts_empty_operations() {
-        let mut ir = DocumentIR::sample();
-        apply_patches(&mut ir, &[]).expect("empty patch list should succeed");
+    fn content_streams_shared_between_pages_are_split_before_editing() {
+        let mut pdf = TestPdf::new();
+        let shared = pdf.stream(b"0 0 5 5 re f");
+        let own = pdf.stream(b"10 10 5 5 re f");
+        pdf.page_with_contents(shared.into(), Dictionary::new());
+        pdf.page_with_contents(vec![shared.into(), own.into()].into(), Dictionary::new());
+        let (bytes, doc) = pdf.finish();
+        let ops = vec![PatchOperation::SetStyle {
+            target: target(1, "p:0"),
+            style: StylePayload {
+                fill_color: Some([1.0, 0.0, 0.0]),
+                ..Default::default()
+            },
+        }];
+        let outcome = apply(&doc, &bytes, &ops).unwrap();
+        let doc = &outcome.document;
+
+        assert_eq!(page_ops(doc, 0), ["0 0 5 5 re", "f"]);
+        assert_eq!(
+            page_ops(doc, 1),
+            ["q", "1 0 0 rg", "0 0 5 5 re", "f", "Q", "10 10 5 5 re", "f"]
+        );
+        let contents = doc.get_page_contents(resources::page_ids(doc)[1]);
+        assert_ne!(contents[0], shared);
+        assert_eq!(contents[1], own);
     }
 }
@@ -0,0 +1,239 @@
+//! Rebuilding the graphics state at a point in a content stream.
+//!
+//! Moving an object to another place in the same content means it must
+//! carry the state it was painted in. The state is kept as the operators
+//! that establish it from the start of the content: the `cm`, `gs` and
+//! clipping operators in order, since they build on each other, and only
+//! the latest setter of every other parameter.
+
+use lopdf::Object;
+
+use crate::pdf::content::{matrix_operands, ContentOp};
+use crate::util::matrix::Matrix2D;
+
+/// Path construction operators, which a clipping path is built from.
+const CONSTRUCTION_OPERATORS: &[&str] = &["m", "l", "c", "v", "y", "re", "h"];
+
+/// Operators that end a path.
+const PAINT_OPERATORS: &[&str] = &["S", "s", "f", "F", "f*", "B", "B*", "b", "b*", "n"];
+
+/// The graphics state in effect at a point in a content stream.
+#[derive(Debug, Clone)]
+pub struct ReplayState {
+    /// Operators that rebuild the state from the start of the content.
+    pub ops: Vec<ContentOp>,
+    /// The CTM relative to the start of the content.
+    pub ctm: Matrix2D,
+}
+
+impl Default for ReplayState {
+    fn default() -> Self {
+        Self {
+            ops: Vec::new(),
+            ctm: Matrix2D::identity(),
+        }
+    }
+}
+
+impl ReplayState {
+    /// Whether `op` is part of this state: the very operator, not just one
+    /// with the same operands.
+    pub fn contains(&self, op: &ContentOp) -> bool {
+        self.ops.contains(op)
+    }
+
+    /// Whether the state sets the parameter `operator` sets.
+    pub fn sets(&self, operator: &str) -> bool {
+        let parameter = family(operator);
+        parameter.is_some() && self.ops.iter().any(|op| family(&op.operator) == parameter)
+    }
+
+    fn push(&mut self, op: &ContentOp) {
+        let Some(parameter) = family(&op.operator) else {
+            self.ops.push(op.clone());
+            return;
+        };
+        // `sc`/`scn` set a colour in the space `cs` chose, so they only
+        // replace each other.
+        let sets_component = matches!(op.operator.as_str(), "sc" | "scn" | "SC" | "SCN");
+        self.ops.retain(|existing| {
+            family(&existing.operator) != Some(parameter)
+                || (sets_component
+                    && !matches!(existing.operator.as_str(), "sc" | "scn" | "SC" | "SCN"))
+        });
+        self.ops.push(op.clone());
+    }
+}
+
+/// The state after running `ops[..end]`.
+pub fn state_at(ops: &[ContentOp], end: usize) -> ReplayState {
+    let mut stack = Vec::new();
+    let mut state = ReplayState::default();
+    let mut path_start = None;
+    let mut clipping = false;
+    for (index, op) in ops[..end.min(ops.len())].iter().enumerate() {
+        let operator = op.operator.as_str();
+        match operator {
+            "q" => stack.push(state.clone()),
+            "Q" => state = stack.pop().unwrap_or_default(),
+            "cm" => {
+                if let Some(values) = op.numbers().filter(|values| values.len() == 6) {
+                    let matrix = Matrix2D::from_array(values.try_into().expect("six numbers"));
+                    state.ctm = state.ctm.multiply(matrix);
+                    state.ops.push(op.clone());
+                }
+            }
+            "W" | "W*" => clipping = true,
+            _ if CONSTRUCTION_OPERATORS.contains(&operator) => {
+                path_start.get_or_insert(index);
+            }
+            _ if PAINT_OPERATORS.contains(&operator) => {
+                if let (true, Some(start)) = (clipping, path_start) {
+                    state.ops.extend(ops[start..index].iter().cloned());
+                    state.ops.push(ContentOp::new("n", vec![]));
+                }
+                path_start = None;
+                clipping = false;
+            }
+            "gs" => state.ops.push(op.clone()),
+            _ if family(operator).is_some() => state.push(op),
+            _ => {}
+        }
+    }
+    state
+}
+
+/// Operators that, run at a point in state `at`, undo what `at` set up so
+/// that replaying `to.ops` afterwards yields `to`. The CTM is reset and
+/// parameters `to` never sets go back to their defaults. `None` when that
+/// is impossible: `at` clips in a way `to` does not, or its CTM is
+/// singular. Extended graphics states are left to the caller.
+pub fn resets(at: &ReplayState, to: &ReplayState) -> Option<Vec<ContentOp>> {
+    let clipped_elsewhere = at
+        .ops
+        .iter()
+        .any(|op| matches!(op.operator.as_str(), "W" | "W*") && !to.contains(op));
+    if clipped_elsewhere {
+        return None;
+    }
+    let mut ops = Vec::new();
+    if at.ctm != Matrix2D::identity() {
+        let inverse = at.ctm.invert()?;
+        ops.push(ContentOp::new("cm", matrix_operands(inverse.to_array())));
+    }
+    let mut reset = Vec::new();
+    for op in &at.ops {
+        let Some(parameter) = family(&op.operator) else {
+            continue;
+        };
+        let set_by_target = to
+            .ops
+            .iter()
+            .any(|other| family(&other.operator) == Some(parameter));
+        if !set_by_target && !reset.contains(&parameter) {
+            reset.push(parameter);
+            ops.extend(default_op(parameter));
+        }
+    }
+    Some(ops)
+}
+
+/// The parameter an operator sets, for operators that set one outright.
+fn family(operator: &str) -> Option<&'static str> {
+    Some(match operator {
+        "g" | "rg" | "k" | "cs" | "sc" | "scn" => "fill",
+        "G" | "RG" | "K" | "CS" | "SC" | "SCN" => "stroke",
+        "w" => "w",
+        "J" => "J",
+        "j" => "j",
+        "M" => "M",
+        "d" => "d",
+        "ri" => "ri",
+        "i" => "i",
+        "Tc" => "Tc",
+        "Tw" => "Tw",
+        "Tz" => "Tz",
+        "TL" => "TL",
+        "Tf" => "Tf",
+        "Tr" => "Tr",
+        "Ts" => "Ts",
+        _ => return None,
+    })
+}
+
+/// The operator restoring a parameter's initial value, if it has one.
+fn default_op(family: &str) -> Option<ContentOp> {
+    let number = |value: i64| vec![Object::Integer(value)];
+    Some(match family {
+        "fill" => ContentOp::new("g", number(0)),
+        "stroke" => ContentOp::new("G", number(0)),
+        "w" => ContentOp::new("w", number(1)),
+        "J" | "j" | "Tc" | "Tw" | "TL" | "Tr" | "Ts" | "i" => ContentOp::new(family, number(0)),
+        "M" => ContentOp::new("M", number(10)),
+        "d" => ContentOp::new("d", vec![Object::Array(Vec::new()), Object::Integer(0)]),
+        "ri" => ContentOp::new("ri", vec![Object::Name(b"RelativeColorimetric".to_vec())]),
+        "Tz" => ContentOp::new("Tz", number(100)),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::content::{encode_ops, parse_content};
+
+    fn encoded(ops: &[ContentOp]) -> String {
+        String::from_utf8(encode_ops(ops))
+            .unwrap()
+            .trim_end()
+            .replace('\n', " ")
+    }
+
+    #[test]
+    fn state_keeps_ordered_operators_and_the_latest_setters() {
+        let ops = parse_content(
+            b"1 0 0 rg 2 w q 2 0 0 2 0 0 cm 0 0 5 5 re W n /CS0 cs 0.5 scn 3 w \
+              0 1 0 rg 1 0 0 1 5 5 cm 0 0 m 1 1 l S Q 0 0 1 RG",
+            64,
+        )
+        .unwrap();
+
+        let inside = state_at(&ops, 14);
+        assert_eq!(
+            encoded(&inside.ops),
+            "2 0 0 2 0 0 cm 0 0 5 5 re W n 3 w 0 1 0 rg 1 0 0 1 5 5 cm"
+        );
+        assert_eq!(
+            inside.ctm,
+            Matrix2D::from_array([2.0, 0.0, 0.0, 2.0, 10.0, 10.0])
+        );
+
+        let after = state_at(&ops, ops.len());
+        assert_eq!(encoded(&after.ops), "1 0 0 rg 2 w 0 0 1 RG");
+        assert_eq!(after.ctm, Matrix2D::identity());
+    }
+
+    #[test]
+    fn colour_components_keep_their_colour_space() {
+        let ops = parse_content(b"/CS0 cs 0.2 scn 0.7 scn", 64).unwrap();
+        assert_eq!(encoded(&state_at(&ops, 3).ops), "/CS0 cs 0.7 scn");
+    }
+
+    #[test]
+    fn resets_undo_the_ctm_and_parameters_the_target_leaves_alone() {
+        let ops = parse_content(b"2 0 0 2 0 0 cm 1 0 0 rg 4 w q 0 0 5 5 re W n Q", 64).unwrap();
+        let at = state_at(&ops, 3);
+        let to = ReplayState {
+            ops: vec![ContentOp::new("w", vec![Object::Integer(2)])],
+            ..Default::default()
+        };
+        assert_eq!(
+            encoded(&resets(&at, &to).unwrap()),
+            "0.5 0 0 0.5 0 0 cm 0 g"
+        );
+
+        let clipped = state_at(&ops, 7);
+        assert!(resets(&clipped, &to).is_none());
+        assert!(resets(&to, &clipped).is_some());
+    }
+}
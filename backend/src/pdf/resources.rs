@@ -0,0 +1,104 @@
+//! Helpers for walking page trees and resource dictionaries.
+
+use lopdf::{Dictionary, Document, Object, ObjectId};
+
+/// Follow a reference to the object it points at; other objects are
+/// returned unchanged.
+pub fn resolve<'a>(doc: &'a Document, object: &'a Object) -> &'a Object {
+    match object {
+        Object::Reference(id) => doc.get_object(*id).unwrap_or(&Object::Null),
+        other => other,
+    }
+}
+
+/// Look up `key` in `dict`, following a reference if needed.
+pub fn get<'a>(doc: &'a Document, dict: &'a Dictionary, key: &[u8]) -> Option<&'a Object> {
+    dict.get(key).ok().map(|object| resolve(doc, object))
+}
+
+pub fn get_dict<'a>(doc: &'a Document, dict: &'a Dictionary, key: &[u8]) -> Option<&'a Dictionary> {
+    get(doc, dict, key)?.as_dict().ok()
+}
+
+pub fn get_number(doc: &Document, dict: &Dictionary, key: &[u8]) -> Option<f64> {
+    match get(doc, dict, key)? {
+        Object::Integer(value) => Some(*value as f64),
+        Object::Real(value) => Some(*value as f64),
+        _ => None,
+    }
+}
+
+/// An inheritable page attribute such as `/Resources` or `/MediaBox`,
+/// looked up on the page and then its ancestors.
+pub fn inherited<'a>(doc: &'a Document, page_id: ObjectId, key: &[u8]) -> Option<&'a Object> {
+    let mut node = doc.get_dictionary(page_id).ok()?;
+    // The page tree depth is bounded to survive cyclic /Parent links.
+    for _ in 0..64 {
+        if let Some(value) = get(doc, node, key) {
+            return Some(value);
+        }
+        node = node
+            .get(b"Parent")
+            .ok()
+            .and_then(|parent| parent.as_reference().ok())
+            .and_then(|id| doc.get_dictionary(id).ok())?;
+    }
+    None
+}
+
+pub fn page_resources(doc: &Document, page_id: ObjectId) -> Option<&Dictionary> {
+    inherited(doc, page_id, b"Resources")?.as_dict().ok()
+}
+
+/// Find resource `name` in `category` (e.g. `Font`, `XObject`), returning
+/// its object id when it is an indirect object.
+pub fn lookup<'a>(
+    doc: &'a Document,
+    resources: Option<&'a Dictionary>,
+    category: &[u8],
+    name: &[u8],
+) -> Option<(Option<ObjectId>, &'a Object)> {
+    let entries = get_dict(doc, resources?, category)?;
+    match entries.get(name).ok()? {
+        Object::Reference(id) => Some((Some(*id), doc.get_object(*id).ok()?)),
+        direct => Some((None, direct)),
+    }
+}
+
+/// Page object ids in page order (zero-based index into the returned list).
+pub fn page_ids(doc: &Document) -> Vec<ObjectId> {
+    doc.get_pages().into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    #[test]
+    fn inherited_attributes_come_from_ancestors() {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+        });
+        let font_id = doc.add_object(dictionary! { "Type" => "Font" });
+        doc.objects.insert(
+            pages_id,
+            dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+                "Resources" => dictionary! { "Font" => dictionary! { "F1" => font_id } },
+            }
+            .into(),
+        );
+
+        let resources = page_resources(&doc, page_id);
+        let (id, font) = lookup(&doc, resources, b"Font", b"F1").expect("inherited font");
+        assert_eq!(id, Some(font_id));
+        assert!(font.as_dict().is_ok());
+        assert!(lookup(&doc, resources, b"Font", b"F2").is_none());
+    }
+}
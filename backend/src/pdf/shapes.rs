@@ -0,0 +1,274 @@
+//! Turning inserted shapes into path operators.
+//!
+//! Every shape becomes ordinary path construction operators, so drawn
+//! annotations end up as vector content indistinguishable from paths the
+//! document already had. Ellipses are four cubic Béziers; freehand strokes
+//! are smoothed with a Catmull-Rom spline through the sampled points.
+
+use lopdf::Object;
+
+use crate::pdf::content::{real, ContentOp};
+use crate::types::{LineCap, LineJoin, PathSegment, PathStyle};
+
+/// Distance of a quarter-ellipse's control points from its ends, as a
+/// fraction of the radius.
+const KAPPA: f64 = 0.552_284_749_8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShapeError {
+    #[error("the path has no segments")]
+    Empty,
+    #[error("a {0} needs at least two points")]
+    TooFewPoints(&'static str),
+    #[error("coordinates and style values must be finite")]
+    NotFinite,
+    #[error("the line width must not be negative")]
+    NegativeWidth,
+    #[error("dash lengths must not be negative or all zero")]
+    InvalidDash,
+}
+
+/// The operators that draw `segments` with `style`, wrapped in `q`/`Q`.
+pub fn path_ops(segments: &[PathSegment], style: &PathStyle) -> Result<Vec<ContentOp>, ShapeError> {
+    if segments.is_empty() {
+        return Err(ShapeError::Empty);
+    }
+    let mut ops = vec![ContentOp::new("q", vec![])];
+    ops.extend(style_ops(style)?);
+    for segment in segments {
+        segment_ops(segment, &mut ops)?;
+    }
+    let paint = match (style.fill_color.is_some(), style.stroke_color.is_some()) {
+        (true, true) => "B",
+        (true, false) => "f",
+        (false, _) => "S",
+    };
+    ops.push(ContentOp::new(paint, vec![]));
+    ops.push(ContentOp::new("Q", vec![]));
+    Ok(ops)
+}
+
+fn style_ops(style: &PathStyle) -> Result<Vec<ContentOp>, ShapeError> {
+    let mut ops = Vec::new();
+    if let Some(color) = style.stroke_color {
+        ops.push(ContentOp::new("RG", finite(&color)?));
+    }
+    if let Some(color) = style.fill_color {
+        ops.push(ContentOp::new("rg", finite(&color)?));
+    }
+    if let Some(width) = style.line_width {
+        if width < 0.0 {
+            return Err(ShapeError::NegativeWidth);
+        }
+        ops.push(ContentOp::new("w", finite(&[width])?));
+    }
+    if let Some(dash) = style.dash_pattern.as_ref().filter(|dash| !dash.is_empty()) {
+        if dash.iter().any(|&length| length < 0.0) || dash.iter().all(|&length| length == 0.0) {
+            return Err(ShapeError::InvalidDash);
+        }
+        ops.push(ContentOp::new("d", vec![finite(dash)?.into(), 0.into()]));
+    }
+    if let Some(cap) = style.line_cap {
+        let cap = match cap {
+            LineCap::Butt => 0,
+            LineCap::Round => 1,
+            LineCap::Square => 2,
+        };
+        ops.push(ContentOp::new("J", vec![cap.into()]));
+    }
+    if let Some(join) = style.line_join {
+        let join = match join {
+            LineJoin::Miter => 0,
+            LineJoin::Round => 1,
+            LineJoin::Bevel => 2,
+        };
+        ops.push(ContentOp::new("j", vec![join.into()]));
+    }
+    Ok(ops)
+}
+
+fn segment_ops(segment: &PathSegment, ops: &mut Vec<ContentOp>) -> Result<(), ShapeError> {
+    match segment {
+        PathSegment::Rectangle {
+            x,
+            y,
+            width,
+            height,
+        } => ops.push(ContentOp::new("re", finite(&[*x, *y, *width, *height])?)),
+        PathSegment::Ellipse { cx, cy, rx, ry } => {
+            finite(&[*cx, *cy, *rx, *ry])?;
+            let (kx, ky) = (rx * KAPPA, ry * KAPPA);
+            ops.push(point("m", [cx + rx, *cy]));
+            for curve in [
+                [cx + rx, cy + ky, cx + kx, cy + ry, *cx, cy + ry],
+                [cx - kx, cy + ry, cx - rx, cy + ky, cx - rx, *cy],
+                [cx - rx, cy - ky, cx - kx, cy - ry, *cx, cy - ry],
+                [cx + kx, cy - ry, cx + rx, cy - ky, cx + rx, *cy],
+            ] {
+                ops.push(ContentOp::new("c", curve.map(real).into()));
+            }
+            ops.push(ContentOp::new("h", vec![]));
+        }
+        PathSegment::Line { from, to } => {
+            finite(&[from[0], from[1], to[0], to[1]])?;
+            ops.push(point("m", *from));
+            ops.push(point("l", *to));
+        }
+        PathSegment::Polyline { points, closed } => {
+            let (first, rest) = split_points(points, "polyline")?;
+            ops.push(point("m", first));
+            ops.extend(rest.iter().map(|&p| point("l", p)));
+            if *closed {
+                ops.push(ContentOp::new("h", vec![]));
+            }
+        }
+        PathSegment::Freehand { points } => {
+            let (first, _) = split_points(points, "freehand path")?;
+            ops.push(point("m", first));
+            for i in 0..points.len() - 1 {
+                let previous = points[i.saturating_sub(1)];
+                let [from, to] = [points[i], points[i + 1]];
+                let next = points[(i + 2).min(points.len() - 1)];
+                ops.push(ContentOp::new(
+                    "c",
+                    [
+                        from[0] + (to[0] - previous[0]) / 6.0,
+                        from[1] + (to[1] - previous[1]) / 6.0,
+                        to[0] - (next[0] - from[0]) / 6.0,
+                        to[1] - (next[1] - from[1]) / 6.0,
+                        to[0],
+                        to[1],
+                    ]
+                    .map(real)
+                    .into(),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn split_points<'a>(
+    points: &'a [[f64; 2]],
+    shape: &'static str,
+) -> Result<([f64; 2], &'a [[f64; 2]]), ShapeError> {
+    if points.len() < 2 {
+        return Err(ShapeError::TooFewPoints(shape));
+    }
+    if points.iter().flatten().any(|value| !value.is_finite()) {
+        return Err(ShapeError::NotFinite);
+    }
+    Ok((points[0], &points[1..]))
+}
+
+fn point(operator: &str, [x, y]: [f64; 2]) -> ContentOp {
+    ContentOp::new(operator, vec![real(x), real(y)])
+}
+
+fn finite(values: &[f64]) -> Result<Vec<Object>, ShapeError> {
+    if values.iter().all(|value| value.is_finite()) {
+        Ok(values.iter().copied().map(real).collect())
+    } else {
+        Err(ShapeError::NotFinite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::content::encode_ops;
+
+    fn encoded(segments: &[PathSegment], style: &PathStyle) -> String {
+        String::from_utf8(encode_ops(&path_ops(segments, style).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn styles_become_state_operators_and_pick_the_paint_operator() {
+        let rectangle = PathSegment::Rectangle {
+            x: 10.0,
+            y: 20.0,
+            width: 30.0,
+            height: 40.0,
+        };
+        let style = PathStyle {
+            stroke_color: Some([1.0, 0.0, 0.0]),
+            fill_color: Some([0.0, 0.0, 1.0]),
+            line_width: Some(2.5),
+            dash_pattern: Some(vec![3.0, 1.0]),
+            line_cap: Some(LineCap::Round),
+            line_join: Some(LineJoin::Bevel),
+        };
+
+        assert_eq!(
+            encoded(std::slice::from_ref(&rectangle), &style),
+            "q\n1 0 0 RG\n0 0 1 rg\n2.5 w\n[3 1] 0 d\n1 J\n2 j\n10 20 30 40 re\nB\nQ\n"
+        );
+        assert_eq!(
+            encoded(&[rectangle], &PathStyle::default()),
+            "q\n10 20 30 40 re\nS\nQ\n"
+        );
+    }
+
+    #[test]
+    fn ellipses_and_freehand_strokes_are_curves_through_their_points() {
+        let ellipse = PathSegment::Ellipse {
+            cx: 0.0,
+            cy: 0.0,
+            rx: 2.0,
+            ry: 1.0,
+        };
+        let ops = path_ops(&[ellipse], &PathStyle::default()).unwrap();
+        let curves: Vec<_> = ops.iter().filter(|op| op.operator == "c").collect();
+        assert_eq!(curves.len(), 4);
+        assert_eq!(curves[0].numbers().unwrap()[4..], [0.0, 1.0]);
+        assert_eq!(curves[3].numbers().unwrap()[4..], [2.0, 0.0]);
+
+        let freehand = PathSegment::Freehand {
+            points: vec![[0.0, 0.0], [6.0, 6.0], [12.0, 0.0]],
+        };
+        let ops = path_ops(&[freehand], &PathStyle::default()).unwrap();
+        let ends: Vec<_> = ops
+            .iter()
+            .filter(|op| op.operator == "c")
+            .map(|op| op.numbers().unwrap()[4..].to_vec())
+            .collect();
+        assert_eq!(ends, [[6.0, 6.0], [12.0, 0.0]]);
+        // The tangent at the middle point runs parallel to the chord.
+        let second = ops.iter().filter(|op| op.operator == "c").nth(1).unwrap();
+        assert_eq!(second.numbers().unwrap()[..2], [8.0, 6.0]);
+    }
+
+    #[test]
+    fn degenerate_shapes_are_rejected() {
+        let style = PathStyle::default();
+        assert!(matches!(path_ops(&[], &style), Err(ShapeError::Empty)));
+        let single = PathSegment::Polyline {
+            points: vec![[0.0, 0.0]],
+            closed: false,
+        };
+        assert!(matches!(
+            path_ops(&[single], &style),
+            Err(ShapeError::TooFewPoints("polyline"))
+        ));
+        let line = PathSegment::Line {
+            from: [0.0, 0.0],
+            to: [f64::NAN, 1.0],
+        };
+        assert!(matches!(
+            path_ops(&[line], &style),
+            Err(ShapeError::NotFinite)
+        ));
+        let dashed = PathStyle {
+            dash_pattern: Some(vec![0.0, 0.0]),
+            ..Default::default()
+        };
+        let line = PathSegment::Line {
+            from: [0.0, 0.0],
+            to: [1.0, 1.0],
+        };
+        assert!(matches!(
+            path_ops(&[line], &dashed),
+            Err(ShapeError::InvalidDash)
+        ));
+    }
+}
@@ -0,0 +1,264 @@
+//! Builders for small in-memory PDFs used by the unit tests.
+
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream};
+
+use crate::pdf::fonts::subset::write_sfnt;
+use crate::pdf::loader::{self, LoadLimits};
+
+pub const SAMPLE_PDF: &[u8] = include_bytes!("../../../e2e/sample.pdf");
+
+pub struct TestPdf {
+    doc: Document,
+    pages_id: ObjectId,
+    kids: Vec<Object>,
+}
+
+impl TestPdf {
+    pub fn new() -> Self {
+        let mut doc = Document::with_version("1.7");
+        let pages_id = doc.new_object_id();
+        Self {
+            doc,
+            pages_id,
+            kids: Vec::new(),
+        }
+    }
+
+    /// The one-page "Hello world" fixture shared with the frontend.
+    pub fn sample() -> (Vec<u8>, Document) {
+        let doc = loader::parse_document(SAMPLE_PDF, &LoadLimits::default()).unwrap();
+        (SAMPLE_PDF.to_vec(), doc)
+    }
+
+    pub fn helvetica(&mut self) -> ObjectId {
+        self.doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        })
+    }
+
+    /// A 1×1-point grey image of `width`×`height` samples.
+    pub fn image(&mut self, width: i64, height: i64) -> ObjectId {
+        let dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => width,
+            "Height" => height,
+            "ColorSpace" => "DeviceGray",
+            "BitsPerComponent" => 8,
+        };
+        let data = vec![0x80; (width * height) as usize];
+        self.doc.add_object(Stream::new(dict, data))
+    }
+
+    pub fn stream(&mut self, content: &[u8]) -> ObjectId {
+        self.doc
+            .add_object(Stream::new(Dictionary::new(), content.to_vec()))
+    }
+
+    pub fn form(
+        &mut self,
+        content: &[u8],
+        bbox: [f64; 4],
+        matrix: [f64; 6],
+        resources: Dictionary,
+    ) -> ObjectId {
+        let dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Form",
+            "BBox" => bbox.iter().map(|v| Object::Real(*v as f32)).collect::<Vec<_>>(),
+            "Matrix" => matrix.iter().map(|v| Object::Real(*v as f32)).collect::<Vec<_>>(),
+            "Resources" => resources,
+        };
+        self.doc.add_object(Stream::new(dict, content.to_vec()))
+    }
+
+    pub fn set_form_resources(&mut self, form: ObjectId, resources: Dictionary) {
+        let stream = self
+            .doc
+            .get_object_mut(form)
+            .unwrap()
+            .as_stream_mut()
+            .unwrap();
+        stream.dict.set("Resources", resources);
+    }
+
+    /// Add a 595×842 page drawing `content` with `resources`.
+    pub fn page(&mut self, content: &[u8], resources: Dictionary) -> ObjectId {
+        let contents = self.stream(content);
+        self.page_with_contents(contents.into(), resources)
+    }
+
+    pub fn page_with_contents(&mut self, contents: Object, resources: Dictionary) -> ObjectId {
+        let page = self.doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => self.pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            "Contents" => contents,
+            "Resources" => resources,
+        });
+        self.kids.push(page.into());
+        page
+    }
+
+    /// Serialise the document and parse it back, as an upload would be.
+    pub fn finish(mut self) -> (Vec<u8>, Document) {
+        let count = self.kids.len() as i64;
+        self.doc.objects.insert(
+            self.pages_id,
+            dictionary! {
+                "Type" => "Pages",
+                "Kids" => self.kids,
+                "Count" => count,
+            }
+            .into(),
+        );
+        let catalog = self.doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => self.pages_id,
+        });
+        self.doc.trailer.set("Root", catalog);
+        let mut bytes = Vec::new();
+        self.doc.save_to(&mut bytes).unwrap();
+        let doc = loader::parse_document(&bytes, &LoadLimits::default()).unwrap();
+        (bytes, doc)
+    }
+}
+
+/// A tiny TrueType font, family "Test Sans", mapping `A`–`D` to glyphs
+/// 1–4 and space to glyph 5. `D` is a composite of `A`; every glyph but
+/// the space is 600 units wide on a 1000-unit em.
+pub fn test_font() -> Vec<u8> {
+    fn be16(out: &mut Vec<u8>, values: &[i32]) {
+        for value in values {
+            out.extend_from_slice(&(*value as u16).to_be_bytes());
+        }
+    }
+    fn simple_glyph(points: [(i32, i32); 3]) -> Vec<u8> {
+        let mut glyph = Vec::new();
+        be16(&mut glyph, &[1, 0, 0, 500, 700, 2, 0]);
+        glyph.extend_from_slice(&[1, 1, 1]);
+        let mut previous = (0, 0);
+        let mut ys = Vec::new();
+        for (x, y) in points {
+            be16(&mut glyph, &[x - previous.0]);
+            ys.push(y - previous.1);
+            previous = (x, y);
+        }
+        be16(&mut glyph, &ys);
+        glyph
+    }
+    let composite = {
+        let mut glyph = Vec::new();
+        // One component: glyph 1 at (0, 0), with word-sized xy arguments.
+        be16(&mut glyph, &[-1, 0, 0, 500, 700, 0x0003, 1, 0, 0]);
+        glyph
+    };
+    let glyphs = [
+        Vec::new(),
+        simple_glyph([(0, 0), (250, 700), (500, 0)]),
+        simple_glyph([(0, 0), (0, 700), (500, 350)]),
+        simple_glyph([(500, 0), (0, 350), (500, 700)]),
+        composite,
+        Vec::new(),
+    ];
+    let count = glyphs.len() as i32;
+
+    let mut glyf = Vec::new();
+    let mut loca = Vec::new();
+    for glyph in &glyphs {
+        loca.extend_from_slice(&(glyf.len() as u32).to_be_bytes());
+        glyf.extend_from_slice(glyph);
+    }
+    loca.extend_from_slice(&(glyf.len() as u32).to_be_bytes());
+
+    let mut head = Vec::new();
+    be16(&mut head, &[1, 0, 1, 0, 0, 0, 0x5F0F, 0x3CF5, 0, 1000]);
+    head.extend_from_slice(&[0; 16]);
+    be16(&mut head, &[0, -200, 600, 800, 0, 8, 2, 1, 0]);
+
+    let mut hhea = Vec::new();
+    be16(&mut hhea, &[1, 0, 800, -200, 0, 600, 0, 0, 500, 1, 0, 0]);
+    be16(&mut hhea, &[0, 0, 0, 0, 0, count]);
+
+    let mut maxp = Vec::new();
+    be16(
+        &mut maxp,
+        &[1, 0, count, 3, 1, 3, 1, 2, 0, 0, 0, 0, 0, 0, 1, 1],
+    );
+
+    let mut hmtx = Vec::new();
+    for gid in 0..count {
+        be16(&mut hmtx, &[if gid == 5 { 250 } else { 600 }, 0]);
+    }
+
+    let mut cmap = Vec::new();
+    be16(&mut cmap, &[0, 1, 3, 1, 0, 12]);
+    // Format 4 with segments for space, A–D and the closing 0xFFFF.
+    be16(&mut cmap, &[4, 40, 0, 6, 4, 1, 2]);
+    be16(&mut cmap, &[0x20, 0x44, 0xFFFF, 0]);
+    be16(&mut cmap, &[0x20, 0x41, 0xFFFF]);
+    be16(&mut cmap, &[5 - 0x20, 1 - 0x41, 1]);
+    be16(&mut cmap, &[0, 0, 0]);
+
+    let names = [
+        (1, "Test Sans"),
+        (2, "Regular"),
+        (4, "Test Sans"),
+        (6, "TestSans-Regular"),
+    ];
+    let mut name = Vec::new();
+    let mut strings = Vec::new();
+    be16(
+        &mut name,
+        &[0, names.len() as i32, 6 + 12 * names.len() as i32],
+    );
+    for (id, text) in names {
+        let encoded: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        be16(
+            &mut name,
+            &[3, 1, 0x409, id, encoded.len() as i32, strings.len() as i32],
+        );
+        strings.extend(encoded);
+    }
+    name.extend(strings);
+
+    let mut tables = vec![
+        (*b"cmap", cmap),
+        (*b"glyf", glyf),
+        (*b"head", head),
+        (*b"hhea", hhea),
+        (*b"hmtx", hmtx),
+        (*b"loca", loca),
+        (*b"maxp", maxp),
+        (*b"name", name),
+    ];
+    write_sfnt(0x0001_0000u32.to_be_bytes(), &mut tables)
+}
+
+/// An 8-bit RGBA PNG of `width`×`height` pixels.
+pub fn test_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(rgba).unwrap();
+    writer.finish().unwrap();
+    out
+}
+
+/// The headers of a baseline RGB JPEG of `width`×`height` pixels, enough
+/// for anything that only reads the frame header.
+pub fn test_jpeg(width: u16, height: u16) -> Vec<u8> {
+    let mut out = vec![0xFF, 0xD8];
+    out.extend_from_slice(&[0xFF, 0xE0, 0, 16]);
+    out.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+    out.extend_from_slice(&[0xFF, 0xC0, 0, 17, 8]);
+    out.extend_from_slice(&height.to_be_bytes());
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&[3, 1, 0x11, 0, 2, 0x11, 1, 3, 0x11, 1]);
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    out
+}
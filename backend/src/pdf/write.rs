@@ -1,11 +1,117 @@
-//! Incremental PDF writer placeholder.
+//! Incremental PDF writer.
 
-use anyhow::Result;
+use std::collections::BTreeSet;
 
-use crate::types::DocumentIR;
+use anyhow::{Context, Result};
+use lopdf::{Document, IncrementalDocument, ObjectId};
 
-/// Write a new revision of the PDF. Currently returns the original bytes
-/// untouched until the incremental writer is implemented.
-pub fn incremental_update(_ir: &DocumentIR, original: &[u8]) -> Result<Vec<u8>> {
-    Ok(original.to_vec())
+/// Append a revision to `original` holding the objects in `changed` as they
+/// are in `updated`. `previous` must be the document parsed from `original`.
+/// Afterwards `updated` has the trailer, cross-reference offset and highest
+/// object number of the appended revision, as if it had been parsed from
+/// the returned bytes, so it can be the `previous` of the next update.
+pub fn incremental_update(
+    original: &[u8],
+    previous: &Document,
+    updated: &mut Document,
+    changed: &BTreeSet<ObjectId>,
+) -> Result<Vec<u8>> {
+    // Only the trailer, cross-reference offset and type, and highest object
+    // number of the previous revision go into the update; its objects do not.
+    let mut prev = Document::new();
+    prev.version = previous.version.clone();
+    prev.trailer = previous.trailer.clone();
+    prev.reference_table.cross_reference_type = previous.reference_table.cross_reference_type;
+    prev.max_id = previous.max_id;
+    prev.xref_start = previous.xref_start;
+
+    let mut revision = IncrementalDocument::create_from(original.to_vec(), prev);
+    revision.new_document.version = previous.version.clone();
+    revision.new_document.max_id = updated.max_id;
+    for &id in changed {
+        let object = updated
+            .get_object(id)
+            .with_context(|| format!("changed object {} {} R is missing", id.0, id.1))?;
+        revision.new_document.objects.insert(id, object.clone());
+    }
+
+    let mut bytes = Vec::with_capacity(original.len() + 4096);
+    revision.save_to(&mut bytes)?;
+
+    let mut trailer = revision.new_document.trailer;
+    // lopdf drops `Prev` when reading a trailer and sets it when writing one.
+    trailer.remove(b"Prev");
+    updated.trailer = trailer;
+    // A cross-reference stream takes an object number of its own.
+    updated.max_id = revision.new_document.max_id;
+    updated.xref_start =
+        startxref(&bytes[original.len()..]).context("the update has no startxref")?;
+    Ok(bytes)
+}
+
+/// The offset given by the last `startxref` keyword in `bytes`.
+fn startxref(bytes: &[u8]) -> Option<usize> {
+    const KEYWORD: &[u8] = b"startxref";
+    let start = bytes.windows(KEYWORD.len()).rposition(|w| w == KEYWORD)? + KEYWORD.len();
+    let digits: String = bytes[start..]
+        .iter()
+        .skip_while(|b| b.is_ascii_whitespace())
+        .take_while(|b| b.is_ascii_digit())
+        .map(|&b| char::from(b))
+        .collect();
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::loader::{parse_document, LoadLimits};
+    use crate::pdf::testing::TestPdf;
+    use lopdf::{Object, Stream};
+
+    #[test]
+    fn appends_changed_objects_after_the_original_bytes() {
+        let (original, previous) = TestPdf::sample();
+        let mut updated = previous.clone();
+        let added = updated.add_object(Stream::new(Default::default(), b"q Q".to_vec()));
+        updated
+            .get_object_mut((4, 0))
+            .and_then(Object::as_stream_mut)
+            .unwrap()
+            .set_plain_content(b"BT /F1 12 Tf (Edited) Tj ET".to_vec());
+
+        let changed = BTreeSet::from([(4, 0), added]);
+        let bytes = incremental_update(&original, &previous, &mut updated, &changed).unwrap();
+
+        assert!(bytes.starts_with(&original));
+        let reparsed = parse_document(&bytes, &LoadLimits::default()).unwrap();
+        let content = reparsed.get_page_content((3, 0)).unwrap();
+        assert_eq!(content, b"BT /F1 12 Tf (Edited) Tj ET");
+        assert!(reparsed.get_object(added).is_ok());
+        let prev = format!("/Prev {}", previous.xref_start);
+        let appended = String::from_utf8_lossy(&bytes[original.len()..]);
+        assert!(appended.contains(&prev));
+        assert_eq!(updated.xref_start, reparsed.xref_start);
+        assert_eq!(updated.max_id, reparsed.max_id);
+        assert_eq!(updated.trailer, reparsed.trailer);
+    }
+
+    #[test]
+    fn updated_document_chains_the_next_update() {
+        let (original, previous) = TestPdf::sample();
+        let mut first = previous.clone();
+        let added = first.add_object(Stream::new(Default::default(), b"q Q".to_vec()));
+        let bytes =
+            incremental_update(&original, &previous, &mut first, &BTreeSet::from([added])).unwrap();
+
+        let mut second = first.clone();
+        let again = second.add_object(Object::Integer(7));
+        let chained =
+            incremental_update(&bytes, &first, &mut second, &BTreeSet::from([again])).unwrap();
+
+        let reparsed = parse_document(&chained, &LoadLimits::default()).unwrap();
+        assert!(reparsed.get_object(added).is_ok());
+        assert_eq!(reparsed.get_object(again).unwrap(), &Object::Integer(7));
+        assert_eq!(second.xref_start, reparsed.xref_start);
+    }
 }
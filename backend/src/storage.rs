@@ -0,0 +1,575 @@
+//! Where documents are kept between requests and across restarts.
+//!
+//! The server works on documents held in memory; a [`Storage`] is told
+//! about every change so it can keep a copy. [`MemoryStorage`] keeps
+//! nothing beyond the documents in memory, so they are lost on restart.
+//! [`FileStorage`] keeps a directory per document:
+//!
+//! ```text
+//! <root>/<doc_id>/original.pdf          the PDF as opened
+//! <root>/<doc_id>/revisions/<n>.bin     the incremental update of revision n
+//! <root>/<doc_id>/uploads/<upload_id>   images for insertImage
+//! <root>/<doc_id>/pages/<index>.json    cached page IR
+//! <root>/<doc_id>/meta.json             history, revision and upload ids
+//! ```
+//!
+//! Every file is written to a temporary name and renamed into place, so a
+//! crash leaves either the old or the new file. `meta.json` is written
+//! last and only names files already written.
+//!
+//! Writes go through a [`SaveQueue`], which runs them one at a time off
+//! the async runtime in the order they were queued.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::history::{History, Revision};
+use crate::types::{PageIR, PatchOperation};
+
+/// A document as it was last saved.
+#[derive(Debug)]
+pub struct StoredDocument {
+    /// The current bytes: the original with the applied revisions' updates.
+    pub pdf: Vec<u8>,
+    pub history: History,
+    pub revision: u64,
+    /// When the document was opened, in milliseconds since the Unix epoch.
+    pub created_at_ms: u64,
+    /// The capability token issued when the document was opened.
+    pub token: String,
+    /// The name of the file the document was opened from, if known.
+    pub filename: Option<String>,
+    pub uploads: HashMap<String, Vec<u8>>,
+    /// Page IR cached when the document was saved, by page index.
+    pub pages: BTreeMap<usize, PageIR>,
+}
+
+pub trait Storage: Send + Sync {
+    /// Ids of the documents stored.
+    fn list(&self) -> Result<Vec<String>>;
+
+    fn load(&self, doc_id: &str) -> Result<Option<StoredDocument>>;
+
+    /// Store a newly opened document.
+    fn create(
+        &self,
+        doc_id: &str,
+        pdf: &[u8],
+        created_at_ms: u64,
+        token: &str,
+        filename: Option<&str>,
+    ) -> Result<()>;
+
+    /// Forget a document.
+    fn delete(&self, doc_id: &str) -> Result<()>;
+
+    fn save_upload(&self, doc_id: &str, upload_id: &str, image: &[u8]) -> Result<()>;
+
+    /// Save the history after a batch, undo or redo changed `pdf` and the
+    /// pages in `touched`, whose cached IR no longer applies.
+    fn save_history(
+        &self,
+        doc_id: &str,
+        pdf: &[u8],
+        history: &History,
+        revision: u64,
+        touched: &BTreeSet<usize>,
+    ) -> Result<()>;
+
+    /// Cache the IR of some pages.
+    fn save_pages(&self, doc_id: &str, pages: &[&PageIR]) -> Result<()>;
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs storage writes one at a time on a thread of its own, in the order
+/// they were queued. Queue a write while holding the store lock, so writes
+/// reach the disk in the order the changes were made, and wait for it once
+/// the lock is released.
+#[derive(Debug, Clone)]
+pub struct SaveQueue {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Default for SaveQueue {
+    fn default() -> Self {
+        let (jobs, queued) = mpsc::channel::<Job>();
+        // The thread ends once every sender is dropped.
+        thread::Builder::new()
+            .name("storage".into())
+            .spawn(move || {
+                for job in queued {
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        tracing::error!("storage write panicked");
+                    }
+                }
+            })
+            .expect("spawning the storage thread");
+        Self { jobs }
+    }
+}
+
+impl SaveQueue {
+    /// Queue `write` for `doc_id`. A failure nobody waits for is logged.
+    pub fn queue(
+        &self,
+        doc_id: &str,
+        write: impl FnOnce() -> Result<()> + Send + 'static,
+    ) -> Saved {
+        let (done, saved) = oneshot::channel();
+        let doc_id = doc_id.to_string();
+        let job = Box::new(move || {
+            if let Err(Err(err)) = done.send(write()) {
+                tracing::error!(doc_id, error = ?err, "could not save document");
+            }
+        });
+        // Should the thread be gone, dropping the job resolves `Saved`.
+        let _ = self.jobs.send(job);
+        Saved(saved)
+    }
+}
+
+/// A queued write, which may be dropped instead of waited for.
+#[derive(Debug)]
+pub struct Saved(oneshot::Receiver<Result<()>>);
+
+impl Saved {
+    /// Wait until the write ran and return its result.
+    pub async fn wait(self) -> Result<()> {
+        self.0
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("the storage write did not run")))
+    }
+}
+
+/// Keeps documents only in the server's memory.
+#[derive(Debug, Default)]
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn load(&self, _doc_id: &str) -> Result<Option<StoredDocument>> {
+        Ok(None)
+    }
+
+    fn create(
+        &self,
+        _doc_id: &str,
+        _pdf: &[u8],
+        _created_at_ms: u64,
+        _token: &str,
+        _filename: Option<&str>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _doc_id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn save_upload(&self, _doc_id: &str, _upload_id: &str, _image: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn save_history(
+        &self,
+        _doc_id: &str,
+        _pdf: &[u8],
+        _history: &History,
+        _revision: u64,
+        _touched: &BTreeSet<usize>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn save_pages(&self, _doc_id: &str, _pages: &[&PageIR]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps each document in a directory under `root`.
+#[derive(Debug)]
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+/// `meta.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    revision: u64,
+    created_at_ms: u64,
+    token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    applied: usize,
+    revisions: Vec<StoredRevision>,
+    uploads: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredRevision {
+    revision: u64,
+    ops: Vec<PatchOperation>,
+    touched_pages: BTreeSet<usize>,
+}
+
+impl FileStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .with_context(|| format!("creating storage directory {}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    fn dir(&self, doc_id: &str) -> Result<PathBuf> {
+        // Ids come from request paths; keep them inside the root.
+        let plain = doc_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if doc_id.is_empty() || !plain {
+            bail!("invalid document id {doc_id:?}");
+        }
+        Ok(self.root.join(doc_id))
+    }
+
+    fn read_meta(dir: &Path) -> Result<Meta> {
+        let meta = fs::read(dir.join("meta.json"))?;
+        Ok(serde_json::from_slice(&meta)?)
+    }
+
+    fn write_meta(dir: &Path, meta: &Meta) -> Result<()> {
+        write_atomic(&dir.join("meta.json"), &serde_json::to_vec(meta)?)
+    }
+}
+
+impl Storage for FileStorage {
+    fn list(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            // A directory without meta.json was never finished.
+            if entry.path().join("meta.json").is_file() {
+                if let Some(id) = entry.file_name().to_str() {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn load(&self, doc_id: &str) -> Result<Option<StoredDocument>> {
+        let dir = self.dir(doc_id)?;
+        let meta = match Self::read_meta(&dir) {
+            Ok(meta) => meta,
+            Err(err) if is_not_found(&err) => return Ok(None),
+            Err(err) => return Err(err.context(format!("reading {doc_id}/meta.json"))),
+        };
+
+        let mut pdf = fs::read(dir.join("original.pdf"))?;
+        let mut undone = Vec::new();
+        let mut revisions = Vec::with_capacity(meta.revisions.len());
+        let mut end = pdf.len();
+        for (index, stored) in meta.revisions.into_iter().enumerate() {
+            let path = dir
+                .join("revisions")
+                .join(format!("{}.bin", stored.revision));
+            let update = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+            let start = end;
+            end += update.len();
+            if index < meta.applied {
+                pdf.extend_from_slice(&update);
+            } else {
+                undone.extend_from_slice(&update);
+            }
+            revisions.push(Revision {
+                revision: stored.revision,
+                ops: stored.ops,
+                update: start..end,
+                touched_pages: stored.touched_pages,
+            });
+        }
+
+        let mut uploads = HashMap::new();
+        for upload_id in meta.uploads {
+            let image = fs::read(dir.join("uploads").join(&upload_id))?;
+            uploads.insert(upload_id, image);
+        }
+
+        let mut pages = BTreeMap::new();
+        if let Ok(entries) = fs::read_dir(dir.join("pages")) {
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    // A cache entry that does not parse is only a cache miss.
+                    if let Ok(page) = serde_json::from_slice::<PageIR>(&fs::read(&path)?) {
+                        pages.insert(page.index, page);
+                    }
+                }
+            }
+        }
+
+        Ok(Some(StoredDocument {
+            pdf,
+            history: History::restore(revisions, meta.applied, undone),
+            revision: meta.revision,
+            created_at_ms: meta.created_at_ms,
+            token: meta.token,
+            filename: meta.filename,
+            uploads,
+            pages,
+        }))
+    }
+
+    fn create(
+        &self,
+        doc_id: &str,
+        pdf: &[u8],
+        created_at_ms: u64,
+        token: &str,
+        filename: Option<&str>,
+    ) -> Result<()> {
+        let dir = self.dir(doc_id)?;
+        fs::create_dir_all(&dir)?;
+        sync_dir(&self.root)?;
+        write_atomic(&dir.join("original.pdf"), pdf)?;
+        let meta = Meta {
+            created_at_ms,
+            token: token.to_string(),
+            filename: filename.map(str::to_string),
+            ..Meta::default()
+        };
+        Self::write_meta(&dir, &meta)
+    }
+
+    fn delete(&self, doc_id: &str) -> Result<()> {
+        let dir = self.dir(doc_id)?;
+        // Without meta.json the rest is ignored, should removing it fail
+        // halfway.
+        remove_if_exists(&dir.join("meta.json"))?;
+        match fs::remove_dir_all(&dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn save_upload(&self, doc_id: &str, upload_id: &str, image: &[u8]) -> Result<()> {
+        let dir = self.dir(doc_id)?;
+        let uploads = dir.join("uploads");
+        fs::create_dir_all(&uploads)?;
+        write_atomic(&uploads.join(upload_id), image)?;
+        let mut meta = Self::read_meta(&dir)?;
+        meta.uploads.insert(upload_id.to_string());
+        Self::write_meta(&dir, &meta)
+    }
+
+    fn save_history(
+        &self,
+        doc_id: &str,
+        pdf: &[u8],
+        history: &History,
+        revision: u64,
+        touched: &BTreeSet<usize>,
+    ) -> Result<()> {
+        let dir = self.dir(doc_id)?;
+        let revisions_dir = dir.join("revisions");
+        fs::create_dir_all(&revisions_dir)?;
+        let mut meta = Self::read_meta(&dir)?;
+
+        // Updates never change once written, so only new ones are written.
+        for (index, stored) in history.revisions().iter().enumerate() {
+            let path = revisions_dir.join(format!("{}.bin", stored.revision));
+            if !path.exists() {
+                write_atomic(&path, history.update(index, pdf))?;
+            }
+        }
+        for page in touched {
+            remove_if_exists(&dir.join("pages").join(format!("{page}.json")))?;
+        }
+
+        let previous: Vec<u64> = meta.revisions.iter().map(|r| r.revision).collect();
+        meta.revision = revision;
+        meta.applied = history.applied();
+        meta.revisions = history
+            .revisions()
+            .iter()
+            .map(|revision| StoredRevision {
+                revision: revision.revision,
+                ops: revision.ops.clone(),
+                touched_pages: revision.touched_pages.clone(),
+            })
+            .collect();
+        Self::write_meta(&dir, &meta)?;
+
+        // Revisions dropped from the history, once meta.json no longer
+        // names them.
+        for dropped in previous {
+            if !meta.revisions.iter().any(|r| r.revision == dropped) {
+                remove_if_exists(&revisions_dir.join(format!("{dropped}.bin")))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_pages(&self, doc_id: &str, pages: &[&PageIR]) -> Result<()> {
+        let dir = self.dir(doc_id)?.join("pages");
+        fs::create_dir_all(&dir)?;
+        for page in pages {
+            let path = dir.join(format!("{}.json", page.index));
+            write_atomic(&path, &serde_json::to_vec(page)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write `bytes` to `path` through a temporary file in the same directory,
+/// so readers see either the old contents or all of the new.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut name = path
+        .file_name()
+        .context("path has no file name")?
+        .to_os_string();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp, path).with_context(|| format!("writing {}", path.display()))?;
+    // The rename only survives a crash once the directory is synced too.
+    sync_dir(path.parent().context("path has no parent")?)
+}
+
+/// Flush the entries of directory `dir` to disk.
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("syncing {}", dir.display()))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("pdf-editor-storage-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn file_storage_round_trips_history_uploads_and_pages() {
+        let root = temp_root("round-trip");
+        let storage = FileStorage::new(&root).unwrap();
+        let mut pdf = b"%PDF base".to_vec();
+        storage
+            .create("doc-0001", &pdf, 1234, "secret", Some("report.pdf"))
+            .unwrap();
+        storage
+            .save_upload("doc-0001", "upload-0002", b"png")
+            .unwrap();
+
+        let mut history = History::default();
+        for (revision, update) in [(1, &b" one"[..]), (2, b" two")] {
+            let start = pdf.len();
+            pdf.extend_from_slice(update);
+            history.record(revision, Vec::new(), start..pdf.len(), BTreeSet::from([0]));
+        }
+        history.undo(&mut pdf);
+        let touched = BTreeSet::from([0]);
+        let page = PageIR {
+            index: 1,
+            width_pt: 100.0,
+            height_pt: 200.0,
+            objects: Vec::new(),
+        };
+        storage.save_pages("doc-0001", &[&page]).unwrap();
+        storage
+            .save_history("doc-0001", &pdf, &history, 3, &touched)
+            .unwrap();
+
+        assert_eq!(storage.list().unwrap(), ["doc-0001"]);
+        let mut stored = storage.load("doc-0001").unwrap().unwrap();
+        assert_eq!(stored.pdf, b"%PDF base one");
+        assert_eq!(stored.revision, 3);
+        assert_eq!(stored.created_at_ms, 1234);
+        assert_eq!(stored.token, "secret");
+        assert_eq!(stored.filename.as_deref(), Some("report.pdf"));
+        assert_eq!(stored.uploads["upload-0002"], b"png");
+        assert_eq!(stored.pages[&1], page);
+        assert_eq!(stored.history.applied(), 1);
+        assert!(stored.history.redo(&mut stored.pdf).is_some());
+        assert_eq!(stored.pdf, b"%PDF base one two");
+
+        // A new batch drops the undone revision's file.
+        let start = pdf.len();
+        pdf.extend_from_slice(b" three");
+        history.record(4, Vec::new(), start..pdf.len(), BTreeSet::new());
+        storage
+            .save_history("doc-0001", &pdf, &history, 4, &BTreeSet::new())
+            .unwrap();
+        assert!(!root.join("doc-0001/revisions/2.bin").exists());
+        assert_eq!(storage.load("doc-0001").unwrap().unwrap().pdf, pdf);
+
+        assert!(storage.load("doc-0404").unwrap().is_none());
+        assert!(storage.load("../etc").is_err());
+        storage.delete("doc-0001").unwrap();
+        assert!(storage.list().unwrap().is_empty());
+        assert!(storage.load("doc-0001").unwrap().is_none());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_queue_runs_writes_in_order_and_reports_their_result() {
+        let queue = SaveQueue::default();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let saves: Vec<Saved> = (0..3)
+            .map(|index| {
+                let order = order.clone();
+                queue.queue("doc-0001", move || {
+                    order.lock().unwrap().push(index);
+                    Ok(())
+                })
+            })
+            .collect();
+        let failed = queue.queue("doc-0001", || bail!("disk full"));
+        let panicked = queue.queue("doc-0001", || panic!("bug"));
+        let after = queue.queue("doc-0001", || Ok(()));
+
+        for saved in saves {
+            saved.wait().await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2]);
+        assert_eq!(failed.wait().await.unwrap_err().to_string(), "disk full");
+        assert!(panicked.wait().await.is_err());
+        after.wait().await.unwrap();
+    }
+}
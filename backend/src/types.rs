@@ -75,6 +75,7 @@ pub struct PageIR {
     pub index: usize,
     pub width_pt: f64,
     pub height_pt: f64,
+    /// In paint order: later objects are drawn over earlier ones.
     pub objects: Vec<PageObject>,
 }
 
@@ -95,7 +96,7 @@ pub struct DocumentIR {
     pub pages: Vec<PageIR>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "camelCase")]
 pub struct PatchTarget {
     pub page: usize,
@@ -124,6 +125,168 @@ pub enum PatchOperation {
         target: PatchTarget,
         style: StylePayload,
     },
+    #[serde(rename_all = "camelCase")]
+    Delete { target: PatchTarget },
+    #[serde(rename_all = "camelCase")]
+    Reorder {
+        target: PatchTarget,
+        direction: ReorderDirection,
+    },
+    #[serde(rename_all = "camelCase")]
+    Duplicate {
+        target: PatchTarget,
+        /// Page to draw the copy on; the target's page when absent.
+        #[serde(default)]
+        to_page: Option<usize>,
+        /// Applied in page space on top of where the target is drawn.
+        #[serde(rename = "offsetMatrixPt")]
+        offset_matrix_pt: [f64; 6],
+    },
+    #[serde(rename_all = "camelCase")]
+    InsertText {
+        page: usize,
+        /// Text matrix in page space; the baseline starts at its origin.
+        #[serde(rename = "matrixPt")]
+        matrix_pt: [f64; 6],
+        text: String,
+        font_family: String,
+        font_size: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        color: Option<[f64; 3]>,
+    },
+    #[serde(rename_all = "camelCase")]
+    InsertImage {
+        page: usize,
+        image: ImageSource,
+        /// Maps the unit square onto the image's place on the page.
+        #[serde(rename = "matrixPt")]
+        matrix_pt: [f64; 6],
+    },
+    #[serde(rename_all = "camelCase")]
+    ReplaceImage {
+        target: PatchTarget,
+        image: ImageSource,
+        #[serde(default)]
+        fit: ImageFit,
+        /// Replace the image everywhere the document draws it, rather
+        /// than only at this placement.
+        #[serde(default)]
+        all_placements: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    InsertPath {
+        page: usize,
+        /// Page-space geometry, drawn as a single path.
+        segments: Vec<PathSegment>,
+        #[serde(default)]
+        style: PathStyle,
+    },
+}
+
+/// One piece of an inserted path. Coordinates are in points, page space.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PathSegment {
+    Rectangle {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    },
+    Ellipse {
+        cx: f64,
+        cy: f64,
+        rx: f64,
+        ry: f64,
+    },
+    Line {
+        from: [f64; 2],
+        to: [f64; 2],
+    },
+    Polyline {
+        points: Vec<[f64; 2]>,
+        #[serde(default)]
+        closed: bool,
+    },
+    /// Points sampled from a pointer drag, smoothed into curves.
+    Freehand {
+        points: Vec<[f64; 2]>,
+    },
+}
+
+/// How an inserted path is painted. Without either colour it is stroked
+/// in black.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PathStyle {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stroke_color: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fill_color: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_width: Option<f64>,
+    /// Dash and gap lengths; empty or absent for a solid line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dash_pattern: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_cap: Option<LineCap>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_join: Option<LineJoin>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LineCap {
+    Butt,
+    Round,
+    Square,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LineJoin {
+    Miter,
+    Round,
+    Bevel,
+}
+
+/// Where `Reorder` moves an object among those painted by the same
+/// content stream.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ReorderDirection {
+    /// Over the next object.
+    Forward,
+    /// Under the previous object.
+    Backward,
+    /// Over every other object.
+    Front,
+    /// Under every other object.
+    Back,
+}
+
+/// How a replacement image fills the place of the old one.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageFit {
+    /// Stretch to the old image's box.
+    #[default]
+    Stretch,
+    /// Scale to fit inside the box, keeping the aspect ratio.
+    Fit,
+    /// Scale to cover the box, keeping the aspect ratio, and clip.
+    Fill,
+}
+
+/// PNG or JPEG bytes for an inserted image.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ImageSource {
+    /// An image stored earlier through `/api/image/:doc_id`.
+    #[serde(rename_all = "camelCase")]
+    Upload { upload_id: String },
+    /// Base64 data, optionally as a `data:` URL.
+    Inline { data: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -148,6 +311,35 @@ pub struct StylePayload {
     pub opacity_stroke: Option<f64>,
 }
 
+/// How much of the changed PDF a patch, undo or redo response carries,
+/// chosen with the `pdf` query parameter.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PdfMode {
+    /// Nothing; the client fetches `/api/pdf` when it needs the file.
+    None,
+    /// The whole PDF as a data URL in `updatedPdf`.
+    #[default]
+    Full,
+    /// Only the bytes that changed, in `pdfUpdate`.
+    Incremental,
+}
+
+/// How to turn the PDF a client had into the one the server now has:
+/// keep its first `offset` bytes and append `data`. Patches and redo
+/// append an incremental update; undo truncates and appends nothing.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PdfUpdate {
+    /// The revision the client's copy must be at for the update to apply.
+    pub base_revision: u64,
+    pub offset: usize,
+    /// The appended bytes, base64-encoded.
+    pub data: String,
+    /// The length of the PDF after the update.
+    pub size_bytes: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct PatchResponse {
@@ -155,11 +347,190 @@ pub struct PatchResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub updated_pdf: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub pdf_update: Option<PdfUpdate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub remap: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
+    /// Objects added by insert operations, in the order of the operations.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub created: Vec<PatchTarget>,
+    /// The document's revision after the request, also sent as the `ETag`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+}
+
+/// The patch batches applied to a document, for undo and redo.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryResponse {
+    /// Oldest first. Revisions past `applied` were undone and can be
+    /// redone.
+    pub revisions: Vec<RevisionSummary>,
+    pub applied: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionSummary {
+    pub ops: Vec<PatchOperation>,
+    pub touched_pages: Vec<usize>,
+    /// Size of the incremental update the batch appended.
+    pub update_bytes: usize,
+}
+
+/// One operation of a rejected patch batch and why it failed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchOpError {
+    /// Position of the operation in the batch.
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<PatchTarget>,
+    /// The kind of failure, as in [`ErrorResponse::code`].
+    pub code: String,
+    pub message: String,
+}
+
+/// The body of every error response.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    /// What went wrong, as a stable snake_case name such as
+    /// `unknown_target` or `limit_exceeded`.
+    pub code: String,
+    /// The same for people.
+    pub message: String,
+    /// The failing operation's position, when a patch batch failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub op_index: Option<usize>,
+    /// The object the failure concerns, when there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object_id: Option<String>,
+    /// Anything else the code calls for, such as which limit was exceeded.
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub details: serde_json::Value,
+}
+
+/// A message a collaborator sends over a document's WebSocket.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    /// Set the name shown to other collaborators.
+    Hello { name: String },
+    /// Replace this collaborator's selection.
+    Select { targets: Vec<PatchTarget> },
+    /// Apply a batch made against `base_revision`.
+    #[serde(rename_all = "camelCase")]
+    Patch {
+        /// Echoed back in the `applied` or `rejected` reply.
+        #[serde(default)]
+        request_id: Option<String>,
+        base_revision: u64,
+        ops: Vec<PatchOperation>,
+    },
+}
+
+/// A message the server sends over a document's WebSocket.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    /// Sent once on connect.
+    #[serde(rename_all = "camelCase")]
+    Welcome {
+        client_id: String,
+        revision: u64,
+        peers: Vec<Peer>,
+    },
+    /// Everyone connected, sent whenever someone joins, leaves, renames or
+    /// changes their selection.
+    Presence { peers: Vec<Peer> },
+    /// A batch was applied, by a collaborator or over HTTP.
+    #[serde(rename_all = "camelCase")]
+    Applied {
+        /// The collaborator that sent the batch; absent for HTTP patches.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        revision: u64,
+        ops: Vec<PatchOperation>,
+        touched_pages: Vec<usize>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        created: Vec<PatchTarget>,
+    },
+    /// A batch was undone or redone.
+    #[serde(rename_all = "camelCase")]
+    Stepped {
+        revision: u64,
+        redo: bool,
+        touched_pages: Vec<usize>,
+    },
+    /// This collaborator's batch was not applied. Sent only to them.
+    #[serde(rename_all = "camelCase")]
+    Rejected {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        revision: u64,
+        message: String,
+        /// Objects the batch edits that changed since its base revision.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        conflicts: Vec<PatchTarget>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        errors: Vec<PatchOpError>,
+    },
+    /// Messages were dropped because this collaborator fell behind; reload
+    /// the document at `revision`.
+    Resync { revision: u64 },
+    /// The document was closed; nothing more can be done with it.
+    Closed { reason: CloseReason },
+    /// A message could not be understood.
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CloseReason {
+    /// Someone sent `DELETE /api/doc/:doc_id`.
+    Deleted,
+    /// Nobody touched it for the idle timeout.
+    Idle,
+    /// Closed to make room for other documents.
+    Evicted,
+}
+
+/// What `GET /api/doc/:doc_id` reports about an open document.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentInfo {
+    pub doc_id: String,
+    /// Size of the current PDF.
+    pub size_bytes: usize,
+    /// Memory held for the document, counted against the quota: the PDF,
+    /// undone revisions and uploaded images.
+    pub stored_bytes: usize,
+    pub pages: usize,
+    pub revision: u64,
+    /// Milliseconds since the Unix epoch.
+    pub created_at_ms: u64,
+    pub last_touched_at_ms: u64,
+    /// The name of the file the document was opened from, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+}
+
+/// A collaborator connected to a document.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Peer {
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub selection: Vec<PatchTarget>,
 }
 
+#[cfg(test)]
 impl DocumentIR {
     pub fn sample() -> Self {
         Self {
@@ -1,5 +1,7 @@
 //! Axis-aligned bounding box utilities.
 
+use super::matrix::Matrix2D;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct BBox {
     pub min_x: f64,
@@ -25,6 +27,53 @@ impl BBox {
     pub fn height(&self) -> f64 {
         self.max_y - self.min_y
     }
+
+    /// Normalise a `[x0 y0 x1 y1]` rectangle whose corners may be swapped.
+    pub fn from_rect([x0, y0, x1, y1]: [f64; 4]) -> Self {
+        Self::new(x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1))
+    }
+
+    /// Smallest box containing every point, or `None` for no points.
+    pub fn from_points(points: impl IntoIterator<Item = (f64, f64)>) -> Option<Self> {
+        points.into_iter().fold(None, |acc, (x, y)| {
+            Some(match acc {
+                None => Self::new(x, y, x, y),
+                Some(b) => Self::new(
+                    b.min_x.min(x),
+                    b.min_y.min(y),
+                    b.max_x.max(x),
+                    b.max_y.max(y),
+                ),
+            })
+        })
+    }
+
+    pub fn to_array(self) -> [f64; 4] {
+        [self.min_x, self.min_y, self.max_x, self.max_y]
+    }
+
+    /// Bounds of this box after mapping its corners through `m`.
+    pub fn transform(self, m: Matrix2D) -> Self {
+        let corners = [
+            m.apply(self.min_x, self.min_y),
+            m.apply(self.max_x, self.min_y),
+            m.apply(self.min_x, self.max_y),
+            m.apply(self.max_x, self.max_y),
+        ];
+        Self::from_points(corners).expect("four corners")
+    }
+
+    /// Overlap of two boxes; collapses to zero size when they are disjoint.
+    pub fn intersect(self, other: Self) -> Self {
+        let min_x = self.min_x.max(other.min_x);
+        let min_y = self.min_y.max(other.min_y);
+        Self::new(
+            min_x,
+            min_y,
+            self.max_x.min(other.max_x).max(min_x),
+            self.max_y.min(other.max_y).max(min_y),
+        )
+    }
 }
 
 #[cfg(test)]
@@ -46,4 +95,19 @@ mod tests {
         assert_eq!(bbox.max_x, 10.0);
         assert_eq!(bbox.max_y, 8.0);
     }
+
+    #[test]
+    fn transform_bounds_rotated_corners() {
+        let rotate = Matrix2D::from_array([0.0, 1.0, -1.0, 0.0, 0.0, 0.0]);
+        let bbox = BBox::new(0.0, 0.0, 10.0, 5.0).transform(rotate);
+        assert_eq!(bbox, BBox::new(-5.0, 0.0, 0.0, 10.0));
+    }
+
+    #[test]
+    fn intersect_collapses_disjoint_boxes() {
+        let a = BBox::new(0.0, 0.0, 10.0, 10.0);
+        let b = BBox::new(20.0, 5.0, 30.0, 15.0);
+        let overlap = a.intersect(b);
+        assert_eq!(overlap.width(), 0.0);
+    }
 }
@@ -22,6 +22,24 @@ impl Matrix2D {
         }
     }
 
+    pub fn from_array([a, b, c, d, e, f]: [f64; 6]) -> Self {
+        Self { a, b, c, d, e, f }
+    }
+
+    pub fn to_array(self) -> [f64; 6] {
+        [self.a, self.b, self.c, self.d, self.e, self.f]
+    }
+
+    pub fn translate(e: f64, f: f64) -> Self {
+        Self {
+            e,
+            f,
+            ..Self::identity()
+        }
+    }
+
+    /// Compose two transforms: the result applies `other` first, then `self`.
+    /// PDF's `cm` operator updates the CTM as `ctm.multiply(m)`.
     pub fn multiply(self, other: Self) -> Self {
         Self {
             a: self.a * other.a + self.c * other.b,
@@ -32,6 +50,43 @@ impl Matrix2D {
             f: self.b * other.e + self.d * other.f + self.f,
         }
     }
+
+    pub fn determinant(self) -> f64 {
+        self.a * self.d - self.b * self.c
+    }
+
+    pub fn invert(self) -> Option<Self> {
+        let det = self.determinant();
+        if !det.is_finite() || det.abs() < 1e-12 {
+            return None;
+        }
+        let a = self.d / det;
+        let b = -self.b / det;
+        let c = -self.c / det;
+        let d = self.a / det;
+        Some(Self {
+            a,
+            b,
+            c,
+            d,
+            e: -(a * self.e + c * self.f),
+            f: -(b * self.e + d * self.f),
+        })
+    }
+
+    pub fn apply(self, x: f64, y: f64) -> (f64, f64) {
+        (
+            self.a * x + self.c * y + self.e,
+            self.b * x + self.d * y + self.f,
+        )
+    }
+
+    pub fn approx_eq(self, other: Self) -> bool {
+        self.to_array()
+            .iter()
+            .zip(other.to_array())
+            .all(|(l, r)| (l - r).abs() < 1e-6)
+    }
 }
 
 #[cfg(test)]
@@ -79,4 +134,21 @@ mod tests {
         assert_eq!(combined.e, 20.0);
         assert_eq!(combined.f, -10.0);
     }
+
+    #[test]
+    fn invert_undoes_the_transform() {
+        let m = Matrix2D::from_array([2.0, 1.0, -1.0, 3.0, 10.0, -4.0]);
+        let inverse = m.invert().expect("matrix is invertible");
+
+        assert!(m.multiply(inverse).approx_eq(Matrix2D::identity()));
+        let (x, y) = m.apply(5.0, 7.0);
+        let (bx, by) = inverse.apply(x, y);
+        assert!((bx - 5.0).abs() < 1e-9 && (by - 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn singular_matrices_have_no_inverse() {
+        let m = Matrix2D::from_array([1.0, 2.0, 2.0, 4.0, 0.0, 0.0]);
+        assert!(m.invert().is_none());
+    }
 }
@@ -1,2 +1,3 @@
 pub mod bbox;
 pub mod matrix;
+pub mod range;
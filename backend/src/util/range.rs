@@ -0,0 +1,71 @@
+//! `Range` request headers (RFC 9110, section 14).
+
+use std::ops::Range;
+
+/// A range that starts past the end of the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unsatisfiable;
+
+/// The part of a `len`-byte body that `header` asks for. `None` means the
+/// whole body: the header is malformed, names another unit or asks for
+/// several ranges, all of which a server may answer with the full body.
+pub fn parse(header: &str, len: u64) -> Result<Option<Range<u64>>, Unsatisfiable> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // `-n`: the last n bytes.
+        let Ok(suffix) = last.parse::<u64>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || len == 0 {
+            return Err(Unsatisfiable);
+        }
+        return Ok(Some(len.saturating_sub(suffix)..len));
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return Ok(None);
+    };
+    let end = match last {
+        "" => len,
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(len),
+            _ => return Ok(None),
+        },
+    };
+    if start >= len {
+        return Err(Unsatisfiable);
+    }
+    Ok(Some(start..end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_byte_ranges_are_clamped_to_the_body() {
+        assert_eq!(parse("bytes=0-99", 1000), Ok(Some(0..100)));
+        assert_eq!(parse("bytes=900-", 1000), Ok(Some(900..1000)));
+        assert_eq!(parse("bytes=900-5000", 1000), Ok(Some(900..1000)));
+        assert_eq!(parse("bytes=-100", 1000), Ok(Some(900..1000)));
+        assert_eq!(parse("bytes=-5000", 1000), Ok(Some(0..1000)));
+
+        assert_eq!(parse("bytes=1000-", 1000), Err(Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 1000), Err(Unsatisfiable));
+
+        // Anything else gets the whole body.
+        assert_eq!(parse("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse("bytes=9-5", 1000), Ok(None));
+        assert_eq!(parse("items=0-1", 1000), Ok(None));
+        assert_eq!(parse("bytes=a-b", 1000), Ok(None));
+    }
+}
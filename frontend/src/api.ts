@@ -1,12 +1,100 @@
-import type { DocumentIR, PatchOperation, PatchResponse } from './types';
+import type {
+  ClientMessage,
+  DocumentIR,
+  DocumentInfo,
+  ErrorResponse,
+  HistoryResponse,
+  PageIR,
+  PatchOpError,
+  PatchOperation,
+  PatchResponse,
+  PdfMode,
+  PdfUpdate,
+  ServerMessage,
+} from './types';
 
 const DEFAULT_BASE =
   ((typeof import.meta !== 'undefined' ? (import.meta as any).env?.VITE_API_BASE : undefined) as
     | string
     | undefined) ?? (globalThis as any).__API_BASE ?? 'http://localhost:8787';
 
+/**
+ * The last revision (ETag) seen for each document. Patches send it back as
+ * `If-Match`, so edits based on a document another tab has changed since
+ * are refused instead of overwriting that tab's work.
+ */
+const revisions = new Map<string, string>();
+
+/**
+ * The access token issued for each document opened here. The server
+ * refuses requests on a document that do not carry its token.
+ */
+const tokens = new Map<string, string>();
+
+function authHeaders(docId: string): Record<string, string> {
+  const token = tokens.get(docId);
+  return token ? { Authorization: `Bearer ${token}` } : {};
+}
+
+function rememberRevision(docId: string, response: Response) {
+  const tag = response.headers?.get('ETag');
+  if (tag) {
+    revisions.set(docId, tag);
+  }
+}
+
+/** What to tell the user for the error codes they can act on. */
+const ERROR_MESSAGES: Record<string, string> = {
+  encrypted: 'This PDF is password-protected. Remove the password and open it again.',
+  not_a_pdf: 'That file is not a PDF.',
+  invalid_pdf: 'That PDF is damaged and could not be read.',
+  limit_exceeded: 'The document is too large or too complex to edit here.',
+  quota_exceeded: 'The server has no room for this document right now.',
+  unsupported_filter: 'The page uses a compression format the editor cannot read.',
+  glyph_missing: 'The font has no glyph for a character in the text.',
+  font_not_found: 'No font of that family is available.',
+  unknown_target: 'The object no longer exists. Reload the document.',
+  unauthorized: 'This document was opened elsewhere; open it again.',
+  document_not_found: 'The document was closed on the server; open it again.',
+};
+
+/** A request the server answered with an error. */
+export class ApiError extends Error {
+  /** The error code, or `unknown` when the body was not an error envelope. */
+  readonly code: string;
+
+  constructor(
+    what: string,
+    readonly status: number,
+    readonly body?: ErrorResponse,
+  ) {
+    super(`${what} failed: ${status}${body ? ` (${body.message})` : ''}`);
+    this.code = body?.code ?? 'unknown';
+  }
+
+  /** A message for the user: a known explanation, else the server's own. */
+  get userMessage(): string {
+    return ERROR_MESSAGES[this.code] ?? this.body?.message ?? this.message;
+  }
+}
+
+async function readError(response: Response): Promise<ErrorResponse | undefined> {
+  try {
+    const body = await response.json();
+    return typeof body?.code === 'string' ? body : undefined;
+  } catch {
+    return undefined;
+  }
+}
+
+async function failure(what: string, response: Response): Promise<ApiError> {
+  return new ApiError(what, response.status, await readError(response));
+}
+
 export type OpenResponse = {
   docId: string;
+  /** Sent back automatically on every later request on the document. */
+  token: string;
 };
 
 export async function openDocument(file: File): Promise<OpenResponse> {
@@ -17,40 +105,231 @@ export async function openDocument(file: File): Promise<OpenResponse> {
     body: formData,
   });
   if (!response.ok) {
-    throw new Error(`open failed: ${response.status}`);
+    throw await failure('open', response);
+  }
+  const opened: OpenResponse = await response.json();
+  tokens.set(opened.docId, opened.token);
+  return opened;
+}
+
+export type UploadResponse = {
+  uploadId: string;
+};
+
+export async function uploadImage(docId: string, file: File): Promise<UploadResponse> {
+  const formData = new FormData();
+  formData.set('file', file);
+  const response = await fetch(`${DEFAULT_BASE}/api/image/${encodeURIComponent(docId)}`, {
+    method: 'POST',
+    headers: authHeaders(docId),
+    body: formData,
+  });
+  if (!response.ok) {
+    throw await failure('image upload', response);
   }
   return response.json();
 }
 
 export async function fetchIR(docId: string): Promise<DocumentIR> {
-  const response = await fetch(`${DEFAULT_BASE}/api/ir/${encodeURIComponent(docId)}`);
+  const response = await fetch(`${DEFAULT_BASE}/api/ir/${encodeURIComponent(docId)}`, {
+    headers: authHeaders(docId),
+  });
+  if (!response.ok) {
+    throw await failure('IR fetch', response);
+  }
+  rememberRevision(docId, response);
+  return response.json();
+}
+
+export async function fetchPageIR(docId: string, index: number): Promise<PageIR> {
+  const response = await fetch(
+    `${DEFAULT_BASE}/api/ir/${encodeURIComponent(docId)}/page/${index}`,
+    { headers: authHeaders(docId) },
+  );
   if (!response.ok) {
-    throw new Error(`IR fetch failed: ${response.status}`);
+    throw await failure('Page IR fetch', response);
   }
+  rememberRevision(docId, response);
   return response.json();
 }
 
+/** A write based on an older revision than the server's. Re-fetch the IR and retry. */
+export class StaleRevisionError extends ApiError {
+  readonly revision: number | undefined;
+
+  constructor(status: number, body?: ErrorResponse) {
+    super('patch', status, body);
+    this.revision = body?.details?.revision as number | undefined;
+    this.message = `document changed on the server (now at revision ${this.revision ?? 'unknown'})`;
+  }
+}
+
+/** A patch batch the server rejected as a whole, with what failed. */
+export class PatchRejectedError extends ApiError {
+  readonly errors: PatchOpError[];
+
+  constructor(status: number, body?: ErrorResponse) {
+    super('patch', status, body);
+    this.errors = (body?.details?.errors as PatchOpError[] | undefined) ?? [];
+  }
+}
+
+/**
+ * Apply a batch. The editor re-reads pages from the IR, so by default the
+ * response carries no PDF; pass `pdf` to get the whole file or only the
+ * bytes that changed (see `applyPdfUpdate`).
+ */
 export async function postPatch(
   docId: string,
   ops: PatchOperation[],
+  pdf: PdfMode = 'none',
 ): Promise<PatchResponse> {
-  const response = await fetch(`${DEFAULT_BASE}/api/patch/${encodeURIComponent(docId)}`, {
+  const headers: Record<string, string> = {
+    ...authHeaders(docId),
+    'Content-Type': 'application/json',
+  };
+  const revision = revisions.get(docId);
+  if (revision) {
+    headers['If-Match'] = revision;
+  }
+  const url = `${DEFAULT_BASE}/api/patch/${encodeURIComponent(docId)}?pdf=${pdf}`;
+  const response = await fetch(url, {
     method: 'POST',
-    headers: {
-      'Content-Type': 'application/json',
-    },
+    headers,
     body: JSON.stringify(ops),
   });
+  rememberRevision(docId, response);
+  if (!response.ok) {
+    const body = await readError(response);
+    if (response.status === 409) {
+      throw new StaleRevisionError(response.status, body);
+    }
+    throw new PatchRejectedError(response.status, body);
+  }
+  return response.json();
+}
+
+/** Take back the last patch batch. */
+export function undo(docId: string, pdf: PdfMode = 'none'): Promise<PatchResponse> {
+  return stepHistory('undo', docId, pdf);
+}
+
+/** Apply the last undone patch batch again. */
+export function redo(docId: string, pdf: PdfMode = 'none'): Promise<PatchResponse> {
+  return stepHistory('redo', docId, pdf);
+}
+
+async function stepHistory(
+  step: 'undo' | 'redo',
+  docId: string,
+  pdf: PdfMode,
+): Promise<PatchResponse> {
+  const url = `${DEFAULT_BASE}/api/${step}/${encodeURIComponent(docId)}?pdf=${pdf}`;
+  const response = await fetch(url, {
+    method: 'POST',
+    headers: authHeaders(docId),
+  });
+  rememberRevision(docId, response);
+  if (!response.ok) {
+    throw await failure(step, response);
+  }
+  return response.json();
+}
+
+/**
+ * The PDF after an incremental response, from the copy the client had.
+ * `pdf` must be the document at `update.baseRevision`; otherwise fetch it
+ * again with `downloadPdf`.
+ */
+export function applyPdfUpdate(pdf: Uint8Array, update: PdfUpdate): Uint8Array {
+  const data = Uint8Array.from(atob(update.data), (c) => c.charCodeAt(0));
+  const next = new Uint8Array(update.sizeBytes);
+  next.set(pdf.subarray(0, update.offset));
+  next.set(data, update.offset);
+  return next;
+}
+
+export async function fetchHistory(docId: string): Promise<HistoryResponse> {
+  const response = await fetch(`${DEFAULT_BASE}/api/history/${encodeURIComponent(docId)}`, {
+    headers: authHeaders(docId),
+  });
   if (!response.ok) {
-    throw new Error(`patch failed: ${response.status}`);
+    throw await failure('history fetch', response);
   }
   return response.json();
 }
 
+export async function fetchDocumentInfo(docId: string): Promise<DocumentInfo> {
+  const response = await fetch(`${DEFAULT_BASE}/api/doc/${encodeURIComponent(docId)}`, {
+    headers: authHeaders(docId),
+  });
+  if (!response.ok) {
+    throw await failure('document info fetch', response);
+  }
+  return response.json();
+}
+
+/** Close a document on the server, discarding it and its history. */
+export async function closeDocument(docId: string): Promise<void> {
+  const response = await fetch(`${DEFAULT_BASE}/api/doc/${encodeURIComponent(docId)}`, {
+    method: 'DELETE',
+    headers: authHeaders(docId),
+  });
+  revisions.delete(docId);
+  tokens.delete(docId);
+  if (!response.ok && response.status !== 404) {
+    throw await failure('close', response);
+  }
+}
+
+export type Collaboration = {
+  send(message: ClientMessage): void;
+  close(): void;
+};
+
+/**
+ * Join the collaboration room of a document. Every message from the server
+ * is passed to `onMessage`; messages sent before the socket opens are
+ * queued.
+ */
+export function collaborate(
+  docId: string,
+  onMessage: (message: ServerMessage) => void,
+): Collaboration {
+  // Browsers cannot set headers on the handshake, so the token goes in
+  // the query.
+  const token = tokens.get(docId);
+  const query = token ? `?token=${token}` : '';
+  const url = `${DEFAULT_BASE.replace(/^http/, 'ws')}/api/ws/${encodeURIComponent(docId)}${query}`;
+  const socket = new WebSocket(url);
+  const queued: string[] = [];
+  socket.addEventListener('open', () => {
+    queued.splice(0).forEach((text) => socket.send(text));
+  });
+  socket.addEventListener('message', (event) => {
+    onMessage(JSON.parse(event.data) as ServerMessage);
+  });
+  return {
+    send(message) {
+      const text = JSON.stringify(message);
+      if (socket.readyState === WebSocket.OPEN) {
+        socket.send(text);
+      } else {
+        queued.push(text);
+      }
+    },
+    close() {
+      socket.close();
+    },
+  };
+}
+
 export async function downloadPdf(docId: string): Promise<Blob> {
-  const response = await fetch(`${DEFAULT_BASE}/api/pdf/${encodeURIComponent(docId)}`);
+  const response = await fetch(`${DEFAULT_BASE}/api/pdf/${encodeURIComponent(docId)}`, {
+    headers: authHeaders(docId),
+  });
   if (!response.ok) {
-    throw new Error(`download failed: ${response.status}`);
+    throw await failure('download', response);
   }
   return response.blob();
 }
@@ -53,6 +53,7 @@ export type PageIR = {
   index: number;
   widthPt: number;
   heightPt: number;
+  /** In paint order: later objects are drawn over earlier ones. */
   objects: PageObject[];
 };
 
@@ -88,16 +89,214 @@ export type StylePatch = {
   };
 };
 
-export type PatchOperation = TransformPatch | EditTextPatch | StylePatch;
+export type DeletePatch = {
+  op: 'delete';
+  target: PatchTarget;
+};
+
+export type ReorderPatch = {
+  op: 'reorder';
+  target: PatchTarget;
+  direction: 'forward' | 'backward' | 'front' | 'back';
+};
+
+export type DuplicatePatch = {
+  op: 'duplicate';
+  target: PatchTarget;
+  /** Defaults to the target's page. */
+  toPage?: number;
+  offsetMatrixPt: [number, number, number, number, number, number];
+};
+
+export type InsertTextPatch = {
+  op: 'insertText';
+  page: number;
+  matrixPt: [number, number, number, number, number, number];
+  text: string;
+  fontFamily: string;
+  fontSize: number;
+  color?: [number, number, number];
+};
+
+export type ImageSource = { uploadId: string } | { data: string };
+
+export type InsertImagePatch = {
+  op: 'insertImage';
+  page: number;
+  image: ImageSource;
+  matrixPt: [number, number, number, number, number, number];
+};
+
+export type ReplaceImagePatch = {
+  op: 'replaceImage';
+  target: PatchTarget;
+  image: ImageSource;
+  fit?: 'stretch' | 'fit' | 'fill';
+  allPlacements?: boolean;
+};
+
+export type Point = [number, number];
+
+export type PathSegment =
+  | { type: 'rectangle'; x: number; y: number; width: number; height: number }
+  | { type: 'ellipse'; cx: number; cy: number; rx: number; ry: number }
+  | { type: 'line'; from: Point; to: Point }
+  | { type: 'polyline'; points: Point[]; closed?: boolean }
+  | { type: 'freehand'; points: Point[] };
+
+export type PathStyle = {
+  strokeColor?: [number, number, number];
+  fillColor?: [number, number, number];
+  lineWidth?: number;
+  dashPattern?: number[];
+  lineCap?: 'butt' | 'round' | 'square';
+  lineJoin?: 'miter' | 'round' | 'bevel';
+};
+
+export type InsertPathPatch = {
+  op: 'insertPath';
+  page: number;
+  segments: PathSegment[];
+  style?: PathStyle;
+};
+
+export type PatchOperation =
+  | TransformPatch
+  | EditTextPatch
+  | StylePatch
+  | DeletePatch
+  | ReorderPatch
+  | DuplicatePatch
+  | InsertTextPatch
+  | InsertImagePatch
+  | ReplaceImagePatch
+  | InsertPathPatch;
 
 export type PatchTarget = {
   page: number;
   id: string;
 };
 
+/**
+ * How much of the changed PDF a patch, undo or redo response carries:
+ * nothing, the whole file as a data URL in `updatedPdf`, or only the
+ * changed bytes in `pdfUpdate`.
+ */
+export type PdfMode = 'none' | 'full' | 'incremental';
+
+/** Keep the first `offset` bytes of the PDF at `baseRevision`, append `data`. */
+export type PdfUpdate = {
+  baseRevision: number;
+  offset: number;
+  /** Base64-encoded. */
+  data: string;
+  /** Length of the PDF after the update. */
+  sizeBytes: number;
+};
+
 export type PatchResponse = {
   ok: boolean;
   updatedPdf?: string;
+  pdfUpdate?: PdfUpdate;
   remap?: Record<string, { pdfRef: PdfRef }>;
   message?: string;
+  created?: PatchTarget[];
+  /** The document's revision after the request, also sent as the `ETag`. */
+  revision?: number;
+};
+
+export type RevisionSummary = {
+  ops: PatchOperation[];
+  touchedPages: number[];
+  /** Size of the incremental update the batch appended. */
+  updateBytes: number;
+};
+
+export type HistoryResponse = {
+  /** Oldest first; those past `applied` were undone and can be redone. */
+  revisions: RevisionSummary[];
+  applied: number;
+};
+
+export type PatchOpError = {
+  /** Position of the failing operation in the batch. */
+  index: number;
+  target?: PatchTarget;
+  /** The kind of failure, as in `ErrorResponse.code`. */
+  code: string;
+  message: string;
+};
+
+/** The body of every error response from the server. */
+export type ErrorResponse = {
+  /** What went wrong, such as `unknown_target` or `limit_exceeded`. */
+  code: string;
+  message: string;
+  /** The failing operation's position, when a patch batch failed. */
+  opIndex?: number;
+  /** The object the failure concerns, when there is one. */
+  objectId?: string;
+  /**
+   * Extra facts depending on the code: `{ limit, max }` for exceeded
+   * limits, `{ revision }` for stale writes, `{ errors }` for rejected
+   * patch batches.
+   */
+  details?: Record<string, unknown>;
+};
+
+export type Peer = {
+  clientId: string;
+  name?: string;
+  selection: PatchTarget[];
+};
+
+/** Sent to the server over the document's WebSocket. */
+export type ClientMessage =
+  | { type: 'hello'; name: string }
+  | { type: 'select'; targets: PatchTarget[] }
+  | { type: 'patch'; requestId?: string; baseRevision: number; ops: PatchOperation[] };
+
+/** Received from the server over the document's WebSocket. */
+export type ServerMessage =
+  | { type: 'welcome'; clientId: string; revision: number; peers: Peer[] }
+  | { type: 'presence'; peers: Peer[] }
+  | {
+      type: 'applied';
+      /** Absent when the batch was posted over HTTP. */
+      clientId?: string;
+      requestId?: string;
+      revision: number;
+      ops: PatchOperation[];
+      touchedPages: number[];
+      created?: PatchTarget[];
+    }
+  | { type: 'stepped'; revision: number; redo: boolean; touchedPages: number[] }
+  | {
+      type: 'rejected';
+      requestId?: string;
+      revision: number;
+      message: string;
+      /** Objects the batch edits that someone else changed first. */
+      conflicts?: PatchTarget[];
+      errors?: PatchOpError[];
+    }
+  | { type: 'resync'; revision: number }
+  | { type: 'closed'; reason: CloseReason }
+  | { type: 'error'; message: string };
+
+export type CloseReason = 'deleted' | 'idle' | 'evicted';
+
+export type DocumentInfo = {
+  docId: string;
+  /** Size of the current PDF. */
+  sizeBytes: number;
+  /** Memory held for the document: the PDF, undone revisions and uploads. */
+  storedBytes: number;
+  pages: number;
+  revision: number;
+  /** Milliseconds since the Unix epoch. */
+  createdAtMs: number;
+  lastTouchedAtMs: number;
+  /** Name of the file the document was opened from, when known. */
+  filename?: string;
 };
@@ -1,12 +1,25 @@
 // @ts-nocheck
 import assert from 'node:assert/strict';
 import test from 'node:test';
-import { downloadPdf, fetchIR, openDocument, postPatch } from '../src/api';
+import {
+  ApiError,
+  applyPdfUpdate,
+  downloadPdf,
+  fetchIR,
+  fetchPageIR,
+  openDocument,
+  PatchRejectedError,
+  postPatch,
+  StaleRevisionError,
+  undo,
+  uploadImage,
+} from '../src/api';
 
-test('openDocument posts multipart data', async (t) => {
+test('openDocument posts multipart data and sends the token afterwards', async (t) => {
   const file = new File(['test'], 'file.pdf', { type: 'application/pdf' });
   const calls: any[] = [];
-  const response = { ok: true, status: 200, json: async () => ({ docId: 'doc-0001' }) };
+  const opened = { docId: 'doc-0001', token: 'c0ffee' };
+  const response = { ok: true, status: 200, json: async () => opened };
 
   const restore = stubFetch((input, init) => {
     calls.push({ input, init });
@@ -15,9 +28,37 @@ test('openDocument posts multipart data', async (t) => {
   t.after(() => restore());
 
   const result = await openDocument(file);
-  assert.deepEqual(result, { docId: 'doc-0001' });
+  assert.deepEqual(result, opened);
   assert.equal(calls[0]?.init?.method, 'POST');
   assert.ok(calls[0]?.init?.body instanceof FormData);
+
+  await fetchPageIR('doc-0001', 0);
+  assert.equal(calls[1]?.init?.headers?.Authorization, 'Bearer c0ffee');
+});
+
+test('uploadImage posts the image for a document', async (t) => {
+  const file = new File(['png'], 'logo.png', { type: 'image/png' });
+  const calls: any[] = [];
+  const restore = stubFetch((input, init) => {
+    calls.push({ input, init });
+    return Promise.resolve({ ok: true, status: 200, json: async () => ({ uploadId: 'upload-0002' }) } as any);
+  });
+  t.after(() => restore());
+
+  assert.deepEqual(await uploadImage('doc-1', file), { uploadId: 'upload-0002' });
+  assert.match(String(calls[0]?.input), /\/api\/image\/doc-1$/);
+  assert.ok(calls[0]?.init?.body instanceof FormData);
+});
+
+test('openDocument reports the error code with a message for the user', async (t) => {
+  const body = { code: 'encrypted', message: 'the document is encrypted' };
+  const restore = stubFetch(() => Promise.resolve({ ok: false, status: 422, json: async () => body } as any));
+  t.after(() => restore());
+
+  await assert.rejects(
+    () => openDocument(new File(['%PDF-'], 'locked.pdf')),
+    (err) => err instanceof ApiError && err.code === 'encrypted' && /password/.test(err.userMessage),
+  );
 });
 
 test('fetchIR throws on non-ok responses', async (t) => {
@@ -26,6 +67,19 @@ test('fetchIR throws on non-ok responses', async (t) => {
   await assert.rejects(() => fetchIR('missing'), /500/);
 });
 
+test('fetchPageIR requests a single page', async (t) => {
+  const page = { index: 2, widthPt: 595, heightPt: 842, objects: [] };
+  const calls: any[] = [];
+  const restore = stubFetch((input) => {
+    calls.push(input);
+    return Promise.resolve({ ok: true, status: 200, json: async () => page } as any);
+  });
+  t.after(() => restore());
+
+  assert.deepEqual(await fetchPageIR('doc 1', 2), page);
+  assert.match(String(calls[0]), /\/api\/ir\/doc%201\/page\/2$/);
+});
+
 test('postPatch serialises payloads as JSON', async (t) => {
   const ops = [{ op: 'setStyle', target: { page: 0, id: 't:1' }, style: { fillColor: [1, 0, 0] } }];
   const calls: any[] = [];
@@ -41,6 +95,82 @@ test('postPatch serialises payloads as JSON', async (t) => {
   assert.equal(calls[0]?.init?.body, JSON.stringify(ops));
 });
 
+test('postPatch asks for incremental updates that apply to the local copy', async (t) => {
+  const calls: any[] = [];
+  const update = { baseRevision: 1, offset: 3, data: btoa('def'), sizeBytes: 6 };
+  const restore = stubFetch((input, init) => {
+    calls.push({ input, init });
+    return Promise.resolve({ ok: true, status: 200, json: async () => ({ ok: true, pdfUpdate: update }) } as any);
+  });
+  t.after(() => restore());
+
+  await postPatch('doc-1', []);
+  assert.match(String(calls[0]?.input), /\/api\/patch\/doc-1\?pdf=none$/);
+  const result = await postPatch('doc-1', [], 'incremental');
+  assert.match(String(calls[1]?.input), /\?pdf=incremental$/);
+
+  const before = new TextEncoder().encode('abcXYZ');
+  const after = applyPdfUpdate(before, result.pdfUpdate!);
+  assert.equal(new TextDecoder().decode(after), 'abcdef');
+  const undone = applyPdfUpdate(after, { baseRevision: 2, offset: 3, data: '', sizeBytes: 3 });
+  assert.equal(new TextDecoder().decode(undone), 'abc');
+});
+
+test('postPatch surfaces per-operation errors of rejected batches', async (t) => {
+  const errors = [
+    { index: 1, target: { page: 0, id: 't:0' }, code: 'wrong_kind', message: 't:0 is a text object, not image' },
+  ];
+  const body = { code: 'wrong_kind', message: 'operation 1 failed', opIndex: 1, objectId: 't:0', details: { errors } };
+  const restore = stubFetch(() => Promise.resolve({ ok: false, status: 422, json: async () => body } as any));
+  t.after(() => restore());
+
+  await assert.rejects(
+    () => postPatch('doc-1', []),
+    (err) =>
+      err instanceof PatchRejectedError &&
+      err.status === 422 &&
+      err.code === 'wrong_kind' &&
+      err.body.objectId === 't:0' &&
+      err.errors[0].index === 1,
+  );
+});
+
+test('postPatch sends the revision it last saw and reports stale ones', async (t) => {
+  const calls: any[] = [];
+  const responses = [
+    { ok: true, status: 200, headers: new Headers({ ETag: '"3"' }), json: async () => ({ pages: [] }) },
+    { ok: false, status: 409, headers: new Headers({ ETag: '"4"' }), json: async () => ({ code: 'stale_revision', message: 'changed', details: { revision: 4 } }) },
+  ];
+  const restore = stubFetch((input, init) => {
+    calls.push({ input, init });
+    return Promise.resolve(responses.shift() as any);
+  });
+  t.after(() => restore());
+
+  await fetchIR('doc-rev');
+  await assert.rejects(
+    () => postPatch('doc-rev', []),
+    (err) => err instanceof StaleRevisionError && err.revision === 4,
+  );
+  assert.equal(calls[1]?.init?.headers?.['If-Match'], '"3"');
+});
+
+test('undo posts to the undo route and reports nothing to undo', async (t) => {
+  const calls: any[] = [];
+  let status = 200;
+  const restore = stubFetch((input, init) => {
+    calls.push({ input, init });
+    return Promise.resolve({ ok: status === 200, status, json: async () => ({ ok: true }) } as any);
+  });
+  t.after(() => restore());
+
+  assert.deepEqual(await undo('doc-1'), { ok: true });
+  assert.match(String(calls[0]?.input), /\/api\/undo\/doc-1\?pdf=none$/);
+  assert.equal(calls[0]?.init?.method, 'POST');
+  status = 409;
+  await assert.rejects(() => undo('doc-1'), /409/);
+});
+
 test('downloadPdf returns the response blob', async (t) => {
   const blob = new Blob(['pdf'], { type: 'application/pdf' });
   const restore = stubFetch(() => Promise.resolve({ ok: true, status: 200, blob: async () => blob } as any));